@@ -0,0 +1,82 @@
+// Chunk framing throughput: whole CBOR frames against a binary header
+// followed by the raw data, sent over an in-memory pipe
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use fastdrop::network::{self, ChunkBuffers, ChunkWire, DEFAULT_ACK_WINDOW};
+use fastdrop::protocol::{ChunkAck, CompressionAlgo, Frame, CHUNK_SIZE};
+use fastdrop::stats::TransferStats;
+use fastdrop::transfer::ChunkReader;
+use futures::AsyncWriteExt;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use tokio_util::compat::TokioAsyncReadCompatExt;
+use tokio_util::sync::CancellationToken;
+
+const FILE_SIZE: usize = 64 * 1024 * 1024;
+
+/// A file of `FILE_SIZE` bytes that don't compress, in the target's scratch directory
+fn source_file() -> PathBuf {
+    let path = Path::new(env!("CARGO_TARGET_TMPDIR")).join("framing.bin");
+    let mut state = 0x9e37_79b9_7f4a_7c15u64;
+    let data: Vec<u8> = (0..FILE_SIZE)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as u8
+        })
+        .collect();
+    std::fs::write(&path, data).unwrap();
+    path
+}
+
+/// Send the file through a pipe to a reader that acks as a receiver would,
+/// without hashing or writing anything so only the framing is measured.
+/// Chunk buffers are handed back as the receiver does once a chunk is written.
+async fn send_through_pipe(path: &Path, binary: bool) {
+    let (sending, receiving) = tokio::io::duplex(1024 * 1024);
+    let (mut sending, mut receiving) = (sending.compat(), receiving.compat());
+    let wire = ChunkWire { window: DEFAULT_ACK_WINDOW, retransmit: false, binary };
+
+    let send = async {
+        let mut chunks = ChunkReader::open(path, 0, None, None, CompressionAlgo::None, None, Some(CHUNK_SIZE))
+            .await
+            .unwrap();
+        let stats = Mutex::new(TransferStats::new());
+        network::send_chunks_over_stream(&mut sending, &mut chunks, None, wire, &CancellationToken::new(), &stats, None)
+            .await
+            .unwrap();
+        sending.close().await.unwrap();
+    };
+    let receive = async {
+        let max_len = network::chunk_frame_limit(CHUNK_SIZE);
+        let buffers = ChunkBuffers::default();
+        while let Some(frame) = network::read_chunk_frame(&mut receiving, max_len, &buffers).await.unwrap() {
+            let Frame::Chunk(chunk) = frame else { unreachable!() };
+            if network::ack_due(&chunk) {
+                let ack = ChunkAck { file_index: 0, chunk_number: chunk.chunk_number, success: true };
+                network::write_ack(&mut receiving, ack).await.unwrap();
+            }
+            buffers.give(chunk.data);
+        }
+    };
+    tokio::join!(send, receive);
+}
+
+fn chunk_framing(c: &mut Criterion) {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let path = source_file();
+
+    let mut group = c.benchmark_group("chunk_framing");
+    group.throughput(Throughput::Bytes(FILE_SIZE as u64));
+    group.sample_size(10);
+    for (name, binary) in [("cbor", false), ("binary", true)] {
+        group.bench_with_input(BenchmarkId::from_parameter(name), &binary, |b, &binary| {
+            b.iter(|| runtime.block_on(send_through_pipe(&path, binary)));
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, chunk_framing);
+criterion_main!(benches);
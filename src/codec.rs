@@ -0,0 +1,305 @@
+// Compression codecs shared by the chunk pipeline and the compression benchmark
+
+use anyhow::{Context, Result};
+use flate2::read::ZlibDecoder;
+use flate2::write::ZlibEncoder;
+use flate2::Compression;
+use std::io::{self, Read, Write};
+
+/// Default zstd level for compressed transfers. The compression benchmark
+/// shows the low levels keep up with LAN speeds while the higher ones become
+/// the bottleneck for little extra ratio.
+pub const DEFAULT_ZSTD_LEVEL: i32 = 3;
+
+/// Compression library
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Codec {
+    Zlib,
+    Zstd,
+}
+
+impl std::str::FromStr for Codec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "zlib" => Ok(Codec::Zlib),
+            "zstd" => Ok(Codec::Zstd),
+            other => Err(format!("Unknown codec '{}' (expected zlib or zstd)", other)),
+        }
+    }
+}
+
+impl std::fmt::Display for Codec {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Codec::Zlib => write!(f, "zlib"),
+            Codec::Zstd => write!(f, "zstd"),
+        }
+    }
+}
+
+impl Codec {
+    /// Compress `data` in one go at `level` (0-9 for zlib, zstd's own range for zstd)
+    pub fn compress(self, data: &[u8], level: i32) -> Result<Vec<u8>> {
+        match self {
+            Codec::Zlib => {
+                let level = level.clamp(0, 9) as u32;
+                let mut encoder = ZlibEncoder::new(Vec::new(), Compression::new(level));
+                encoder.write_all(data).context("zlib compression failed")?;
+                encoder.finish().context("zlib compression failed")
+            }
+            Codec::Zstd => zstd::bulk::compress(data, level).context("zstd compression failed"),
+        }
+    }
+
+    /// Decompress `data`, failing if it expands past `max_len` bytes
+    ///
+    /// The limit keeps a malicious peer from making us allocate without bound.
+    pub fn decompress(self, data: &[u8], max_len: usize) -> Result<Vec<u8>> {
+        match self {
+            Codec::Zlib => {
+                let mut decoder = ZlibDecoder::new(data).take(max_len as u64 + 1);
+                let mut decompressed = Vec::new();
+                decoder
+                    .read_to_end(&mut decompressed)
+                    .context("zlib decompression failed")?;
+                if decompressed.len() > max_len {
+                    anyhow::bail!("Decompressed data exceeds {} bytes", max_len);
+                }
+                Ok(decompressed)
+            }
+            Codec::Zstd => zstd::bulk::decompress(data, max_len).context("zstd decompression failed"),
+        }
+    }
+}
+
+/// Compress with zstd, the codec used for transfers
+pub fn compress(data: &[u8], level: i32) -> Result<Vec<u8>> {
+    Codec::Zstd.compress(data, level)
+}
+
+/// Decompress zstd data of at most `max_len` bytes
+pub fn decompress(data: &[u8], max_len: usize) -> Result<Vec<u8>> {
+    Codec::Zstd.decompress(data, max_len)
+}
+
+/* ========== Reusable Contexts ========== */
+
+/// zstd compressor for many independent chunks, optionally primed with a dictionary
+pub struct Compressor {
+    compressor: zstd::bulk::Compressor<'static>,
+}
+
+impl Compressor {
+    pub fn new(level: i32, dictionary: Option<&[u8]>) -> Result<Self> {
+        let compressor = match dictionary {
+            Some(dictionary) => zstd::bulk::Compressor::with_dictionary(level, dictionary),
+            None => zstd::bulk::Compressor::new(level),
+        }
+        .context("Failed to create zstd compressor")?;
+        Ok(Self { compressor })
+    }
+
+    pub fn compress(&mut self, data: &[u8]) -> Result<Vec<u8>> {
+        self.compressor.compress(data).context("zstd compression failed")
+    }
+}
+
+/// Counterpart of `Compressor`, which must use the same dictionary
+pub struct Decompressor {
+    decompressor: zstd::bulk::Decompressor<'static>,
+}
+
+impl Decompressor {
+    pub fn new(dictionary: Option<&[u8]>) -> Result<Self> {
+        let decompressor = match dictionary {
+            Some(dictionary) => zstd::bulk::Decompressor::with_dictionary(dictionary),
+            None => zstd::bulk::Decompressor::new(),
+        }
+        .context("Failed to create zstd decompressor")?;
+        Ok(Self { decompressor })
+    }
+
+    /// Decompress `data`, failing if it expands past `max_len` bytes
+    pub fn decompress(&mut self, data: &[u8], max_len: usize) -> Result<Vec<u8>> {
+        self.decompressor
+            .decompress(data, max_len)
+            .context("zstd decompression failed")
+    }
+}
+
+/// Train a zstd dictionary of at most `max_size` bytes on sample data
+pub fn train_dictionary(samples: &[Vec<u8>], max_size: usize) -> Result<Vec<u8>> {
+    zstd::dict::from_samples(samples, max_size).context("Failed to train zstd dictionary")
+}
+
+/* ========== Streaming ========== */
+
+/// One zstd stream spanning the chunks of a file
+///
+/// Each chunk is flushed as it is written, so its output decodes on its own
+/// given everything before it, while later chunks can still refer back to
+/// earlier ones for a better ratio than compressing chunks independently.
+pub struct StreamEncoder {
+    encoder: zstd::stream::write::Encoder<'static, Vec<u8>>,
+}
+
+impl StreamEncoder {
+    pub fn new(level: i32, dictionary: Option<&[u8]>) -> Result<Self> {
+        let encoder = match dictionary {
+            Some(dictionary) => zstd::stream::write::Encoder::with_dictionary(Vec::new(), level, dictionary),
+            None => zstd::stream::write::Encoder::new(Vec::new(), level),
+        }
+        .context("Failed to create zstd encoder")?;
+        Ok(Self { encoder })
+    }
+
+    /// Compress the next chunk of the file
+    pub fn compress_chunk(&mut self, data: &[u8]) -> Result<Vec<u8>> {
+        self.encoder.write_all(data).context("zstd compression failed")?;
+        self.encoder.flush().context("zstd compression failed")?;
+        Ok(std::mem::take(self.encoder.get_mut()))
+    }
+}
+
+/// Receiving end of a `StreamEncoder`, fed the chunks in the order they were compressed
+pub struct StreamDecoder {
+    decoder: zstd::stream::write::Decoder<'static, LimitedBuffer>,
+}
+
+impl StreamDecoder {
+    pub fn new(dictionary: Option<&[u8]>) -> Result<Self> {
+        let decoder = match dictionary {
+            Some(dictionary) => zstd::stream::write::Decoder::with_dictionary(LimitedBuffer::default(), dictionary),
+            None => zstd::stream::write::Decoder::new(LimitedBuffer::default()),
+        }
+        .context("Failed to create zstd decoder")?;
+        Ok(Self { decoder })
+    }
+
+    /// Decompress the next chunk, failing if it expands past `max_len` bytes
+    pub fn decompress_chunk(&mut self, data: &[u8], max_len: usize) -> Result<Vec<u8>> {
+        self.decoder.get_mut().limit = max_len;
+        self.decoder.write_all(data).context("zstd decompression failed")?;
+        self.decoder.flush().context("zstd decompression failed")?;
+        Ok(std::mem::take(&mut self.decoder.get_mut().buf))
+    }
+}
+
+/// Output buffer that refuses to grow past `limit`
+#[derive(Default)]
+struct LimitedBuffer {
+    buf: Vec<u8>,
+    limit: usize,
+}
+
+impl Write for LimitedBuffer {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        if self.buf.len() + data.len() > self.limit {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Decompressed chunk exceeds {} bytes", self.limit),
+            ));
+        }
+        self.buf.extend_from_slice(data);
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample() -> Vec<u8> {
+        b"Lorem ipsum dolor sit amet, consectetur adipiscing elit. ".repeat(200)
+    }
+
+    #[test]
+    fn round_trips_both_codecs() {
+        let data = sample();
+        for codec in [Codec::Zlib, Codec::Zstd] {
+            let compressed = codec.compress(&data, 3).unwrap();
+            assert!(compressed.len() < data.len(), "{} didn't compress", codec);
+            assert_eq!(codec.decompress(&compressed, data.len()).unwrap(), data);
+        }
+    }
+
+    #[test]
+    fn round_trips_empty_input() {
+        for codec in [Codec::Zlib, Codec::Zstd] {
+            let compressed = codec.compress(&[], DEFAULT_ZSTD_LEVEL).unwrap();
+            assert!(codec.decompress(&compressed, 0).unwrap().is_empty());
+        }
+    }
+
+    #[test]
+    fn module_functions_use_zstd() {
+        let data = sample();
+        let compressed = compress(&data, DEFAULT_ZSTD_LEVEL).unwrap();
+        assert_eq!(Codec::Zstd.decompress(&compressed, data.len()).unwrap(), data);
+        assert_eq!(decompress(&compressed, data.len()).unwrap(), data);
+    }
+
+    #[test]
+    fn decompress_refuses_output_past_the_limit() {
+        let data = sample();
+        for codec in [Codec::Zlib, Codec::Zstd] {
+            let compressed = codec.compress(&data, 3).unwrap();
+            assert!(codec.decompress(&compressed, data.len() - 1).is_err(), "{} ignored the limit", codec);
+        }
+    }
+
+    #[test]
+    fn decompress_rejects_garbage() {
+        for codec in [Codec::Zlib, Codec::Zstd] {
+            assert!(codec.decompress(b"not compressed at all", 1024).is_err());
+        }
+    }
+
+    #[test]
+    fn dictionary_contexts_round_trip() {
+        let samples: Vec<Vec<u8>> = (0..64)
+            .map(|i| format!("{{\"id\": {}, \"name\": \"file-{}\", \"kind\": \"sample\"}}", i, i).into_bytes())
+            .collect();
+        let dictionary = train_dictionary(&samples, 4096).unwrap();
+
+        let mut compressor = Compressor::new(DEFAULT_ZSTD_LEVEL, Some(&dictionary)).unwrap();
+        let mut decompressor = Decompressor::new(Some(&dictionary)).unwrap();
+        for data in &samples {
+            let compressed = compressor.compress(data).unwrap();
+            assert_eq!(&decompressor.decompress(&compressed, data.len()).unwrap(), data);
+        }
+    }
+
+    #[test]
+    fn stream_chunks_decode_in_order() {
+        let chunks: Vec<Vec<u8>> = (0..8).map(|i| sample().into_iter().map(|b| b ^ i).collect()).collect();
+        let mut encoder = StreamEncoder::new(DEFAULT_ZSTD_LEVEL, None).unwrap();
+        let mut decoder = StreamDecoder::new(None).unwrap();
+        for chunk in &chunks {
+            let compressed = encoder.compress_chunk(chunk).unwrap();
+            assert_eq!(&decoder.decompress_chunk(&compressed, chunk.len()).unwrap(), chunk);
+        }
+    }
+
+    #[test]
+    fn stream_decoder_refuses_output_past_the_limit() {
+        let data = sample();
+        let mut encoder = StreamEncoder::new(DEFAULT_ZSTD_LEVEL, None).unwrap();
+        let mut decoder = StreamDecoder::new(None).unwrap();
+        let compressed = encoder.compress_chunk(&data).unwrap();
+        assert!(decoder.decompress_chunk(&compressed, data.len() / 2).is_err());
+    }
+
+    #[test]
+    fn codec_names_parse() {
+        assert_eq!("ZSTD".parse::<Codec>().unwrap(), Codec::Zstd);
+        assert_eq!("zlib".parse::<Codec>().unwrap(), Codec::Zlib);
+        assert!("lz4".parse::<Codec>().is_err());
+    }
+}
@@ -1,17 +1,20 @@
-// Compression Benchmark Tool - Tests Zstd-style compression/decompression speed
+// Compression Benchmark Tool - Tests zlib and zstd compression/decompression speed
 
-use flate2::write::{ZlibEncoder, ZlibDecoder};
-use flate2::Compression;
+use fastdrop::codec::Codec;
+use std::env;
 use std::fs::File;
 use std::io::Write;
 use std::time::Instant;
 
 #[derive(Debug)]
 struct BenchmarkResult {
+    codec: Codec,
     size_mb: usize,
     compression_level: u32,
     compress_speed: f64,
     decompress_speed: f64,
+    /// Compressed size as a percentage of the original
+    ratio: f64,
 }
 
 fn format_speed(bytes: usize, duration_secs: f64) -> f64 {
@@ -36,16 +39,16 @@ fn generate_test_data(size_mb: usize) -> Vec<u8> {
     data
 }
 
-fn benchmark_compression(data: &[u8], compression_level: u32, quiet: bool) -> (Vec<u8>, f64, f64) {
+fn benchmark_compression(codec: Codec, data: &[u8], compression_level: u32, quiet: bool) -> (Vec<u8>, f64, f64) {
     if !quiet {
-        println!("   🗜️  Compressing {} MB (level {})...", 
-            data.len() / (1024 * 1024), compression_level);
+        println!("   🗜️  Compressing {} MB with {} (level {})...", 
+            data.len() / (1024 * 1024), codec, compression_level);
     }
     
     let start = Instant::now();
-    let mut encoder = ZlibEncoder::new(Vec::new(), Compression::new(compression_level));
-    encoder.write_all(data).expect("Compression failed");
-    let compressed = encoder.finish().expect("Failed to finish compression");
+    let compressed = codec
+        .compress(data, compression_level as i32)
+        .expect("Compression failed");
     let compress_duration = start.elapsed().as_secs_f64();
     
     let ratio = (compressed.len() as f64 / data.len() as f64) * 100.0;
@@ -58,15 +61,15 @@ fn benchmark_compression(data: &[u8], compression_level: u32, quiet: bool) -> (V
     (compressed, compress_duration, ratio)
 }
 
-fn benchmark_decompression(compressed: &[u8], original_size: usize, quiet: bool) -> (Vec<u8>, f64) {
+fn benchmark_decompression(codec: Codec, compressed: &[u8], original_size: usize, quiet: bool) -> (Vec<u8>, f64) {
     if !quiet {
-        println!("   📦 Decompressing {} MB...", compressed.len() / (1024 * 1024));
+        println!("   📦 Decompressing {} MB with {}...", compressed.len() / (1024 * 1024), codec);
     }
     
     let start = Instant::now();
-    let mut decoder = ZlibDecoder::new(Vec::new());
-    decoder.write_all(compressed).expect("Decompression failed");
-    let decompressed = decoder.finish().expect("Failed to finish decompression");
+    let decompressed = codec
+        .decompress(compressed, original_size)
+        .expect("Decompression failed");
     let decompress_duration = start.elapsed().as_secs_f64();
     
     let speed = format_speed(original_size, decompress_duration);
@@ -84,17 +87,21 @@ fn benchmark_decompression(compressed: &[u8], original_size: usize, quiet: bool)
 }
 
 fn save_results(
+    codec: Codec,
     compress_score: f64,
     decompress_score: f64,
+    ratio: f64,
 ) {
     let results = format!(
-        "Compress_score: {:.2}\nDecompress_score: {:.2}\n",
+        "Codec: {}\nCompress_score: {:.2}\nDecompress_score: {:.2}\nRatio: {:.1}\n",
+        codec,
         compress_score,
-        decompress_score
+        decompress_score,
+        ratio
     );
     
-    let filename = "compression_benchmark.txt";
-    match File::create(filename) {
+    let filename = format!("compression_benchmark_{}.txt", codec);
+    match File::create(&filename) {
         Ok(mut file) => {
             file.write_all(results.as_bytes()).expect("Failed to write results");
             println!("\n💾 Results saved to: {}", filename);
@@ -106,28 +113,55 @@ fn save_results(
     }
 }
 
-fn run_single_test(size_mb: usize, compression_level: u32) -> BenchmarkResult {
+fn run_single_test(codec: Codec, size_mb: usize, compression_level: u32) -> BenchmarkResult {
     let test_data = generate_test_data(size_mb);
     
-    let (compressed, compress_time, _ratio) = benchmark_compression(&test_data, compression_level, true);
-    let (_decompressed, decompress_time) = benchmark_decompression(&compressed, test_data.len(), true);
+    let (compressed, compress_time, ratio) = benchmark_compression(codec, &test_data, compression_level, true);
+    let (_decompressed, decompress_time) = benchmark_decompression(codec, &compressed, test_data.len(), true);
     
     let compress_speed = format_speed(test_data.len(), compress_time);
     let decompress_speed = format_speed(test_data.len(), decompress_time);
     
     BenchmarkResult {
+        codec,
         size_mb,
         compression_level,
         compress_speed,
         decompress_speed,
+        ratio,
     }
 }
 
+/// Codecs to benchmark: the one given with `--codec`, or both for a comparison
+fn parse_codecs() -> Result<Vec<Codec>, String> {
+    let mut args = env::args().skip(1);
+    let mut codecs = vec![Codec::Zlib, Codec::Zstd];
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--codec" => {
+                let codec = args.next().ok_or("--codec needs a value (zlib or zstd)")?;
+                codecs = vec![codec.parse()?];
+            }
+            other => return Err(format!("Unknown option: {}", other)),
+        }
+    }
+
+    Ok(codecs)
+}
+
 fn main() {
-    println!("🚀 Zlib Compression Benchmark");
-    println!("==============================\n");
-    
-    let mut results = Vec::new();
+    let codecs = match parse_codecs() {
+        Ok(codecs) => codecs,
+        Err(e) => {
+            eprintln!("❌ {}", e);
+            eprintln!("Usage: compress_bench [--codec zlib|zstd]");
+            std::process::exit(1);
+        }
+    };
+
+    println!("🚀 Compression Benchmark");
+    println!("========================\n");
     
     // Test configurations: (size_mb, compression_level)
     let test_configs = vec![
@@ -145,29 +179,45 @@ fn main() {
         (50, 6),
     ];
     
-    println!("Running {} tests...\n", test_configs.len());
-    
-    for (i, (size_mb, level)) in test_configs.iter().enumerate() {
-        print!("Test {}/{}... ", i + 1, test_configs.len());
-        std::io::stdout().flush().unwrap();
+    let mut summaries = Vec::new();
+    for codec in codecs {
+        println!("Running {} {} tests...\n", test_configs.len(), codec);
+        
+        let mut results = Vec::new();
+        for (i, (size_mb, level)) in test_configs.iter().enumerate() {
+            print!("Test {}/{}... ", i + 1, test_configs.len());
+            std::io::stdout().flush().unwrap();
+            
+            let result = run_single_test(codec, *size_mb, *level);
+            println!("✓ {} {} MB (level {}): Compress {:.2} MB/s, Decompress {:.2} MB/s, {:.1}% ratio",
+                result.codec, result.size_mb, result.compression_level, result.compress_speed, result.decompress_speed, result.ratio);
+            
+            results.push(result);
+        }
+        
+        // Calculate averages
+        let count = results.len() as f64;
+        let avg_compress: f64 = results.iter().map(|r| r.compress_speed).sum::<f64>() / count;
+        let avg_decompress: f64 = results.iter().map(|r| r.decompress_speed).sum::<f64>() / count;
+        let avg_ratio: f64 = results.iter().map(|r| r.ratio).sum::<f64>() / count;
         
-        let result = run_single_test(*size_mb, *level);
-        println!("✓ {} MB (level {}): Compress {:.2} MB/s, Decompress {:.2} MB/s",
-            size_mb, level, result.compress_speed, result.decompress_speed);
+        println!("\n📊 Average {} Performance:", codec);
+        println!("   Compression: {:.2} MB/s", avg_compress);
+        println!("   Decompression: {:.2} MB/s", avg_decompress);
+        println!("   Ratio: {:.1}%", avg_ratio);
         
-        results.push(result);
+        // Save results
+        save_results(codec, avg_compress, avg_decompress, avg_ratio);
+        summaries.push((codec, avg_compress, avg_decompress, avg_ratio));
     }
     
-    // Calculate averages
-    let avg_compress: f64 = results.iter().map(|r| r.compress_speed).sum::<f64>() / results.len() as f64;
-    let avg_decompress: f64 = results.iter().map(|r| r.decompress_speed).sum::<f64>() / results.len() as f64;
-    
-    println!("\n📊 Average Performance:");
-    println!("   Compression: {:.2} MB/s", avg_compress);
-    println!("   Decompression: {:.2} MB/s", avg_decompress);
-    
-    // Save results
-    save_results(avg_compress, avg_decompress);
+    if summaries.len() > 1 {
+        println!("\n⚖️  Comparison:");
+        for (codec, compress, decompress, ratio) in &summaries {
+            println!("   {:<5} compress {:>9.2} MB/s, decompress {:>9.2} MB/s, {:>5.1}% ratio",
+                codec, compress, decompress, ratio);
+        }
+    }
     
     println!("\n✅ Benchmark complete!");
 }
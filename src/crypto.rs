@@ -0,0 +1,127 @@
+// End-to-end encryption of chunk data, on top of what the transport already encrypts
+
+use crate::protocol::FileChunk;
+use crate::ticket::SessionId;
+use anyhow::{Context, Result};
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use hkdf::Hkdf;
+use sha2::Sha256;
+use x25519_dalek::{EphemeralSecret, PublicKey};
+
+/// HKDF label for the chunk key, bumped if the derivation ever changes
+const KEY_LABEL: &[u8] = b"fastdrop chunk key v1";
+
+/// One side's ephemeral X25519 key for a single transfer
+///
+/// The public halves travel in the `TransferRequest` and `TransferResponse`,
+/// inside the noise/QUIC session with the peer the ticket named, so nobody
+/// relaying the connection can swap them.
+pub struct KeyExchange {
+    secret: EphemeralSecret,
+    public: PublicKey,
+}
+
+impl KeyExchange {
+    pub fn new() -> Self {
+        let secret = EphemeralSecret::random();
+        let public = PublicKey::from(&secret);
+        Self { secret, public }
+    }
+
+    /// Our half, to send to the peer
+    pub fn public_key(&self) -> [u8; 32] {
+        self.public.to_bytes()
+    }
+
+    /// Combine with the peer's half into the cipher for this transfer
+    ///
+    /// The key also depends on the ticket's nonce and session ID, so it is
+    /// tied to the ticket the receiver read over BLE.
+    pub fn finish(self, peer_key: [u8; 32], nonce: u64, session_id: &SessionId) -> Result<ChunkCipher> {
+        let ours = self.public.to_bytes();
+        let shared = self.secret.diffie_hellman(&PublicKey::from(peer_key));
+        if !shared.was_contributory() {
+            anyhow::bail!("Peer sent a weak encryption key");
+        }
+
+        let mut salt = nonce.to_be_bytes().to_vec();
+        salt.extend_from_slice(session_id);
+        // Both sides list the public keys in the same order
+        let (first, second) = if ours <= peer_key { (ours, peer_key) } else { (peer_key, ours) };
+        let mut info = KEY_LABEL.to_vec();
+        info.extend_from_slice(&first);
+        info.extend_from_slice(&second);
+
+        let mut key = [0u8; 32];
+        Hkdf::<Sha256>::new(Some(&salt), shared.as_bytes())
+            .expand(&info, &mut key)
+            .map_err(|_| anyhow::anyhow!("Failed to derive the chunk key"))?;
+        Ok(ChunkCipher { cipher: ChaCha20Poly1305::new(Key::from_slice(&key)) })
+    }
+}
+
+impl Default for KeyExchange {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Encrypts and authenticates chunk data with ChaCha20-Poly1305
+///
+/// Every chunk gets a fresh random nonce, sent along in `FileChunk.nonce`, so
+/// a chunk sent twice never reuses one. The file index and chunk number are
+/// authenticated as well, so chunks can't be swapped around.
+#[derive(Clone)]
+pub struct ChunkCipher {
+    cipher: ChaCha20Poly1305,
+}
+
+impl ChunkCipher {
+    /// Encrypt the chunk's data in place, updating its CRC32 to match
+    pub fn seal(&self, chunk: &mut FileChunk) -> Result<()> {
+        let nonce: [u8; 12] = rand::random();
+        let aad = associated_data(chunk);
+        let payload = Payload { msg: &chunk.data, aad: &aad };
+        chunk.data = self
+            .cipher
+            .encrypt(Nonce::from_slice(&nonce), payload)
+            .map_err(|_| anyhow::anyhow!("Failed to encrypt chunk {}", chunk.chunk_number))?;
+        chunk.nonce = Some(nonce);
+        if chunk.crc32.is_some() {
+            chunk.crc32 = Some(crc32fast::hash(&chunk.data));
+        }
+        Ok(())
+    }
+
+    /// Decrypt the chunk's data in place, failing if it was tampered with
+    pub fn open(&self, chunk: &mut FileChunk) -> Result<()> {
+        let nonce = chunk.nonce.with_context(|| {
+            format!(
+                "Chunk {} of file {} is not encrypted although encryption was agreed",
+                chunk.chunk_number, chunk.file_index
+            )
+        })?;
+        let aad = associated_data(chunk);
+        let payload = Payload { msg: &chunk.data, aad: &aad };
+        chunk.data = self
+            .cipher
+            .decrypt(Nonce::from_slice(&nonce), payload)
+            .map_err(|_| {
+                anyhow::anyhow!(
+                    "Chunk {} of file {} failed authentication",
+                    chunk.chunk_number,
+                    chunk.file_index
+                )
+            })?;
+        Ok(())
+    }
+}
+
+/// Where the chunk belongs, authenticated alongside its data
+fn associated_data(chunk: &FileChunk) -> [u8; 16] {
+    let mut aad = [0u8; 16];
+    aad[..8].copy_from_slice(&(chunk.file_index as u64).to_be_bytes());
+    aad[8..].copy_from_slice(&chunk.chunk_number.to_be_bytes());
+    aad
+}
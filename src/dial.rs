@@ -0,0 +1,200 @@
+// Receiver dialing: tries the ticket's addresses round-robin (or all at once) with backoff
+
+use crate::network;
+use crate::protocol::TransportProtocol;
+use libp2p::swarm::dial_opts::DialOpts;
+use libp2p::swarm::{ConnectionId, NetworkBehaviour, Swarm};
+use libp2p::{Multiaddr, PeerId};
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// How long to wait for one address before moving on to the next
+const ATTEMPT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Head start QUIC addresses get over TCP ones when racing both transports
+///
+/// QUIC is preferred when it works, but networks that drop UDP never answer
+/// it, so TCP joins in after this long (or as soon as every QUIC dial failed).
+/// Relayed addresses wait alongside TCP, since a direct path is better.
+const TCP_FALLBACK_DELAY: Duration = Duration::from_secs(2);
+
+/// Pause after a full pass over the addresses fails, doubled on each pass
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(8);
+
+/// Default for how long to keep trying before giving up
+pub const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Dials one address at a time until a connection is established or time runs out
+///
+/// A failed address is followed immediately by the next one; once every
+/// address has failed, the next pass waits with exponential backoff. In
+/// racing mode each pass dials every QUIC address at once, then every TCP
+/// address after `TCP_FALLBACK_DELAY`, and the first connection wins.
+pub struct Redialer {
+    peer_id: PeerId,
+    addrs: Vec<Multiaddr>,
+    /// Most recent outcome per address, `None` until it has been tried
+    last_error: Vec<Option<String>>,
+    next: usize,
+    backoff: Duration,
+    /// Dial every address at once
+    race: bool,
+    /// TCP addresses of the current racing pass still waiting out QUIC's head start
+    held_back: Vec<usize>,
+    /// Current attempts and the index of the address each dialed
+    pending: Vec<(ConnectionId, usize)>,
+    next_attempt: Instant,
+    deadline: Instant,
+}
+
+impl Redialer {
+    pub fn new(peer_id: PeerId, addrs: Vec<Multiaddr>, timeout: Duration, race: bool) -> Self {
+        let now = Instant::now();
+        Self {
+            peer_id,
+            last_error: vec![None; addrs.len()],
+            addrs,
+            next: 0,
+            backoff: INITIAL_BACKOFF,
+            race,
+            held_back: Vec::new(),
+            pending: Vec::new(),
+            next_attempt: now,
+            deadline: now + timeout,
+        }
+    }
+
+    /// When the next address should be dialed
+    pub fn next_attempt(&self) -> Instant {
+        self.next_attempt
+    }
+
+    /// When to stop trying altogether
+    pub fn deadline(&self) -> Instant {
+        self.deadline
+    }
+
+    /// Dial the next address in the rotation, or all of them when racing
+    pub fn dial_next<B: NetworkBehaviour>(&mut self, swarm: &mut Swarm<B>) {
+        if !self.held_back.is_empty() {
+            // QUIC's head start is over; its dials keep going alongside TCP
+            say!("🔁 No QUIC connection yet, trying TCP as well");
+            for index in std::mem::take(&mut self.held_back) {
+                self.dial(swarm, index);
+            }
+            if self.pending.is_empty() {
+                self.schedule_retry();
+            } else {
+                self.next_attempt = Instant::now() + ATTEMPT_TIMEOUT;
+            }
+            return;
+        }
+
+        for (_, index) in self.pending.drain(..) {
+            self.last_error[index] = Some("no response".to_string());
+        }
+
+        if !self.race {
+            let index = self.next;
+            self.next = (self.next + 1) % self.addrs.len();
+            if self.dial(swarm, index) {
+                self.next_attempt = Instant::now() + ATTEMPT_TIMEOUT;
+            } else {
+                self.schedule_retry();
+            }
+            return;
+        }
+
+        let (quic, tcp): (Vec<usize>, Vec<usize>) = (0..self.addrs.len()).partition(|&index| {
+            let addr = &self.addrs[index];
+            network::transport_of(addr) == TransportProtocol::Quic && !network::is_relayed(addr)
+        });
+        let (first, held_back) = if quic.is_empty() { (tcp, Vec::new()) } else { (quic, tcp) };
+        for index in first {
+            self.dial(swarm, index);
+        }
+        self.held_back = held_back;
+        if !self.held_back.is_empty() {
+            // Straight on to TCP if no QUIC dial even started
+            let delay = if self.pending.is_empty() { Duration::ZERO } else { TCP_FALLBACK_DELAY };
+            self.next_attempt = Instant::now() + delay;
+        } else if self.pending.is_empty() {
+            self.schedule_retry();
+        } else {
+            self.next_attempt = Instant::now() + ATTEMPT_TIMEOUT;
+        }
+    }
+
+    /// Start dialing one address, returning whether the attempt is under way
+    fn dial<B: NetworkBehaviour>(&mut self, swarm: &mut Swarm<B>, index: usize) -> bool {
+        let addr = self.addrs[index].clone();
+        say!("📞 Dialing {}", addr);
+        // Naming the peer makes the handshake fail if someone else answers
+        let opts = DialOpts::peer_id(self.peer_id).addresses(vec![addr]).build();
+        let connection_id = opts.connection_id();
+
+        match swarm.dial(opts) {
+            Ok(()) => {
+                self.pending.push((connection_id, index));
+                true
+            }
+            Err(e) => {
+                eprintln!("   ⚠️  Failed: {}", e);
+                self.last_error[index] = Some(e.to_string());
+                false
+            }
+        }
+    }
+
+    /// Note a failed outgoing connection, moving on once no attempt is left
+    pub fn on_dial_failure(&mut self, connection_id: ConnectionId, error: &impl std::fmt::Display) {
+        if let Some(position) = self.pending.iter().position(|(id, _)| *id == connection_id) {
+            let (_, index) = self.pending.remove(position);
+            self.last_error[index] = Some(error.to_string());
+            if !self.held_back.is_empty() && self.pending.is_empty() {
+                // Every QUIC dial failed, no point waiting out the head start
+                self.next_attempt = Instant::now();
+            } else if self.pending.is_empty() {
+                self.schedule_retry();
+            }
+        }
+    }
+
+    /// Each address with the reason its last attempt failed
+    pub fn failure_report(&self) -> String {
+        self.addrs
+            .iter()
+            .zip(&self.last_error)
+            .enumerate()
+            .map(|(index, (addr, error))| {
+                let pending = self.pending.iter().any(|(_, pending)| *pending == index);
+                let error = match error {
+                    _ if pending => "no response",
+                    Some(error) => error.as_str(),
+                    None => "not tried",
+                };
+                format!("   - {} ({})", addr, error)
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    fn schedule_retry(&mut self) {
+        let now = Instant::now();
+        if self.race || self.next == 0 {
+            // Every address failed this pass - back off before starting over
+            if now + self.backoff >= self.deadline {
+                // No pass could start in time, so wait out the deadline instead of
+                // announcing a retry that never happens
+                self.next_attempt = self.deadline + ATTEMPT_TIMEOUT;
+                return;
+            }
+            say!("   ⏳ All addresses failed, retrying in {}s", self.backoff.as_secs());
+            self.next_attempt = now + self.backoff;
+            self.backoff = (self.backoff * 2).min(MAX_BACKOFF);
+        } else {
+            self.next_attempt = now;
+        }
+    }
+}
@@ -0,0 +1,223 @@
+// Machine-readable output: newline-delimited JSON events for scripts and GUIs
+
+use crate::protocol::FileStatus;
+use crate::stats::StatsReport;
+use serde::Serialize;
+use std::cell::RefCell;
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, Ordering};
+use tracing::Instrument;
+use tracing_subscriber::EnvFilter;
+
+/// Set once by `--json`, read by every `say!` and `emit`
+static JSON: AtomicBool = AtomicBool::new(false);
+
+/// Switch the process to JSON output
+pub fn enable_json() {
+    JSON.store(true, Ordering::Relaxed);
+}
+
+/// Whether events are printed as JSON instead of decorative text
+pub fn json() -> bool {
+    JSON.load(Ordering::Relaxed)
+}
+
+tokio::task_local! {
+    /// Session of the transfer the current task works on, once it's known
+    static SESSION: RefCell<Option<String>>;
+}
+
+/// Run `transfer` with a `transfer` span around its logs
+///
+/// Once [`set_session`] names the session, it shows up in the span and on
+/// every event the transfer emits, so concurrent transfers can be told apart.
+pub fn transfer<F: Future>(transfer: F) -> impl Future<Output = F::Output> {
+    let span = tracing::info_span!("transfer", session = tracing::field::Empty);
+    SESSION.scope(RefCell::new(None), transfer.instrument(span))
+}
+
+/// Name the session of the transfer running in this task
+pub fn set_session(session: &str) {
+    tracing::Span::current().record("session", session);
+    let _ = SESSION.try_with(|current| *current.borrow_mut() = Some(session.to_string()));
+}
+
+/// Keep the current transfer's span and session in a task spawned for it
+pub fn in_current_transfer<F: Future>(task: F) -> impl Future<Output = F::Output> {
+    let session = SESSION.try_with(|current| current.borrow().clone()).ok();
+    let task = task.in_current_span();
+    async move {
+        match session {
+            Some(session) => SESSION.scope(RefCell::new(session), task).await,
+            None => task.await,
+        }
+    }
+}
+
+/// Send diagnostics to stderr, filtered by `RUST_LOG` (warnings and errors by default)
+///
+/// stdout is left to the user-facing output, and to the events in JSON mode.
+pub fn init_tracing() {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("warn"));
+    tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(std::io::stderr)
+        .init();
+}
+
+/// `println!` for human-readable output, silent in JSON mode
+///
+/// Errors keep going to stderr with `eprintln!`, so JSON consumers still
+/// see them without having to parse stdout for them.
+#[macro_export]
+macro_rules! say {
+    ($($arg:tt)*) => {
+        if !$crate::events::json() {
+            println!($($arg)*);
+        }
+    };
+}
+
+/// A Fastdrop device found by the receiver's scan
+#[derive(Debug, Serialize)]
+pub struct Device {
+    /// 1-based number to answer the selection prompt with
+    pub number: usize,
+    pub name: String,
+    pub address: String,
+    pub rssi: Option<i16>,
+}
+
+/// A file in the sender's offer
+#[derive(Debug, Serialize)]
+pub struct OfferedFile {
+    pub file_index: usize,
+    pub path: String,
+    pub size: u64,
+    pub duplicate_of: Option<usize>,
+}
+
+/// One line of `--json` output
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    /// Fastdrop devices found by the receiver's scan
+    Devices { devices: Vec<Device> },
+
+    /// The sender started advertising a session ticket
+    TicketCreated {
+        peer_id: String,
+        protocol: String,
+        session: String,
+        addrs: Vec<String>,
+    },
+
+    /// A libp2p connection to the other side is up
+    Connected { peer_id: String, address: String },
+
+    /// The first answered ping on the connection to the other side
+    RoundTrip { peer_id: String, rtt_ms: u64 },
+
+    /// The file list the receiver is about to accept or decline
+    Offer { files: Vec<OfferedFile>, total_size: u64 },
+
+    /// Code both sides show so the user can compare them
+    VerificationCode { peer_id: String, code: String },
+
+    /// A question waits for an answer line on stdin
+    Prompt { question: String },
+
+    /// A file started sending or receiving, `offset` past 0 when resumed
+    FileStarted {
+        file_index: usize,
+        path: String,
+        size: u64,
+        offset: u64,
+    },
+
+    /// Bytes of a file on disk (receiver) or sent (sender) so far, reported at every chunk ack
+    FileProgress { file_index: usize, bytes: u64, total: u64 },
+
+    /// The same across all files of the transfer
+    TransferProgress { bytes: u64, total: u64 },
+
+    /// A file is complete; the receiver adds how it was verified
+    FileCompleted {
+        file_index: usize,
+        path: String,
+        status: Option<FileStatus>,
+    },
+
+    /// Every file arrived (receiver) or was sent (sender), and over which transport
+    TransferComplete {
+        peer_id: String,
+        files: usize,
+        transport: String,
+    },
+
+    /// Throughput and timings, at the end of a transfer
+    TransferStats { peer_id: String, stats: StatsReport },
+
+    /// Either side cancelled the transfer on purpose
+    TransferCancelled { peer_id: Option<String>, reason: String },
+
+    /// The transfer stopped before all files arrived
+    TransferFailed { peer_id: Option<String>, error: String },
+}
+
+/// An event with the session of the transfer it belongs to
+#[derive(Serialize)]
+struct Stamped<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    session: Option<String>,
+    #[serde(flatten)]
+    event: &'a Event,
+}
+
+/// Print `event` as one JSON line, if JSON output is on
+///
+/// Events emitted inside a [`transfer`] carry its session.
+pub fn emit(event: Event) {
+    if !json() {
+        return;
+    }
+    let session = SESSION.try_with(|current| current.borrow().clone()).ok().flatten();
+    match serde_json::to_string(&Stamped { session, event: &event }) {
+        Ok(line) => println!("{}", line),
+        Err(e) => eprintln!("⚠️  Failed to encode event: {}", e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode(event: Event) -> serde_json::Value {
+        let session = SESSION.try_with(|current| current.borrow().clone()).ok().flatten();
+        serde_json::to_value(Stamped { session, event: &event }).unwrap()
+    }
+
+    fn progress() -> Event {
+        Event::TransferProgress { bytes: 1, total: 2 }
+    }
+
+    #[tokio::test]
+    async fn events_carry_the_session_of_their_transfer() {
+        assert!(encode(progress()).get("session").is_none());
+
+        transfer(async {
+            assert!(encode(progress()).get("session").is_none());
+            set_session("00ff");
+            let event = encode(progress());
+            assert_eq!(event["session"], "00ff");
+            assert_eq!(event["event"], "transfer_progress");
+            assert_eq!(event["bytes"], 1);
+
+            let spawned = tokio::spawn(in_current_transfer(async { encode(progress()) }));
+            assert_eq!(spawned.await.unwrap()["session"], "00ff");
+        })
+        .await;
+
+        assert!(encode(progress()).get("session").is_none());
+    }
+}
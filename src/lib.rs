@@ -0,0 +1,12 @@
+// Fastdrop: the transfer engine shared by the receiver, sender and benchmark binaries
+
+#[macro_use]
+pub mod events;
+pub mod codec;
+pub mod crypto;
+pub mod network;
+pub mod protocol;
+pub mod ratelimit;
+pub mod stats;
+pub mod ticket;
+pub mod transfer;
@@ -1,43 +1,482 @@
 // Receiver: Scans for BLE devices and receives files via libp2p
 
-mod network;
-mod protocol;
-mod transfer;
+#[macro_use]
+extern crate fastdrop;
+
+mod dial;
+mod picker;
+
+use fastdrop::{crypto, events, network, protocol, ratelimit, ticket, transfer};
 
 use btleplug::api::{Central, Manager as _, Peripheral as _, ScanFilter};
-use btleplug::platform::Manager;
+use btleplug::platform::{Adapter, Manager, Peripheral};
+use dial::Redialer;
+use events::{Device, Event, OfferedFile};
 use futures::StreamExt;
 use libp2p::identity::Keypair;
-use libp2p::swarm::SwarmEvent;
-use libp2p::StreamProtocol;
-use protocol::{SessionTicket, TransferRequest};
+use libp2p::swarm::{ConnectionId, SwarmEvent};
+use libp2p::{PeerId, Stream, StreamProtocol};
+use network::{FileTransferBehaviourEvent, Heartbeat, NetworkEvent, ReceiveSummary};
+use protocol::{
+    Capability, Confirmed, FileList, FileStatus, RejectReason, ResumeRequest, SessionTicket, TransferDecision,
+    TransferRequest, TransferResponse, TransportProtocol,
+};
 use serde_cbor::from_slice;
+use ratelimit::RateLimiter;
 use std::{
+    env,
     error::Error,
-    io::{self, Write},
+    io::{self, IsTerminal, Write},
     path::PathBuf,
+    sync::Arc,
     time::Duration,
 };
-use tokio::io::AsyncWriteExt;
-use tokio::time;
+use tokio::sync::{mpsc, watch};
+use tokio::task::JoinHandle;
+use tokio::{signal, time};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, trace, warn};
+use transfer::{ConflictPolicy, PartialPolicy, SizeLimits};
 use uuid::Uuid;
 
-/* ========== All UUIDs to scan for ========== */
-const ALL_SERVICE_UUIDS: &[&str] = &[
-    protocol::QUIC_SERVICE_UUID,
-    protocol::TCP_SERVICE_UUID,
-];
+/// Process exit code when a transfer is cancelled, with Ctrl+C, q or by the
+/// sender, or the offer was declined
+const EXIT_INTERRUPTED: i32 = 130;
+
+/// How long to scan for BLE advertisements
+const SCAN_DURATION: Duration = Duration::from_secs(15);
+
+/// How long a relayed connection waits for hole punching before the
+/// transfer goes through the relay after all
+const HOLE_PUNCH_TIMEOUT: Duration = Duration::from_secs(15);
 
-const ALL_CHAR_UUIDS: &[&str] = &[
-    protocol::QUIC_CHAR_UUID,
-    protocol::TCP_CHAR_UUID,
-];
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
-    println!("🚀 Fastdrop Receiver");
-    println!("====================\n");
+    events::init_tracing();
+    events::transfer(receive()).await
+}
+
+/// The receiver runs a single transfer, named by the ticket's session
+async fn receive() -> Result<(), Box<dyn Error>> {
+    let options = match Options::parse() {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("❌ {}", e);
+            eprintln!("\nUsage: receiver [--yes] [--ignore-expiry] [--no-preserve-permissions] [--connect-timeout SECS] [--control-timeout SECS] [--idle-timeout SECS] [--max-file-size SIZE] [--max-total-size SIZE] [--no-limit] [--allow-replay] [--no-verify] [--on-failure keep|delete|rename] [--chunk-size SIZE] [--conflict overwrite|skip|rename] [--parallel N] [--output-dir DIR] [--ticket FILE] [--rate-limit RATE] [--json]");
+            eprintln!("   -y, --yes          Accept offered files and resume partial ones without prompting");
+            eprintln!("   --ignore-expiry    Connect even if the session ticket has expired");
+            eprintln!("   --no-preserve-permissions  Don't apply the sender's Unix permission bits (for untrusted senders)");
+            eprintln!(
+                "   --connect-timeout SECS  Give up if no connection to the sender is made in time, listing the addresses tried (default {})",
+                dial::DEFAULT_CONNECT_TIMEOUT.as_secs()
+            );
+            eprintln!(
+                "   --control-timeout SECS  Give up on a sender that takes longer to send or take a message (default {})",
+                network::DEFAULT_CONTROL_TIMEOUT.as_secs()
+            );
+            eprintln!(
+                "   --idle-timeout SECS     Give up on a sender that sends no chunk for this long (default {})",
+                network::DEFAULT_IDLE_TIMEOUT.as_secs()
+            );
+            eprintln!("   --max-file-size SIZE   Refuse offers with a file larger than SIZE, e.g. 500M (default 16G)");
+            eprintln!("   --max-total-size SIZE  Refuse offers larger than SIZE in total (default 64G)");
+            eprintln!("   --no-limit         Accept offers of any size");
+            eprintln!("   --no-verify        Don't compare verification codes (trusted networks only)");
+            eprintln!("   --on-failure MODE  keep, delete or rename (to .partial) unfinished files (default rename; keep to resume later)");
+            eprintln!("   --chunk-size SIZE  Ask the sender for chunks of SIZE, e.g. 256K (the sender may cap it)");
+            eprintln!("   --conflict MODE    overwrite, skip or rename existing files to \"name (1).ext\" (default rename)");
+            eprintln!("   --parallel N       Receive up to N files at once over QUIC, 1 for a single stream (default 8)");
+            eprintln!("   -o, --output-dir DIR   Save received files under DIR (created if missing) instead of here");
+            eprintln!("   --rate-limit RATE  Limit download speed, e.g. 5MB (the sender's --rate-limit is gentler on its uplink)");
+            eprintln!("   --ticket FILE      Connect with a ticket from sender --emit-ticket or --qr instead of scanning Bluetooth (- reads stdin)");
+            eprintln!("   --json             Print newline-delimited JSON events instead of text");
+            std::process::exit(1);
+        }
+    };
+
+    if options.json {
+        events::enable_json();
+    }
+    network::set_timeouts(options.control_timeout, options.idle_timeout);
+    say!("🚀 Fastdrop Receiver");
+    say!("====================\n");
+
+    // Every received path is relative to the working directory, including the
+    // `.part` files used for resuming, so moving there covers all of them
+    if let Some(dir) = &options.output_dir {
+        std::fs::create_dir_all(dir)
+            .and_then(|()| env::set_current_dir(dir))
+            .map_err(|e| format!("Can't use output directory {}: {}", dir.display(), e))?;
+        say!("📂 Saving files to {}\n", dir.display());
+    }
+
+    /* 1-5. Read the session ticket, from a file or over BLE */
+    let ticket_data = match &options.ticket {
+        Some(path) => {
+            say!("📄 Reading session ticket from {}\n", path.display());
+            ticket::read_ticket_file(path)?
+        }
+        None => match read_ticket_over_ble(&options).await? {
+            Some(ticket_data) => ticket_data,
+            None => return Ok(()),
+        },
+    };
+    let ticket: SessionTicket = from_slice(&ticket_data)?;
+
+    // Never show or dial anything from an advertisement we can't authenticate
+    if let Err(e) = ticket::verify_ticket(&ticket) {
+        eprintln!("❌ {}", e);
+        eprintln!("   Refusing to connect to {}", ticket.peer_id);
+        refuse(&ticket, e.to_string());
+    }
+
+    events::set_session(&ticket::format_session_id(&ticket.session_id));
+    say!("🎫 Session Ticket (signature verified):");
+    if !ticket.sender_name.is_empty() {
+        say!("   Sender: {} ({})", ticket.sender_name, ticket.hostname);
+    }
+    if !ticket.app_version.is_empty() {
+        say!("   Fastdrop: {}", ticket.app_version);
+    }
+    if ticket.file_count > 0 {
+        say!(
+            "   Offer: {} file(s), {}",
+            ticket.file_count,
+            transfer::format_bytes(ticket.total_size)
+        );
+    }
+    say!("   Version: {}", protocol::format_version(ticket.version));
+    say!("   Protocol: {:?}", ticket.protocol);
+    say!("   Peer ID: {}", ticket.peer_id);
+    say!("   Session: {}", ticket::format_session_id(&ticket.session_id));
+    say!("   Addresses: {}", ticket.addrs.len());
+    for addr in &ticket.addrs {
+        say!("      - {}", addr);
+    }
+    say!();
+
+    let app_version = env!("CARGO_PKG_VERSION");
+    if !ticket.app_version.is_empty() && ticket.app_version != app_version {
+        say!(
+            "⚠️  Sender runs Fastdrop {}, this receiver is {} - update both if the transfer misbehaves\n",
+            ticket.app_version, app_version
+        );
+    }
+
+    // A stale ticket (sender restarted, cached GATT value) points at dead addresses
+    match ticket::check_freshness(&ticket, ticket::unix_now()) {
+        ticket::Freshness::Fresh => {}
+        ticket::Freshness::FromFuture { skew_secs } => {
+            say!(
+                "⚠️  Ticket was issued {}s in the future - the clocks on the two devices disagree",
+                skew_secs
+            );
+        }
+        ticket::Freshness::Expired { age_secs } => {
+            let skewed = ticket::expiry_looks_like_skew(&ticket, age_secs);
+            if options.ignore_expiry {
+                say!("⚠️  Ticket expired {}s ago, continuing anyway (--ignore-expiry)", age_secs);
+            } else {
+                eprintln!(
+                    "❌ Session ticket expired ({}s old, valid for {}s)",
+                    age_secs, ticket.ttl_secs
+                );
+                if skewed {
+                    eprintln!("   That is far too old to be a cached ticket - check both clocks,");
+                    eprintln!("   or pass --ignore-expiry if they can't be fixed");
+                } else {
+                    eprintln!("   Restart the scan to pick up the sender's current ticket");
+                }
+                refuse(&ticket, format!("Session ticket expired ({}s old, valid for {}s)", age_secs, ticket.ttl_secs));
+            }
+        }
+    }
+
+    // No point connecting for an offer we would refuse anyway. The file list
+    // is checked again once it arrives, and must match this total.
+    if let Some(max_size) = options.limits.max_total_size.filter(|&max| ticket.total_size > max) {
+        let error = format!(
+            "Sender offers {}, more than the {} limit (see --max-total-size)",
+            transfer::format_bytes(ticket.total_size),
+            transfer::format_bytes(max_size)
+        );
+        eprintln!("❌ {}", error);
+        refuse(&ticket, error);
+    }
+
+    if protocol::negotiate_version(protocol::PROTOCOL_VERSION, ticket.version).is_none() {
+        let error = format!(
+            "Sender uses protocol version {}, this receiver only understands {}.x",
+            protocol::format_version(ticket.version),
+            protocol::version_major(protocol::PROTOCOL_VERSION)
+        );
+        eprintln!("❌ {}", error);
+        eprintln!("   Update both devices to the same Fastdrop release");
+        refuse(&ticket, error);
+    }
+
+    // Each ticket is good for one transfer, so a recorded advertisement can't
+    // be played back later. It is only spent once the sender has answered.
+    if ticket::SeenNonces::load().contains(&ticket.peer_id, ticket.nonce) {
+        if options.allow_replay {
+            say!("⚠️  Ticket was already used, connecting anyway (--allow-replay)");
+        } else {
+            eprintln!("❌ This session ticket was already used - possible replayed advertisement");
+            eprintln!("   Restart the scan to pick up the sender's current ticket");
+            events::emit(Event::TransferFailed {
+                peer_id: Some(ticket.peer_id.to_string()),
+                error: "Session ticket was already used".to_string(),
+            });
+            std::process::exit(1);
+        }
+    }
+
+    /* 6. Setup libp2p with appropriate protocol */
+    let keypair = Keypair::generate_ed25519();
+    let mut swarm = network::build_swarm(keypair, ticket.protocol, false)?;
+
+    // Accept per-file streams in case the sender negotiates parallel transfer
+    let mut incoming_files = Some(
+        network::get_stream_control(&swarm)
+            .accept(StreamProtocol::new(network::FILE_PROTOCOL))?,
+    );
+
+    say!("🌐 Building P2P connection...");
+
+    /* 7. Dial the sender */
+    if ticket.addrs.is_empty() {
+        return Err("Session ticket contains no addresses to dial".into());
+    }
+    // A dual-transport sender is dialed over QUIC first, with TCP joining in
+    // shortly after; the first connection wins
+    let race = ticket.protocol == TransportProtocol::Both;
+    let mut dialer = Redialer::new(ticket.peer_id, ticket.addrs.clone(), options.connect_timeout, race);
+    // Relays the ticket's relayed addresses go through; we connect to them too
+    let relays: Vec<PeerId> = ticket.addrs.iter().filter_map(network::relay_peer_of).collect();
+
+    /* 8. Wait for connection and open stream for transfer */
+    let mut connected_peer = None;
+    let mut relayed: Option<RelayedConnection> = None;
+    let mut heartbeat = Heartbeat::default();
+    let mut transfer_task: Option<JoinHandle<anyhow::Result<ReceiveSummary>>> = None;
+    let cancel = CancellationToken::new();
+
+    say!("\n⏳ Waiting for P2P connection...\n");
+    debug!("Entering event loop...");
+
+    loop {
+        trace!("Waiting for next swarm event...");
+        let event = tokio::select! {
+            event = swarm.select_next_some() => event,
+            _ = time::sleep_until(dialer.next_attempt()), if connected_peer.is_none() => {
+                dialer.dial_next(&mut swarm);
+                continue;
+            }
+            _ = time::sleep_until(dialer.deadline()), if connected_peer.is_none() => {
+                eprintln!(
+                    "❌ Could not reach the sender within {}s. Addresses tried:\n{}",
+                    options.connect_timeout.as_secs(),
+                    dialer.failure_report()
+                );
+                eprintln!("   Check both devices are on the same network and no firewall blocks the connection");
+                events::emit(Event::TransferFailed {
+                    peer_id: Some(ticket.peer_id.to_string()),
+                    error: format!("Could not reach the sender within {}s", options.connect_timeout.as_secs()),
+                });
+                std::process::exit(1);
+            }
+            _ = time::sleep_until(relayed.as_ref().map_or_else(time::Instant::now, |r| r.give_up)),
+                if relayed.as_ref().is_some_and(|r| !r.punched) => {
+                if let Some(relayed) = relayed.take() {
+                    eprintln!("⚠️  No direct connection, transferring through the relay (slower)");
+                    transfer_task = Some(spawn_transfer(
+                        network::get_stream_control(&swarm),
+                        ticket.peer_id,
+                        relayed.transport,
+                        session(&swarm, &ticket),
+                        incoming_files.take(),
+                        options.clone(),
+                        cancel.clone(),
+                    ));
+                }
+                continue;
+            }
+            _ = signal::ctrl_c() => {
+                say!("\n\n🛑 Received Ctrl+C, cancelling transfer...");
+                cancel.cancel();
+                continue;
+            }
+            // Ctrl+C, or q typed during the transfer
+            _ = cancel.cancelled() => {
+                if let Some(task) = transfer_task.take() {
+                    match task.await {
+                        Ok(Ok(summary)) => print_partial_summary("📋 Transfer interrupted:", &summary),
+                        Ok(Err(_)) => {} // Already reported by the task
+                        Err(e) => eprintln!("❌ Transfer task panicked: {}", e),
+                    }
+                }
+                std::process::exit(EXIT_INTERRUPTED);
+            }
+        };
+
+        match event {
+            SwarmEvent::ConnectionEstablished { peer_id, .. } if relays.contains(&peer_id) => {
+                debug!("Connected to relay {}", peer_id);
+            }
+            SwarmEvent::ConnectionEstablished { peer_id, .. } if peer_id != ticket.peer_id => {
+                // Whoever answered at that address is not the peer that signed the ticket
+                eprintln!(
+                    "❌ Connected peer {} does not match the ticket's peer {}, disconnecting",
+                    peer_id, ticket.peer_id
+                );
+                let _ = swarm.disconnect_peer_id(peer_id);
+            }
+            SwarmEvent::ConnectionEstablished { endpoint, .. }
+                if relayed.as_ref().is_some_and(|r| !r.punched)
+                    && !network::is_relayed(endpoint.get_remote_address()) =>
+            {
+                // Hole punching worked; the transfer starts once the relayed connection is gone
+                say!("🕳️  Direct connection via {}, leaving the relay", endpoint.get_remote_address());
+                if let Some(relayed) = relayed.as_mut() {
+                    relayed.punched = true;
+                    relayed.transport = network::transport_of(endpoint.get_remote_address());
+                    swarm.close_connection(relayed.id);
+                }
+            }
+            SwarmEvent::ConnectionEstablished { connection_id, endpoint, .. } if connected_peer.is_some() => {
+                // Lost the race against a connection over another address
+                say!("🔌 Closing extra connection via {}", endpoint.get_remote_address());
+                swarm.close_connection(connection_id);
+            }
+            SwarmEvent::ConnectionEstablished { peer_id, connection_id, endpoint, .. } => {
+                say!("✅ P2P connection established with {}", peer_id);
+                debug!("Endpoint: {:?}", endpoint);
+                events::emit(Event::Connected {
+                    peer_id: peer_id.to_string(),
+                    address: endpoint.get_remote_address().to_string(),
+                });
+                connected_peer = Some(peer_id);
+                let transport = network::transport_of(endpoint.get_remote_address());
+
+                if network::is_relayed(endpoint.get_remote_address()) {
+                    // Relays cap circuit time and data, so give DCUtR a chance first
+                    say!("🛰️  Connected through a relay, trying to punch a direct connection...");
+                    relayed = Some(RelayedConnection {
+                        id: connection_id,
+                        give_up: time::Instant::now() + HOLE_PUNCH_TIMEOUT,
+                        transport,
+                        punched: false,
+                    });
+                    continue;
+                }
+
+                transfer_task = Some(spawn_transfer(
+                    network::get_stream_control(&swarm),
+                    peer_id,
+                    transport,
+                    session(&swarm, &ticket),
+                    incoming_files.take(),
+                    options.clone(),
+                    cancel.clone(),
+                ));
+            }
+            SwarmEvent::ConnectionClosed { peer_id, connection_id, cause, num_established, .. } => {
+                heartbeat.forget(connection_id);
+                if let Some(relayed) = relayed.take_if(|r| r.id == connection_id)
+                    && relayed.punched
+                    && num_established > 0
+                {
+                    debug!("Relayed connection closed, transferring directly");
+                    transfer_task = Some(spawn_transfer(
+                        network::get_stream_control(&swarm),
+                        peer_id,
+                        relayed.transport,
+                        session(&swarm, &ticket),
+                        incoming_files.take(),
+                        options.clone(),
+                        cancel.clone(),
+                    ));
+                    continue;
+                }
+                say!("❌ Connection closed with {}: {:?}", peer_id, cause);
+                if Some(peer_id) == connected_peer && num_established == 0 {
+                    break;
+                }
+            }
+            SwarmEvent::Behaviour(FileTransferBehaviourEvent::Dcutr(event)) => match event.result {
+                Ok(_) => debug!("Hole punched to {}", event.remote_peer_id),
+                Err(e) => {
+                    warn!("Hole punching to {} failed: {}", event.remote_peer_id, e);
+                    // No point waiting out the timeout
+                    if let Some(relayed) = relayed.as_mut().filter(|r| !r.punched) {
+                        relayed.give_up = time::Instant::now();
+                    }
+                }
+            },
+            SwarmEvent::Behaviour(FileTransferBehaviourEvent::Ping(event)) => {
+                let first = heartbeat.rtt(event.connection).is_none();
+                if heartbeat.on_ping(&event) {
+                    // Closing the connection fails the transfer's pending reads right away
+                    eprintln!(
+                        "❌ Peer {} unreachable: {} heartbeats missed",
+                        event.peer,
+                        network::MAX_MISSED_PINGS
+                    );
+                    swarm.close_connection(event.connection);
+                } else if first
+                    && Some(event.peer) == connected_peer
+                    && let Some(rtt) = heartbeat.rtt(event.connection)
+                {
+                    // A first hint at how the transfer will go
+                    say!("📶 Round trip to the sender: {} ms", rtt.as_millis());
+                    events::emit(Event::RoundTrip { peer_id: event.peer.to_string(), rtt_ms: rtt.as_millis() as u64 });
+                }
+            }
+            SwarmEvent::OutgoingConnectionError { connection_id, peer_id, error } => {
+                warn!("Outgoing connection error to {:?}: {}", peer_id, error);
+                dialer.on_dial_failure(connection_id, &error);
+            }
+            SwarmEvent::IncomingConnectionError { send_back_addr, error, .. } => {
+                warn!("Incoming connection error from {:?}: {}", send_back_addr, error);
+            }
+            SwarmEvent::Dialing { peer_id, .. } => {
+                debug!("Dialing peer {:?}", peer_id);
+            }
+            event => {
+                debug!("Received event: {:?}", event);
+            }
+        }
+    }
 
+    // Scripts rely on the exit status to tell a complete transfer from a failed one
+    if let Some(task) = transfer_task {
+        match task.await {
+            Ok(Ok(summary)) if summary.cancelled => std::process::exit(EXIT_INTERRUPTED),
+            Ok(Ok(_)) => {}
+            Ok(Err(e)) if e.is::<network::CancelledByPeer>() => std::process::exit(EXIT_INTERRUPTED),
+            Ok(Err(_)) => std::process::exit(1), // Already reported by the task
+            Err(e) => return Err(format!("Transfer task panicked: {}", e).into()),
+        }
+    }
+
+    say!("👋 Done!");
+    Ok(())
+}
+
+/// Give up on a ticket before connecting, reporting it and exiting non-zero
+/// so scripts don't take it for a completed transfer
+fn refuse(ticket: &SessionTicket, error: String) -> ! {
+    events::emit(Event::TransferFailed { peer_id: Some(ticket.peer_id.to_string()), error });
+    std::process::exit(1);
+}
+
+/* ========== Discovery ========== */
+
+/// Scan for senders, let the user pick one and read its session ticket
+///
+/// Returns `None` when no sender was picked or it had no ticket to read.
+async fn read_ticket_over_ble(options: &Options) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
     /* 1. Setup Bluetooth adapter */
     let manager = Manager::new().await?;
     let adapter = manager
@@ -46,57 +485,776 @@ async fn main() -> Result<(), Box<dyn Error>> {
         .into_iter()
         .next()
         .ok_or("No Bluetooth adapters found")?;
-    println!("📡 Using adapter: {}", adapter.adapter_info().await?);
+    say!("📡 Using adapter: {}", adapter.adapter_info().await?);
 
-    /* 2. Scan for devices */
+    /* 2. Fastdrop service UUIDs to look for, the old TCP one included */
+    // A malformed constant would make its senders undiscoverable, so don't skip it
+    let target_uuids = protocol::SCANNED_SERVICE_UUIDS
+        .iter()
+        .map(|s| Uuid::parse_str(s))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let char_uuids = protocol::SCANNED_CHAR_UUIDS
+        .iter()
+        .map(|s| Uuid::parse_str(s))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    /* 3. Scan for devices and let the user pick one */
     adapter.start_scan(ScanFilter::default()).await?;
-    println!("🔍 Scanning for 15 seconds...\n");
-    time::sleep(Duration::from_secs(15)).await;
-    adapter.stop_scan().await?;
+    // The picker is a terminal UI, JSON consumers answer a numbered prompt instead
+    let peripheral = if !options.json && io::stdin().is_terminal() && io::stdout().is_terminal() {
+        picker::pick_device(&adapter, &target_uuids, &char_uuids, SCAN_DURATION).await?
+    } else {
+        scan_and_prompt(&adapter, &target_uuids).await?
+    };
+
+    let Some(peripheral) = peripheral else {
+        say!("❌ No Fastdrop device selected");
+        say!("   Make sure the sender is running and advertising");
+        events::emit(Event::TransferFailed {
+            peer_id: None,
+            error: "No Fastdrop device selected".to_string(),
+        });
+        return Ok(None);
+    };
+    say!("\n🔗 Connecting to device {}...", peripheral.address());
+
+    /* 5. Connect and read session ticket */
+    peripheral.connect().await?;
+    peripheral.discover_services().await?;
+    say!("✅ Connected\n");
+
+    for service in peripheral.services() {
+        debug!("Discovered service {}", service.uuid);
+        for ch in peripheral.characteristics() {
+            if ch.service_uuid == service.uuid {
+                debug!("   characteristic {}", ch.uuid);
+            }
+        }
+    }
+
+    // Try to find characteristic from any of the UUIDs
+    debug!("Looking for Fastdrop characteristics {:?}", char_uuids);
+    let ticket_data = picker::read_ticket(&peripheral, &char_uuids).await?;
+    if ticket_data.is_none() {
+        eprintln!("❌ No Fastdrop characteristic found among discovered characteristics");
+    }
+    peripheral.disconnect().await?;
+    say!("🔌 Disconnected from BLE\n");
+    Ok(ticket_data)
+}
+
+/* ========== Transfer ========== */
+
+/// A connection to the sender through a relay, kept until hole punching
+/// replaces it with a direct one or `give_up` passes
+struct RelayedConnection {
+    id: ConnectionId,
+
+    /// When to stop waiting and transfer through the relay
+    give_up: time::Instant,
+
+    /// Transport of the connection the transfer will run over
+    transport: TransportProtocol,
+
+    /// A direct connection is up and the relayed one is being closed
+    punched: bool,
+}
+
+/// Spawn the task that requests the files and receives them
+fn spawn_transfer(
+    control: libp2p_stream::Control,
+    peer_id: PeerId,
+    transport: TransportProtocol,
+    session: Session,
+    incoming_files: Option<libp2p_stream::IncomingStreams>,
+    options: Options,
+    cancel: CancellationToken,
+) -> JoinHandle<anyhow::Result<ReceiveSummary>> {
+    say!("📨 Opening stream to send transfer request...");
+    tokio::spawn(events::in_current_transfer(async move {
+        let result = receive_files(control, peer_id, session, incoming_files, options, cancel).await;
+        match &result {
+            Ok(summary) if !summary.cancelled => {
+                say!("   Transport: {:?}\n", transport);
+                events::emit(Event::TransferStats {
+                    peer_id: peer_id.to_string(),
+                    stats: summary.stats.clone(),
+                });
+                events::emit(Event::TransferComplete {
+                    peer_id: peer_id.to_string(),
+                    files: summary.completed.len(),
+                    transport: format!("{:?}", transport).to_lowercase(),
+                });
+            }
+            Ok(summary) => events::emit(Event::TransferCancelled {
+                peer_id: Some(peer_id.to_string()),
+                reason: if summary.declined { "declined by receiver" } else { "cancelled by receiver" }.to_string(),
+            }),
+            // A sender stopping the transfer on purpose is not a failure
+            Err(e) => match e.downcast_ref::<network::CancelledByPeer>() {
+                Some(cancelled) => {
+                    say!("🛑 {}", cancelled);
+                    events::emit(Event::TransferCancelled {
+                        peer_id: Some(peer_id.to_string()),
+                        reason: cancelled.reason.clone(),
+                    });
+                }
+                None => {
+                    eprintln!("❌ Transfer failed: {:#}", e);
+                    events::emit(Event::TransferFailed {
+                        peer_id: Some(peer_id.to_string()),
+                        error: format!("{:#}", e),
+                    });
+                }
+            },
+        }
+        result
+    }))
+}
+
+/// The session the ticket set up, as seen from our swarm
+fn session(swarm: &libp2p::Swarm<network::FileTransferBehaviour>, ticket: &SessionTicket) -> Session {
+    Session {
+        local_peer_id: *swarm.local_peer_id(),
+        sender: ticket.peer_id,
+        nonce: ticket.nonce,
+        session_id: ticket.session_id,
+        announced: (ticket.file_count > 0).then_some((ticket.file_count, ticket.total_size)),
+        signs_manifests: ticket::signs_manifests(ticket),
+    }
+}
+
+/// What the receiver knows about the connection it is transferring over
+struct Session {
+    /// Our own (ephemeral) identity
+    local_peer_id: PeerId,
+
+    /// The sender the ticket names, the only peer whose file streams are taken
+    sender: PeerId,
+
+    /// Nonce of the ticket we connected with
+    nonce: u64,
+
+    /// Session ID of that ticket, echoed so the sender knows we read it
+    session_id: ticket::SessionId,
+
+    /// File count and total size the ticket announced, if it did
+    announced: Option<(u32, u64)>,
+
+    /// Whether the sender signs its file lists, so an unsigned one was tampered with
+    signs_manifests: bool,
+}
+
+/// Open a transfer stream to the sender, request the files and write them to disk
+async fn receive_files(
+    mut control: libp2p_stream::Control,
+    peer_id: PeerId,
+    session: Session,
+    mut incoming_files: Option<libp2p_stream::IncomingStreams>,
+    options: Options,
+    cancel: CancellationToken,
+) -> anyhow::Result<ReceiveSummary> {
+    debug!("Spawned stream handler task");
+
+    let key_exchange = crypto::KeyExchange::new();
+    let handshake = async {
+        debug!("Attempting to open stream to {}", peer_id);
+
+        let protocol = StreamProtocol::new(network::TRANSFER_PROTOCOL);
+        let mut stream = control
+            .open_stream(peer_id, protocol)
+            .await
+            .map_err(|e| anyhow::anyhow!("Failed to open stream: {}", e))?;
+        say!("✅ Stream opened successfully");
+
+        // Send request
+        let request = TransferRequest {
+            request_id: rand::random(),
+            ready: true,
+            version: protocol::PROTOCOL_VERSION,
+            capabilities: protocol::CAPABILITIES
+                .iter()
+                // `--parallel 1` asks for everything on the one stream
+                .filter(|c| {
+                    (incoming_files.is_some() && options.parallel != Some(1))
+                        || **c != Capability::ParallelFiles.token()
+                })
+                .filter(|c| !options.no_verify || **c != Capability::Sas.token())
+                .map(|c| c.to_string())
+                .collect(),
+            nonce: session.nonce,
+            session_id: session.session_id,
+            preferred_chunk_size: options.chunk_size.map_or(0, |size| size as u32),
+            max_parallel_files: options.parallel.map_or(0, |parallel| parallel as u32),
+            key_share: Some(key_exchange.public_key()),
+        };
+
+        debug!(
+            "Sending transfer request for session {}...",
+            ticket::format_session_id(&session.session_id)
+        );
+        network::write_request(&mut stream, request).await?;
+
+        say!("📨 Request sent, waiting for response...");
+
+        // Read response
+        let response = network::read_response(&mut stream).await?;
+        anyhow::Ok((stream, response))
+    };
+
+    // Nothing has been written yet, so cancelling here needs no cleanup
+    let (mut stream, mut response) = tokio::select! {
+        _ = cancel.cancelled() => {
+            return Ok(ReceiveSummary { cancelled: true, ..Default::default() });
+        }
+        result = handshake => result?,
+    };
+
+    if !response.accepted {
+        let reason = match (response.reject_reason, response.reason.as_deref()) {
+            (Some(kind), Some(detail)) => format!("{} ({})", kind, detail),
+            (Some(kind), None) => kind.to_string(),
+            (None, Some(detail)) => detail.to_string(),
+            (None, None) => "no reason given".to_string(),
+        };
+        anyhow::bail!("Sender rejected the transfer: {}", reason);
+    }
+    // The sender has to pick a version we speak, at most our own
+    if protocol::negotiate_version(protocol::PROTOCOL_VERSION, response.version) != Some(response.version) {
+        return Err(protocol::VersionMismatch {
+            local: protocol::PROTOCOL_VERSION,
+            remote: response.version,
+        }
+        .into());
+    }
+    say!("🤝 Protocol version {}", protocol::format_version(response.version));
+
+    // Chunk data is encrypted end to end whenever the sender agreed to it
+    let our_key = key_exchange.public_key();
+    let cipher = if response.supports(Capability::Encrypt) {
+        let Some(peer_key) = response.key_share else {
+            anyhow::bail!("Sender agreed to encryption but sent no key");
+        };
+        say!("🔒 File contents are end-to-end encrypted (ChaCha20-Poly1305)");
+        Some(key_exchange.finish(peer_key, session.nonce, &session.session_id)?)
+    } else {
+        None
+    };
+    let chunk_size = (response.chunk_size != 0).then_some(response.chunk_size as usize);
+    if let Some(chunk_size) = chunk_size {
+        say!("📏 Chunk size: {}", transfer::format_bytes(chunk_size as u64));
+    }
+
+    // The file list must come from the identity that signed the ticket
+    if response.manifest_sig == protocol::unsigned() {
+        if session.signs_manifests {
+            eprintln!("🚨 POSSIBLE TAMPERING: the sender signs its file lists, but this one arrived unsigned");
+            anyhow::bail!("Refusing an unsigned file list from a sender that signs them");
+        }
+        say!("⚠️  Sender did not sign the file list (older Fastdrop version)");
+    } else if let Err(e) = ticket::verify_manifest(&response.signed_file_list, &response.manifest_sig, &peer_id) {
+        eprintln!("🚨 POSSIBLE TAMPERING: {:#}", e);
+        anyhow::bail!("Refusing a file list that doesn't match the sender's signature");
+    }
+
+    // The ticket got us to its sender, so it must not be used again
+    if let Err(e) = ticket::SeenNonces::load().record(&peer_id, session.nonce) {
+        eprintln!("⚠️  Failed to remember ticket nonce: {:#}", e);
+    }
+
+    // Anyone in radio range can advertise, so let the user confirm who answered
+    let mut verified_code = None;
+    if response.supports(Capability::Sas) {
+        let key_shares = response.key_share.filter(|_| cipher.is_some()).map(|sender_key| (sender_key, our_key));
+        let code = ticket::verification_code(&peer_id, &session.local_peer_id, session.nonce, key_shares);
+        say!("\n🔢 Verification code: {}", code);
+        say!("   Check the sender shows the same code");
+        events::emit(Event::VerificationCode { peer_id: peer_id.to_string(), code: code.clone() });
+        let prompt = tokio::task::spawn_blocking(|| confirm("❓ Do the codes match? [y/N]: "));
+        let confirmed = tokio::select! {
+            _ = cancel.cancelled() => {
+                return Ok(ReceiveSummary { cancelled: true, ..Default::default() });
+            }
+            answer = prompt => answer??,
+        };
+
+        let answer = Confirmed {
+            request_id: response.request_id,
+            confirmed,
+        };
+        network::write_confirmed(&mut stream, answer).await?;
+        if !confirmed {
+            anyhow::bail!("Verification codes don't match - not receiving from this device");
+        }
+        say!("🔐 Sender verified\n");
+        verified_code = Some(code);
+    }
+
+    if options.no_preserve_permissions {
+        for file in &mut response.file_list.files {
+            file.mode = None;
+        }
+    }
+
+    // Features the sender didn't agree to are ignored, whatever the response carries
+    if !response.supports(Capability::Dedup) {
+        for file in &mut response.file_list.files {
+            file.duplicate_of = None;
+        }
+    }
+    if !response.supports(Capability::ZstdDict) {
+        response.dictionary = None;
+    }
+
+    // Never write outside the current directory, whatever the sender claims,
+    // and hold the sender to the sizes it stated
+    let checked = transfer::validate_output_paths(&response.file_list)
+        .and_then(|()| transfer::validate_sizes(&response.file_list, session.announced));
+    if let Err(e) = checked {
+        let decision = TransferDecision {
+            request_id: response.request_id,
+            accepted: false,
+            resume: None,
+            reject_reason: None,
+            skip: Vec::new(),
+        };
+        network::write_decision(&mut stream, decision).await?;
+        anyhow::bail!("Refusing unsafe file list: {:#}", e);
+    }
+
+    // The ticket only carries the total, and older senders leave even that out
+    if let Some(problem) = options.limits.exceeded_by(&response.file_list) {
+        let decision = TransferDecision {
+            request_id: response.request_id,
+            accepted: false,
+            resume: None,
+            reject_reason: Some(RejectReason::TooLarge),
+            skip: Vec::new(),
+        };
+        network::write_decision(&mut stream, decision).await?;
+        anyhow::bail!("Refusing offer: {} (see --max-file-size/--max-total-size)", problem);
+    }
+
+    say!("📦 Received file list:");
+    say!("   Files: {}", response.file_list.files.len());
+    for file in &response.file_list.files {
+        match file.duplicate_of {
+            Some(original) => say!(
+                "      - {} (copy of {})",
+                transfer::display_path(file),
+                transfer::display_path(&response.file_list.files[original])
+            ),
+            None => say!("      - {} ({})", transfer::display_path(file), transfer::format_bytes(file.size)),
+        }
+    }
+    say!(
+        "   Total size: {}",
+        transfer::format_bytes(response.file_list.total_size)
+    );
+    if response.file_list.unverified {
+        say!("   ⚠️  UNVERIFIED: the sender disabled hashing, corruption can't be detected");
+    }
+    say!();
+    events::emit(Event::Offer {
+        files: response
+            .file_list
+            .files
+            .iter()
+            .enumerate()
+            .map(|(file_index, file)| OfferedFile {
+                file_index,
+                path: transfer::display_path(file).to_string(),
+                size: file.size,
+                duplicate_of: file.duplicate_of,
+            })
+            .collect(),
+        total_size: response.file_list.total_size,
+    });
+
+    // Let the user review the offer before anything touches the disk
+    let accepted = if options.yes {
+        true
+    } else {
+        // Repeat the code so the decision is tied to the device that was checked
+        let question = match &verified_code {
+            Some(code) => format!("📥 Accept these files from the sender showing {}? [y/N]: ", code),
+            None => "📥 Accept these files? [y/N]: ".to_string(),
+        };
+        let prompt = tokio::task::spawn_blocking(move || confirm(&question));
+        tokio::select! {
+            _ = cancel.cancelled() => {
+                return Ok(ReceiveSummary { cancelled: true, ..Default::default() });
+            }
+            answer = prompt => answer??,
+        }
+    };
+
+    // Settle files that already exist before looking for partial copies
+    let skip = if accepted {
+        let mut policy = options.conflict;
+        if policy == ConflictPolicy::Skip && !response.supports(Capability::Skip) {
+            say!("⚠️  Sender can't skip files, receiving existing ones under new names instead");
+            policy = ConflictPolicy::Rename;
+        }
+        transfer::resolve_conflicts(&mut response.file_list, policy).await?
+    } else {
+        Vec::new()
+    };
+
+    // Pick up where an interrupted transfer of the same files left off
+    let mut resume = if accepted && response.supports(Capability::Resume) {
+        find_resume_points(&response.file_list, chunk_size).await?
+    } else {
+        None
+    };
+    if resume.is_some() && !options.yes {
+        let prompt = tokio::task::spawn_blocking(|| confirm("♻️  Resume from these partial files? [y/N]: "));
+        let answer = tokio::select! {
+            _ = cancel.cancelled() => {
+                return Ok(ReceiveSummary { cancelled: true, ..Default::default() });
+            }
+            answer = prompt => answer??,
+        };
+        if !answer {
+            say!("🆕 Downloading from the start, the partial files will be overwritten");
+            resume = None;
+        }
+    }
+
+    let decision = TransferDecision {
+        request_id: response.request_id,
+        accepted,
+        resume: resume.clone(),
+        reject_reason: None,
+        skip: skip.clone(),
+    };
+    network::write_decision(&mut stream, decision).await?;
+
+    if !accepted {
+        say!("🚫 Transfer declined, sender has been notified");
+        // Nothing was received, so scripts must not take it for a completed transfer
+        return Ok(ReceiveSummary { cancelled: true, declined: true, ..Default::default() });
+    }
+
+    let resume = resume.unwrap_or_default();
+    let settings = network::ReceiveSettings {
+        cipher,
+        rate_limiter: options.rate_limit.map(|rate| Arc::new(RateLimiter::new(rate))),
+        events: None,
+    };
+    let source = match incoming_files.as_mut() {
+        Some(incoming) if response.supports(Capability::ParallelFiles) => {
+            ChunkSource::Files { incoming, sender: session.sender }
+        }
+        _ if response.supports(Capability::DataStream) => {
+            let data = control
+                .open_stream(peer_id, StreamProtocol::new(network::DATA_PROTOCOL))
+                .await
+                .map_err(|e| anyhow::anyhow!("Failed to open data stream: {}", e))?;
+            ChunkSource::Data(data)
+        }
+        _ => ChunkSource::Transfer,
+    };
+    let result = receive_accepted(&mut stream, &response, &resume, &skip, source, &settings, &cancel).await;
+
+    // Whatever didn't finish is still on disk as `.part` files
+    if result.as_ref().map_or(true, |summary| summary.cancelled) {
+        if let Err(e) = transfer::handle_partial_files(&response.file_list, options.on_failure).await {
+            eprintln!("⚠️  {:#}", e);
+        }
+    }
+    result
+}
+
+/// Where the chunks of an accepted transfer arrive
+enum ChunkSource<'a> {
+    /// After the handshake, on the transfer stream itself
+    Transfer,
+    /// On the data stream opened after accepting
+    Data(Stream),
+    /// On one stream per file, opened by the sender
+    Files { incoming: &'a mut libp2p_stream::IncomingStreams, sender: PeerId },
+}
 
-    /* 3. Filter for Fastdrop devices (any of the 4 UUIDs) */
-    let target_uuids: Vec<Uuid> = ALL_SERVICE_UUIDS
+/// Receive the accepted files and tell the sender how they arrived
+async fn receive_accepted(
+    stream: &mut Stream,
+    response: &TransferResponse,
+    resume: &ResumeRequest,
+    skip: &[usize],
+    source: ChunkSource<'_>,
+    settings: &network::ReceiveSettings,
+    cancel: &CancellationToken,
+) -> anyhow::Result<ReceiveSummary> {
+    // Receive and write chunks streaming (optimized - writes as we receive)
+    say!("📥 Receiving and writing file chunks...");
+    if let Some(limiter) = &settings.rate_limiter {
+        say!("🐢 Download rate limited to {}", transfer::format_speed(limiter.bytes_per_sec()));
+    }
+    // Chunks on their own streams leave the completion message on this one
+    let separate = !matches!(source, ChunkSource::Transfer);
+    let completion = response.supports(Capability::TransferComplete);
+    let (pause_tx, pause) = watch::channel(false);
+    let pausable = response.supports(Capability::Pause);
+    if pausable {
+        say!("⌨️  Type p + Enter to pause, r + Enter to resume, q + Enter to cancel");
+    } else {
+        say!("⌨️  Type q + Enter to cancel");
+    }
+    watch_keys(pausable.then_some(pause_tx), cancel.clone());
+
+    let (network_events, rendered) = mpsc::unbounded_channel();
+    let renderer = tokio::spawn(events::in_current_transfer(render_network_events(
+        rendered,
+        response.file_list.clone(),
+    )));
+    let settings = network::ReceiveSettings { events: Some(network_events), ..settings.clone() };
+    let received = match source {
+        ChunkSource::Files { incoming, sender } => {
+            say!("🔀 Sender is using parallel per-file streams");
+            network::receive_files_over_streams(incoming, sender, response, resume, skip, &settings, cancel, &pause)
+                .await
+        }
+        ChunkSource::Data(mut data) => {
+            debug!("Receiving chunks on the data stream");
+            network::receive_and_write_chunks_streaming(&mut data, response, resume, skip, &settings, cancel, &pause)
+                .await
+        }
+        ChunkSource::Transfer => {
+            network::receive_and_write_chunks_streaming(stream, response, resume, skip, &settings, cancel, &pause)
+                .await
+        }
+    };
+    let received = match received {
+        Ok(mut summary) if !summary.cancelled => {
+            let finished = async {
+                network::copy_duplicates(&response.file_list, skip, &mut summary, settings.events.as_ref()).await?;
+                network::create_empty_files(&response.file_list, skip, &mut summary).await
+            };
+            finished.await.map(|()| summary)
+        }
+        received => received,
+    };
+    // Everything the transfer reported is on screen before what follows
+    drop(settings);
+    let _ = renderer.await;
+
+    let mut summary = received?;
+    if summary.cancelled {
+        return Ok(summary);
+    }
+    summary.note_not_started(&response.file_list, skip);
+
+    if completion {
+        // Without the sender's completion message, EOF may just be a dropped connection
+        let complete = match summary.transfer_complete.take() {
+            Some(complete) => complete,
+            None if separate => network::read_complete(stream).await?,
+            None => {
+                print_partial_summary("📋 Transfer incomplete:", &summary);
+                anyhow::bail!("Connection ended before the sender finished the transfer");
+            }
+        };
+        let expected = response
+            .file_list
+            .files
+            .iter()
+            .enumerate()
+            .filter(|(file_index, f)| f.duplicate_of.is_none() && !skip.contains(file_index))
+            .count();
+        if complete.file_count != expected {
+            eprintln!(
+                "⚠️  Sender reports {} file(s) sent, expected {}",
+                complete.file_count,
+                expected
+            );
+        }
+        for file_index in summary.check_file_hashes(&response.file_list, &complete).await {
+            eprintln!(
+                "❌ {} doesn't match the hash the sender reported at the end",
+                transfer::display_path(&response.file_list.files[file_index])
+            );
+        }
+        network::write_receipt(stream, summary.receipt(&response.file_list)).await?;
+    }
+
+    let failed = summary
+        .statuses
         .iter()
-        .filter_map(|s| Uuid::parse_str(s).ok())
-        .collect();
+        .filter(|r| r.status == FileStatus::Failed)
+        .count();
+    let incomplete = summary.partial.len() + summary.not_started.len();
+    if incomplete > 0 {
+        print_partial_summary("📋 Transfer incomplete:", &summary);
+    }
+    match (failed, incomplete) {
+        (0, 0) => {}
+        (failed, 0) => anyhow::bail!("{} file(s) failed hash verification", failed),
+        (0, incomplete) => anyhow::bail!("{} file(s) were not received in full", incomplete),
+        (failed, incomplete) => anyhow::bail!(
+            "{} file(s) failed hash verification and {} were not received in full",
+            failed,
+            incomplete
+        ),
+    }
+
+    say!("\n✅ Transfer complete!");
+    say!("   Received {} file(s)", summary.completed.len());
+    summary
+        .stats
+        .print(|file_index| transfer::display_path(&response.file_list.files[file_index]).to_string());
+
+    Ok(summary)
+}
+
+/// Look for `.part` files left by an earlier attempt at this transfer
+async fn find_resume_points(
+    file_list: &FileList,
+    chunk_size: Option<usize>,
+) -> anyhow::Result<Option<ResumeRequest>> {
+    let files = transfer::find_resume_points(file_list, chunk_size).await?;
+    for point in &files {
+        say!(
+            "♻️  Partial copy of {} has {}",
+            transfer::display_path(&file_list.files[point.file_index]),
+            transfer::format_bytes(point.offset)
+        );
+    }
+
+    Ok((!files.is_empty()).then_some(ResumeRequest { files }))
+}
 
-    println!("🔎 Filtering for Fastdrop devices...");
-    println!("   Looking for UUIDs:");
-    for uuid in &target_uuids {
-        println!("      - {}", uuid);
+/// Print what the transfer engine reports, until the transfer drops its sender
+async fn render_network_events(mut reports: mpsc::UnboundedReceiver<NetworkEvent>, file_list: FileList) {
+    while let Some(event) = reports.recv().await {
+        match event {
+            NetworkEvent::StreamOpened { peer } => debug!("File stream opened by {}", peer),
+            NetworkEvent::StreamRefused { peer } => say!("⚠️  Ignored a file stream from {}, which is not the sender", peer),
+            NetworkEvent::FileStarted { file_index, offset } => {
+                let meta = &file_list.files[file_index];
+                if offset == 0 {
+                    say!("📄 Writing: {}", transfer::display_path(meta));
+                } else {
+                    say!("📄 Resuming: {} at {}", transfer::display_path(meta), transfer::format_bytes(offset));
+                }
+                events::emit(Event::FileStarted {
+                    file_index,
+                    path: transfer::display_path(meta).to_string(),
+                    size: meta.size,
+                    offset,
+                });
+            }
+            NetworkEvent::DuplicateChunk { file_index, chunk_number } => {
+                debug!("Ignoring duplicate chunk {} of file {}", chunk_number, file_index);
+            }
+            NetworkEvent::ChunkReceived { file_index, chunk_number, bytes } => {
+                trace!("Chunk {} of file {} written ({} bytes)", chunk_number, file_index, bytes);
+            }
+            NetworkEvent::Progress { file_index, file_bytes, transfer_bytes, transfer_total } => {
+                if let Some(bytes) = file_bytes {
+                    let total = file_list.files[file_index].size;
+                    events::emit(Event::FileProgress { file_index, bytes, total });
+                }
+                events::emit(Event::TransferProgress { bytes: transfer_bytes, total: transfer_total });
+            }
+            NetworkEvent::FileWritten { chunks, bytes, .. } => {
+                say!("   ✅ Completed: {} chunks, {} bytes", chunks, bytes);
+            }
+            NetworkEvent::FileCompleted { file_index, status, transfer_bytes, transfer_total } => {
+                let meta = &file_list.files[file_index];
+                match status {
+                    FileStatus::Verified => say!("   🔐 {:?} hash verified", meta.hash_algo),
+                    FileStatus::Unverified if file_list.unverified => {
+                        say!("   ⚠️  Not verified (sender disabled hashing)");
+                    }
+                    _ => {}
+                }
+                events::emit(Event::FileCompleted {
+                    file_index,
+                    path: transfer::display_path(meta).to_string(),
+                    status: Some(status),
+                });
+                say!(
+                    "   📊 {} of {} received ({:.0}%)",
+                    transfer::format_bytes(transfer_bytes),
+                    transfer::format_bytes(transfer_total),
+                    transfer_bytes as f64 * 100.0 / transfer_total.max(1) as f64
+                );
+            }
+            NetworkEvent::DuplicateCopied { file_index, original, status } => {
+                let meta = &file_list.files[file_index];
+                say!(
+                    "   ♊ Copied {} from {}",
+                    transfer::display_path(meta),
+                    transfer::display_path(&file_list.files[original])
+                );
+                events::emit(Event::FileCompleted {
+                    file_index,
+                    path: transfer::display_path(meta).to_string(),
+                    status: Some(status),
+                });
+            }
+            NetworkEvent::Retransmit { file_index, chunk_number } => {
+                say!(
+                    "   🔁 Chunk {} of {} arrived corrupted, asking for it again",
+                    chunk_number,
+                    transfer::display_path(&file_list.files[file_index])
+                );
+            }
+            NetworkEvent::Error { message, .. } => eprintln!("   ❌ {}", message),
+            // Only the sending side sees these
+            NetworkEvent::Listening { .. }
+            | NetworkEvent::PortMapped { .. }
+            | NetworkEvent::ChunkSent { .. }
+            | NetworkEvent::PeerPaused
+            | NetworkEvent::PeerResumed => {}
+        }
     }
-    println!();
+}
+
+/* ========== Device Selection ========== */
+
+/// Non-interactive fallback: scan for the full duration, then ask for a device number
+async fn scan_and_prompt(
+    adapter: &Adapter,
+    target_uuids: &[Uuid],
+) -> Result<Option<Peripheral>, Box<dyn Error>> {
+    say!("🔍 Scanning for {} seconds...\n", SCAN_DURATION.as_secs());
+    time::sleep(SCAN_DURATION).await;
+    adapter.stop_scan().await?;
+
+    debug!("Filtering for Fastdrop service UUIDs {:?}", target_uuids);
 
     let mut fastdrop_devices = Vec::new();
     for p in adapter.peripherals().await? {
         if let Some(props) = p.properties().await? {
-            // Debug: print all discovered devices
             let name = props.local_name.as_deref().unwrap_or("Unknown");
             let has_service = target_uuids
                 .iter()
                 .any(|uuid| props.services.contains(uuid));
             
             if has_service {
-                println!("✓ Found Fastdrop device: {} ({})", name, p.address());
+                say!("✓ Found Fastdrop device: {} ({})", name, p.address());
                 fastdrop_devices.push(p);
+            } else {
+                debug!("Ignoring {} ({}) without a Fastdrop service", name, p.address());
             }
         }
     }
 
     if fastdrop_devices.is_empty() {
-        println!("❌ No Fastdrop devices found");
-        println!("   Make sure the sender is running and advertising");
-        return Ok(());
+        return Ok(None);
     }
 
-    println!("\n✅ Found {} Fastdrop device(s):\n", fastdrop_devices.len());
+    say!("\n✅ Found {} Fastdrop device(s):\n", fastdrop_devices.len());
+    let mut devices = Vec::new();
     for (i, p) in fastdrop_devices.iter().enumerate() {
-        print_device_summary(i, p).await;
+        devices.push(print_device_summary(i, p).await);
     }
+    events::emit(Event::Devices { devices });
 
-    /* 4. User selection */
-    print!("\n📱 Select device number (1-{}): ", fastdrop_devices.len());
-    io::stdout().flush()?;
+    ask(&format!("\n📱 Select device number (1-{}): ", fastdrop_devices.len()))?;
     let mut buf = String::new();
     io::stdin().read_line(&mut buf)?;
     
@@ -107,196 +1265,246 @@ async fn main() -> Result<(), Box<dyn Error>> {
 
     if selection == 0 || selection > fastdrop_devices.len() {
         eprintln!("❌ Invalid device number");
-        return Ok(());
+        return Ok(None);
     }
 
-    let peripheral = &fastdrop_devices[selection - 1];
-    println!("\n🔗 Connecting to device {}...", selection);
+    Ok(Some(fastdrop_devices.swap_remove(selection - 1)))
+}
 
-    /* 5. Connect and read session ticket */
-    peripheral.connect().await?;
-    peripheral.discover_services().await?;
-    println!("✅ Connected\n");
+/* ========== Command Line ========== */
 
-    // Debug: List all discovered services and characteristics
-    println!("🔍 Discovered services:");
-    for service in peripheral.services() {
-        println!("   Service: {}", service.uuid);
-        for ch in peripheral.characteristics() {
-            if ch.service_uuid == service.uuid {
-                println!("      Char: {}", ch.uuid);
-            }
-        }
-    }
-    println!();
+/// Receiver command line options
+#[derive(Debug, Clone, Default)]
+struct Options {
+    /// Accept offered files and resume partial ones without prompting
+    yes: bool,
 
-    // Try to find characteristic from any of the UUIDs
-    let char_uuids: Vec<Uuid> = ALL_CHAR_UUIDS
-        .iter()
-        .filter_map(|s| Uuid::parse_str(s).ok())
-        .collect();
+    /// Dial even if the session ticket has expired
+    ignore_expiry: bool,
 
-    println!("🔎 Looking for Fastdrop characteristics:");
-    for uuid in &char_uuids {
-        println!("   - {}", uuid);
-    }
-    println!();
+    /// Ignore the sender's file permissions
+    no_preserve_permissions: bool,
 
-    let mut ticket_data = None;
-    for uuid in &char_uuids {
-        if let Some(ch) = peripheral.characteristics().iter().find(|c| c.uuid == *uuid) {
-            println!("✓ Found matching characteristic: {}", uuid);
-            ticket_data = Some(peripheral.read(ch).await?);
-            println!("📥 Read {} bytes from characteristic", ticket_data.as_ref().unwrap().len());
-            break;
-        }
-    }
+    /// Give up if no connection to the sender is made within this time
+    connect_timeout: Duration,
 
-    if ticket_data.is_none() {
-        eprintln!("❌ No Fastdrop characteristic found among discovered characteristics");
-        peripheral.disconnect().await?;
-        return Ok(());
-    }
+    /// Refuse offers with files or totals larger than these
+    limits: SizeLimits,
 
-    let ticket_data = ticket_data.unwrap();
-    let ticket: SessionTicket = from_slice(&ticket_data)?;
+    /// Accept tickets whose nonce was already used (debugging only)
+    allow_replay: bool,
 
-    println!("🎫 Session Ticket:");
-    println!("   Protocol: {:?}", ticket.protocol);
-    println!("   Peer ID: {}", ticket.peer_id);
-    println!("   Addresses: {}", ticket.addrs.len());
-    for addr in &ticket.addrs {
-        println!("      - {}", addr);
-    }
-    println!();
+    /// Skip comparing verification codes (trusted environments only)
+    no_verify: bool,
 
-    peripheral.disconnect().await?;
-    println!("🔌 Disconnected from BLE\n");
+    /// What to do with partial files when a transfer doesn't finish
+    on_failure: PartialPolicy,
 
-    /* 6. Setup libp2p with appropriate protocol */
-    let keypair = Keypair::generate_ed25519();
-    let mut swarm = network::build_swarm(keypair, ticket.protocol)?;
+    /// Chunk size to ask the sender for
+    chunk_size: Option<usize>,
 
-    println!("🌐 Building P2P connection...");
+    /// What to do with files that already exist
+    conflict: ConflictPolicy,
 
-    /* 7. Dial the sender */
-    for addr in &ticket.addrs {
-        println!("📞 Dialing {}", addr);
-        if let Err(e) = swarm.dial(addr.clone()) {
-            eprintln!("   ⚠️  Failed: {}", e);
-        } else {
-            println!("   ✅ Dial initiated successfully");
-        }
-    }
+    /// Most files to receive at once, 1 for a single stream
+    parallel: Option<usize>,
 
-    /* 8. Wait for connection and open stream for transfer */
-    let mut connected_peer = None;
+    /// Directory to save received files in, instead of the current one
+    output_dir: Option<PathBuf>,
 
-    println!("\n⏳ Waiting for P2P connection...\n");
-    println!("🔍 Debug: Entering event loop...");
+    /// Session ticket file from `sender --emit-ticket`, instead of scanning BLE
+    ticket: Option<PathBuf>,
 
-    loop {
-        println!("🔍 Debug: Waiting for next swarm event...");
-        match swarm.select_next_some().await {
-            SwarmEvent::ConnectionEstablished { peer_id, endpoint, .. } => {
-                println!("✅ P2P connection established with {}", peer_id);
-                println!("   Endpoint: {:?}", endpoint);
-                connected_peer = Some(peer_id);
+    /// Cap on incoming throughput in bytes per second
+    rate_limit: Option<u64>,
+
+    /// Print JSON events instead of decorative text
+    json: bool,
 
-                // Open a stream to the sender
-                println!("📨 Opening stream to send transfer request...");
-                
-                let protocol = StreamProtocol::new(network::TRANSFER_PROTOCOL);
-                let peer_id_copy = peer_id;
-                
-                // Get a fresh control for this connection
-                let mut control = network::get_stream_control(&swarm);
-                
-                // Spawn task to handle stream communication
-                tokio::spawn(async move {
-                    println!("🔍 Debug: Spawned stream handler task");
-                    println!("🔍 Debug: Attempting to open stream to {}", peer_id_copy);
-                    
-                    match control.open_stream(peer_id_copy, protocol).await {
-                        Ok(mut stream) => {
-                            println!("✅ Stream opened successfully");
-                            
-                            // Send request
-                            let request = TransferRequest {
-                                request_id: 1,
-                                ready: true,
-                            };
-                            
-                            println!("🔍 Debug: Sending transfer request...");
-                            if let Err(e) = network::write_request(&mut stream, request).await {
-                                eprintln!("❌ Failed to send request: {}", e);
-                                return;
-                            }
-                            
-                            println!("📨 Request sent, waiting for response...");
-                            
-                            // Read response
-                            match network::read_response(&mut stream).await {
-                                Ok(response) => {
-                                    println!("📦 Received file list:");
-                                    println!("   Files: {}", response.file_list.files.len());
-                                    println!(
-                                        "   Total size: {}",
-                                        transfer::format_bytes(response.file_list.total_size)
-                                    );
-                                    println!();
-
-                                    // Receive and write chunks streaming (optimized - writes as we receive)
-                                    println!("📥 Receiving and writing file chunks...");
-                                    match network::receive_and_write_chunks_streaming(&mut stream, &response.file_list).await {
-                                        Ok(()) => {
-                                            println!("\n✅ Transfer complete!");
-                                            println!("   Received {} file(s)\n", response.file_list.files.len());
-                                        }
-                                        Err(e) => {
-                                            eprintln!("❌ Failed to receive and write chunks: {}", e);
-                                        }
-                                    }
-                                }
-                                Err(e) => {
-                                    eprintln!("❌ Failed to read response: {}", e);
-                                }
-                            }
-                        }
-                        Err(e) => {
-                            eprintln!("❌ Failed to open stream: {}", e);
-                        }
+    /// Limit on reading or writing one control message
+    control_timeout: Duration,
+
+    /// Limit on a chunk stream going without a chunk
+    idle_timeout: Duration,
+}
+
+impl Options {
+    fn parse() -> Result<Self, String> {
+        let mut options = Options {
+            connect_timeout: dial::DEFAULT_CONNECT_TIMEOUT,
+            control_timeout: network::DEFAULT_CONTROL_TIMEOUT,
+            idle_timeout: network::DEFAULT_IDLE_TIMEOUT,
+            ..Default::default()
+        };
+        let mut args = env::args().skip(1);
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "-y" | "--yes" => options.yes = true,
+                "--ignore-expiry" => options.ignore_expiry = true,
+                "--allow-replay" => options.allow_replay = true,
+                "--no-verify" => options.no_verify = true,
+                "--no-preserve-permissions" => options.no_preserve_permissions = true,
+                "--json" => options.json = true,
+                "--connect-timeout" => {
+                    let secs = args
+                        .next()
+                        .and_then(|value| value.parse::<u64>().ok())
+                        .filter(|&secs| secs > 0)
+                        .ok_or("--connect-timeout needs a number of seconds greater than zero")?;
+                    options.connect_timeout = Duration::from_secs(secs);
+                }
+                "--control-timeout" | "--idle-timeout" => {
+                    let secs = args
+                        .next()
+                        .and_then(|value| value.parse::<u64>().ok())
+                        .filter(|&secs| secs > 0)
+                        .ok_or_else(|| format!("{} needs a number of seconds greater than zero", arg))?;
+                    if arg == "--control-timeout" {
+                        options.control_timeout = Duration::from_secs(secs);
+                    } else {
+                        options.idle_timeout = Duration::from_secs(secs);
                     }
-                });
-            }
-            SwarmEvent::ConnectionClosed { peer_id, cause, .. } => {
-                println!("❌ Connection closed with {}: {:?}", peer_id, cause);
-                if Some(peer_id) == connected_peer {
-                    break;
                 }
-            }
-            SwarmEvent::OutgoingConnectionError { peer_id, error, .. } => {
-                eprintln!("❌ Outgoing connection error to {:?}: {}", peer_id, error);
-            }
-            SwarmEvent::IncomingConnectionError { send_back_addr, error, .. } => {
-                eprintln!("❌ Incoming connection error from {:?}: {}", send_back_addr, error);
-            }
-            SwarmEvent::Dialing { peer_id, .. } => {
-                println!("📞 Dialing peer: {:?}", peer_id);
-            }
-            event => {
-                println!("🔍 Debug: Received event: {:?}", event);
+                "--max-file-size" => {
+                    let max_size = args
+                        .next()
+                        .and_then(|value| transfer::parse_size(&value))
+                        .ok_or("--max-file-size needs a size such as 500M or 2G")?;
+                    options.limits.max_file_size = Some(max_size);
+                }
+                // --max-size predates the per-file limit and caps the total
+                "--max-total-size" | "--max-size" => {
+                    let max_size = args
+                        .next()
+                        .and_then(|value| transfer::parse_size(&value))
+                        .ok_or_else(|| format!("{} needs a size such as 500M or 2G", arg))?;
+                    options.limits.max_total_size = Some(max_size);
+                }
+                "--no-limit" => options.limits = SizeLimits::NONE,
+                "--on-failure" => {
+                    options.on_failure = args
+                        .next()
+                        .ok_or("--on-failure needs keep, delete or rename")?
+                        .parse()?;
+                }
+                "--conflict" => {
+                    options.conflict = args
+                        .next()
+                        .ok_or("--conflict needs overwrite, skip or rename")?
+                        .parse()?;
+                }
+                "--rate-limit" => {
+                    let rate = args.next().ok_or("--rate-limit needs a rate such as 5MB")?;
+                    options.rate_limit = Some(ratelimit::parse_rate(&rate)?);
+                }
+                "--ticket" => {
+                    let path = args.next().ok_or("--ticket needs a ticket file, or - for stdin")?;
+                    // Made absolute now, since --output-dir changes directory before it is read
+                    let path = match path.as_str() {
+                        "-" => PathBuf::from(path),
+                        _ => std::path::absolute(&path).map_err(|e| format!("Invalid ticket path '{}': {}", path, e))?,
+                    };
+                    options.ticket = Some(path);
+                }
+                "-o" | "--output-dir" => {
+                    let dir = args.next().ok_or_else(|| format!("{} needs a directory", arg))?;
+                    options.output_dir = Some(PathBuf::from(dir));
+                }
+                "--parallel" => {
+                    let parallel = args
+                        .next()
+                        .and_then(|value| value.parse::<usize>().ok())
+                        .filter(|parallel| (1..=network::MAX_PARALLEL_FILES).contains(parallel))
+                        .ok_or_else(|| {
+                            format!("--parallel needs a number of files from 1 to {}", network::MAX_PARALLEL_FILES)
+                        })?;
+                    options.parallel = Some(parallel);
+                }
+                "--chunk-size" => {
+                    let value = args.next().ok_or("--chunk-size needs a size such as 256K or 4M")?;
+                    options.chunk_size = Some(transfer::parse_chunk_size(&value)?);
+                }
+                other => return Err(format!("Unknown argument: {}", other)),
             }
         }
-    }
 
-    println!("👋 Done!");
-    Ok(())
+        Ok(options)
+    }
 }
 
 /* ========== Helper Functions ========== */
 
-async fn print_device_summary<P: btleplug::api::Peripheral>(i: usize, p: &P) {
+/// Show a prompt, or announce it as an event in JSON mode
+fn ask(prompt: &str) -> io::Result<()> {
+    if events::json() {
+        events::emit(Event::Prompt { question: prompt.trim().to_string() });
+    } else {
+        print!("{}", prompt);
+    }
+    io::stdout().flush()
+}
+
+/// Ask a yes/no question on stdin, defaulting to no
+fn confirm(prompt: &str) -> io::Result<bool> {
+    ask(prompt)?;
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer)?;
+    Ok(matches!(answer.trim().to_lowercase().as_str(), "y" | "yes"))
+}
+
+/// Pause, resume or cancel the transfer from stdin lines until stdin closes
+///
+/// `pause` is `None` when the sender can't pause. Runs on its own thread
+/// because reading stdin blocks; the thread ends with the process.
+fn watch_keys(pause: Option<watch::Sender<bool>>, cancel: CancellationToken) {
+    std::thread::spawn(move || {
+        let mut line = String::new();
+        loop {
+            line.clear();
+            if !matches!(io::stdin().read_line(&mut line), Ok(n) if n > 0) {
+                return;
+            }
+            let paused = match line.trim().to_lowercase().as_str() {
+                "q" | "quit" | "cancel" => {
+                    say!("🛑 Cancelling transfer...");
+                    cancel.cancel();
+                    return;
+                }
+                "p" | "pause" => true,
+                "r" | "resume" => false,
+                _ => continue,
+            };
+            let Some(pause) = &pause else {
+                continue;
+            };
+            if pause.send_if_modified(|current| std::mem::replace(current, paused) != paused) {
+                if paused {
+                    say!("⏸️  Pausing - the sender stops after the chunks already on their way");
+                } else {
+                    say!("▶️  Resuming");
+                }
+            }
+        }
+    });
+}
+
+/// Report which files made it to disk when a transfer stopped early
+fn print_partial_summary(heading: &str, summary: &ReceiveSummary) {
+    say!("{}", heading);
+    for name in &summary.completed {
+        say!("   ✅ Completed: {}", name);
+    }
+    for name in &summary.partial {
+        say!("   ⏸️  Partially received: {}", name);
+    }
+    for name in &summary.not_started {
+        say!("   ⏭️  Not received: {}", name);
+    }
+}
+
+/// List a device under its selection number, returning it for the `devices` event
+async fn print_device_summary<P: btleplug::api::Peripheral>(i: usize, p: &P) -> Device {
     let props = p.properties().await.unwrap_or(None);
     let addr = p.address();
     let name = props
@@ -304,11 +1512,17 @@ async fn print_device_summary<P: btleplug::api::Peripheral>(i: usize, p: &P) {
         .and_then(|pr| pr.local_name.clone())
         .unwrap_or_else(|| "Unknown".into());
     
-    println!("{:>2}. {} - {}", i + 1, addr, name);
+    say!("{:>2}. {} - {}", i + 1, addr, name);
     
-    if let Some(pr) = props {
-        if let Some(rssi) = pr.rssi {
-            println!("      RSSI: {} dBm", rssi);
-        }
+    let rssi = props.and_then(|pr| pr.rssi);
+    if let Some(rssi) = rssi {
+        say!("      RSSI: {} dBm", rssi);
+    }
+
+    Device {
+        number: i + 1,
+        name,
+        address: addr.to_string(),
+        rssi,
     }
 }
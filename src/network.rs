@@ -1,43 +1,250 @@
 // libp2p networking layer for file transfer
 
-use crate::protocol::{TransferRequest, TransferResponse, TransportProtocol};
+use crate::crypto::ChunkCipher;
+use crate::protocol::{
+    Cancel, Capability, ChunkAck, Confirmed, ErrorCode, FileChunk, FileList, FileMetadata, FileReceipt, FileStatus,
+    Frame, FrameKind, ProtocolError, ResumeRequest, TransferComplete, TransferControl, TransferDecision, TransferReceipt, TransferRequest, TransferResponse, TransportProtocol, VersionMismatch, MAX_FRAME_SIZE,
+};
+use crate::ratelimit::RateLimiter;
+use crate::stats::{StatsReport, TransferStats};
+use crate::transfer;
 use anyhow::{Context, Result};
 use futures::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use futures::{FutureExt, Stream, StreamExt};
 use libp2p::{
+    core::transport::ListenerId,
+    dcutr, identify,
     identity::Keypair,
-    noise,
-    swarm::NetworkBehaviour,
-    tcp, yamux, Multiaddr, Swarm, SwarmBuilder,
+    noise, ping, relay,
+    swarm::{behaviour::toggle::Toggle, ConnectionId, NetworkBehaviour, SwarmEvent},
+    tcp, upnp, yamux, Multiaddr, PeerId, Swarm, SwarmBuilder,
 };
+use libp2p::multiaddr::Protocol;
 use libp2p_stream as stream;
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::io;
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 use std::time::Duration;
+use tokio::sync::{mpsc, watch};
+use tokio::time::Instant;
+use tokio::task::JoinSet;
+use tokio_util::sync::CancellationToken;
+use tracing::debug;
 
 /* ========== Stream Protocols ========== */
 
+/// The control stream the receiver opens: handshake, completion and receipt,
+/// and with older peers the chunks as well
 pub const TRANSFER_PROTOCOL: &str = "/fastdrop/transfer/1.0.0";
 
+/// Chunk data of a single-stream transfer, opened by the receiver after the
+/// handshake when `CAP_DATA_STREAM` was agreed
+pub const DATA_PROTOCOL: &str = "/fastdrop/data/1.0.0";
+
+/// Per-file data streams opened by the sender when parallel transfer is negotiated
+pub const FILE_PROTOCOL: &str = "/fastdrop/file/1.0.0";
+
+/// Files the sender streams at the same time unless the receiver asks otherwise
+pub const DEFAULT_PARALLEL_FILES: usize = 8;
+
+/// Most files a receiver may ask to have streamed at the same time
+pub const MAX_PARALLEL_FILES: usize = 64;
+
+/// Number of concurrent file streams for a receiver asking for `requested`
+///
+/// 0 means the receiver has no preference (or predates `--parallel`).
+pub fn negotiate_parallel_files(requested: u32) -> usize {
+    match requested {
+        0 => DEFAULT_PARALLEL_FILES,
+        requested => (requested as usize).min(MAX_PARALLEL_FILES),
+    }
+}
+
+/* ========== Acknowledgments ========== */
+
+/// The receiver acks (cumulatively) every this many chunks and at the end of each file
+pub const ACK_INTERVAL: u64 = 16;
+
+/// Default number of unacknowledged chunks the sender keeps in flight
+pub const DEFAULT_ACK_WINDOW: u64 = 64;
+
+/// Times the receiver asks for the same corrupted chunk again before giving up
+pub const MAX_CHUNK_RETRIES: u32 = 3;
+
+/// How the sender puts chunks on the wire, as agreed with the receiver
+#[derive(Debug, Clone, Copy)]
+pub struct ChunkWire {
+    /// Unacknowledged chunks in flight before waiting for an ack
+    pub window: u64,
+
+    /// A failed ack asks for a resend (`CAP_RETRANSMIT` was agreed)
+    pub retransmit: bool,
+
+    /// Chunks go out as binary frames (`CAP_BINARY_CHUNKS` was agreed)
+    pub binary: bool,
+}
+
+/* ========== Timeouts ========== */
+
+/// Default limit on reading or writing one control message
+pub const DEFAULT_CONTROL_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Default limit on a chunk stream going without a chunk or an ack
+pub const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Set once from the command line, read by every framed read and write
+static CONTROL_TIMEOUT_SECS: AtomicU64 = AtomicU64::new(DEFAULT_CONTROL_TIMEOUT.as_secs());
+static IDLE_TIMEOUT_SECS: AtomicU64 = AtomicU64::new(DEFAULT_IDLE_TIMEOUT.as_secs());
+
+/// Replace the default timeouts, from `--control-timeout` and `--idle-timeout`
+pub fn set_timeouts(control: Duration, idle: Duration) {
+    CONTROL_TIMEOUT_SECS.store(control.as_secs(), Ordering::Relaxed);
+    IDLE_TIMEOUT_SECS.store(idle.as_secs(), Ordering::Relaxed);
+}
+
+/// Current limit on reading or writing one control message
+pub fn control_timeout() -> Duration {
+    Duration::from_secs(CONTROL_TIMEOUT_SECS.load(Ordering::Relaxed))
+}
+
+fn idle_timeout() -> Duration {
+    Duration::from_secs(IDLE_TIMEOUT_SECS.load(Ordering::Relaxed))
+}
+
+/// The peer took longer than allowed to send or take a frame
+#[derive(Debug)]
+pub struct StreamTimeout {
+    /// "reading", "writing" or "flushing"
+    pub action: &'static str,
+    pub what: &'static str,
+    pub limit: Duration,
+}
+
+impl std::fmt::Display for StreamTimeout {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Timed out after {}s {} the {}", self.limit.as_secs(), self.action, self.what)
+    }
+}
+
+impl std::error::Error for StreamTimeout {}
+
+/// Give a read or write on a stream `limit` to finish
+async fn within<F: Future>(limit: Duration, action: &'static str, what: &'static str, io: F) -> Result<F::Output> {
+    tokio::time::timeout(limit, io)
+        .await
+        .map_err(|_| StreamTimeout { action, what, limit }.into())
+}
+
+/* ========== Heartbeat ========== */
+
+/// How often each side pings the other, which also keeps NAT mappings alive
+/// while a user takes their time confirming
+pub const PING_INTERVAL: Duration = Duration::from_secs(15);
+
+/// How long a single ping may go unanswered
+pub const PING_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Consecutive failed pings after which the peer is considered gone
+pub const MAX_MISSED_PINGS: u32 = 3;
+
+/// Counts consecutive failed pings on each connection, and keeps the
+/// latest round-trip time of each
+#[derive(Debug, Default)]
+pub struct Heartbeat {
+    missed: HashMap<ConnectionId, u32>,
+    rtt: HashMap<ConnectionId, Duration>,
+}
+
+impl Heartbeat {
+    /// Record a ping result, returning true once the peer has missed
+    /// `MAX_MISSED_PINGS` in a row and its connection should be closed
+    pub fn on_ping(&mut self, event: &ping::Event) -> bool {
+        match &event.result {
+            Ok(rtt) => {
+                self.rtt.insert(event.connection, *rtt);
+                self.missed.remove(&event.connection);
+                false
+            }
+            Err(ping::Failure::Unsupported) => {
+                // Peers without ping can't be monitored, so never give up on them
+                self.missed.remove(&event.connection);
+                false
+            }
+            Err(_) => {
+                let missed = self.missed.entry(event.connection).or_default();
+                *missed += 1;
+                *missed >= MAX_MISSED_PINGS
+            }
+        }
+    }
+
+    /// Round-trip time of the last answered ping on `connection`
+    pub fn rtt(&self, connection: ConnectionId) -> Option<Duration> {
+        self.rtt.get(&connection).copied()
+    }
+
+    /// Forget a closed connection
+    pub fn forget(&mut self, connection: ConnectionId) {
+        self.missed.remove(&connection);
+        self.rtt.remove(&connection);
+    }
+}
+
 /* ========== Network Behaviour ========== */
 
+/// Protocol version string announced over identify
+const IDENTIFY_VERSION: &str = "/fastdrop/1.0.0";
+
+/// The relay client and DCUtR sit idle unless the sender listens through a
+/// relay (`--relay`) or the receiver dials a relayed ticket address. DCUtR
+/// learns the addresses to punch through from identify's observed addresses.
+/// UPnP is only switched on by the sender's `--upnp`.
 #[derive(NetworkBehaviour)]
 pub struct FileTransferBehaviour {
     pub stream: stream::Behaviour,
+    pub ping: ping::Behaviour,
+    pub relay_client: relay::client::Behaviour,
+    pub dcutr: dcutr::Behaviour,
+    pub identify: identify::Behaviour,
+    pub upnp: Toggle<upnp::tokio::Behaviour>,
+}
+
+fn new_behaviour(key: &Keypair, relay_client: relay::client::Behaviour, use_upnp: bool) -> FileTransferBehaviour {
+    let peer_id = key.public().to_peer_id();
+    FileTransferBehaviour {
+        stream: stream::Behaviour::new(),
+        ping: ping::Behaviour::new(
+            ping::Config::new()
+                .with_interval(PING_INTERVAL)
+                .with_timeout(PING_TIMEOUT),
+        ),
+        relay_client,
+        dcutr: dcutr::Behaviour::new(peer_id),
+        identify: identify::Behaviour::new(identify::Config::new(
+            IDENTIFY_VERSION.to_string(),
+            key.public(),
+        )),
+        upnp: Toggle::from(use_upnp.then(upnp::tokio::Behaviour::default)),
+    }
 }
 
 /* ========== Swarm Building ========== */
 
-/// Build a swarm with QUIC transport
-pub fn build_quic_swarm(keypair: Keypair) -> Result<Swarm<FileTransferBehaviour>> {
+/// Build a swarm with QUIC transport, mapping its port on the router with `upnp`
+pub fn build_quic_swarm(keypair: Keypair, upnp: bool) -> Result<Swarm<FileTransferBehaviour>> {
     let peer_id = keypair.public().to_peer_id();
-    println!("🔑 Local PeerId: {}", peer_id);
+    debug!("Local PeerId: {}", peer_id);
 
     let swarm = SwarmBuilder::with_existing_identity(keypair)
         .with_tokio()
         .with_quic()
-        .with_behaviour(|_key| {
-            let behaviour = stream::Behaviour::new();
-            FileTransferBehaviour { stream: behaviour }
-        })
+        .with_relay_client(noise::Config::new, yamux::Config::default)
+        .context("Failed to configure relay client")?
+        .with_behaviour(|key, relay_client| new_behaviour(key, relay_client, upnp))
         .context("Failed to create behaviour")?
         .with_swarm_config(|cfg| {
             cfg.with_idle_connection_timeout(Duration::from_secs(300))
@@ -47,10 +254,10 @@ pub fn build_quic_swarm(keypair: Keypair) -> Result<Swarm<FileTransferBehaviour>
     Ok(swarm)
 }
 
-/// Build a swarm with TCP transport
-pub fn build_tcp_swarm(keypair: Keypair) -> Result<Swarm<FileTransferBehaviour>> {
+/// Build a swarm with TCP transport, mapping its port on the router with `upnp`
+pub fn build_tcp_swarm(keypair: Keypair, upnp: bool) -> Result<Swarm<FileTransferBehaviour>> {
     let peer_id = keypair.public().to_peer_id();
-    println!("🔑 Local PeerId: {}", peer_id);
+    debug!("Local PeerId: {}", peer_id);
 
     let swarm = SwarmBuilder::with_existing_identity(keypair)
         .with_tokio()
@@ -60,10 +267,35 @@ pub fn build_tcp_swarm(keypair: Keypair) -> Result<Swarm<FileTransferBehaviour>>
             yamux::Config::default,
         )
         .context("Failed to configure TCP transport")?
-        .with_behaviour(|_key| {
-            let behaviour = stream::Behaviour::new();
-            FileTransferBehaviour { stream: behaviour }
+        .with_relay_client(noise::Config::new, yamux::Config::default)
+        .context("Failed to configure relay client")?
+        .with_behaviour(|key, relay_client| new_behaviour(key, relay_client, upnp))
+        .context("Failed to create behaviour")?
+        .with_swarm_config(|cfg| {
+            cfg.with_idle_connection_timeout(Duration::from_secs(300))
         })
+        .build();
+
+    Ok(swarm)
+}
+
+/// Build a swarm with TCP and QUIC transports side by side, mapping their ports with `upnp`
+pub fn build_dual_swarm(keypair: Keypair, upnp: bool) -> Result<Swarm<FileTransferBehaviour>> {
+    let peer_id = keypair.public().to_peer_id();
+    debug!("Local PeerId: {}", peer_id);
+
+    let swarm = SwarmBuilder::with_existing_identity(keypair)
+        .with_tokio()
+        .with_tcp(
+            tcp::Config::default(),
+            noise::Config::new,
+            yamux::Config::default,
+        )
+        .context("Failed to configure TCP transport")?
+        .with_quic()
+        .with_relay_client(noise::Config::new, yamux::Config::default)
+        .context("Failed to configure relay client")?
+        .with_behaviour(|key, relay_client| new_behaviour(key, relay_client, upnp))
         .context("Failed to create behaviour")?
         .with_swarm_config(|cfg| {
             cfg.with_idle_connection_timeout(Duration::from_secs(300))
@@ -77,306 +309,2440 @@ pub fn build_tcp_swarm(keypair: Keypair) -> Result<Swarm<FileTransferBehaviour>>
 pub fn build_swarm(
     keypair: Keypair,
     protocol: TransportProtocol,
+    upnp: bool,
 ) -> Result<Swarm<FileTransferBehaviour>> {
     match protocol {
-        TransportProtocol::Quic => build_quic_swarm(keypair),
-        TransportProtocol::Tcp => build_tcp_swarm(keypair),
+        TransportProtocol::Quic => build_quic_swarm(keypair, upnp),
+        TransportProtocol::Tcp => build_tcp_swarm(keypair, upnp),
+        TransportProtocol::Both => build_dual_swarm(keypair, upnp),
     }
 }
 
 /* ========== Helper Functions ========== */
 
-/// Start listening on appropriate addresses for the protocol
-pub fn listen_on_protocol(
+/// Listen addresses for `protocol` on one address family, e.g. `/ip4/0.0.0.0`
+pub fn listen_addrs_for(protocol: TransportProtocol, family: &str, port: u16) -> Vec<String> {
+    let quic = format!("{}/udp/{}/quic-v1", family, port);
+    let tcp = format!("{}/tcp/{}", family, port);
+    match protocol {
+        TransportProtocol::Quic => vec![quic],
+        TransportProtocol::Tcp => vec![tcp],
+        TransportProtocol::Both => vec![quic, tcp],
+    }
+}
+
+/// Local addresses to listen on for `--interface`, given an interface name or one of its IPs
+///
+/// IPv6 link-local addresses are left out: a multiaddr has no room for the
+/// scope needed to bind one.
+pub fn interface_ips(interface: &str) -> Result<Vec<IpAddr>> {
+    if let Ok(ip) = interface.parse::<IpAddr>() {
+        return Ok(vec![ip]);
+    }
+
+    let interfaces = if_addrs::get_if_addrs().context("Failed to list network interfaces")?;
+    let ips: Vec<IpAddr> = interfaces
+        .iter()
+        .filter(|iface| iface.name == interface)
+        .map(|iface| iface.ip())
+        .filter(|ip| !matches!(ip, IpAddr::V6(ip) if (ip.segments()[0] & 0xffc0) == 0xfe80))
+        .collect();
+    if ips.is_empty() {
+        let mut names: Vec<&str> = interfaces.iter().map(|iface| iface.name.as_str()).collect();
+        names.sort_unstable();
+        names.dedup();
+        anyhow::bail!("No usable address on interface '{}' (found: {})", interface, names.join(", "));
+    }
+    Ok(ips)
+}
+
+/// Transport a connection to `addr` runs over
+pub fn transport_of(addr: &Multiaddr) -> TransportProtocol {
+    if addr.iter().any(|p| matches!(p, Protocol::QuicV1)) {
+        TransportProtocol::Quic
+    } else {
+        TransportProtocol::Tcp
+    }
+}
+
+/// Whether `addr` goes through a relay rather than straight to the peer
+pub fn is_relayed(addr: &Multiaddr) -> bool {
+    addr.iter().any(|p| matches!(p, Protocol::P2pCircuit))
+}
+
+/// The relay a `.../p2p/<relay>/p2p-circuit` address goes through
+pub fn relay_peer_of(addr: &Multiaddr) -> Option<PeerId> {
+    let mut relay = None;
+    for protocol in addr.iter() {
+        match protocol {
+            Protocol::P2p(peer_id) => relay = Some(peer_id),
+            Protocol::P2pCircuit => return relay,
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Parse a `--relay` address, which has to end with the relay's peer ID
+pub fn parse_relay_addr(s: &str) -> Result<Multiaddr, String> {
+    let addr: Multiaddr = s
+        .parse()
+        .map_err(|e| format!("Invalid relay address '{}': {}", s, e))?;
+    if is_relayed(&addr) || !matches!(addr.iter().last(), Some(Protocol::P2p(_))) {
+        return Err(format!(
+            "Relay address '{}' must end with /p2p/<relay peer ID>, e.g. /ip4/203.0.113.5/tcp/4001/p2p/12D3KooW...",
+            s
+        ));
+    }
+    Ok(addr)
+}
+
+/* ========== Listen Addresses ========== */
+
+/// Stop collecting listen addresses once none has appeared for this long
+const LISTEN_GRACE: Duration = Duration::from_millis(500);
+
+/// Drive the swarm until its listeners have reported their addresses
+///
+/// Call after `listen_on`. Keeps collecting until at least `min_addrs` usable
+/// addresses are in and no new one has appeared for `LISTEN_GRACE` (interfaces
+/// are reported one by one), but never longer than `timeout`. With
+/// `relay_listener` it also waits for the relayed address, unless the
+/// reservation fails. Every address is reported to `events`, and only those
+/// passing `is_usable_listen_addr` are returned.
+pub async fn listen_and_wait(
     swarm: &mut Swarm<FileTransferBehaviour>,
-    protocol: TransportProtocol,
+    mut relay_listener: Option<ListenerId>,
+    min_addrs: usize,
+    timeout: Duration,
+    events: Option<&mpsc::UnboundedSender<NetworkEvent>>,
 ) -> Result<Vec<Multiaddr>> {
-    let listen_addrs = Vec::new();
+    let mut listen_addrs: Vec<Multiaddr> = Vec::new();
+    let mut seen = HashSet::new();
+    let give_up = tokio::time::sleep(timeout);
+    tokio::pin!(give_up);
+    let mut quiet = Instant::now() + LISTEN_GRACE;
 
-    match protocol {
-        TransportProtocol::Quic => {
-            // QUIC uses UDP
-            let addr: Multiaddr = "/ip4/0.0.0.0/udp/0/quic-v1".parse()?;
-            swarm.listen_on(addr)?;
-        }
-        TransportProtocol::Tcp => {
-            // TCP
-            let addr: Multiaddr = "/ip4/0.0.0.0/tcp/0".parse()?;
-            swarm.listen_on(addr)?;
+    loop {
+        tokio::select! {
+            event = swarm.select_next_some() => match event {
+                SwarmEvent::NewListenAddr { listener_id, address, .. } => {
+                    quiet = Instant::now() + LISTEN_GRACE;
+                    if Some(listener_id) == relay_listener {
+                        relay_listener = None;
+                    }
+                    if !seen.insert(address.clone()) {
+                        continue;
+                    }
+
+                    // Only advertise addresses other devices can reach
+                    let usable = is_usable_listen_addr(&address);
+                    report(events, NetworkEvent::Listening { address: address.clone(), usable });
+                    if usable {
+                        listen_addrs.push(address);
+                    }
+                }
+                SwarmEvent::ListenerClosed { listener_id, reason, .. } if Some(listener_id) == relay_listener => {
+                    // Receivers on the same network can still connect directly
+                    let message = format!("Relay reservation failed, not advertising a relayed address: {:?}", reason);
+                    report(events, NetworkEvent::Error { file_index: None, message });
+                    relay_listener = None;
+                }
+                SwarmEvent::ListenerClosed { reason: Err(e), .. } => {
+                    return Err(anyhow::Error::new(e).context("Listener failed while binding"));
+                }
+                SwarmEvent::Behaviour(FileTransferBehaviourEvent::Upnp(event)) => match event {
+                    upnp::Event::NewExternalAddr(address) => {
+                        report(events, NetworkEvent::PortMapped { address: address.clone() });
+                        if seen.insert(address.clone()) {
+                            listen_addrs.push(address);
+                        }
+                    }
+                    event => {
+                        if let Some(message) = upnp_failure(&event) {
+                            report(events, NetworkEvent::Error { file_index: None, message: message.to_string() });
+                        }
+                    }
+                },
+                _ => {}
+            },
+            _ = tokio::time::sleep_until(quiet), if listen_addrs.len() >= min_addrs && relay_listener.is_none() => break,
+            _ = &mut give_up => break,
         }
     }
 
+    if listen_addrs.is_empty() {
+        anyhow::bail!("No usable listen addresses obtained (all were loopback, link-local or bridge)");
+    }
     Ok(listen_addrs)
 }
 
-/// Extract actual listening addresses from swarm
-pub fn get_listen_addrs(swarm: &Swarm<FileTransferBehaviour>) -> Vec<Multiaddr> {
-    swarm.listeners().cloned().collect()
+/// Default Docker bridge network, not reachable from other machines
+const DOCKER_BRIDGE_PREFIX: [u8; 2] = [172, 17];
+
+/// Whether a listen address is worth advertising to receivers
+///
+/// Rejects loopback, link-local, unspecified, multicast and Docker bridge
+/// addresses, which another device on the LAN can't reach. IPv6 link-local
+/// addresses are rejected even with a zone (`/ip6zone/...`): the zone names
+/// one of the sender's interfaces and means nothing to the receiver.
+///
+/// Relayed addresses (`.../p2p-circuit`) are always usable: reaching the
+/// relay is the whole point of them.
+pub fn is_usable_listen_addr(addr: &Multiaddr) -> bool {
+    if is_relayed(addr) {
+        return true;
+    }
+    match addr.iter().next() {
+        Some(Protocol::Ip4(ip)) => is_usable_ip4(ip),
+        Some(Protocol::Ip6(ip)) => match ip.to_ipv4_mapped() {
+            // ::ffff:a.b.c.d is an IPv4 address in disguise
+            Some(ip) => is_usable_ip4(ip),
+            None => {
+                // fe80::/10
+                let link_local = (ip.segments()[0] & 0xffc0) == 0xfe80;
+                !(ip.is_loopback() || ip.is_unspecified() || ip.is_multicast() || link_local)
+            }
+        },
+        _ => false,
+    }
+}
+
+fn is_usable_ip4(ip: std::net::Ipv4Addr) -> bool {
+    !(ip.is_loopback()
+        || ip.is_link_local()
+        || ip.is_unspecified()
+        || ip.is_broadcast()
+        || ip.is_multicast()
+        || ip.octets()[..2] == DOCKER_BRIDGE_PREFIX)
+}
+
+/// Whether the address is in a private LAN range (RFC 1918 or IPv6 ULA)
+pub fn is_private_lan_addr(addr: &Multiaddr) -> bool {
+    match addr.iter().next() {
+        Some(Protocol::Ip4(ip)) => ip.is_private(),
+        // fc00::/7
+        Some(Protocol::Ip6(ip)) => (ip.segments()[0] & 0xfe00) == 0xfc00,
+        _ => false,
+    }
+}
+
+/// The first address of each kind (transport, address family, LAN or not,
+/// relayed or not), for tickets that have to stay small such as QR codes
+///
+/// Expects `addrs` already in preference order.
+pub fn compact_addrs(addrs: &[Multiaddr]) -> Vec<Multiaddr> {
+    let mut seen = HashSet::new();
+    addrs
+        .iter()
+        .filter(|addr| {
+            let ip6 = matches!(addr.iter().next(), Some(Protocol::Ip6(_)));
+            seen.insert((transport_of(addr), ip6, is_private_lan_addr(addr), is_relayed(addr)))
+        })
+        .cloned()
+        .collect()
+}
+
+/// A shorter list for a ticket that came out too large: one address of each
+/// kind, or failing that all but the least preferred; `None` at one address
+pub fn fewer_addrs(addrs: &[Multiaddr]) -> Option<Vec<Multiaddr>> {
+    let compact = compact_addrs(addrs);
+    if compact.len() < addrs.len() {
+        return Some(compact);
+    }
+    (addrs.len() > 1).then(|| addrs[..addrs.len() - 1].to_vec())
+}
+
+/// Order advertised addresses so private LAN addresses are dialed first and
+/// relayed ones last
+pub fn sort_by_preference(addrs: &mut [Multiaddr]) {
+    addrs.sort_by_key(|addr| (is_relayed(addr), !is_private_lan_addr(addr)));
+}
+
+/// Why UPnP can't open the sender to the internet, if `event` says it can't
+///
+/// Either way receivers on the same network still connect as before.
+pub fn upnp_failure(event: &upnp::Event) -> Option<&'static str> {
+    match event {
+        upnp::Event::GatewayNotFound => Some("No UPnP gateway found, only receivers on this network can connect"),
+        upnp::Event::NonRoutableGateway => {
+            Some("The UPnP gateway is itself behind a NAT, only receivers on this network can connect")
+        }
+        upnp::Event::NewExternalAddr(_) | upnp::Event::ExpiredExternalAddr(_) => None,
+    }
 }
 
 /// Get stream control for opening/accepting streams
 pub fn get_stream_control(swarm: &Swarm<FileTransferBehaviour>) -> stream::Control {
-    println!("🔍 Debug: Creating new stream control");
+    debug!("Creating new stream control");
     swarm.behaviour().stream.new_control()
 }
 
-/// Write a request to a stream
-pub async fn write_request<T>(stream: &mut T, request: TransferRequest) -> Result<()>
+/* ========== Framing ========== */
+
+/// Largest frame for control messages other than the `TransferResponse`
+///
+/// The response carries the file list and a dictionary and may use the full
+/// `MAX_FRAME_SIZE`; requests, decisions and receipts are much smaller.
+pub const MAX_CONTROL_FRAME_SIZE: usize = 4 * 1024 * 1024;
+
+/// Largest frame the receiver sends back on a chunk stream (acks, pauses, errors)
+pub const MAX_ACK_FRAME_SIZE: usize = 64 * 1024;
+
+/// Largest frame a chunk of up to `chunk_size` bytes may arrive in
+///
+/// CBOR spends up to two bytes per byte of chunk data, compression may grow
+/// incompressible data slightly and encryption adds a tag, plus a little for
+/// the other fields. `chunk_size` 0 means the sender scales it per file.
+pub fn chunk_frame_limit(chunk_size: usize) -> usize {
+    let chunk_size = if chunk_size == 0 { transfer::MAX_CHUNK_SIZE } else { chunk_size };
+    let data = chunk_size + chunk_size / 128 + 1024;
+    (2 * data + 4096).min(MAX_FRAME_SIZE)
+}
+
+/// The peer announced a frame larger than that message may be
+#[derive(Debug)]
+pub struct FrameTooLarge {
+    pub what: &'static str,
+    pub len: usize,
+    pub max_len: usize,
+}
+
+impl std::fmt::Display for FrameTooLarge {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Peer sent a {} byte {}, over the {} byte limit", self.len, self.what, self.max_len)
+    }
+}
+
+impl std::error::Error for FrameTooLarge {}
+
+/// Write one u32 length-prefixed frame and flush it, within the control timeout
+async fn write_frame_bytes<T>(stream: &mut T, data: &[u8], what: &'static str) -> Result<()>
 where
     T: AsyncWrite + Unpin,
 {
-    println!("🔍 Debug: Serializing request...");
-    let data = serde_cbor::to_vec(&request)
-        .context("Failed to serialize request")?;
-    
-    println!("🔍 Debug: Writing request ({} bytes)...", data.len());
-    // Write length prefix
-    let len = data.len() as u32;
-    stream.write_all(&len.to_be_bytes()).await
-        .context("Failed to write length")?;
-    
-    // Write data
-    stream.write_all(&data).await
-        .context("Failed to write request")?;
-    
-    stream.flush().await
-        .context("Failed to flush stream")?;
-    
-    println!("🔍 Debug: Request written successfully");
-    Ok(())
+    let write = async {
+        write_frame_data(stream, data).await?;
+        stream.flush().await
+    };
+    within(control_timeout(), "writing", what, write)
+        .await?
+        .with_context(|| format!("Failed to write {}", what))
 }
 
-/// Read a request from a stream
-pub async fn read_request<T>(stream: &mut T) -> Result<TransferRequest>
+/// Write one u32 length-prefixed frame, leaving the flush to the caller
+async fn write_frame_data<T>(stream: &mut T, data: &[u8]) -> io::Result<()>
 where
-    T: AsyncRead + Unpin,
+    T: AsyncWrite + Unpin,
 {
-    println!("🔍 Debug: Reading request length...");
-    // Read length prefix
-    let mut len_bytes = [0u8; 4];
-    stream.read_exact(&mut len_bytes).await
-        .context("Failed to read length")?;
-    let len = u32::from_be_bytes(len_bytes) as usize;
-    
-    println!("🔍 Debug: Reading request data ({} bytes)...", len);
-    // Read data
-    let mut data = vec![0u8; len];
-    stream.read_exact(&mut data).await
-        .context("Failed to read request")?;
-    
-    println!("🔍 Debug: Deserializing request...");
-    serde_cbor::from_slice(&data)
-        .context("Failed to deserialize request")
+    stream.write_all(&(data.len() as u32).to_be_bytes()).await?;
+    stream.write_all(data).await
 }
 
-/// Write a response to a stream
-pub async fn write_response<T>(stream: &mut T, response: TransferResponse) -> Result<()>
+/// Write one message in a frame of its own and flush it, within the control timeout
+pub async fn write_frame<T>(stream: &mut T, frame: &Frame) -> Result<()>
 where
     T: AsyncWrite + Unpin,
 {
-    println!("🔍 Debug: Serializing response...");
-    let data = serde_cbor::to_vec(&response)
-        .context("Failed to serialize response")?;
-    
-    println!("🔍 Debug: Writing response ({} bytes)...", data.len());
-    // Write length prefix
-    let len = data.len() as u32;
-    stream.write_all(&len.to_be_bytes()).await
-        .context("Failed to write length")?;
-    
-    // Write data
-    stream.write_all(&data).await
-        .context("Failed to write response")?;
-    
-    stream.flush().await
-        .context("Failed to flush stream")?;
-    
-    println!("🔍 Debug: Response written successfully");
-    Ok(())
+    let what = frame.kind().name();
+    let data = frame
+        .to_cbor()
+        .with_context(|| format!("Failed to serialize {}", what))?;
+    write_frame_bytes(stream, &data, what).await
 }
 
-/// Read a response from a stream
-pub async fn read_response<T>(stream: &mut T) -> Result<TransferResponse>
+/// Read one frame holding any of the `expected` messages, `None` if the stream ends before it starts
+///
+/// The messages are tried in the order given, so the usual one goes first
+/// and costs no extra parsing; a frame that is none of them fails with the
+/// first one's error. Binary chunks are told apart by their first byte.
+///
+/// Without a `limit` the read waits as long as it takes, for answers that
+/// depend on a person or on work the peer does first. A frame announced as
+/// longer than `max_len` is refused before anything is allocated for it.
+pub async fn read_frame<T>(
+    stream: &mut T,
+    expected: &[FrameKind],
+    max_len: usize,
+    limit: Option<Duration>,
+) -> Result<Option<Frame>>
 where
     T: AsyncRead + Unpin,
 {
-    println!("🔍 Debug: Reading response length...");
-    // Read length prefix
-    let mut len_bytes = [0u8; 4];
-    stream.read_exact(&mut len_bytes).await
-        .context("Failed to read length")?;
-    let len = u32::from_be_bytes(len_bytes) as usize;
-    
-    println!("🔍 Debug: Reading response data ({} bytes)...", len);
-    // Read data
-    let mut data = vec![0u8; len];
-    stream.read_exact(&mut data).await
-        .context("Failed to read response")?;
-    
-    println!("🔍 Debug: Deserializing response...");
-    serde_cbor::from_slice(&data)
-        .context("Failed to deserialize response")
+    let what = expected.first().map_or("frame", |kind| kind.name());
+    match limit {
+        Some(limit) => within(limit, "reading", what, read_frame_data(stream, expected, what, max_len, None)).await?,
+        None => read_frame_data(stream, expected, what, max_len, None).await,
+    }
 }
 
-/* ========== Chunk Transfer via Stream ========== */
-
-/// Send chunks over a raw stream
-pub async fn send_chunks_over_stream<T>(
+/// `read_frame` for a message that has to come, failing if the stream ends first
+async fn expect_frame<T>(
     stream: &mut T,
-    chunks: Vec<crate::protocol::FileChunk>,
-) -> Result<()>
+    expected: &[FrameKind],
+    max_len: usize,
+    limit: Option<Duration>,
+) -> Result<Frame>
 where
-    T: AsyncWrite + Unpin,
+    T: AsyncRead + Unpin,
 {
-    for chunk in chunks {
-        let data = serde_cbor::to_vec(&chunk)
-            .context("Failed to serialize chunk")?;
-        
-        // Write length prefix
-        let len = data.len() as u32;
-        stream.write_all(&len.to_be_bytes()).await
-            .context("Failed to write chunk length")?;
-        
-        // Write data
-        stream.write_all(&data).await
-            .context("Failed to write chunk")?;
-    }
-    stream.flush().await.context("Failed to flush stream")?;
-    Ok(())
+    let what = expected.first().map_or("frame", |kind| kind.name());
+    read_frame(stream, expected, max_len, limit)
+        .await?
+        .with_context(|| format!("Stream closed before the {}", what))
+}
+
+/// A frame `read_frame` returned although it wasn't expected there
+fn unexpected(frame: Frame) -> anyhow::Error {
+    anyhow::anyhow!("Unexpected {} frame", frame.kind().name())
+}
+
+/// Read one frame of a chunk stream, binary chunks into a buffer from `buffers`
+pub async fn read_chunk_frame<T>(stream: &mut T, max_len: usize, buffers: &ChunkBuffers) -> Result<Option<Frame>>
+where
+    T: AsyncRead + Unpin,
+{
+    read_frame_data(stream, CHUNK_FRAMES, "chunk", max_len, Some(buffers)).await
 }
 
-/// Receive chunks from a raw stream (old implementation - buffers all chunks in memory)
-pub async fn receive_chunks_from_stream<T>(
+/// `read_frame` without the time limit, reading binary chunks into `buffers` if given
+async fn read_frame_data<T>(
     stream: &mut T,
-) -> Result<Vec<crate::protocol::FileChunk>>
+    expected: &[FrameKind],
+    what: &'static str,
+    max_len: usize,
+    buffers: Option<&ChunkBuffers>,
+) -> Result<Option<Frame>>
 where
     T: AsyncRead + Unpin,
 {
-    let mut chunks = Vec::new();
-    
-    loop {
-        // Try to read length prefix
-        let mut len_bytes = [0u8; 4];
-        match stream.read_exact(&mut len_bytes).await {
-            Ok(_) => {
-                let len = u32::from_be_bytes(len_bytes) as usize;
-                
-                // Read data
-                let mut data = vec![0u8; len];
-                stream.read_exact(&mut data).await
-                    .context("Failed to read chunk data")?;
-                
-                let chunk: crate::protocol::FileChunk = serde_cbor::from_slice(&data)
-                    .context("Failed to deserialize chunk")?;
-                chunks.push(chunk);
-            }
-            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
-                break; // End of stream
-            }
-            Err(e) => return Err(e.into()),
+    let Some(len) = read_frame_len(stream, what, max_len).await? else {
+        return Ok(None);
+    };
+    let mut first = [0u8; 1];
+    if len > 0 {
+        stream.read_exact(&mut first).await
+            .with_context(|| format!("Failed to read {}", what))?;
+        if first[0] == BINARY_CHUNK_TAG && expected.contains(&FrameKind::Chunk) {
+            return Ok(Some(Frame::Chunk(read_binary_chunk(stream, len, buffers).await?)));
         }
     }
-    
-    Ok(chunks)
+    let mut data = vec![0u8; len];
+    if let Some((head, rest)) = data.split_first_mut() {
+        *head = first[0];
+        stream.read_exact(rest).await
+            .with_context(|| format!("Failed to read {}", what))?;
+    }
+    decode_frame(&data, expected).map(Some)
 }
 
-/// Receive and write chunks streaming - optimized to write as we receive
-/// This avoids buffering all chunks in memory before writing
-pub async fn receive_and_write_chunks_streaming<T>(
-    stream: &mut T,
-    file_list: &crate::protocol::FileList,
-) -> Result<()>
+/// Decode a frame as the first of the `expected` messages it fits
+///
+/// A handshake message that fits none of them may come from a build with an
+/// incompatible protocol version, which is reported as such.
+fn decode_frame(data: &[u8], expected: &[FrameKind]) -> Result<Frame> {
+    let mut first_error = None;
+    for &kind in expected {
+        match Frame::from_cbor(kind, data) {
+            Ok(frame) => return Ok(frame),
+            Err(e) => {
+                first_error.get_or_insert((kind, e));
+            }
+        }
+    }
+    let (kind, error) = first_error.context("No message expected")?;
+    if matches!(kind, FrameKind::Request | FrameKind::Response)
+        && let Some(mismatch) = VersionMismatch::probe(data)
+    {
+        return Err(mismatch.into());
+    }
+    Err(anyhow::Error::new(error).context(format!("Failed to deserialize {}", kind.name())))
+}
+
+/// Read a frame's u32 length prefix, `None` if the stream ends before it starts
+async fn read_frame_len<T>(stream: &mut T, what: &'static str, max_len: usize) -> Result<Option<usize>>
 where
     T: AsyncRead + Unpin,
 {
-    use std::collections::HashMap;
-    use std::path::PathBuf;
-    use tokio::fs::File;
-    use tokio::io::AsyncWriteExt;
-    
-    // Track open file handles and chunk counts
-    let mut file_handles: HashMap<usize, File> = HashMap::new();
-    let mut chunks_received: HashMap<usize, u64> = HashMap::new();
-    let mut total_bytes_written: HashMap<usize, u64> = HashMap::new();
-    
-    loop {
-        // Try to read length prefix
-        let mut len_bytes = [0u8; 4];
-        match stream.read_exact(&mut len_bytes).await {
-            Ok(_) => {
-                let len = u32::from_be_bytes(len_bytes) as usize;
-                
-                // Read chunk data
-                let mut data = vec![0u8; len];
-                stream.read_exact(&mut data).await
-                    .context("Failed to read chunk data")?;
-                
-                let chunk: crate::protocol::FileChunk = serde_cbor::from_slice(&data)
-                    .context("Failed to deserialize chunk")?;
-                
-                let file_index = chunk.file_index;
-                
-                // Get or create file handle
-                if !file_handles.contains_key(&file_index) {
-                    if file_index >= file_list.files.len() {
-                        return Err(anyhow::anyhow!(
-                            "Invalid file_index {} (only {} files in list)",
-                            file_index,
-                            file_list.files.len()
-                        ));
-                    }
-                    
-                    let file_meta = &file_list.files[file_index];
-                    let output_path = PathBuf::from(&file_meta.name);
-                    
-                    // Create parent directories if needed
-                    if let Some(parent) = output_path.parent() {
-                        tokio::fs::create_dir_all(parent).await
-                            .context("Failed to create parent directories")?;
-                    }
-                    
-                    println!("📄 Writing: {}", file_meta.name);
-                    
-                    let file = File::create(&output_path).await
-                        .with_context(|| format!("Failed to create {}", output_path.display()))?;
-                    
-                    file_handles.insert(file_index, file);
-                    chunks_received.insert(file_index, 0);
-                    total_bytes_written.insert(file_index, 0);
-                }
-                
-                // Write chunk data immediately
-                let file = file_handles.get_mut(&file_index).unwrap();
-                file.write_all(&chunk.data).await
-                    .context("Failed to write chunk data")?;
-                
-                // Update counters
-                *chunks_received.get_mut(&file_index).unwrap() += 1;
-                *total_bytes_written.get_mut(&file_index).unwrap() += chunk.data.len() as u64;
-                
-                // Check if file is complete
-                if chunk.chunk_number + 1 == chunk.total_chunks {
-                    file.flush().await.context("Failed to flush file")?;
-                    let bytes_written = total_bytes_written[&file_index];
-                    let chunks_count = chunks_received[&file_index];
-                    println!("   ✅ Completed: {} chunks, {} bytes", chunks_count, bytes_written);
-                    
-                    // Close the file by removing it from the map
-                    file_handles.remove(&file_index);
-                }
-            }
-            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
-                break; // End of stream
-            }
-            Err(e) => return Err(e.into()),
+    // The stream may only end cleanly between frames, not partway into a length
+    let mut len_bytes = [0u8; 4];
+    let mut filled = 0;
+    while filled < len_bytes.len() {
+        match stream.read(&mut len_bytes[filled..]).await {
+            Ok(0) if filled == 0 => return Ok(None),
+            Ok(0) => anyhow::bail!("Stream ended partway into the {} length", what),
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e).with_context(|| format!("Failed to read {} length", what)),
         }
     }
-    
-    // Flush and close any remaining open files
-    for (file_index, mut file) in file_handles.into_iter() {
-        file.flush().await
-            .with_context(|| format!("Failed to flush file {}", file_index))?;
+    let len = u32::from_be_bytes(len_bytes) as usize;
+    if len > max_len {
+        return Err(FrameTooLarge { what, len, max_len }.into());
+    }
+    Ok(Some(len))
+}
+
+/* ========== Binary Chunk Frames ========== */
+
+/// First byte of a binary chunk frame; the CBOR messages sharing a chunk
+/// stream all start with a map (0xa0 to 0xbf)
+const BINARY_CHUNK_TAG: u8 = 0x00;
+
+/// Bytes of a binary chunk header, tag included, before the optional file hash
+const BINARY_CHUNK_HEADER_LEN: usize = 58;
+
+/// Bytes of a binary chunk header that carries the file hash
+const MAX_BINARY_CHUNK_HEADER_LEN: usize = BINARY_CHUNK_HEADER_LEN + 32;
+
+const FLAG_COMPRESSED: u8 = 1;
+const FLAG_CRC: u8 = 1 << 1;
+const FLAG_OFFSET: u8 = 1 << 2;
+const FLAG_NONCE: u8 = 1 << 3;
+const FLAG_FILE_HASH: u8 = 1 << 4;
+
+/// The header written in front of a chunk's data when `CAP_BINARY_CHUNKS` is agreed
+///
+/// Big endian: tag, flags, file index (u64), chunk number (u64), total chunks
+/// (u64), chunk size (u32), uncompressed length (u32), offset (u64), CRC32
+/// (u32) and nonce (12 bytes), then the file hash (32 bytes) if the flags say
+/// so. Optional fields that are absent are zero with their flag clear.
+///
+/// Returns how many bytes of `header` were written.
+fn binary_chunk_header(chunk: &FileChunk, header: &mut [u8; MAX_BINARY_CHUNK_HEADER_LEN]) -> usize {
+    let flags = [
+        (chunk.compressed, FLAG_COMPRESSED),
+        (chunk.crc32.is_some(), FLAG_CRC),
+        (chunk.offset.is_some(), FLAG_OFFSET),
+        (chunk.nonce.is_some(), FLAG_NONCE),
+        (chunk.file_hash.is_some(), FLAG_FILE_HASH),
+    ]
+    .iter()
+    .filter(|(set, _)| *set)
+    .fold(0, |flags, (_, flag)| flags | flag);
+
+    let mut len = 0;
+    let mut put = |bytes: &[u8]| {
+        header[len..len + bytes.len()].copy_from_slice(bytes);
+        len += bytes.len();
+    };
+    put(&[BINARY_CHUNK_TAG, flags]);
+    put(&(chunk.file_index as u64).to_be_bytes());
+    put(&chunk.chunk_number.to_be_bytes());
+    put(&chunk.total_chunks.to_be_bytes());
+    put(&chunk.chunk_size.to_be_bytes());
+    put(&chunk.uncompressed_len.to_be_bytes());
+    put(&chunk.offset.unwrap_or(0).to_be_bytes());
+    put(&chunk.crc32.unwrap_or(0).to_be_bytes());
+    put(&chunk.nonce.unwrap_or_default());
+    if let Some(hash) = &chunk.file_hash {
+        put(hash);
+    }
+    len
+}
+
+/// Read the rest of a `len` byte binary chunk frame whose tag was already read
+///
+/// The data is read straight into the chunk, without a copy of the frame,
+/// into a buffer from `buffers` when given.
+async fn read_binary_chunk<T>(stream: &mut T, len: usize, buffers: Option<&ChunkBuffers>) -> Result<FileChunk>
+where
+    T: AsyncRead + Unpin,
+{
+    // Checked before reading, or a short frame would eat into the next one
+    if len < BINARY_CHUNK_HEADER_LEN {
+        anyhow::bail!("Binary chunk frame of {} bytes is shorter than its header", len);
+    }
+    let mut header = [BINARY_CHUNK_TAG; BINARY_CHUNK_HEADER_LEN];
+    stream.read_exact(&mut header[1..]).await
+        .context("Failed to read chunk header")?;
+    let flags = header[1];
+    let mut fields = &header[2..];
+    let mut field = |len: usize| {
+        let (value, rest) = fields.split_at(len);
+        fields = rest;
+        value
+    };
+    let file_index = u64::from_be_bytes(field(8).try_into()?);
+    let chunk_number = u64::from_be_bytes(field(8).try_into()?);
+    let total_chunks = u64::from_be_bytes(field(8).try_into()?);
+    let chunk_size = u32::from_be_bytes(field(4).try_into()?);
+    let uncompressed_len = u32::from_be_bytes(field(4).try_into()?);
+    let offset = u64::from_be_bytes(field(8).try_into()?);
+    let crc32 = u32::from_be_bytes(field(4).try_into()?);
+    let nonce: [u8; 12] = field(12).try_into()?;
+
+    let header_len = BINARY_CHUNK_HEADER_LEN + if flags & FLAG_FILE_HASH != 0 { 32 } else { 0 };
+    let data_len = len
+        .checked_sub(header_len)
+        .with_context(|| format!("Binary chunk frame of {} bytes is shorter than its header", len))?;
+
+    let file_hash = if flags & FLAG_FILE_HASH != 0 {
+        let mut hash = [0u8; 32];
+        stream.read_exact(&mut hash).await
+            .context("Failed to read chunk file hash")?;
+        Some(hash)
+    } else {
+        None
+    };
+    let mut data = match buffers {
+        Some(buffers) => buffers.take(data_len),
+        None => vec![0u8; data_len],
+    };
+    stream.read_exact(&mut data).await
+        .with_context(|| format!("Failed to read chunk {}", chunk_number))?;
+    Ok(FileChunk {
+        file_index: usize::try_from(file_index).context("Chunk names a file index out of range")?,
+        chunk_number,
+        total_chunks,
+        data,
+        file_hash,
+        crc32: (flags & FLAG_CRC != 0).then_some(crc32),
+        compressed: flags & FLAG_COMPRESSED != 0,
+        uncompressed_len,
+        chunk_size,
+        offset: (flags & FLAG_OFFSET != 0).then_some(offset),
+        nonce: (flags & FLAG_NONCE != 0).then_some(nonce),
+    })
+}
+
+/// Chunk buffers handed back once their data is written, for the next
+/// binary chunks `read_chunk_frame` reads to go into
+#[derive(Clone, Default)]
+pub struct ChunkBuffers(Arc<Mutex<Vec<Vec<u8>>>>);
+
+impl ChunkBuffers {
+    /// A buffer of `len` bytes, one handed back if there is any
+    fn take(&self, len: usize) -> Vec<u8> {
+        let mut buffer = self.0.lock().unwrap().pop().unwrap_or_default();
+        // Whatever the buffer held is read over, so only growth is zeroed
+        buffer.resize(len, 0);
+        buffer
+    }
+
+    /// Hand back a chunk's data once it is no longer needed
+    pub fn give(&self, buffer: Vec<u8>) {
+        let mut spare = self.0.lock().unwrap();
+        if spare.len() < transfer::SPARE_CHUNK_BUFFERS {
+            spare.push(buffer);
+        }
+    }
+}
+
+/// A chunk encoded for the wire, kept until acked when resends are possible
+struct ChunkFrame {
+    chunk_number: u64,
+
+    /// The binary header, of which the first `header_len` bytes are used
+    header: [u8; MAX_BINARY_CHUNK_HEADER_LEN],
+    header_len: usize,
+
+    /// The chunk data of a binary frame, written as it is after the header,
+    /// or the whole CBOR encoding
+    body: Vec<u8>,
+}
+
+impl ChunkFrame {
+    fn new(chunk: FileChunk, binary: bool) -> Result<Self> {
+        let chunk_number = chunk.chunk_number;
+        let mut header = [0u8; MAX_BINARY_CHUNK_HEADER_LEN];
+        if binary {
+            let header_len = binary_chunk_header(&chunk, &mut header);
+            Ok(Self { chunk_number, header, header_len, body: chunk.data })
+        } else {
+            let body = Frame::Chunk(chunk).to_cbor().context("Failed to serialize chunk")?;
+            Ok(Self { chunk_number, header, header_len: 0, body })
+        }
+    }
+
+    /// Length of the frame, not counting its length prefix
+    fn len(&self) -> usize {
+        self.header_len + self.body.len()
+    }
+}
+
+/// Write one chunk frame, leaving the flush to the caller
+async fn write_chunk_frame<T>(stream: &mut T, frame: &ChunkFrame) -> io::Result<()>
+where
+    T: AsyncWrite + Unpin,
+{
+    stream.write_all(&(frame.len() as u32).to_be_bytes()).await?;
+    stream.write_all(&frame.header[..frame.header_len]).await?;
+    stream.write_all(&frame.body).await
+}
+
+/* ========== Control Messages ========== */
+
+/// Write a request to a stream
+pub async fn write_request<T>(stream: &mut T, request: TransferRequest) -> Result<()>
+where
+    T: AsyncWrite + Unpin,
+{
+    debug!("Writing request...");
+    write_frame(stream, &Frame::Request(request)).await?;
+    
+    debug!("Request written successfully");
+    Ok(())
+}
+
+/// Read a request from a stream
+pub async fn read_request<T>(stream: &mut T) -> Result<TransferRequest>
+where
+    T: AsyncRead + Unpin,
+{
+    debug!("Reading request...");
+    match expect_frame(stream, &[FrameKind::Request], MAX_CONTROL_FRAME_SIZE, Some(control_timeout())).await? {
+        Frame::Request(request) => Ok(request),
+        frame => Err(unexpected(frame)),
+    }
+}
+
+/// Write a response to a stream
+pub async fn write_response<T>(stream: &mut T, response: TransferResponse) -> Result<()>
+where
+    T: AsyncWrite + Unpin,
+{
+    debug!("Writing response...");
+    write_frame(stream, &Frame::Response(response)).await?;
+    
+    debug!("Response written successfully");
+    Ok(())
+}
+
+/// What the sender answers a request with, the usual first
+const RESPONSE_FRAMES: &[FrameKind] = &[FrameKind::Response, FrameKind::Error];
+
+/// Read a response from a stream
+pub async fn read_response<T>(stream: &mut T) -> Result<TransferResponse>
+where
+    T: AsyncRead + Unpin,
+{
+    // Not timed: the sender may be asking its user first (`--confirm`)
+    debug!("Reading response...");
+    match expect_frame(stream, RESPONSE_FRAMES, MAX_FRAME_SIZE, None).await? {
+        Frame::Response(response) => Ok(response),
+        Frame::Error(error) => Err(anyhow::Error::new(error).context("Sender reported an error")),
+        frame => Err(unexpected(frame)),
+    }
+}
+
+/// Write the receiver's accept/reject decision to a stream
+pub async fn write_decision<T>(stream: &mut T, decision: TransferDecision) -> Result<()>
+where
+    T: AsyncWrite + Unpin,
+{
+    write_frame(stream, &Frame::Decision(decision)).await
+}
+
+/// Read the receiver's accept/reject decision from a stream
+pub async fn read_decision<T>(stream: &mut T) -> Result<TransferDecision>
+where
+    T: AsyncRead + Unpin,
+{
+    // Not timed: the receiver's user is looking through the file list
+    match expect_frame(stream, &[FrameKind::Decision], MAX_CONTROL_FRAME_SIZE, None).await? {
+        Frame::Decision(decision) => Ok(decision),
+        frame => Err(unexpected(frame)),
+    }
+}
+
+/// Write the receiver's verification code answer to a stream
+pub async fn write_confirmed<T>(stream: &mut T, confirmed: Confirmed) -> Result<()>
+where
+    T: AsyncWrite + Unpin,
+{
+    write_frame(stream, &Frame::Confirmed(confirmed)).await
+}
+
+/// Read the receiver's verification code answer from a stream
+pub async fn read_confirmed<T>(stream: &mut T) -> Result<Confirmed>
+where
+    T: AsyncRead + Unpin,
+{
+    // Not timed: the receiver's user is comparing the codes
+    match expect_frame(stream, &[FrameKind::Confirmed], MAX_CONTROL_FRAME_SIZE, None).await? {
+        Frame::Confirmed(confirmed) => Ok(confirmed),
+        frame => Err(unexpected(frame)),
+    }
+}
+
+/* ========== Stream Limits ========== */
+
+/// Streams held open at once across all receivers
+pub const MAX_OPEN_STREAMS: usize = 256;
+
+/// Transfer streams each receiver may hold open by default
+pub const DEFAULT_STREAMS_PER_PEER: usize = 4;
+
+/// Streams over the limit that are told so at once; any more are reset unanswered
+pub const MAX_REFUSING_STREAMS: usize = 16;
+
+/// Transfer streams held open, by receiver
+///
+/// Every stream the accept loop takes on, whether it is served or turned away,
+/// holds a `StreamSlot` until its task ends, so a receiver opening streams in
+/// a loop can't pile up tasks. Refusing takes a task too, so only
+/// `MAX_REFUSING_STREAMS` refusals run at once.
+pub struct StreamLimits {
+    open: Mutex<HashMap<PeerId, usize>>,
+    refusing: AtomicUsize,
+    per_peer: usize,
+}
+
+/// What the accept loop does with a new stream
+pub enum Admission {
+    /// Serve it, holding the slot until done
+    Open(StreamSlot),
+    /// Answer it with a busy error, holding the slot while doing so
+    Refuse(StreamSlot),
+    /// Drop it, which resets it: too many refusals are under way already
+    Reset,
+}
+
+impl StreamLimits {
+    pub fn new(per_peer: usize) -> Self {
+        Self { open: Mutex::default(), refusing: AtomicUsize::new(0), per_peer }
+    }
+
+    /// Take a stream from `peer` on if neither it nor everyone together has too many
+    pub fn admit(self: &Arc<Self>, peer: PeerId) -> Admission {
+        if let Some(slot) = self.try_open(peer) {
+            return Admission::Open(slot);
+        }
+        self.refuse()
+    }
+
+    /// Count a stream that is turned away without being served, such as
+    /// one from a peer that can't start a transfer now
+    pub fn refuse(self: &Arc<Self>) -> Admission {
+        let refusing = self.refusing.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |refusing| {
+            (refusing < MAX_REFUSING_STREAMS).then_some(refusing + 1)
+        });
+        match refusing {
+            Ok(_) => Admission::Refuse(StreamSlot { limits: self.clone(), peer: None }),
+            Err(_) => Admission::Reset,
+        }
+    }
+
+    fn try_open(self: &Arc<Self>, peer: PeerId) -> Option<StreamSlot> {
+        let mut open = self.open.lock().unwrap();
+        let total: usize = open.values().sum();
+        let count = open.entry(peer).or_default();
+        if *count >= self.per_peer || total >= MAX_OPEN_STREAMS {
+            if *count == 0 {
+                open.remove(&peer);
+            }
+            return None;
+        }
+        *count += 1;
+        Some(StreamSlot { limits: self.clone(), peer: Some(peer) })
+    }
+}
+
+/// One stream counted against its receiver's limit, or against the
+/// refusals under way, released on drop
+pub struct StreamSlot {
+    limits: Arc<StreamLimits>,
+    /// `None` for a refusal
+    peer: Option<PeerId>,
+}
+
+impl Drop for StreamSlot {
+    fn drop(&mut self) {
+        let Some(peer) = self.peer else {
+            self.limits.refusing.fetch_sub(1, Ordering::Relaxed);
+            return;
+        };
+        let mut open = self.limits.open.lock().unwrap();
+        if let Some(count) = open.get_mut(&peer) {
+            *count -= 1;
+            if *count == 0 {
+                open.remove(&peer);
+            }
+        }
+    }
+}
+
+/// Answer a stream over the limit with a busy error, without waiting for its request
+pub async fn refuse_busy<T>(stream: &mut T) -> Result<()>
+where
+    T: AsyncWrite + Unpin,
+{
+    write_error(stream, ErrorCode::Busy, "Too many streams open, try again later", None).await?;
+    stream.close().await.context("Failed to close stream")
+}
+
+/* ========== Network Events ========== */
+
+/// What the transfer engine reports while it runs, for the binaries to render
+///
+/// Nothing in this module prints; progress and outcomes go out as events and
+/// errors come back as `Err`. Diagnostics stay on `tracing`.
+#[derive(Debug, Clone)]
+pub enum NetworkEvent {
+    /// A listener is bound to `address`; only usable ones go into the ticket
+    Listening { address: Multiaddr, usable: bool },
+
+    /// The router forwards `address` from the internet to one of our listeners
+    PortMapped { address: Multiaddr },
+
+    /// The sender opened a stream for one of the files
+    StreamOpened { peer: PeerId },
+
+    /// A peer other than the sender opened a file stream, which was dropped
+    StreamRefused { peer: PeerId },
+
+    /// A chunk went out to the receiver, `bytes` on the wire carrying `file_bytes` of the file
+    ChunkSent { file_index: usize, chunk_number: u64, bytes: u64, file_bytes: u64 },
+
+    /// A chunk arrived corrupted and is sent again, along with the ones after it
+    Retransmit { file_index: usize, chunk_number: u64 },
+
+    /// The receiver paused or resumed the transfer
+    PeerPaused,
+    PeerResumed,
+
+    /// A file was opened for writing, `offset` past 0 when resumed
+    FileStarted { file_index: usize, offset: u64 },
+
+    /// A chunk arrived again after it was already written, and was ignored
+    DuplicateChunk { file_index: usize, chunk_number: u64 },
+
+    /// A chunk of a file is on disk
+    ChunkReceived { file_index: usize, chunk_number: u64, bytes: u64 },
+
+    /// Progress as of an acked chunk; `file_bytes` is `None` once the file is done
+    Progress { file_index: usize, file_bytes: Option<u64>, transfer_bytes: u64, transfer_total: u64 },
+
+    /// Every byte of a file is on disk, about to be verified
+    FileWritten { file_index: usize, chunks: usize, bytes: u64 },
+
+    /// A file is verified (or not) and in place
+    FileCompleted { file_index: usize, status: FileStatus, transfer_bytes: u64, transfer_total: u64 },
+
+    /// A duplicate was copied from its original instead of being sent
+    DuplicateCopied { file_index: usize, original: usize, status: FileStatus },
+
+    /// Something went wrong with a file without stopping the transfer
+    Error { file_index: Option<usize>, message: String },
+}
+
+/// Pass `event` on to whoever listens, if anyone still does
+pub(crate) fn report(events: Option<&mpsc::UnboundedSender<NetworkEvent>>, event: NetworkEvent) {
+    if let Some(events) = events {
+        let _ = events.send(event);
+    }
+}
+
+/* ========== Chunk Transfer via Stream ========== */
+
+/// Whether the receiver should acknowledge after this chunk
+///
+/// Acks are sent every `ACK_INTERVAL` chunks and after the last chunk of each
+/// file, so both sides can compute this independently.
+pub fn ack_due(chunk: &FileChunk) -> bool {
+    (chunk.chunk_number + 1).is_multiple_of(ACK_INTERVAL) || chunk.chunk_number + 1 == chunk.total_chunks
+}
+
+/// Send a file's chunks over a raw stream as they are read
+///
+/// At most `wire.window` chunks are in flight: once that many are unacknowledged
+/// the sender waits for the receiver's cumulative `ChunkAck`, which keeps a
+/// slow receiver from being flooded and detects one that has stalled. The
+/// window never drops below `ACK_INTERVAL`, or no ack would ever be due.
+///
+/// With `wire.retransmit` the frames in flight are kept until acked, and a
+/// failed ack writes them again from the chunk the receiver couldn't use.
+/// Chunks are resent as they were, so a compressed stream stays in order.
+///
+/// Cancelling `cancel` sends a `Cancel` to the receiver and stops. A `Cancel`
+/// from the receiver is noticed the next time the sender waits for an ack, and
+/// so is a `TransferControl::Pause`, after which no more chunks are read until
+/// the receiver resumes.
+///
+/// Every chunk written is counted into `stats` and reported to `events`.
+pub async fn send_chunks_over_stream<T>(
+    stream: &mut T,
+    chunks: &mut transfer::ChunkReader,
+    rate_limiter: Option<&RateLimiter>,
+    wire: ChunkWire,
+    cancel: &CancellationToken,
+    stats: &Mutex<TransferStats>,
+    events: Option<&mpsc::UnboundedSender<NetworkEvent>>,
+) -> Result<()>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    let retransmit = wire.retransmit;
+    let window = wire.window.max(ACK_INTERVAL);
+    let file_index = chunks.file_index();
+
+    // Chunk numbers below `acked` are confirmed, below `sent` are written
+    let mut acked = chunks.position();
+    let mut sent = acked;
+    let mut paused = false;
+    let mut unacked = VecDeque::new();
+
+    // The receiver's frames are read by a stream of their own, so they are
+    // picked up between chunks and a read cut short never loses half a frame
+    let (reader, mut writer) = stream.split();
+    let acks = futures::stream::unfold(reader, |mut reader| async move {
+        let frame = read_ack(&mut reader).await;
+        Some((frame, reader))
+    });
+    let mut acks = std::pin::pin!(acks);
+
+    loop {
+        if cancel.is_cancelled() {
+            return abort_send(&mut writer).await;
+        }
+        // Act on whatever the receiver sent meanwhile, without waiting for more
+        while let Some(Some(frame)) = acks.next().now_or_never() {
+            if let Some(progress) = on_ack_frame(frame?, file_index, acked, sent, &mut paused, retransmit, events)? {
+                acked = apply_ack(&mut writer, progress, acked, &mut unacked, chunks, rate_limiter, stats).await?;
+            }
+        }
+        while paused || sent - acked >= window {
+            within(idle_timeout(), "flushing", "chunks", writer.flush())
+                .await?
+                .context("Failed to flush stream")?;
+            let progress = tokio::select! {
+                _ = cancel.cancelled() => return abort_send(&mut writer).await,
+                progress = await_ack(&mut acks, file_index, acked, sent, &mut paused, retransmit, events) => progress?,
+            };
+            acked = apply_ack(&mut writer, progress, acked, &mut unacked, chunks, rate_limiter, stats).await?;
+        }
+
+        let file_bytes = chunks.file_bytes();
+        let chunk = match chunks.next_chunk().await {
+            Ok(Some(chunk)) => chunk,
+            Ok(None) => break,
+            Err(e) => {
+                // Tell the receiver before giving up so it doesn't wait for the rest
+                let _ = write_error(&mut writer, ErrorCode::FileUnavailable, format!("{:#}", e), Some(file_index)).await;
+                return Err(e);
+            }
+        };
+
+        let chunk_number = chunk.chunk_number;
+        let wire_bytes = chunk.data.len() as u64;
+        let frame = ChunkFrame::new(chunk, wire.binary)?;
+        if frame.len() > MAX_FRAME_SIZE {
+            anyhow::bail!(
+                "Chunk {} of file {} needs a {} byte frame, over the {} byte limit",
+                chunk_number,
+                file_index,
+                frame.len(),
+                MAX_FRAME_SIZE
+            );
+        }
+
+        if let Some(limiter) = rate_limiter {
+            limiter.acquire(frame.len() + 4).await;
+        }
+        
+        within(idle_timeout(), "writing", "chunk", write_chunk_frame(&mut writer, &frame))
+            .await?
+            .context("Failed to write chunk")?;
+
+        sent = chunk_number + 1;
+        if retransmit {
+            unacked.push_back(frame);
+        } else {
+            chunks.recycle(frame.body);
+        }
+        let file_bytes = chunks.file_bytes() - file_bytes;
+        stats.lock().unwrap().chunk(file_index, file_bytes, wire_bytes);
+        report(events, NetworkEvent::ChunkSent { file_index, chunk_number, bytes: wire_bytes, file_bytes });
+    }
+    within(idle_timeout(), "flushing", "chunks", writer.flush())
+        .await?
+        .context("Failed to flush stream")?;
+
+    // The last chunk is always acked, so wait until everything is confirmed.
+    // A paused sender also holds on to the stream, so the next file waits too.
+    // Nothing more is read after that, so the next file's frames stay put.
+    while paused || acked < sent {
+        let progress = tokio::select! {
+            _ = cancel.cancelled() => return abort_send(&mut writer).await,
+            progress = await_ack(&mut acks, file_index, acked, sent, &mut paused, retransmit, events) => progress?,
+        };
+        acked = apply_ack(&mut writer, progress, acked, &mut unacked, chunks, rate_limiter, stats).await?;
+    }
+    stats.lock().unwrap().file_done(file_index);
+    Ok(())
+}
+
+/// Tell the receiver the sender is giving up on this stream
+async fn abort_send<T>(stream: &mut T) -> Result<()>
+where
+    T: AsyncWrite + Unpin,
+{
+    let _ = write_cancel(stream, "cancelled by sender").await;
+    anyhow::bail!("Transfer cancelled")
+}
+
+/// What the receiver's latest ack asks of the sender
+enum AckProgress {
+    /// Chunks below this number are confirmed
+    Acked(u64),
+
+    /// This chunk and every one after it must be written again
+    Resend(u64),
+}
+
+/// Wait for the next cumulative ack on the stream of the receiver's frames
+///
+/// A paused receiver isn't timed out. See `on_ack_frame` for what each frame does.
+async fn await_ack<S>(
+    acks: &mut S,
+    file_index: usize,
+    acked: u64,
+    sent: u64,
+    paused: &mut bool,
+    retransmit: bool,
+    events: Option<&mpsc::UnboundedSender<NetworkEvent>>,
+) -> Result<AckProgress>
+where
+    S: Stream<Item = Result<Frame>> + Unpin,
+{
+    loop {
+        let frame = if *paused {
+            acks.next().await
+        } else {
+            let limit = idle_timeout();
+            tokio::time::timeout(limit, acks.next())
+                .await
+                .map_err(|_| {
+                    anyhow::anyhow!(
+                        "Receiver stalled: no ack for chunks {}..{} of file {} within {}s",
+                        acked,
+                        sent,
+                        file_index,
+                        limit.as_secs()
+                    )
+                })?
+        };
+        let frame = frame.context("Stream closed before the ack")??;
+        if let Some(progress) = on_ack_frame(frame, file_index, acked, sent, paused, retransmit, events)? {
+            return Ok(progress);
+        }
+    }
+}
+
+/// What a frame from the receiver means for the chunks in flight, `None` for a pause
+///
+/// An ack must cover at least one more chunk than `acked` and no more than
+/// `sent`. Pause and resume requests update `paused` and are reported to
+/// `events`; a resume leaves `acked` unchanged. A failed ack is a resend
+/// request if `retransmit` was agreed, and ends the transfer otherwise.
+fn on_ack_frame(
+    frame: Frame,
+    file_index: usize,
+    acked: u64,
+    sent: u64,
+    paused: &mut bool,
+    retransmit: bool,
+    events: Option<&mpsc::UnboundedSender<NetworkEvent>>,
+) -> Result<Option<AckProgress>> {
+    let ack = match frame {
+        Frame::Ack(ack) => ack,
+        Frame::Cancel(cancel) => {
+            return Err(CancelledByPeer { peer: "Receiver", reason: cancel.reason }.into());
+        }
+        Frame::Error(error) => return Err(anyhow::Error::new(error).context("Receiver reported an error")),
+        Frame::Control(TransferControl::Pause) => {
+            if !*paused {
+                report(events, NetworkEvent::PeerPaused);
+            }
+            *paused = true;
+            return Ok(None);
+        }
+        Frame::Control(TransferControl::Resume) => {
+            if *paused {
+                report(events, NetworkEvent::PeerResumed);
+            }
+            *paused = false;
+            return Ok(Some(AckProgress::Acked(acked)));
+        }
+        frame => return Err(unexpected(frame)),
+    };
+    // A failed ack is sent as soon as a chunk is refused, not only at ack
+    // points, so it is a resend request or a failure but never "unexpected"
+    if !ack.success {
+        let in_flight = ack.file_index == file_index && (acked..sent).contains(&ack.chunk_number);
+        if retransmit && in_flight {
+            report(events, NetworkEvent::Retransmit { file_index, chunk_number: ack.chunk_number });
+            return Ok(Some(AckProgress::Resend(ack.chunk_number)));
+        }
+        anyhow::bail!(
+            "Receiver failed to store chunk {} of file {}",
+            ack.chunk_number,
+            ack.file_index
+        );
+    }
+    if ack.file_index != file_index || ack.chunk_number < acked || ack.chunk_number >= sent {
+        anyhow::bail!(
+            "Unexpected ack for chunk {} of file {} (waiting on chunks {}..{} of file {})",
+            ack.chunk_number,
+            ack.file_index,
+            acked,
+            sent,
+            file_index
+        );
+    }
+    Ok(Some(AckProgress::Acked(ack.chunk_number + 1)))
+}
+
+/// Act on an ack, returning the new acknowledged count
+///
+/// Confirmed frames are dropped from `unacked`. For a resend the frames from
+/// the failed chunk on are written and flushed again; the chunks before it
+/// were fine, but their acks may still be on the way, so `acked` stays put.
+async fn apply_ack<T>(
+    stream: &mut T,
+    progress: AckProgress,
+    acked: u64,
+    unacked: &mut VecDeque<ChunkFrame>,
+    chunks: &mut transfer::ChunkReader,
+    rate_limiter: Option<&RateLimiter>,
+    stats: &Mutex<TransferStats>,
+) -> Result<u64>
+where
+    T: AsyncWrite + Unpin,
+{
+    match progress {
+        AckProgress::Acked(acked) => {
+            let confirmed = unacked.iter().take_while(|frame| frame.chunk_number < acked).count();
+            for frame in unacked.drain(..confirmed) {
+                chunks.recycle(frame.body);
+            }
+            Ok(acked)
+        }
+        AckProgress::Resend(from) => {
+            for frame in unacked.iter().filter(|frame| frame.chunk_number >= from) {
+                if let Some(limiter) = rate_limiter {
+                    limiter.acquire(frame.len() + 4).await;
+                }
+                within(idle_timeout(), "writing", "chunk", write_chunk_frame(stream, frame))
+                    .await?
+                    .context("Failed to resend chunk")?;
+                stats.lock().unwrap().retransmit();
+            }
+            within(idle_timeout(), "flushing", "chunks", stream.flush())
+                .await?
+                .context("Failed to flush stream")?;
+            Ok(acked)
+        }
+    }
+}
+
+/// Write a chunk acknowledgment to a stream
+pub async fn write_ack<T>(stream: &mut T, ack: ChunkAck) -> Result<()>
+where
+    T: AsyncWrite + Unpin,
+{
+    write_frame(stream, &Frame::Ack(ack)).await
+}
+
+/// What the receiver sends back on a chunk stream, the usual first
+const ACK_FRAMES: &[FrameKind] = &[FrameKind::Ack, FrameKind::Cancel, FrameKind::Error, FrameKind::Control];
+
+/// What the sender writes on a chunk stream, the usual first
+const CHUNK_FRAMES: &[FrameKind] = &[FrameKind::Chunk, FrameKind::Done, FrameKind::Cancel, FrameKind::Error];
+
+/// Read a chunk acknowledgment (or the receiver's cancellation or pause) from a stream
+pub async fn read_ack<T>(stream: &mut T) -> Result<Frame>
+where
+    T: AsyncRead + Unpin,
+{
+    // The sender times acks itself, except while the receiver has it paused
+    expect_frame(stream, ACK_FRAMES, MAX_ACK_FRAME_SIZE, None).await
+}
+
+/// Tell the other side of a chunk stream that the transfer is being aborted
+pub async fn write_cancel<T>(stream: &mut T, reason: &str) -> Result<()>
+where
+    T: AsyncWrite + Unpin,
+{
+    write_frame(stream, &Frame::Cancel(Cancel { reason: reason.to_string() })).await
+}
+
+/// Ask the sender on a chunk stream to pause or resume
+pub async fn write_control<T>(stream: &mut T, control: TransferControl) -> Result<()>
+where
+    T: AsyncWrite + Unpin,
+{
+    write_frame(stream, &Frame::Control(control)).await
+}
+
+/// Tell the peer why we are giving up on this stream
+///
+/// Best effort: callers are already failing and ignore the result when the
+/// stream itself is what broke.
+pub async fn write_error<T>(
+    stream: &mut T,
+    code: ErrorCode,
+    message: impl Into<String>,
+    file_index: Option<usize>,
+) -> Result<()>
+where
+    T: AsyncWrite + Unpin,
+{
+    let error = ProtocolError { code, message: message.into(), file_index };
+    write_frame(stream, &Frame::Error(error)).await
+}
+
+/// Write the end-of-transfer message after the last chunk
+pub async fn write_complete<T>(stream: &mut T, complete: TransferComplete) -> Result<()>
+where
+    T: AsyncWrite + Unpin,
+{
+    write_frame(stream, &Frame::Done(complete)).await
+}
+
+/// Read the end-of-transfer message on its own (when chunks went over other streams)
+pub async fn read_complete<T>(stream: &mut T) -> Result<TransferComplete>
+where
+    T: AsyncRead + Unpin,
+{
+    let frame = read_frame(stream, &[FrameKind::Done], MAX_CONTROL_FRAME_SIZE, Some(control_timeout()))
+        .await?
+        .context("Connection ended before the sender finished the transfer")?;
+    match frame {
+        Frame::Done(complete) => Ok(complete),
+        frame => Err(unexpected(frame)),
+    }
+}
+
+/// Write the receiver's per-file verification results
+pub async fn write_receipt<T>(stream: &mut T, receipt: TransferReceipt) -> Result<()>
+where
+    T: AsyncWrite + Unpin,
+{
+    write_frame(stream, &Frame::Receipt(receipt)).await
+}
+
+/// Read the receiver's per-file verification results
+///
+/// A pause or resume the receiver sent while the last ack was on its way is
+/// skipped: there is nothing left to hold.
+pub async fn read_receipt<T>(stream: &mut T) -> Result<TransferReceipt>
+where
+    T: AsyncRead + Unpin,
+{
+    loop {
+        // Not timed: the receiver copies duplicates before answering, which
+        // takes as long as the files are big
+        let expected = [FrameKind::Receipt, FrameKind::Control];
+        match expect_frame(stream, &expected, MAX_CONTROL_FRAME_SIZE, None).await? {
+            Frame::Receipt(receipt) => return Ok(receipt),
+            Frame::Control(_) => continue,
+            frame => return Err(unexpected(frame)),
+        }
+    }
+}
+
+/// Tell the sender a chunk is unusable and the receiver is giving up
+///
+/// Best effort. Older senders expect a failed ack, but with `retransmit`
+/// agreed that asks for a resend, so those get an error instead.
+async fn reject_chunk<T>(stream: &mut T, chunk: &FileChunk, retransmit: bool, reason: String)
+where
+    T: AsyncWrite + Unpin,
+{
+    if retransmit {
+        let _ = write_error(stream, ErrorCode::InvalidRequest, reason, Some(chunk.file_index)).await;
+    } else {
+        let nack = ChunkAck { file_index: chunk.file_index, chunk_number: chunk.chunk_number, success: false };
+        let _ = write_ack(stream, nack).await;
+    }
+}
+
+/// A received chunk whose CRC32 doesn't match its data
+#[derive(Debug)]
+pub struct ChunkCorrupted {
+    pub file_index: usize,
+    pub chunk_number: u64,
+    pub expected: u32,
+    pub actual: u32,
+}
+
+impl std::fmt::Display for ChunkCorrupted {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Chunk {} of file {} is corrupted (CRC32 {:08x}, expected {:08x})",
+            self.chunk_number, self.file_index, self.actual, self.expected
+        )
+    }
+}
+
+impl std::error::Error for ChunkCorrupted {}
+
+/// The other side sent a `Cancel`: the transfer stopped on purpose, not because something broke
+#[derive(Debug)]
+pub struct CancelledByPeer {
+    /// "Sender" or "Receiver"
+    pub peer: &'static str,
+    pub reason: String,
+}
+
+impl std::fmt::Display for CancelledByPeer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} cancelled the transfer: {}", self.peer, self.reason)
+    }
+}
+
+impl std::error::Error for CancelledByPeer {}
+
+/// Outcome of a streaming receive
+#[derive(Debug, Default)]
+pub struct ReceiveSummary {
+    /// Files that were fully written
+    pub completed: Vec<String>,
+
+    /// Partially written files kept as `.part` after cancellation, for resuming
+    pub partial: Vec<String>,
+
+    /// Files that were never started
+    pub not_started: Vec<String>,
+
+    /// Whether the transfer was cancelled before the sender finished
+    pub cancelled: bool,
+
+    /// Whether the user declined the offer, which also counts as cancelled
+    pub declined: bool,
+
+    /// Verification outcome of each file that was fully written
+    pub statuses: Vec<FileReceipt>,
+
+    /// Hash each `Verified` file was found to have, by index
+    pub hashes: HashMap<usize, [u8; 32]>,
+
+    /// The sender's end-of-transfer message, if it arrived on this stream
+    pub transfer_complete: Option<TransferComplete>,
+
+    /// Throughput and timings of the whole transfer
+    pub stats: StatsReport,
+}
+
+impl ReceiveSummary {
+    /// Fold the result of one per-file stream into the overall summary
+    fn merge(&mut self, other: ReceiveSummary) {
+        self.completed.extend(other.completed);
+        self.partial.extend(other.partial);
+        self.cancelled |= other.cancelled;
+        self.statuses.extend(other.statuses);
+        self.hashes.extend(other.hashes);
+        self.transfer_complete = self.transfer_complete.take().or(other.transfer_complete);
+    }
+
+    /// List every file that is neither completed, kept as a partial file nor skipped
+    pub fn note_not_started(&mut self, file_list: &FileList, skip: &[usize]) {
+        self.not_started = file_list
+            .files
+            .iter()
+            .enumerate()
+            .filter(|(file_index, _)| !skip.contains(file_index))
+            .map(|(_, f)| transfer::display_path(f).to_string())
+            .filter(|name| !self.completed.contains(name) && !self.partial.contains(name))
+            .collect();
+    }
+
+    /// Check the received files against the hashes in the sender's `TransferComplete`
+    ///
+    /// A file verified against a different hash than the sender reports at the
+    /// end is marked `Failed`, and so is one that arrived without a hash and
+    /// doesn't match the reported one. Returns the indices of those files.
+    pub async fn check_file_hashes(&mut self, file_list: &FileList, complete: &TransferComplete) -> Vec<usize> {
+        let mut mismatched = Vec::new();
+        // Older senders listed the hashes of the files they sent, in order
+        if complete.file_hashes.len() != file_list.files.len() {
+            return mismatched;
+        }
+        for receipt in &mut self.statuses {
+            let file_index = receipt.file_index;
+            let Some(Some(reported)) = complete.file_hashes.get(file_index) else {
+                continue;
+            };
+            let matches = match receipt.status {
+                FileStatus::Verified => self.hashes.get(&file_index) == Some(reported),
+                FileStatus::Unverified => {
+                    let file = &file_list.files[file_index];
+                    match transfer::output_path(file) {
+                        Ok(path) => transfer::verify_file_hash(&path, *reported, file.hash_algo).await.is_ok(),
+                        Err(_) => false,
+                    }
+                }
+                FileStatus::Failed | FileStatus::Missing => continue,
+            };
+            if matches {
+                receipt.status = FileStatus::Verified;
+            } else {
+                receipt.status = FileStatus::Failed;
+                mismatched.push(file_index);
+            }
+        }
+        mismatched
+    }
+
+    /// Receipt for the sender, listing files that never arrived as missing
+    pub fn receipt(&self, file_list: &FileList) -> TransferReceipt {
+        let files = (0..file_list.files.len())
+            .map(|file_index| {
+                let status = self
+                    .statuses
+                    .iter()
+                    .find(|r| r.file_index == file_index)
+                    .map_or(FileStatus::Missing, |r| r.status);
+                FileReceipt { file_index, status }
+            })
+            .collect();
+        TransferReceipt { files }
+    }
+}
+
+/// A file being written by the streaming receiver
+struct OpenFile {
+    file: tokio::fs::File,
+
+    /// Offsets of the chunks written so far, to spot duplicates
+    written: HashSet<u64>,
+
+    /// Byte ranges of the file on disk, including a resumed prefix
+    covered: transfer::CoveredRanges,
+
+    /// Bytes written during this transfer
+    bytes_written: u64,
+
+    /// The sender's hash of the whole file, once the chunk carrying it arrived
+    file_hash: Option<[u8; 32]>,
+}
+
+/// Bytes on disk across every file of a transfer, shared by its file streams
+#[derive(Clone)]
+pub struct TransferProgress {
+    received: Arc<AtomicU64>,
+    total: u64,
+}
+
+impl TransferProgress {
+    /// Progress over the files the sender will actually send, starting from
+    /// what earlier attempts left behind
+    pub fn new(file_list: &FileList, resume: &ResumeRequest, skip: &[usize]) -> Self {
+        let sent = |(file_index, file): &(usize, &FileMetadata)| {
+            file.duplicate_of.is_none() && !skip.contains(file_index)
+        };
+        let files = || file_list.files.iter().enumerate().filter(sent);
+        let received = files()
+            .map(|(file_index, file)| resume.get(file_index).map_or(0, |point| point.offset.min(file.size)))
+            .sum();
+        Self {
+            received: Arc::new(AtomicU64::new(received)),
+            total: files().map(|(_, file)| file.size).sum(),
+        }
+    }
+
+    /// Count newly written bytes, returning the running total
+    fn add(&self, bytes: u64) -> u64 {
+        self.received.fetch_add(bytes, Ordering::Relaxed) + bytes
+    }
+
+    /// Bytes received so far and the total expected
+    pub fn get(&self) -> (u64, u64) {
+        (self.received.load(Ordering::Relaxed), self.total)
+    }
+}
+
+/// Receive and write chunks streaming - optimized to write as we receive
+/// This avoids buffering all chunks in memory before writing
+///
+/// Cancelling `cancel` stops the transfer at the next read, deletes any
+/// partially written files and returns a summary with `cancelled` set.
+///
+/// Each chunk is written at its own offset and a file is complete once all of
+/// its bytes are on disk, so arrival order doesn't matter. Repeated chunks are
+/// acknowledged but not written again. Files listed in `resume` keep their
+/// `.part` prefix; everything else starts from an empty file whichever chunk
+/// arrives first.
+///
+/// Files, compression and dictionary are taken from the sender's
+/// `TransferResponse`. With a cipher in `settings`, every chunk must be
+/// encrypted.
+///
+/// Every change of `pause` is passed on to the sender as a
+/// `TransferControl`; only set it when the sender supports `CAP_PAUSE`.
+pub async fn receive_and_write_chunks_streaming<T>(
+    stream: &mut T,
+    response: &TransferResponse,
+    resume: &ResumeRequest,
+    skip: &[usize],
+    settings: &ReceiveSettings,
+    cancel: &CancellationToken,
+    pause: &watch::Receiver<bool>,
+) -> Result<ReceiveSummary>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    let context = ReceiveContext::new(response, resume, skip, settings);
+    let mut summary = receive_chunks(stream, &context, cancel, pause).await?;
+    summary.stats = context.stats.lock().unwrap().report();
+    Ok(summary)
+}
+
+/// How the receiver handles chunk data, beyond what the response negotiated
+#[derive(Clone, Default)]
+pub struct ReceiveSettings {
+    /// Decrypts chunk data when encryption was agreed
+    pub cipher: Option<ChunkCipher>,
+
+    /// Caps how fast chunk data is read, in case the sender doesn't limit itself
+    pub rate_limiter: Option<Arc<RateLimiter>>,
+
+    /// Where progress and per-file outcomes are reported, `None` to run silently
+    pub events: Option<mpsc::UnboundedSender<NetworkEvent>>,
+}
+
+/// What every chunk stream of one transfer shares
+struct ReceiveContext {
+    response: TransferResponse,
+    resume: ResumeRequest,
+    skip: Vec<usize>,
+    settings: ReceiveSettings,
+
+    /// Counted into by every stream, so parallel file streams add up to one total
+    progress: TransferProgress,
+    stats: Mutex<TransferStats>,
+}
+
+impl ReceiveContext {
+    fn new(response: &TransferResponse, resume: &ResumeRequest, skip: &[usize], settings: &ReceiveSettings) -> Self {
+        Self {
+            response: response.clone(),
+            resume: resume.clone(),
+            skip: skip.to_vec(),
+            settings: settings.clone(),
+            progress: TransferProgress::new(&response.file_list, resume, skip),
+            stats: Mutex::new(TransferStats::new()),
+        }
+    }
+}
+
+/// Body of `receive_and_write_chunks_streaming`, for one stream of the transfer
+async fn receive_chunks<T>(
+    stream: &mut T,
+    context: &ReceiveContext,
+    cancel: &CancellationToken,
+    pause: &watch::Receiver<bool>,
+) -> Result<ReceiveSummary>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    use tokio::fs::File;
+    use tokio::io::{AsyncSeekExt, AsyncWriteExt};
+
+    let ReceiveContext { response, resume, skip, settings, progress, stats } = context;
+    let events = settings.events.as_ref();
+    let retransmit = response.supports(Capability::Retransmit);
+    let file_list = &response.file_list;
+    let mut decompressor =
+        transfer::ChunkDecompressor::new(response.compression, response.dictionary.as_deref())?;
+
+    // Frames are read by a stream of their own so that a pause, which has to
+    // be written in between, never interrupts a frame halfway through
+    let (reader, mut writer) = stream.split();
+    let max_frame = chunk_frame_limit(response.chunk_size as usize);
+    let buffers = ChunkBuffers::default();
+    let frames = futures::stream::unfold((reader, buffers.clone()), move |(mut reader, buffers)| async move {
+        // The receiver keeps its own idle timer, which a pause holds
+        read_chunk_frame(&mut reader, max_frame, &buffers).await.transpose().map(|frame| (frame, (reader, buffers)))
+    });
+    let mut frames = std::pin::pin!(frames);
+
+    // A pause from before this stream opened applies to it as well
+    let mut pause = pause.clone();
+    if *pause.borrow_and_update() {
+        let _ = write_control(&mut writer, TransferControl::Pause).await;
+    }
+    
+    // Track open files and which ones are already done
+    let mut file_handles: HashMap<usize, OpenFile> = HashMap::new();
+    let mut finished: HashSet<usize> = HashSet::new();
+    let mut summary = ReceiveSummary::default();
+
+    // The chunk each file waits for after asking for a resend, and the
+    // resends asked for so far per chunk
+    let mut resending: HashMap<usize, u64> = HashMap::new();
+    let mut retries: HashMap<(usize, u64), u32> = HashMap::new();
+    let output_paths = file_list
+        .files
+        .iter()
+        .map(transfer::output_path)
+        .collect::<Result<Vec<_>>>()?;
+
+    // Reset by every frame; a paused transfer is quiet on purpose
+    let idle = tokio::time::sleep(idle_timeout());
+    tokio::pin!(idle);
+    
+    loop {
+        let paused = *pause.borrow();
+        let frame = tokio::select! {
+            _ = cancel.cancelled() => {
+                // Best effort: the sender stops at its next ack check either way
+                let _ = write_cancel(&mut writer, "cancelled by receiver").await;
+                summary.cancelled = true;
+                break;
+            }
+            Ok(()) = pause.changed() => {
+                let control = if *pause.borrow_and_update() {
+                    TransferControl::Pause
+                } else {
+                    TransferControl::Resume
+                };
+                // Best effort: a sender done with this stream has nothing to hold
+                let _ = write_control(&mut writer, control).await;
+                idle.as_mut().reset(Instant::now() + idle_timeout());
+                continue;
+            }
+            _ = &mut idle, if !paused => {
+                let limit = idle_timeout();
+                return Err(StreamTimeout { action: "waiting for", what: "next chunk", limit }.into());
+            }
+            frame = frames.next() => frame.transpose()?,
+        };
+        idle.as_mut().reset(Instant::now() + idle_timeout());
+
+        let mut chunk = match frame {
+            Some(Frame::Chunk(chunk)) => chunk,
+            Some(Frame::Done(complete)) => {
+                summary.transfer_complete = Some(complete);
+                break;
+            }
+            Some(Frame::Cancel(cancel)) => {
+                // The sender won't finish these, so there is nothing to resume
+                for (file_index, open) in file_handles.drain() {
+                    drop(open.file);
+                    let partial = transfer::partial_path(&output_paths[file_index]);
+                    let _ = tokio::fs::remove_file(&partial).await;
+                }
+                return Err(CancelledByPeer { peer: "Sender", reason: cancel.reason }.into());
+            }
+            Some(Frame::Error(error)) => {
+                // Unlike a cancel, the sender may manage next time, so `.part` files stay
+                return Err(anyhow::Error::new(error).context("Sender reported an error"));
+            }
+            Some(frame) => return Err(unexpected(frame)),
+            None => break, // End of stream
+        };
+
+        // Holding back reads slows the sender down through the ack window
+        if let Some(limiter) = &settings.rate_limiter {
+            limiter.acquire(chunk.data.len()).await;
+        }
+        let wire_bytes = chunk.data.len() as u64;
+
+        let file_index = chunk.file_index;
+        if skip.contains(&file_index) {
+            anyhow::bail!("Sender sent file {} although the receiver is keeping its own copy", file_index);
+        }
+
+        // A retransmitted chunk of a file that is already done
+        if finished.contains(&file_index) {
+            stats.lock().unwrap().retransmit();
+            if ack_due(&chunk) {
+                let ack = ChunkAck { file_index, chunk_number: chunk.chunk_number, success: true };
+                write_ack(&mut writer, ack).await?;
+            }
+            continue;
+        }
+
+        // The chunks that were in flight behind a corrupted one are coming again after it
+        if let Some(&awaited) = resending.get(&file_index) {
+            if chunk.chunk_number != awaited {
+                continue;
+            }
+            // Only the resent chunk counts; the ones skipped were never used
+            stats.lock().unwrap().retransmit();
+            resending.remove(&file_index);
+        }
+        
+        // Get or create file handle
+        let open = match file_handles.entry(file_index) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                if file_index >= file_list.files.len() {
+                    return Err(anyhow::anyhow!(
+                        "Invalid file_index {} (only {} files in list)",
+                        file_index,
+                        file_list.files.len()
+                    ));
+                }
+            
+                let output_path = &output_paths[file_index];
+            
+                // Create parent directories if needed
+                if let Some(parent) = output_path.parent() {
+                    tokio::fs::create_dir_all(parent).await
+                        .context("Failed to create parent directories")?;
+                }
+            
+                // Written under a `.part` name until complete, so an interrupted
+                // transfer can be resumed and never passes for a complete file
+                let partial = transfer::partial_path(output_path);
+                let resumed_at = resume.get(file_index).map_or(0, |point| point.offset);
+                let file = if resumed_at == 0 {
+                    File::create(&partial).await
+                        .with_context(|| format!("Failed to create {}", partial.display()))?
+                } else {
+                    open_for_resume(&partial, resumed_at).await?
+                };
+                report(events, NetworkEvent::FileStarted { file_index, offset: resumed_at });
+            
+                entry.insert(OpenFile {
+                    file,
+                    written: HashSet::new(),
+                    covered: transfer::CoveredRanges::up_to(resumed_at),
+                    bytes_written: 0,
+                    file_hash: None,
+                })
+            }
+        };
+
+        let offset = chunk.file_offset();
+        if open.written.contains(&offset) {
+            stats.lock().unwrap().retransmit();
+            report(events, NetworkEvent::DuplicateChunk { file_index, chunk_number: chunk.chunk_number });
+            if ack_due(&chunk) {
+                let ack = ChunkAck { file_index, chunk_number: chunk.chunk_number, success: true };
+                write_ack(&mut writer, ack).await?;
+            }
+            continue;
+        }
+        
+        // Catch corruption at the chunk instead of after the whole file
+        if let Some(expected) = chunk.crc32 {
+            let actual = crc32fast::hash(&chunk.data);
+            if actual != expected {
+                let chunk_number = chunk.chunk_number;
+                let attempts = retries.entry((file_index, chunk_number)).or_default();
+                *attempts += 1;
+                if retransmit && *attempts <= MAX_CHUNK_RETRIES {
+                    let nack = ChunkAck { file_index, chunk_number, success: false };
+                    write_ack(&mut writer, nack).await?;
+                    report(events, NetworkEvent::Retransmit { file_index, chunk_number });
+                    resending.insert(file_index, chunk_number);
+                    continue;
+                }
+                let corrupted = ChunkCorrupted { file_index, chunk_number, expected, actual };
+                reject_chunk(&mut writer, &chunk, retransmit, corrupted.to_string()).await;
+                return Err(corrupted.into());
+            }
+        }
+
+        if let Some(cipher) = &settings.cipher
+            && let Err(e) = cipher.open(&mut chunk)
+        {
+            reject_chunk(&mut writer, &chunk, retransmit, format!("{:#}", e)).await;
+            return Err(e);
+        }
+
+        let file_size = file_list.files[file_index].size;
+        let decompressed = decompressor
+            .decompress(&chunk)
+            .and_then(|data| transfer::check_chunk_bounds(&chunk, data.len(), file_size).map(|()| data));
+        let data = match decompressed {
+            Ok(data) => data,
+            Err(e) => {
+                reject_chunk(&mut writer, &chunk, retransmit, format!("{:#}", e)).await;
+                return Err(e);
+            }
+        };
+
+        // Write chunk data immediately, at its place in the file
+        let open = file_handles.get_mut(&file_index).unwrap();
+        let written = match open.file.seek(io::SeekFrom::Start(offset)).await {
+            Ok(_) => open.file.write_all(&data).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = written {
+            // Tell the sender before giving up so it doesn't wait for an ack
+            let _ = write_error(&mut writer, ErrorCode::WriteFailed, e.to_string(), Some(file_index)).await;
+            return Err(e).context("Failed to write chunk data");
+        }
+        
+        // Update counters
+        open.written.insert(offset);
+        // Only bytes not on disk yet count: a sender may start below the resume
+        // point (a different chunk size, or a prefix that didn't match), and
+        // a chunk may overlap others
+        let covered = open.covered.insert(offset, offset + data.len() as u64);
+        let received = progress.add(covered);
+        stats.lock().unwrap().chunk(file_index, covered, wire_bytes);
+        open.bytes_written += data.len() as u64;
+        report(
+            events,
+            NetworkEvent::ChunkReceived { file_index, chunk_number: chunk.chunk_number, bytes: data.len() as u64 },
+        );
+        open.file_hash = open.file_hash.or(chunk.file_hash);
+        
+        // Check if file is complete
+        if open.covered.len() >= file_size {
+            open.file.flush().await.context("Failed to flush file")?;
+            report(
+                events,
+                NetworkEvent::FileWritten { file_index, chunks: open.written.len(), bytes: open.bytes_written },
+            );
+            
+            // Close the file by removing it from the map
+            let open = file_handles.remove(&file_index).unwrap();
+            drop(open.file);
+            finished.insert(file_index);
+            decompressor.finish(file_index);
+            stats.lock().unwrap().file_done(file_index);
+
+            let file_meta = &file_list.files[file_index];
+            let output_path = &output_paths[file_index];
+            let partial = transfer::partial_path(output_path);
+            let status = if let Some(expected_hash) = open.file_hash.or(file_meta.hash) {
+                let verified = transfer::verify_file_hash(
+                    &partial,
+                    expected_hash,
+                    file_meta.hash_algo,
+                )
+                .await;
+                match verified {
+                    Ok(()) => {
+                        summary.hashes.insert(file_index, expected_hash);
+                        FileStatus::Verified
+                    }
+                    Err(e) => {
+                        report(events, NetworkEvent::Error { file_index: Some(file_index), message: format!("{:#}", e) });
+                        FileStatus::Failed
+                    }
+                }
+            } else {
+                FileStatus::Unverified
+            };
+            summary.statuses.push(FileReceipt { file_index, status });
+
+            if status == FileStatus::Failed {
+                // Corrupt data must not be resumed from either
+                let _ = tokio::fs::remove_file(&partial).await;
+            } else {
+                tokio::fs::rename(&partial, output_path).await
+                    .with_context(|| format!("Failed to move {} into place", partial.display()))?;
+                if let Some(mode) = file_meta.mode {
+                    transfer::apply_mode(output_path, mode).await?;
+                }
+            }
+            summary.completed.push(transfer::display_path(file_meta).to_string());
+
+            let (transfer_bytes, transfer_total) = progress.get();
+            report(events, NetworkEvent::FileCompleted { file_index, status, transfer_bytes, transfer_total });
+        }
+
+        if ack_due(&chunk) {
+            report(
+                events,
+                NetworkEvent::Progress {
+                    file_index,
+                    file_bytes: file_handles.get(&file_index).map(|open| open.covered.len()),
+                    transfer_bytes: received,
+                    transfer_total: progress.total,
+                },
+            );
+            let ack = ChunkAck {
+                file_index,
+                chunk_number: chunk.chunk_number,
+                success: true,
+            };
+            write_ack(&mut writer, ack).await?;
+        }
+
+        // The data is on disk, so its buffer can take a later chunk
+        drop(data);
+        buffers.give(chunk.data);
+    }
+    
+    if summary.cancelled {
+        // Anything still open stays behind as a `.part` file to resume from later
+        for (file_index, mut open) in file_handles.into_iter() {
+            let _ = open.file.flush().await;
+            summary.partial.push(transfer::display_path(&file_list.files[file_index]).to_string());
+        }
+        summary.note_not_started(file_list, skip);
+        return Ok(summary);
+    }
+
+    // Flush and close any remaining open files; the stream ended before they were complete
+    for (file_index, mut open) in file_handles.into_iter() {
+        open.file.flush().await
+            .with_context(|| format!("Failed to flush file {}", file_index))?;
+        summary.partial.push(transfer::display_path(&file_list.files[file_index]).to_string());
+    }
+    
+    Ok(summary)
+}
+
+/// Open a `.part` file to continue writing at `offset`
+///
+/// Anything past `offset` is discarded; a file shorter than `offset` can't be
+/// continued because the sender would leave a gap.
+async fn open_for_resume(partial: &Path, offset: u64) -> Result<tokio::fs::File> {
+    use tokio::io::AsyncSeekExt;
+
+    let mut file = tokio::fs::OpenOptions::new()
+        .write(true)
+        .open(partial)
+        .await
+        .with_context(|| format!("Failed to open {} to resume", partial.display()))?;
+
+    let on_disk = file.metadata().await.context("Failed to get file metadata")?.len();
+    if on_disk < offset {
+        anyhow::bail!(
+            "Cannot resume {}: sender starts at {} but only {} bytes are on disk",
+            partial.display(),
+            offset,
+            on_disk
+        );
+    }
+
+    file.set_len(offset).await.context("Failed to truncate partial file")?;
+    file.seek(io::SeekFrom::Start(offset)).await.context("Failed to seek")?;
+    Ok(file)
+}
+
+/// Create each duplicate file by copying its original
+///
+/// Duplicates take their original's verification status; those whose
+/// original failed or never arrived stay missing, and those in `skip` are
+/// left as they are. Each copy is reported to `events`.
+pub async fn copy_duplicates(
+    file_list: &FileList,
+    skip: &[usize],
+    summary: &mut ReceiveSummary,
+    events: Option<&mpsc::UnboundedSender<NetworkEvent>>,
+) -> Result<()> {
+    for (file_index, meta) in file_list.files.iter().enumerate() {
+        let Some(original) = meta.duplicate_of.filter(|_| !skip.contains(&file_index)) else {
+            continue;
+        };
+        let status = summary
+            .statuses
+            .iter()
+            .find(|r| r.file_index == original)
+            .map(|r| r.status);
+        let Some(status @ (FileStatus::Verified | FileStatus::Unverified)) = status else {
+            continue;
+        };
+
+        let source = transfer::output_path(&file_list.files[original])?;
+        let output_path = transfer::output_path(meta)?;
+        if let Some(parent) = output_path.parent() {
+            tokio::fs::create_dir_all(parent).await
+                .context("Failed to create parent directories")?;
+        }
+        tokio::fs::copy(&source, &output_path).await
+            .with_context(|| format!("Failed to copy {} to {}", source.display(), output_path.display()))?;
+        if let Some(mode) = meta.mode {
+            transfer::apply_mode(&output_path, mode).await?;
+        }
+
+        summary.statuses.push(FileReceipt { file_index, status });
+        summary.completed.push(transfer::display_path(meta).to_string());
+        report(events, NetworkEvent::DuplicateCopied { file_index, original, status });
+    }
+    Ok(())
+}
+
+/// Create the empty files no chunk arrived for
+///
+/// Senders from before empty files were sent as one empty chunk send nothing
+/// for them, which would leave them missing. They are checked against the
+/// listed hash if there is one, and otherwise left unverified for the
+/// sender's completion message to settle. Files in `skip` are left as they are.
+pub async fn create_empty_files(file_list: &FileList, skip: &[usize], summary: &mut ReceiveSummary) -> Result<()> {
+    for (file_index, meta) in file_list.files.iter().enumerate() {
+        if meta.size != 0 || meta.duplicate_of.is_some() || skip.contains(&file_index) {
+            continue;
+        }
+        if summary.statuses.iter().any(|r| r.file_index == file_index) {
+            continue;
+        }
+
+        let output_path = transfer::output_path(meta)?;
+        if let Some(parent) = output_path.parent() {
+            tokio::fs::create_dir_all(parent).await
+                .context("Failed to create parent directories")?;
+        }
+        tokio::fs::write(&output_path, b"").await
+            .with_context(|| format!("Failed to create {}", output_path.display()))?;
+        if let Some(mode) = meta.mode {
+            transfer::apply_mode(&output_path, mode).await?;
+        }
+
+        let status = match meta.hash {
+            Some(hash) => match transfer::verify_file_hash(&output_path, hash, meta.hash_algo).await {
+                Ok(()) => FileStatus::Verified,
+                Err(_) => FileStatus::Failed,
+            },
+            None => FileStatus::Unverified,
+        };
+        summary.statuses.push(FileReceipt { file_index, status });
+        summary.completed.push(transfer::display_path(meta).to_string());
+    }
+    Ok(())
+}
+
+/// Receive every file in `file_list` on its own incoming stream
+///
+/// Used when parallel transfer was negotiated: the sender opens one stream
+/// per file and each is written independently by its own task. Streams
+/// opened by any peer other than `sender` are dropped unread and don't
+/// count toward the files expected.
+#[allow(clippy::too_many_arguments)]
+pub async fn receive_files_over_streams<S, T>(
+    incoming: &mut S,
+    sender: PeerId,
+    response: &TransferResponse,
+    resume: &ResumeRequest,
+    skip: &[usize],
+    settings: &ReceiveSettings,
+    cancel: &CancellationToken,
+    pause: &watch::Receiver<bool>,
+) -> Result<ReceiveSummary>
+where
+    S: Stream<Item = (PeerId, T)> + Unpin,
+    T: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let context = Arc::new(ReceiveContext::new(response, resume, skip, settings));
+    let file_list = &response.file_list;
+    // Duplicates and skipped files get no stream of their own
+    let expected = file_list
+        .files
+        .iter()
+        .enumerate()
+        .filter(|(file_index, f)| f.duplicate_of.is_none() && !skip.contains(file_index))
+        .count();
+    let mut accepted = 0;
+    let mut tasks = JoinSet::new();
+    let mut summary = ReceiveSummary::default();
+
+    while accepted < expected || !tasks.is_empty() {
+        tokio::select! {
+            _ = cancel.cancelled(), if accepted < expected => {
+                // Stop taking new streams; running tasks clean up after themselves
+                summary.cancelled = true;
+                accepted = expected;
+            }
+            next = incoming.next(), if accepted < expected => {
+                let (peer, mut stream) = next.context("Stream acceptor closed")?;
+                if peer != sender {
+                    report(settings.events.as_ref(), NetworkEvent::StreamRefused { peer });
+                    continue;
+                }
+                accepted += 1;
+                report(settings.events.as_ref(), NetworkEvent::StreamOpened { peer });
+
+                let context = context.clone();
+                let cancel = cancel.clone();
+                let pause = pause.clone();
+                tasks.spawn(async move { receive_chunks(&mut stream, &context, &cancel, &pause).await });
+            }
+            Some(result) = tasks.join_next() => {
+                summary.merge(result.context("File receive task panicked")??);
+            }
+        }
+    }
+
+    if summary.cancelled {
+        summary.note_not_started(file_list, skip);
+    }
+    summary.stats = context.stats.lock().unwrap().report();
+
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::{HashAlgo, CHUNK_SIZE};
+
+    fn nack(chunk_number: u64) -> Frame {
+        Frame::Ack(ChunkAck { file_index: 0, chunk_number, success: false })
+    }
+
+    #[test]
+    fn nack_in_flight_is_a_resend_request() {
+        let mut paused = false;
+        // Chunk 5 isn't an ack point, the receiver refused it on arrival
+        let progress = on_ack_frame(nack(5), 0, 4, 9, &mut paused, true, None).unwrap();
+        assert!(matches!(progress, Some(AckProgress::Resend(5))));
+    }
+
+    #[test]
+    fn nack_is_a_failure_not_an_unexpected_ack() {
+        let mut paused = false;
+        for (chunk_number, retransmit) in [(5, false), (2, true), (12, true)] {
+            let error = on_ack_frame(nack(chunk_number), 0, 4, 9, &mut paused, retransmit, None)
+                .err()
+                .unwrap()
+                .to_string();
+            assert!(error.contains("failed to store"), "{}", error);
+        }
+    }
+
+    fn file_list(count: usize) -> FileList {
+        let file = |file_index| FileMetadata {
+            name: format!("{}.bin", file_index),
+            relative_path: String::new(),
+            size: 0,
+            hash: None,
+            hash_algo: HashAlgo::Blake3,
+            mode: None,
+            duplicate_of: None,
+        };
+        FileList { files: (0..count).map(file).collect(), total_size: 0, unverified: false }
+    }
+
+    #[tokio::test]
+    async fn hash_reported_at_the_end_must_match() {
+        let file_list = file_list(3);
+        let mut summary = ReceiveSummary::default();
+        for file_index in 0..3 {
+            summary.statuses.push(FileReceipt { file_index, status: FileStatus::Verified });
+            summary.hashes.insert(file_index, [file_index as u8; 32]);
+        }
+        let complete = TransferComplete {
+            file_count: 3,
+            total_bytes: 0,
+            file_hashes: vec![Some([0; 32]), Some([9; 32]), None],
+        };
+
+        assert_eq!(summary.check_file_hashes(&file_list, &complete).await, [1]);
+        let statuses: Vec<FileStatus> = summary.statuses.iter().map(|r| r.status).collect();
+        assert_eq!(statuses, [FileStatus::Verified, FileStatus::Failed, FileStatus::Verified]);
+    }
+
+    #[tokio::test]
+    async fn hashes_listed_in_sending_order_are_not_checked() {
+        let file_list = file_list(3);
+        let mut summary = ReceiveSummary::default();
+        summary.statuses.push(FileReceipt { file_index: 2, status: FileStatus::Verified });
+        summary.hashes.insert(2, [2; 32]);
+        let complete = TransferComplete { file_count: 2, total_bytes: 0, file_hashes: vec![Some([0; 32]), Some([2; 32])] };
+
+        assert!(summary.check_file_hashes(&file_list, &complete).await.is_empty());
+        assert_eq!(summary.statuses[0].status, FileStatus::Verified);
+    }
+
+    fn addr(text: &str) -> Multiaddr {
+        text.parse().unwrap()
+    }
+
+    #[test]
+    fn unreachable_listen_addrs_are_not_usable() {
+        for text in [
+            "/ip4/127.0.0.1/tcp/4001",
+            "/ip4/169.254.10.20/tcp/4001",
+            "/ip4/0.0.0.0/tcp/4001",
+            "/ip4/224.0.0.251/udp/5353/quic-v1",
+            "/ip4/172.17.0.1/tcp/4001",
+            "/ip6/::1/tcp/4001",
+            "/ip6/fe80::1/tcp/4001",
+            "/ip6zone/eth0/ip6/fe80::1/tcp/4001",
+            "/ip6/::ffff:127.0.0.1/tcp/4001",
+            "/dns4/example.com/tcp/4001",
+        ] {
+            assert!(!is_usable_listen_addr(&addr(text)), "{}", text);
+        }
+    }
+
+    #[test]
+    fn reachable_listen_addrs_are_usable() {
+        for text in [
+            "/ip4/192.168.1.20/tcp/4001",
+            "/ip4/10.0.0.5/udp/4001/quic-v1",
+            "/ip4/172.18.0.2/tcp/4001",
+            "/ip4/203.0.113.7/tcp/4001",
+            "/ip6/fd12:3456::1/tcp/4001",
+            "/ip6/2001:db8::1/tcp/4001",
+        ] {
+            assert!(is_usable_listen_addr(&addr(text)), "{}", text);
+        }
+    }
+
+    #[test]
+    fn only_rfc1918_and_ula_are_private() {
+        for text in ["/ip4/10.1.2.3/tcp/1", "/ip4/172.31.0.1/tcp/1", "/ip4/192.168.0.1/tcp/1", "/ip6/fd00::1/tcp/1"] {
+            assert!(is_private_lan_addr(&addr(text)), "{}", text);
+        }
+        for text in ["/ip4/203.0.113.7/tcp/1", "/ip4/172.32.0.1/tcp/1", "/ip6/2001:db8::1/tcp/1", "/dns4/nas.local/tcp/1"] {
+            assert!(!is_private_lan_addr(&addr(text)), "{}", text);
+        }
+    }
+
+    #[test]
+    fn private_lan_addrs_sort_first() {
+        let mut addrs = vec![
+            addr("/ip4/203.0.113.7/tcp/4001"),
+            addr("/ip4/192.168.1.20/tcp/4001"),
+            addr("/ip6/2001:db8::1/tcp/4001"),
+            addr("/ip6/fd00::1/tcp/4001"),
+        ];
+        sort_by_preference(&mut addrs);
+        assert_eq!(addrs[..2], [addr("/ip4/192.168.1.20/tcp/4001"), addr("/ip6/fd00::1/tcp/4001")]);
+    }
+
+    #[test]
+    fn fewer_addrs_keeps_one_of_each_kind_then_drops_the_last() {
+        let addrs = vec![
+            addr("/ip4/192.168.1.20/udp/4001/quic-v1"),
+            addr("/ip4/10.0.0.5/udp/4001/quic-v1"),
+            addr("/ip4/192.168.1.20/tcp/4001"),
+            addr("/ip4/10.0.0.5/tcp/4001"),
+        ];
+        let compact = fewer_addrs(&addrs).unwrap();
+        assert_eq!(compact, [addrs[0].clone(), addrs[2].clone()]);
+        assert_eq!(fewer_addrs(&compact).unwrap(), [addrs[0].clone()]);
+        assert_eq!(fewer_addrs(&addrs[..1]), None);
+    }
+
+    /// The limit of every kind of frame, as the read sites pass it
+    fn frame_limits() -> [(FrameKind, usize); 4] {
+        [
+            (FrameKind::Request, MAX_CONTROL_FRAME_SIZE),
+            (FrameKind::Response, MAX_CONTROL_FRAME_SIZE),
+            (FrameKind::Ack, MAX_ACK_FRAME_SIZE),
+            (FrameKind::Chunk, chunk_frame_limit(CHUNK_SIZE)),
+        ]
+    }
+
+    /// A stream holding just the length prefix `len`, no frame behind it
+    fn prefix(len: u32) -> futures::io::Cursor<Vec<u8>> {
+        futures::io::Cursor::new(len.to_be_bytes().to_vec())
+    }
+
+    fn too_large(error: anyhow::Error) -> FrameTooLarge {
+        match error.downcast::<FrameTooLarge>() {
+            Ok(too_large) => too_large,
+            Err(error) => panic!("expected FrameTooLarge, got {:#}", error),
+        }
+    }
+
+    #[tokio::test]
+    async fn absurd_length_prefixes_are_refused_before_reading() {
+        for (kind, max_len) in frame_limits() {
+            for len in [u32::MAX, max_len as u32 + 1] {
+                // Refused off the prefix alone: reading the missing frame would fail differently
+                let error = read_frame(&mut prefix(len), &[kind], max_len, None).await.unwrap_err();
+                let error = too_large(error);
+                assert_eq!((error.what, error.len, error.max_len), (kind.name(), len as usize, max_len));
+                assert!(error.to_string().contains(kind.name()), "{}", error);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn length_prefixes_up_to_the_limit_are_read() {
+        for (kind, max_len) in frame_limits() {
+            let len = read_frame_len(&mut prefix(max_len as u32), kind.name(), max_len).await.unwrap();
+            assert_eq!(len, Some(max_len));
+        }
+        let mut empty = futures::io::Cursor::new(Vec::new());
+        assert_eq!(read_frame_len(&mut empty, "request", MAX_CONTROL_FRAME_SIZE).await.unwrap(), None);
+        let mut short = futures::io::Cursor::new(vec![0, 0]);
+        assert!(read_frame_len(&mut short, "request", MAX_CONTROL_FRAME_SIZE).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn binary_chunks_shorter_than_their_header_are_refused_unread() {
+        // Shorter than the fixed header: only the prefix and tag are read
+        let mut frame = 10u32.to_be_bytes().to_vec();
+        frame.extend_from_slice(&[BINARY_CHUNK_TAG; 10]);
+        let mut stream = futures::io::Cursor::new(frame);
+        let error = read_frame(&mut stream, CHUNK_FRAMES, MAX_ACK_FRAME_SIZE, None).await.unwrap_err();
+        assert!(error.to_string().contains("shorter than its header"), "{}", error);
+        assert_eq!(stream.position(), 5);
+
+        // Room for the fixed header but not the file hash its flags announce
+        let len = BINARY_CHUNK_HEADER_LEN + 10;
+        let mut frame = (len as u32).to_be_bytes().to_vec();
+        frame.extend_from_slice(&[BINARY_CHUNK_TAG, FLAG_FILE_HASH]);
+        frame.resize(4 + len, 0);
+        let mut stream = futures::io::Cursor::new(frame);
+        let error = read_frame(&mut stream, CHUNK_FRAMES, MAX_ACK_FRAME_SIZE, None).await.unwrap_err();
+        assert!(error.to_string().contains("shorter than its header"), "{}", error);
+        assert_eq!(stream.position(), 4 + BINARY_CHUNK_HEADER_LEN as u64);
+    }
+
+    /// A message of each kind that shares a read with others, in the smallest
+    /// form a peer may send it and with every optional field set
+    fn samples(kind: FrameKind) -> Vec<Frame> {
+        let chunk = |nonce: Option<[u8; 12]>| FileChunk {
+            file_index: 1,
+            chunk_number: 2,
+            total_chunks: 3,
+            data: vec![7; 16],
+            file_hash: nonce.map(|_| [8; 32]),
+            crc32: nonce.map(|_| 9),
+            compressed: nonce.is_some(),
+            uncompressed_len: 16,
+            chunk_size: 16,
+            offset: nonce.map(|_| 32),
+            nonce,
+        };
+        let response = |accepted| TransferResponse {
+            request_id: 1,
+            file_list: if accepted { file_list(2) } else { FileList::default() },
+            accepted,
+            reject_reason: None,
+            reason: (!accepted).then(|| "no".to_string()),
+            version: crate::protocol::PROTOCOL_VERSION,
+            capabilities: Vec::new(),
+            compression: crate::protocol::CompressionAlgo::None,
+            dictionary: None,
+            manifest_sig: [0; 64],
+            chunk_size: 0,
+            key_share: None,
+            signed_file_list: Vec::new(),
+        };
+        let error = |file_index| ProtocolError { code: ErrorCode::Busy, message: String::new(), file_index };
+        match kind {
+            FrameKind::Response => vec![Frame::Response(response(true)), Frame::Response(response(false))],
+            FrameKind::Chunk => vec![Frame::Chunk(chunk(None)), Frame::Chunk(chunk(Some([4; 12])))],
+            FrameKind::Ack => vec![Frame::Ack(ChunkAck { file_index: 0, chunk_number: 0, success: true })],
+            FrameKind::Control => vec![Frame::Control(TransferControl::Pause), Frame::Control(TransferControl::Resume)],
+            FrameKind::Done => vec![
+                Frame::Done(TransferComplete { file_count: 0, total_bytes: 0, file_hashes: Vec::new() }),
+                Frame::Done(TransferComplete { file_count: 2, total_bytes: 5, file_hashes: vec![Some([1; 32]), None] }),
+            ],
+            FrameKind::Cancel => vec![Frame::Cancel(Cancel { reason: String::new() })],
+            FrameKind::Error => vec![Frame::Error(error(None)), Frame::Error(error(Some(3)))],
+            kind => panic!("no samples of {}", kind.name()),
+        }
+    }
+
+    #[test]
+    fn frames_read_together_never_decode_as_one_another() {
+        // Frames carry no tag, so each message that may arrive at the same
+        // point has to fail to decode as every other one expected there
+        for expected in [RESPONSE_FRAMES, ACK_FRAMES, CHUNK_FRAMES] {
+            for &kind in expected {
+                for sample in samples(kind) {
+                    let data = sample.to_cbor().unwrap();
+                    for &other in expected.iter().filter(|&&other| other != kind) {
+                        assert!(
+                            Frame::from_cbor(other, &data).is_err(),
+                            "{:?} decodes as a {}",
+                            sample,
+                            other.name()
+                        );
+                    }
+                    assert_eq!(decode_frame(&data, expected).unwrap().kind(), kind);
+                }
+            }
+        }
     }
-    
-    Ok(())
 }
@@ -0,0 +1,342 @@
+// Interactive device picker: lists Fastdrop devices live while the BLE scan runs
+
+use crate::protocol::SessionTicket;
+use crate::ticket;
+use anyhow::{Context, Result};
+use btleplug::api::{Central, Peripheral};
+use crossterm::event::{Event, EventStream, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use crossterm::{cursor, execute, queue, terminal};
+use futures::stream::FuturesUnordered;
+use futures::StreamExt;
+use std::collections::HashSet;
+use std::io::{self, Write};
+use std::time::Duration;
+use tokio::time::{self, Instant};
+use tracing::debug;
+use uuid::Uuid;
+
+/// How often the device list is refreshed from the adapter
+const REFRESH_INTERVAL: Duration = Duration::from_millis(500);
+
+/// A discovered Fastdrop device as shown in the picker
+struct DeviceRow<P> {
+    peripheral: P,
+
+    /// Name in the BLE advertisement, which anyone can claim and which is
+    /// cut short to fit
+    name: String,
+    address: String,
+    rssi: Option<i16>,
+
+    /// Who signed the device's session ticket
+    sender: Sender,
+}
+
+/// What the device's session ticket says about its sender
+#[derive(Debug, Clone, PartialEq)]
+enum Sender {
+    /// The ticket hasn't been read yet
+    Pending,
+
+    /// The name and hostname from a ticket whose signature checks out
+    Verified(String),
+
+    /// The ticket couldn't be read, didn't verify or names no sender
+    Unverified,
+}
+
+/// Restores the terminal when the picker exits, even on error
+struct RawModeGuard;
+
+impl RawModeGuard {
+    fn enable() -> Result<Self> {
+        terminal::enable_raw_mode().context("Failed to enable raw terminal mode")?;
+        execute!(io::stdout(), cursor::Hide)?;
+        Ok(Self)
+    }
+}
+
+impl Drop for RawModeGuard {
+    fn drop(&mut self) {
+        let _ = execute!(io::stdout(), cursor::Show);
+        let _ = terminal::disable_raw_mode();
+    }
+}
+
+/// Show Fastdrop devices as they are discovered and let the user pick one
+///
+/// The adapter must already be scanning. Scanning is stopped as soon as a
+/// device is picked or `scan_time` runs out; the user can still pick from the
+/// list after that. Returns `None` if the user cancels or nothing was found.
+///
+/// Each device's ticket is read from one of `char_uuids` in the background,
+/// so the list can show the sender named in its signed ticket rather than
+/// the advertised name.
+pub async fn pick_device<C: Central>(
+    adapter: &C,
+    target_uuids: &[Uuid],
+    char_uuids: &[Uuid],
+    scan_time: Duration,
+) -> Result<Option<C::Peripheral>> {
+    let guard = RawModeGuard::enable()?;
+    let mut events = EventStream::new();
+    let mut lookups = FuturesUnordered::new();
+    let mut looked_up = HashSet::new();
+    let mut refresh = time::interval(REFRESH_INTERVAL);
+    let deadline = time::sleep_until(Instant::now() + scan_time);
+    tokio::pin!(deadline);
+
+    let mut devices: Vec<DeviceRow<C::Peripheral>> = Vec::new();
+    let mut selected = 0usize;
+    let mut scanning = true;
+    let mut drawn_lines = 0u16;
+
+    let picked = loop {
+        tokio::select! {
+            _ = refresh.tick() => {
+                merge(&mut devices, discover(adapter, target_uuids).await?);
+                for device in &devices {
+                    if looked_up.insert(device.address.clone()) {
+                        let (peripheral, address) = (device.peripheral.clone(), device.address.clone());
+                        lookups.push(async move { (address, verified_sender(&peripheral, char_uuids).await) });
+                    }
+                }
+            }
+            Some((address, sender)) = lookups.next() => {
+                if let Some(device) = devices.iter_mut().find(|device| device.address == address) {
+                    device.sender = sender.map_or(Sender::Unverified, Sender::Verified);
+                }
+            }
+            _ = &mut deadline, if scanning => {
+                scanning = false;
+                adapter.stop_scan().await?;
+                if devices.is_empty() {
+                    break None;
+                }
+            }
+            event = events.next() => {
+                let Some(event) = event else { break None };
+                if let Event::Key(key) = event.context("Failed to read terminal event")? {
+                    match handle_key(key) {
+                        Some(Action::Up) => selected = selected.saturating_sub(1),
+                        Some(Action::Down) if selected + 1 < devices.len() => selected += 1,
+                        Some(Action::Select) if !devices.is_empty() => {
+                            break Some(devices.swap_remove(selected).peripheral);
+                        }
+                        Some(Action::Cancel) => break None,
+                        _ => {}
+                    }
+                }
+            }
+        }
+
+        drawn_lines = draw(&devices, selected, scanning, drawn_lines)?;
+    };
+
+    drop(guard);
+    if scanning {
+        // Picking a device cancels the rest of the scan
+        adapter.stop_scan().await?;
+    }
+    println!();
+
+    Ok(picked)
+}
+
+/// Collect all peripherals advertising one of the Fastdrop service UUIDs
+async fn discover<C: Central>(
+    adapter: &C,
+    target_uuids: &[Uuid],
+) -> Result<Vec<DeviceRow<C::Peripheral>>> {
+    let mut devices = Vec::new();
+    for p in adapter.peripherals().await? {
+        if let Some(props) = p.properties().await? {
+            let has_service = target_uuids
+                .iter()
+                .any(|uuid| props.services.contains(uuid));
+
+            if has_service {
+                devices.push(DeviceRow {
+                    name: props.local_name.unwrap_or_else(|| "Unknown".into()),
+                    address: p.address().to_string(),
+                    rssi: props.rssi,
+                    sender: Sender::Pending,
+                    peripheral: p,
+                });
+            }
+        }
+    }
+    Ok(devices)
+}
+
+/// Fold a fresh scan into the list shown to the user
+///
+/// Rows keep their place and new devices go at the end, so the row under the
+/// cursor is still the device the user is looking at when they press Enter.
+/// Devices that drop out of a scan stay listed, and a device keeps what its
+/// ticket said about the sender.
+fn merge<P>(devices: &mut Vec<DeviceRow<P>>, discovered: Vec<DeviceRow<P>>) {
+    for mut row in discovered {
+        match devices.iter_mut().find(|known| known.address == row.address) {
+            Some(known) => {
+                row.sender = known.sender.clone();
+                *known = row;
+            }
+            None => devices.push(row),
+        }
+    }
+}
+
+/// Read the session ticket from a connected device's Fastdrop characteristic,
+/// the first of `char_uuids` it has
+pub async fn read_ticket<P: Peripheral>(peripheral: &P, char_uuids: &[Uuid]) -> Result<Option<Vec<u8>>> {
+    for uuid in char_uuids {
+        if let Some(ch) = peripheral.characteristics().iter().find(|c| c.uuid == *uuid) {
+            let data = peripheral.read(ch).await?;
+            debug!("Read {} bytes from characteristic {}", data.len(), uuid);
+            return Ok(Some(data));
+        }
+    }
+    Ok(None)
+}
+
+/// Sender name and hostname from the device's ticket, if it has a ticket
+/// naming its sender and signed by the key it names
+async fn verified_sender<P: Peripheral>(peripheral: &P, char_uuids: &[Uuid]) -> Option<String> {
+    peripheral.connect().await.ok()?;
+    let data = match peripheral.discover_services().await {
+        Ok(()) => read_ticket(peripheral, char_uuids).await.ok().flatten(),
+        Err(_) => None,
+    };
+    let _ = peripheral.disconnect().await;
+
+    let ticket: SessionTicket = serde_cbor::from_slice(&data?).ok()?;
+    if let Err(e) = ticket::verify_ticket(&ticket) {
+        debug!("Ticket of {} doesn't verify: {:#}", peripheral.address(), e);
+        return None;
+    }
+    sender_label(&ticket)
+}
+
+/// How a verified ticket's sender is shown, `None` for older senders that
+/// don't name themselves
+fn sender_label(ticket: &SessionTicket) -> Option<String> {
+    match (ticket.sender_name.as_str(), ticket.hostname.as_str()) {
+        ("", _) => None,
+        (name, "") => Some(name.to_string()),
+        (name, hostname) if name == hostname => Some(name.to_string()),
+        (name, hostname) => Some(format!("{} ({})", name, hostname)),
+    }
+}
+
+/* ========== Input ========== */
+
+enum Action {
+    Up,
+    Down,
+    Select,
+    Cancel,
+}
+
+fn handle_key(key: KeyEvent) -> Option<Action> {
+    if key.kind == KeyEventKind::Release {
+        return None;
+    }
+
+    match key.code {
+        KeyCode::Up | KeyCode::Char('k') => Some(Action::Up),
+        KeyCode::Down | KeyCode::Char('j') => Some(Action::Down),
+        KeyCode::Enter => Some(Action::Select),
+        KeyCode::Esc | KeyCode::Char('q') => Some(Action::Cancel),
+        KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => Some(Action::Cancel),
+        _ => None,
+    }
+}
+
+/* ========== Rendering ========== */
+
+/// Redraw the device list in place, returning the number of lines drawn
+fn draw<P>(
+    devices: &[DeviceRow<P>],
+    selected: usize,
+    scanning: bool,
+    previous_lines: u16,
+) -> Result<u16> {
+    let mut out = io::stdout();
+
+    // Raw mode needs explicit carriage returns, so build the lines first
+    let mut lines = Vec::new();
+    if scanning {
+        lines.push("🔍 Scanning for Fastdrop devices... (↑/↓ to move, Enter to select, q to quit)".to_string());
+    } else {
+        lines.push("✅ Scan finished (↑/↓ to move, Enter to select, q to quit)".to_string());
+    }
+    lines.push(String::new());
+
+    if devices.is_empty() {
+        lines.push("   Waiting for devices...".to_string());
+    }
+    for (i, device) in devices.iter().enumerate() {
+        let marker = if i == selected { "▶" } else { " " };
+        let rssi = device
+            .rssi
+            .map(|r| format!("{} dBm", r))
+            .unwrap_or_else(|| "-".to_string());
+        let name = match &device.sender {
+            Sender::Pending => format!("{} (checking ticket...)", device.name),
+            Sender::Verified(sender) => format!("🔏 {}", sender),
+            Sender::Unverified => format!("{} (unverified)", device.name),
+        };
+        lines.push(format!(
+            " {} {:>2}. {} - {}  RSSI: {}",
+            marker,
+            i + 1,
+            device.address,
+            name,
+            rssi
+        ));
+    }
+
+    if previous_lines > 0 {
+        queue!(out, cursor::MoveUp(previous_lines))?;
+    }
+    queue!(
+        out,
+        cursor::MoveToColumn(0),
+        terminal::Clear(terminal::ClearType::FromCursorDown)
+    )?;
+    for line in &lines {
+        write!(out, "{}\r\n", line)?;
+    }
+    out.flush()?;
+
+    Ok(lines.len() as u16)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn row(address: &str, rssi: i16) -> DeviceRow<()> {
+        DeviceRow {
+            peripheral: (),
+            name: "Fastdrop".to_string(),
+            address: address.to_string(),
+            rssi: Some(rssi),
+            sender: Sender::Pending,
+        }
+    }
+
+    #[test]
+    fn rescan_keeps_rows_in_place_and_the_verified_sender() {
+        let mut devices = vec![row("a", -40), row("b", -50)];
+        devices[1].sender = Sender::Verified("Alice (laptop)".to_string());
+
+        merge(&mut devices, vec![row("c", -60), row("b", -45)]);
+        let addresses: Vec<&str> = devices.iter().map(|d| d.address.as_str()).collect();
+        assert_eq!(addresses, ["a", "b", "c"]);
+        assert_eq!(devices[1].rssi, Some(-45));
+        assert_eq!(devices[1].sender, Sender::Verified("Alice (laptop)".to_string()));
+        assert_eq!(devices[2].sender, Sender::Pending);
+    }
+}
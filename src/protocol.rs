@@ -12,12 +12,251 @@ pub const QUIC_SERVICE_UUID: &str = "12345678-1234-5678-1234-56789ABCDEF0";
 pub const QUIC_CHAR_UUID: &str = "ABCDEFAB-CDEF-1234-5678-1234567890AB";
 
 // TCP Protocol UUIDs
-pub const TCP_SERVICE_UUID: &str = "87654321-4321-8765-4321-FEDCBA9876543";
+pub const TCP_SERVICE_UUID: &str = "87654321-4321-8765-4321-FEDCBA987654";
 pub const TCP_CHAR_UUID: &str = "BAFEDCBA-FEDC-4321-8765-BA0987654321";
 
+/// The TCP service UUID once had a 13-digit last group (`...-FEDCBA9876543`),
+/// which doesn't parse; this is it with the other extra digit dropped, as a
+/// build repairing it differently advertises. Scanned for until the next release.
+pub const LEGACY_TCP_SERVICE_UUID: &str = "87654321-4321-8765-4321-EDCBA9876543";
+
+/// Every service UUID receivers scan for
+pub const SCANNED_SERVICE_UUIDS: &[&str] = &[QUIC_SERVICE_UUID, TCP_SERVICE_UUID, LEGACY_TCP_SERVICE_UUID];
+
+/// Every characteristic UUID receivers read a ticket from
+pub const SCANNED_CHAR_UUIDS: &[&str] = &[QUIC_CHAR_UUID, TCP_CHAR_UUID];
+
+/* ========== Protocol Versioning ========== */
+
+/// Wire format version spoken by this build, encoded as `(major << 8) | minor`
+///
+/// Peers with a different major version can't talk to each other. Minor
+/// versions only add fields (which older peers ignore), so two peers use the
+/// lower of their minor versions.
+///
+/// The rules for changing the wire format:
+/// - A new optional field gets `#[serde(default)]`, and a new message or
+///   behaviour is gated behind a `CAP_*` capability; neither needs a bump.
+/// - A bump of the minor version is for a change that a peer has to know the
+///   other side understands but that doesn't fit a capability.
+/// - Anything an older peer would misread (a renamed, removed or retyped
+///   field, a different framing) bumps the major version. The stream
+///   protocol IDs such as `/fastdrop/transfer/1.0.0` keep the same major.
+pub const PROTOCOL_VERSION: u16 = 0x0100;
+
+/// Version assumed for peers that predate the version field
+const LEGACY_PROTOCOL_VERSION: u16 = 0x0100;
+
+/// Receiver acknowledges chunk batches (see `ChunkAck`)
+pub const CAP_CHUNK_ACK: &str = "chunk-ack";
+
+/// Each file is sent on its own stream instead of the primary transfer stream
+pub const CAP_PARALLEL_FILES: &str = "parallel-files";
+
+/// Sender ends with `TransferComplete` and the receiver answers with a `TransferReceipt`
+pub const CAP_TRANSFER_COMPLETE: &str = "transfer-complete";
+
+/// Receiver may ask to continue files from `.part` files of an interrupted transfer
+pub const CAP_RESUME: &str = "resume";
+
+/// Receiver can decompress zstd chunks (see `CompressionAlgo`)
+pub const CAP_ZSTD: &str = "zstd";
+
+/// Receiver can follow a zstd stream across the chunks of a file
+pub const CAP_ZSTD_STREAM: &str = "zstd-stream";
+
+/// Receiver accepts a zstd dictionary in the `TransferResponse`
+pub const CAP_ZSTD_DICT: &str = "zstd-dict";
+
+/// Both users compare a verification code before any files are sent (see `Confirmed`)
+pub const CAP_SAS: &str = "sas";
+
+/// Files marked `duplicate_of` are not sent; the receiver copies the original
+pub const CAP_DEDUP: &str = "dedup";
+
+/// Receiver may list files in `TransferDecision.skip` that must not be sent
+pub const CAP_SKIP: &str = "skip";
+
+/// Receiver may pause and resume the sender with `TransferControl`
+pub const CAP_PAUSE: &str = "pause";
+
+/// Chunk data is encrypted end to end with ChaCha20-Poly1305 (see crypto.rs)
+pub const CAP_ENCRYPT: &str = "chacha20-poly1305";
+
+/// A failed `ChunkAck` asks the sender to resend from that chunk on instead of ending the transfer
+pub const CAP_RETRANSMIT: &str = "retransmit";
+
+/// Chunks of a single-stream transfer go over a `/fastdrop/data` stream the
+/// receiver opens once it accepts, leaving the transfer stream to control messages
+pub const CAP_DATA_STREAM: &str = "data-stream";
+
+/// Chunks are sent as a fixed binary header followed by the raw data instead
+/// of as CBOR; every other message stays CBOR (see network.rs)
+pub const CAP_BINARY_CHUNKS: &str = "binary-chunks";
+
+/// Optional features this build supports, exchanged in the transfer handshake
+pub const CAPABILITIES: &[&str] = &[
+    CAP_CHUNK_ACK,
+    CAP_PARALLEL_FILES,
+    CAP_TRANSFER_COMPLETE,
+    CAP_RESUME,
+    CAP_ZSTD,
+    CAP_ZSTD_STREAM,
+    CAP_ZSTD_DICT,
+    CAP_SAS,
+    CAP_DEDUP,
+    CAP_SKIP,
+    CAP_PAUSE,
+    CAP_ENCRYPT,
+    CAP_RETRANSMIT,
+    CAP_DATA_STREAM,
+    CAP_BINARY_CHUNKS,
+];
+
+/// An optional feature, sent on the wire as one of the `CAP_*` tokens
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Capability {
+    ChunkAck,
+    ParallelFiles,
+    TransferComplete,
+    Resume,
+    Zstd,
+    ZstdStream,
+    ZstdDict,
+    Sas,
+    Dedup,
+    Skip,
+    Pause,
+    Encrypt,
+    Retransmit,
+    DataStream,
+    BinaryChunks,
+}
+
+impl Capability {
+    /// Token exchanged in the handshake
+    pub fn token(self) -> &'static str {
+        match self {
+            Capability::ChunkAck => CAP_CHUNK_ACK,
+            Capability::ParallelFiles => CAP_PARALLEL_FILES,
+            Capability::TransferComplete => CAP_TRANSFER_COMPLETE,
+            Capability::Resume => CAP_RESUME,
+            Capability::Zstd => CAP_ZSTD,
+            Capability::ZstdStream => CAP_ZSTD_STREAM,
+            Capability::ZstdDict => CAP_ZSTD_DICT,
+            Capability::Sas => CAP_SAS,
+            Capability::Dedup => CAP_DEDUP,
+            Capability::Skip => CAP_SKIP,
+            Capability::Pause => CAP_PAUSE,
+            Capability::Encrypt => CAP_ENCRYPT,
+            Capability::Retransmit => CAP_RETRANSMIT,
+            Capability::DataStream => CAP_DATA_STREAM,
+            Capability::BinaryChunks => CAP_BINARY_CHUNKS,
+        }
+    }
+}
+
+/// Whether a capability list contains `capability`
+pub fn has_capability(capabilities: &[String], capability: Capability) -> bool {
+    capabilities.iter().any(|c| c == capability.token())
+}
+
+/// Drop `capability` from a capability list
+pub fn remove_capability(capabilities: &mut Vec<String>, capability: Capability) {
+    capabilities.retain(|c| c != capability.token());
+}
+
+/// Major part of an encoded protocol version
+pub fn version_major(version: u16) -> u8 {
+    (version >> 8) as u8
+}
+
+/// Minor part of an encoded protocol version
+pub fn version_minor(version: u16) -> u8 {
+    (version & 0xff) as u8
+}
+
+/// Human-readable `major.minor` form of a protocol version
+pub fn format_version(version: u16) -> String {
+    format!("{}.{}", version_major(version), version_minor(version))
+}
+
+/// Pick the version both peers understand, or `None` if the majors differ
+pub fn negotiate_version(local: u16, remote: u16) -> Option<u16> {
+    if version_major(local) != version_major(remote) {
+        return None;
+    }
+    Some(local.min(remote))
+}
+
+/// Capabilities offered by the peer that this build also supports
+pub fn common_capabilities(remote: &[String]) -> Vec<String> {
+    remote
+        .iter()
+        .filter(|c| CAPABILITIES.contains(&c.as_str()))
+        .cloned()
+        .collect()
+}
+
+fn legacy_protocol_version() -> u16 {
+    LEGACY_PROTOCOL_VERSION
+}
+
+/// Only the `version` field of a handshake message, which every major version keeps
+#[derive(Deserialize)]
+struct VersionProbe {
+    #[serde(default = "legacy_protocol_version")]
+    version: u16,
+}
+
+/// The peer speaks a wire format this build can't talk to
+#[derive(Debug)]
+pub struct VersionMismatch {
+    pub local: u16,
+    pub remote: u16,
+}
+
+impl VersionMismatch {
+    /// Explain a handshake message that failed to decode, if the peer's version is to blame
+    pub fn probe(data: &[u8]) -> Option<Self> {
+        let remote = serde_cbor::from_slice::<VersionProbe>(data).ok()?.version;
+        negotiate_version(PROTOCOL_VERSION, remote)
+            .is_none()
+            .then_some(Self { local: PROTOCOL_VERSION, remote })
+    }
+}
+
+impl std::fmt::Display for VersionMismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Protocol version mismatch: peer speaks {}, this build speaks {} (update both devices to the same Fastdrop release)",
+            format_version(self.remote),
+            format_version(self.local)
+        )
+    }
+}
+
+impl std::error::Error for VersionMismatch {}
+
+/* ========== Size Limits ========== */
+
+/// Largest single file offered or accepted unless overridden
+pub const MAX_FILE_SIZE: u64 = 16 * 1024 * 1024 * 1024; // 16 GiB
+
+/// Largest whole transfer offered or accepted unless overridden
+pub const MAX_TOTAL_SIZE: u64 = 64 * 1024 * 1024 * 1024; // 64 GiB
+
+/// Largest length-prefixed frame a sender may write
+pub const MAX_FRAME_SIZE: usize = 16 * 1024 * 1024; // 16 MiB
+
+/// Default chunk size (64KB), used for mid-sized files and assumed for
+/// chunks from senders that don't state their size
+pub const CHUNK_SIZE: usize = 64 * 1024;
+
 /* ========== Transport Protocol Selection ========== */
 
-#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Hash, Default)]
 pub enum TransportProtocol {
     /// QUIC transport - best for many small/moderate files
     /// Advantages: multiplexing, lower latency, 0-RTT
@@ -26,13 +265,22 @@ pub enum TransportProtocol {
     /// TCP transport - best for few large files
     /// Advantages: simpler, well-tested, better congestion control
     Tcp,
+
+    /// QUIC and TCP side by side - the receiver dials the QUIC addresses,
+    /// adds the TCP ones if QUIC hasn't connected shortly after and keeps
+    /// whichever connects first, so a blocked UDP port isn't fatal
+    #[default]
+    Both,
 }
 
 impl TransportProtocol {
     /// Returns the BLE service UUID for this protocol
+    ///
+    /// Dual-transport senders advertise under the QUIC UUIDs, which every
+    /// receiver scans for.
     pub fn service_uuid(&self) -> &'static str {
         match self {
-            TransportProtocol::Quic => QUIC_SERVICE_UUID,
+            TransportProtocol::Quic | TransportProtocol::Both => QUIC_SERVICE_UUID,
             TransportProtocol::Tcp => TCP_SERVICE_UUID,
         }
     }
@@ -40,18 +288,35 @@ impl TransportProtocol {
     /// Returns the BLE characteristic UUID for this protocol
     pub fn char_uuid(&self) -> &'static str {
         match self {
-            TransportProtocol::Quic => QUIC_CHAR_UUID,
+            TransportProtocol::Quic | TransportProtocol::Both => QUIC_CHAR_UUID,
             TransportProtocol::Tcp => TCP_CHAR_UUID,
         }
     }
 }
 
+impl std::str::FromStr for TransportProtocol {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "quic" => Ok(TransportProtocol::Quic),
+            "tcp" => Ok(TransportProtocol::Tcp),
+            "both" => Ok(TransportProtocol::Both),
+            other => Err(format!("Unknown transport '{}' (expected quic, tcp or both)", other)),
+        }
+    }
+}
+
 /* ========== Session Information ========== */
 
 /// SessionTicket is advertised via BLE characteristic
 /// Contains all info needed for receiver to connect via libp2p
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct SessionTicket {
+    /// Wire format version of the sender (see `PROTOCOL_VERSION`)
+    #[serde(default = "legacy_protocol_version")]
+    pub version: u16,
+    
     /// The sender's libp2p peer ID
     pub peer_id: PeerId,
     
@@ -64,28 +329,165 @@ pub struct SessionTicket {
     /// Random nonce for freshness/replay prevention
     pub nonce: u64,
     
-    /// Ed25519 signature of (peer_id || addrs || protocol || nonce)
+    /// When the sender issued this ticket (unix seconds)
+    #[serde(default)]
+    pub issued_at: u64,
+    
+    /// How long the ticket stays valid after `issued_at` (0 = no expiry)
+    #[serde(default)]
+    pub ttl_secs: u32,
+    
+    /// Name the sender chose with `--name` (defaults to its hostname)
+    #[serde(default)]
+    pub sender_name: String,
+    
+    /// Hostname of the sending machine
+    #[serde(default)]
+    pub hostname: String,
+    
+    /// Fastdrop release of the sender (`CARGO_PKG_VERSION`)
+    #[serde(default)]
+    pub app_version: String,
+    
+    /// Number of files on offer, so the receiver can preview them before dialing
+    #[serde(default)]
+    pub file_count: u32,
+    
+    /// Combined size of the files on offer in bytes
+    #[serde(default)]
+    pub total_size: u64,
+    
+    /// Random ID of this ticket, echoed in `TransferRequest.session_id`
+    #[serde(default)]
+    pub session_id: [u8; 16],
+    
+    /// Ed25519 signature over the CBOR encoding of
+    /// (version, peer_id, addrs, protocol, nonce, issued_at, ttl_secs,
+    /// sender_name, hostname, app_version, file_count, total_size,
+    /// session_id) - see ticket.rs
     #[serde(with = "BigArray")]
     pub sig: [u8; 64],
 }
 
 /* ========== File Transfer Metadata ========== */
 
+/// Hash algorithm used for whole-file verification
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Default)]
+pub enum HashAlgo {
+    /// SHA-256 - the default, understood by every Fastdrop version
+    #[default]
+    Sha256,
+    
+    /// BLAKE3 - considerably faster on large files
+    Blake3,
+}
+
+impl std::str::FromStr for HashAlgo {
+    type Err = String;
+    
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "sha256" => Ok(HashAlgo::Sha256),
+            "blake3" => Ok(HashAlgo::Blake3),
+            other => Err(format!("Unknown hash algorithm '{}' (expected sha256 or blake3)", other)),
+        }
+    }
+}
+
+/// Compression applied to chunk data, chosen by the sender
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Default)]
+pub enum CompressionAlgo {
+    /// Chunks are sent as read from disk
+    #[default]
+    None,
+
+    /// Each chunk is compressed on its own with zstd at the given level
+    Zstd { level: i32 },
+
+    /// Each file is one zstd stream, flushed at every chunk. Compressed chunks
+    /// only decode in order, after all the compressed chunks before them.
+    ZstdStream { level: i32 },
+}
+
+/// Why a sender declined a transfer request
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub enum RejectReason {
+    /// The sender is already serving another receiver
+    Busy,
+
+    /// The receiver is not on the sender's allowlist
+    Unauthorized,
+
+    /// The offer exceeds a size policy
+    TooLarge,
+
+    /// The sender's user declined
+    Declined,
+
+    /// No common protocol version
+    UnsupportedVersion,
+
+    /// The offered files can no longer be read
+    FilesUnavailable,
+
+    /// The request said the receiver isn't ready to receive
+    NotReady,
+
+    /// A reason added in a newer Fastdrop version
+    #[serde(other)]
+    Other,
+}
+
+impl std::fmt::Display for RejectReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let text = match self {
+            RejectReason::Busy => "sender is busy",
+            RejectReason::Unauthorized => "not authorized",
+            RejectReason::TooLarge => "transfer too large",
+            RejectReason::Declined => "declined by sender",
+            RejectReason::UnsupportedVersion => "unsupported protocol version",
+            RejectReason::FilesUnavailable => "files unavailable",
+            RejectReason::NotReady => "receiver not ready",
+            RejectReason::Other => "rejected",
+        };
+        f.write_str(text)
+    }
+}
+
 /// Metadata for a single file being transferred
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct FileMetadata {
     /// Original filename
     pub name: String,
+
+    /// Path relative to the transfer root, '/'-separated with no `..` components.
+    /// Empty from older senders, in which case `name` is used.
+    #[serde(default)]
+    pub relative_path: String,
     
     /// File size in bytes
     pub size: u64,
     
-    /// SHA256 hash of file contents (for verification)
+    /// Hash of file contents (for verification), if known up front.
+    /// Current senders leave this empty and send the hash with the last chunk.
     pub hash: Option<[u8; 32]>,
+    
+    /// Algorithm that produced `hash`
+    #[serde(default)]
+    pub hash_algo: HashAlgo,
+
+    /// Unix permission bits, if the sender has them
+    #[serde(default)]
+    pub mode: Option<u32>,
+
+    /// Index of an earlier file with identical contents. Its bytes are not
+    /// sent again; the receiver copies that file once it has arrived.
+    #[serde(default)]
+    pub duplicate_of: Option<usize>,
 }
 
 /// List of files to be transferred
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
 pub struct FileList {
     /// Collection of file metadata
     pub files: Vec<FileMetadata>,
@@ -93,21 +495,16 @@ pub struct FileList {
     /// Total size of all files combined
     pub total_size: u64,
     
-    /// Actual file contents (name -> data)
-    pub file_data: Vec<FileData>,
+    /// Sender skipped hashing (--no-verify), so nothing can be verified
+    #[serde(default)]
+    pub unverified: bool,
 }
 
-/// File content data
-#[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct FileData {
-    /// File index in the files list
-    pub index: usize,
-    
-    /// File name
-    pub name: String,
-    
-    /// File contents
-    pub data: Vec<u8>,
+impl FileList {
+    /// What the files add up to, whatever `total_size` says
+    pub fn files_size(&self) -> u64 {
+        self.files.iter().fold(0u64, |sum, f| sum.saturating_add(f.size))
+    }
 }
 
 /* ========== Transfer Protocol Messages ========== */
@@ -120,6 +517,43 @@ pub struct TransferRequest {
     
     /// Ready to receive?
     pub ready: bool,
+    
+    /// Wire format version of the receiver
+    #[serde(default = "legacy_protocol_version")]
+    pub version: u16,
+    
+    /// Optional features the receiver supports
+    #[serde(default)]
+    pub capabilities: Vec<String>,
+
+    /// Nonce of the ticket the receiver connected with, mixed into the
+    /// verification code
+    #[serde(default)]
+    pub nonce: u64,
+
+    /// `SessionTicket.session_id` of that ticket; the sender refuses IDs it
+    /// didn't issue recently
+    #[serde(default)]
+    pub session_id: [u8; 16],
+
+    /// Chunk size the receiver would like, 0 to leave it to the sender
+    #[serde(default)]
+    pub preferred_chunk_size: u32,
+
+    /// Most files the receiver wants streamed at once, 0 to leave it to the sender
+    #[serde(default)]
+    pub max_parallel_files: u32,
+
+    /// Receiver's ephemeral X25519 key, when it offers `CAP_ENCRYPT`
+    #[serde(default)]
+    pub key_share: Option<[u8; 32]>,
+}
+
+impl TransferRequest {
+    /// Whether the receiver offered `capability`
+    pub fn supports(&self, capability: Capability) -> bool {
+        has_capability(&self.capabilities, capability)
+    }
 }
 
 /// Response sent by sender
@@ -133,6 +567,122 @@ pub struct TransferResponse {
     
     /// Accepted or rejected
     pub accepted: bool,
+    
+    /// Why the transfer was not accepted (only set when rejected)
+    #[serde(default)]
+    pub reject_reason: Option<RejectReason>,
+
+    /// Human-readable details for the rejection
+    #[serde(default)]
+    pub reason: Option<String>,
+    
+    /// Negotiated wire format version for the rest of the transfer
+    #[serde(default = "legacy_protocol_version")]
+    pub version: u16,
+    
+    /// Capabilities both sides support
+    #[serde(default)]
+    pub capabilities: Vec<String>,
+
+    /// Compression the sender may apply to chunks; only anything other than
+    /// `None` when the receiver advertised support for it
+    #[serde(default)]
+    pub compression: CompressionAlgo,
+
+    /// zstd dictionary trained on the offered files, used for every
+    /// compressed chunk when present
+    #[serde(default)]
+    pub dictionary: Option<Vec<u8>>,
+
+    /// Ed25519 signature over `file_list` by the ticket's identity key - see
+    /// ticket.rs. All zeros from older senders and in rejections.
+    #[serde(default = "unsigned", with = "BigArray")]
+    pub manifest_sig: [u8; 64],
+
+    /// Chunk size used for every file, or 0 when the sender scales it to
+    /// each file's size (as all older senders do)
+    #[serde(default)]
+    pub chunk_size: u32,
+
+    /// Sender's ephemeral X25519 key, present when `CAP_ENCRYPT` was agreed
+    #[serde(default)]
+    pub key_share: Option<[u8; 32]>,
+
+    /// `file_list` as the frame carried it, the bytes `manifest_sig` covers;
+    /// filled in when a response is read
+    #[serde(skip)]
+    pub signed_file_list: Vec<u8>,
+}
+
+impl TransferResponse {
+    /// Whether both sides agreed on `capability`
+    pub fn supports(&self, capability: Capability) -> bool {
+        has_capability(&self.capabilities, capability)
+    }
+}
+
+/// Signature field of responses that carry none
+pub fn unsigned() -> [u8; 64] {
+    [0u8; 64]
+}
+
+/// Sent by receiver after reviewing the file list in TransferResponse
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TransferDecision {
+    /// Corresponding request ID
+    pub request_id: u64,
+    
+    /// Whether the user agreed to receive the files
+    pub accepted: bool,
+
+    /// Why the receiver declined, when it wasn't simply the user saying no
+    #[serde(default)]
+    pub reject_reason: Option<RejectReason>,
+
+    /// Files to continue rather than send from the start
+    #[serde(default)]
+    pub resume: Option<ResumeRequest>,
+
+    /// Files the receiver keeps its existing copy of; they must not be sent
+    #[serde(default)]
+    pub skip: Vec<usize>,
+}
+
+/// Sent by the receiver after its user compared the verification codes
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Confirmed {
+    /// Corresponding request ID
+    pub request_id: u64,
+
+    /// Whether the user said both devices show the same code
+    pub confirmed: bool,
+}
+
+/// Partially received files the receiver wants continued
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ResumeRequest {
+    pub files: Vec<ResumeFile>,
+}
+
+impl ResumeRequest {
+    /// Resume point for a file, if the receiver has part of it
+    pub fn get(&self, file_index: usize) -> Option<&ResumeFile> {
+        self.files.iter().find(|f| f.file_index == file_index)
+    }
+}
+
+/// How much of one file the receiver already has on disk
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ResumeFile {
+    /// Index of file in FileList
+    pub file_index: usize,
+
+    /// Bytes already on disk, a multiple of the chunk size
+    pub offset: u64,
+
+    /// Hash of those bytes (with `FileMetadata.hash_algo`), so the sender can
+    /// detect a prefix that doesn't match and start over
+    pub prefix_hash: Option<[u8; 32]>,
 }
 
 /// Chunk of file data being transferred
@@ -149,8 +699,144 @@ pub struct FileChunk {
     
     /// Actual data bytes
     pub data: Vec<u8>,
+    
+    /// Hash of the whole file, computed while chunking and only set on the
+    /// final chunk (algorithm given by `FileMetadata.hash_algo`)
+    #[serde(default)]
+    pub file_hash: Option<[u8; 32]>,
+
+    /// CRC32 of `data` as sent (compressed if `compressed` is set), checked
+    /// before the chunk is decompressed and written. Absent from older senders.
+    #[serde(default)]
+    pub crc32: Option<u32>,
+
+    /// `data` is compressed with the algorithm from `TransferResponse.compression`.
+    /// Only set when that actually made the chunk smaller.
+    #[serde(default)]
+    pub compressed: bool,
+
+    /// Length of `data` once decompressed (only meaningful when `compressed`)
+    #[serde(default)]
+    pub uncompressed_len: u32,
+
+    /// Chunk size the sender picked for this file; every chunk but the last
+    /// holds exactly this many bytes, so `chunk_number * chunk_size` is its offset
+    #[serde(default = "default_chunk_size")]
+    pub chunk_size: u32,
+
+    /// Where `data` belongs in the file, so chunks can be written in any order.
+    /// Absent from older senders; see `file_offset`.
+    #[serde(default)]
+    pub offset: Option<u64>,
+
+    /// ChaCha20-Poly1305 nonce when `data` is encrypted (after compression)
+    #[serde(default)]
+    pub nonce: Option<[u8; 12]>,
+}
+
+/// Chunk size of senders that predate adaptive chunk sizes
+fn default_chunk_size() -> u32 {
+    CHUNK_SIZE as u32
+}
+
+impl FileChunk {
+    /// Byte offset of this chunk in the file
+    pub fn file_offset(&self) -> u64 {
+        self.offset
+            .unwrap_or(self.chunk_number * self.chunk_size as u64)
+    }
+
+    /// Number of file bytes this chunk carries
+    pub fn file_bytes(&self) -> u64 {
+        if self.compressed {
+            self.uncompressed_len as u64
+        } else {
+            self.data.len() as u64
+        }
+    }
+}
+
+/// Sent by either side on a chunk stream to abort the transfer
+///
+/// The receiver sends it in place of an ack, the sender in place of a chunk.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Cancel {
+    /// Why the transfer was stopped, for display on the other side
+    pub reason: String,
+}
+
+/// Sent by the receiver in place of an ack to hold or continue the sender
+///
+/// A paused sender stops once its in-flight chunks are acknowledged and
+/// waits, without timing out, until `Resume` arrives.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransferControl {
+    Pause,
+    Resume,
+}
+
+/// What went wrong on the side that sent a `ProtocolError`
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    /// The peer's message could not be decoded or made no sense
+    InvalidRequest,
+
+    /// A file could not be read for sending
+    FileUnavailable,
+
+    /// A file could not be written
+    WriteFailed,
+
+    /// Anything else that made the side give up
+    Internal,
+
+    /// The peer has too many streams open to take another
+    Busy,
+
+    /// A code added in a newer Fastdrop version
+    #[serde(other)]
+    Other,
+}
+
+impl std::fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let text = match self {
+            ErrorCode::InvalidRequest => "invalid request",
+            ErrorCode::FileUnavailable => "file unavailable",
+            ErrorCode::WriteFailed => "write failed",
+            ErrorCode::Internal => "internal error",
+            ErrorCode::Busy => "busy",
+            ErrorCode::Other => "error",
+        };
+        f.write_str(text)
+    }
+}
+
+/// Written by either side in place of its next message before giving up on a
+/// stream, so the other side can report why instead of an unexpected EOF
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ProtocolError {
+    pub code: ErrorCode,
+
+    /// Human-readable detail
+    pub message: String,
+
+    /// The file the error concerns, if any
+    #[serde(default)]
+    pub file_index: Option<usize>,
+}
+
+impl std::fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.file_index {
+            Some(file_index) => write!(f, "{} for file {}: {}", self.code, file_index, self.message),
+            None => write!(f, "{}: {}", self.code, self.message),
+        }
+    }
 }
 
+impl std::error::Error for ProtocolError {}
+
 /// Acknowledgment for received chunk
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ChunkAck {
@@ -161,5 +847,575 @@ pub struct ChunkAck {
     pub chunk_number: u64,
     
     /// Success or error
+    ///
+    /// With `CAP_RETRANSMIT` a failed ack asks for `chunk_number` and every
+    /// chunk after it to be sent again; otherwise the receiver gives up.
     pub success: bool,
 }
+
+/// Sent by the sender on the transfer stream after the last chunk of the last file
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TransferComplete {
+    /// Number of files sent
+    pub file_count: usize,
+
+    /// Total file bytes sent
+    pub total_bytes: u64,
+
+    /// Hash of each file by index (`None` when verification is disabled)
+    pub file_hashes: Vec<Option<[u8; 32]>>,
+}
+
+/// Outcome for one file as seen by the receiver
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileStatus {
+    /// Written and hash verified
+    Verified,
+
+    /// Written, but there was no hash to check
+    Unverified,
+
+    /// Written, but the hash didn't match
+    Failed,
+
+    /// Never (fully) received
+    Missing,
+}
+
+/// Verification result for one file
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FileReceipt {
+    /// Index of file in FileList
+    pub file_index: usize,
+
+    /// What happened to the file
+    pub status: FileStatus,
+}
+
+/// Receiver's answer to `TransferComplete`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TransferReceipt {
+    /// One entry per file in the FileList
+    pub files: Vec<FileReceipt>,
+}
+
+/* ========== Frames ========== */
+
+/// Any message of the transfer protocol, each sent in a length-prefixed frame of its own
+///
+/// Frames hold the message as plain CBOR (or a chunk in the binary layout)
+/// without a tag, as older builds send them. Which message a frame holds
+/// follows from what the reader expects at that point and which of those it
+/// decodes as, see `network::read_frame`.
+#[derive(Debug)]
+pub enum Frame {
+    Request(TransferRequest),
+    Response(TransferResponse),
+    Decision(TransferDecision),
+    Confirmed(Confirmed),
+    Chunk(FileChunk),
+    Ack(ChunkAck),
+    Control(TransferControl),
+    Done(TransferComplete),
+    Receipt(TransferReceipt),
+    Cancel(Cancel),
+    Error(ProtocolError),
+}
+
+/// Which message a `Frame` holds, to list the ones a read accepts
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameKind {
+    Request,
+    Response,
+    Decision,
+    Confirmed,
+    Chunk,
+    Ack,
+    Control,
+    Done,
+    Receipt,
+    Cancel,
+    Error,
+}
+
+impl FrameKind {
+    /// What the message is called in logs and errors
+    pub fn name(self) -> &'static str {
+        match self {
+            FrameKind::Request => "request",
+            FrameKind::Response => "response",
+            FrameKind::Decision => "decision",
+            FrameKind::Confirmed => "confirmation",
+            FrameKind::Chunk => "chunk",
+            FrameKind::Ack => "ack",
+            FrameKind::Control => "transfer control",
+            FrameKind::Done => "transfer completion",
+            FrameKind::Receipt => "receipt",
+            FrameKind::Cancel => "cancel",
+            FrameKind::Error => "error",
+        }
+    }
+}
+
+impl Frame {
+    pub fn kind(&self) -> FrameKind {
+        match self {
+            Frame::Request(_) => FrameKind::Request,
+            Frame::Response(_) => FrameKind::Response,
+            Frame::Decision(_) => FrameKind::Decision,
+            Frame::Confirmed(_) => FrameKind::Confirmed,
+            Frame::Chunk(_) => FrameKind::Chunk,
+            Frame::Ack(_) => FrameKind::Ack,
+            Frame::Control(_) => FrameKind::Control,
+            Frame::Done(_) => FrameKind::Done,
+            Frame::Receipt(_) => FrameKind::Receipt,
+            Frame::Cancel(_) => FrameKind::Cancel,
+            Frame::Error(_) => FrameKind::Error,
+        }
+    }
+
+    /// The message as CBOR, what goes in the frame
+    pub fn to_cbor(&self) -> serde_cbor::Result<Vec<u8>> {
+        match self {
+            Frame::Request(request) => serde_cbor::to_vec(request),
+            Frame::Response(response) => serde_cbor::to_vec(response),
+            Frame::Decision(decision) => serde_cbor::to_vec(decision),
+            Frame::Confirmed(confirmed) => serde_cbor::to_vec(confirmed),
+            Frame::Chunk(chunk) => serde_cbor::to_vec(chunk),
+            Frame::Ack(ack) => serde_cbor::to_vec(ack),
+            Frame::Control(control) => serde_cbor::to_vec(control),
+            Frame::Done(complete) => serde_cbor::to_vec(complete),
+            Frame::Receipt(receipt) => serde_cbor::to_vec(receipt),
+            Frame::Cancel(cancel) => serde_cbor::to_vec(cancel),
+            Frame::Error(error) => serde_cbor::to_vec(error),
+        }
+    }
+
+    /// Decode a frame's CBOR as a `kind` message
+    pub fn from_cbor(kind: FrameKind, data: &[u8]) -> serde_cbor::Result<Self> {
+        match kind {
+            FrameKind::Request => serde_cbor::from_slice(data).map(Frame::Request),
+            FrameKind::Response => {
+                let mut response: TransferResponse = serde_cbor::from_slice(data)?;
+                // Re-encoding the decoded list would drop fields a newer sender added
+                response.signed_file_list = cbor_map_value(data, "file_list").unwrap_or_default().to_vec();
+                Ok(Frame::Response(response))
+            }
+            FrameKind::Decision => serde_cbor::from_slice(data).map(Frame::Decision),
+            FrameKind::Confirmed => serde_cbor::from_slice(data).map(Frame::Confirmed),
+            FrameKind::Chunk => serde_cbor::from_slice(data).map(Frame::Chunk),
+            FrameKind::Ack => serde_cbor::from_slice(data).map(Frame::Ack),
+            FrameKind::Control => serde_cbor::from_slice(data).map(Frame::Control),
+            FrameKind::Done => serde_cbor::from_slice(data).map(Frame::Done),
+            FrameKind::Receipt => serde_cbor::from_slice(data).map(Frame::Receipt),
+            FrameKind::Cancel => serde_cbor::from_slice(data).map(Frame::Cancel),
+            FrameKind::Error => serde_cbor::from_slice(data).map(Frame::Error),
+        }
+    }
+}
+
+/// The encoded value of `key` in the CBOR map `data`, as it appears there
+fn cbor_map_value<'a>(data: &'a [u8], key: &str) -> Option<&'a [u8]> {
+    let (major, count, mut at) = cbor_head(data)?;
+    if major != 5 {
+        return None;
+    }
+    let mut remaining = count;
+    loop {
+        match remaining.as_mut() {
+            Some(0) => return None,
+            Some(n) => *n -= 1,
+            None if *data.get(at)? == CBOR_BREAK => return None,
+            None => {}
+        }
+        let key_len = cbor_item_len(data.get(at..)?)?;
+        let value_at = at + key_len;
+        let value_len = cbor_item_len(data.get(value_at..)?)?;
+        if serde_cbor::from_slice::<&str>(&data[at..value_at]).ok() == Some(key) {
+            return Some(&data[value_at..value_at + value_len]);
+        }
+        at = value_at + value_len;
+    }
+}
+
+/// Ends an item of indefinite length
+const CBOR_BREAK: u8 = 0xff;
+
+/// Major type, argument (`None` for indefinite lengths) and length of the
+/// head of the CBOR item `data` starts with
+fn cbor_head(data: &[u8]) -> Option<(u8, Option<u64>, usize)> {
+    let first = *data.first()?;
+    let (major, info) = (first >> 5, first & 0x1f);
+    match info {
+        0..=23 => Some((major, Some(info as u64), 1)),
+        24..=27 => {
+            let size = 1 << (info - 24);
+            let bytes = data.get(1..1 + size)?;
+            let arg = bytes.iter().fold(0u64, |arg, &byte| arg << 8 | byte as u64);
+            Some((major, Some(arg), 1 + size))
+        }
+        31 => Some((major, None, 1)),
+        _ => None,
+    }
+}
+
+/// Length of the CBOR item `data` starts with, `None` if it's cut short or malformed
+///
+/// Only used on frames serde_cbor already decoded, which bounds the nesting.
+fn cbor_item_len(data: &[u8]) -> Option<usize> {
+    let (major, arg, mut len) = cbor_head(data)?;
+    match (major, arg) {
+        // Integers, floats and simple values are all head
+        (0 | 1 | 7, Some(_)) => {}
+        (2 | 3, Some(n)) => len = len.checked_add(usize::try_from(n).ok()?)?,
+        (4 | 5, Some(n)) => {
+            let items = if major == 5 { n.checked_mul(2)? } else { n };
+            for _ in 0..items {
+                len += cbor_item_len(data.get(len..)?)?;
+            }
+        }
+        (6, Some(_)) => len += cbor_item_len(data.get(len..)?)?,
+        (2..=5, None) => {
+            while *data.get(len)? != CBOR_BREAK {
+                len += cbor_item_len(data.get(len..)?)?;
+            }
+            len += 1;
+        }
+        _ => return None,
+    }
+    (len <= data.len()).then_some(len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_cbor::Value;
+
+    fn request() -> TransferRequest {
+        TransferRequest {
+            request_id: 7,
+            ready: true,
+            version: PROTOCOL_VERSION,
+            capabilities: CAPABILITIES.iter().map(|c| c.to_string()).collect(),
+            nonce: 42,
+            session_id: [1; 16],
+            preferred_chunk_size: 0,
+            max_parallel_files: 0,
+            key_share: Some([2; 32]),
+        }
+    }
+
+    fn chunk() -> FileChunk {
+        FileChunk {
+            file_index: 1,
+            chunk_number: 3,
+            total_chunks: 4,
+            data: vec![9; 100],
+            file_hash: None,
+            crc32: Some(0xdead_beef),
+            compressed: false,
+            uncompressed_len: 0,
+            chunk_size: 1024,
+            offset: Some(3072),
+            nonce: None,
+        }
+    }
+
+    fn response() -> TransferResponse {
+        let file = |name: &str, relative_path: &str, size: u64| FileMetadata {
+            name: name.to_string(),
+            relative_path: relative_path.to_string(),
+            size,
+            hash: None,
+            hash_algo: HashAlgo::Blake3,
+            mode: Some(0o644),
+            duplicate_of: None,
+        };
+        let mut copy = file("c.txt", "docs/c.txt", 5);
+        copy.duplicate_of = Some(1);
+        TransferResponse {
+            request_id: 7,
+            file_list: FileList {
+                files: vec![file("a.bin", "a.bin", 70_000), file("b.txt", "docs/b.txt", 5), copy],
+                total_size: 70_010,
+                unverified: false,
+            },
+            accepted: true,
+            reject_reason: None,
+            reason: None,
+            version: PROTOCOL_VERSION,
+            capabilities: vec![CAP_CHUNK_ACK.to_string(), CAP_ZSTD.to_string()],
+            compression: CompressionAlgo::Zstd { level: 3 },
+            dictionary: Some(vec![1, 2, 3]),
+            manifest_sig: [5; 64],
+            chunk_size: 65_536,
+            key_share: Some([6; 32]),
+            signed_file_list: Vec::new(),
+        }
+    }
+
+    /// Field names of a CBOR map, sorted
+    fn field_names(encoded: &Value) -> Vec<String> {
+        let Value::Map(map) = encoded else {
+            panic!("expected a CBOR map, got {:?}", encoded);
+        };
+        let mut names: Vec<String> = map
+            .keys()
+            .map(|k| match k {
+                Value::Text(name) => name.clone(),
+                other => panic!("unexpected key {:?}", other),
+            })
+            .collect();
+        names.sort();
+        names
+    }
+
+    /// Encode `message` with its CBOR map changed by `edit`, as another build might send it
+    fn edited<T: Serialize>(message: &T, edit: impl FnOnce(&mut std::collections::BTreeMap<Value, Value>)) -> Vec<u8> {
+        let Value::Map(mut map) = serde_cbor::value::to_value(message).unwrap() else {
+            panic!("messages encode as CBOR maps");
+        };
+        edit(&mut map);
+        serde_cbor::to_vec(&Value::Map(map)).unwrap()
+    }
+
+    fn key(name: &str) -> Value {
+        Value::Text(name.to_string())
+    }
+
+    #[test]
+    fn newer_peer_fields_are_ignored() {
+        let add = |map: &mut std::collections::BTreeMap<Value, Value>| {
+            map.insert(key("added_in_a_later_release"), Value::Integer(1));
+            map.insert(key("another_one"), Value::Array(vec![Value::Text("x".into())]));
+        };
+
+        let decoded: TransferRequest = serde_cbor::from_slice(&edited(&request(), add)).unwrap();
+        assert_eq!(decoded.request_id, 7);
+        assert_eq!(decoded.nonce, 42);
+
+        let decoded: FileChunk = serde_cbor::from_slice(&edited(&chunk(), add)).unwrap();
+        assert_eq!(decoded.offset, Some(3072));
+        assert_eq!(decoded.data, vec![9; 100]);
+
+        let ack = ChunkAck { file_index: 0, chunk_number: 5, success: true };
+        let decoded: ChunkAck = serde_cbor::from_slice(&edited(&ack, add)).unwrap();
+        assert_eq!(decoded.chunk_number, 5);
+    }
+
+    #[test]
+    fn older_peer_messages_decode_with_defaults() {
+        let bare = edited(&request(), |map| {
+            map.retain(|k, _| *k == key("request_id") || *k == key("ready"));
+        });
+        let decoded: TransferRequest = serde_cbor::from_slice(&bare).unwrap();
+        assert_eq!(decoded.version, LEGACY_PROTOCOL_VERSION);
+        assert!(decoded.capabilities.is_empty());
+        assert_eq!(decoded.session_id, [0; 16]);
+        assert_eq!(decoded.key_share, None);
+
+        let bare = edited(&chunk(), |map| {
+            for field in ["file_hash", "crc32", "compressed", "uncompressed_len", "chunk_size", "offset", "nonce"] {
+                map.remove(&key(field));
+            }
+        });
+        let decoded: FileChunk = serde_cbor::from_slice(&bare).unwrap();
+        assert_eq!(decoded.chunk_size, CHUNK_SIZE as u32);
+        assert_eq!(decoded.crc32, None);
+        assert_eq!(decoded.file_offset(), 3 * CHUNK_SIZE as u64);
+    }
+
+    #[test]
+    fn required_fields_are_still_required() {
+        let broken = edited(&request(), |map| {
+            map.remove(&key("request_id"));
+        });
+        assert!(serde_cbor::from_slice::<TransferRequest>(&broken).is_err());
+    }
+
+    #[test]
+    fn versions_negotiate_to_the_lower_minor() {
+        assert_eq!(negotiate_version(0x0103, 0x0101), Some(0x0101));
+        assert_eq!(negotiate_version(0x0100, 0x0102), Some(0x0100));
+        assert_eq!(negotiate_version(0x0100, 0x0200), None);
+        assert_eq!(format_version(0x0102), "1.2");
+    }
+
+    #[test]
+    fn unknown_capabilities_are_dropped() {
+        let remote = vec![CAP_CHUNK_ACK.to_string(), "teleport".to_string()];
+        assert_eq!(common_capabilities(&remote), vec![CAP_CHUNK_ACK.to_string()]);
+    }
+
+    #[test]
+    fn probe_explains_a_newer_major_version() {
+        let newer = edited(&request(), |map| {
+            map.insert(key("version"), Value::Integer(0x0200));
+            map.insert(key("request_id"), Value::Text("changed type".into()));
+        });
+        assert!(serde_cbor::from_slice::<TransferRequest>(&newer).is_err());
+        let mismatch = VersionMismatch::probe(&newer).unwrap();
+        assert_eq!(mismatch.remote, 0x0200);
+        assert_eq!(mismatch.local, PROTOCOL_VERSION);
+
+        // Same major: the decode error isn't the version's fault
+        let same = edited(&request(), |map| {
+            map.insert(key("request_id"), Value::Text("changed type".into()));
+        });
+        assert!(VersionMismatch::probe(&same).is_none());
+    }
+
+    #[test]
+    fn transfer_response_round_trips() {
+        let encoded = serde_cbor::to_vec(&response()).unwrap();
+        let decoded: TransferResponse = serde_cbor::from_slice(&encoded).unwrap();
+        assert_eq!(serde_cbor::to_vec(&decoded).unwrap(), encoded);
+
+        assert_eq!(decoded.request_id, 7);
+        assert_eq!(decoded.file_list.files.len(), 3);
+        assert_eq!(decoded.file_list.files[1].relative_path, "docs/b.txt");
+        assert_eq!(decoded.file_list.files[2].duplicate_of, Some(1));
+        assert_eq!(decoded.file_list.files[0].mode, Some(0o644));
+        assert_eq!(decoded.file_list.total_size, 70_010);
+        assert_eq!(decoded.compression, CompressionAlgo::Zstd { level: 3 });
+        assert_eq!(decoded.manifest_sig, [5; 64]);
+        assert_eq!(decoded.key_share, Some([6; 32]));
+        assert!(decoded.supports(Capability::Zstd));
+    }
+
+    /// Renaming or dropping any of these breaks older peers (see `PROTOCOL_VERSION`)
+    #[test]
+    fn transfer_response_encoding_is_pinned() {
+        let encoded: Value = serde_cbor::from_slice(&serde_cbor::to_vec(&response()).unwrap()).unwrap();
+        assert_eq!(
+            field_names(&encoded),
+            [
+                "accepted", "capabilities", "chunk_size", "compression", "dictionary", "file_list", "key_share",
+                "manifest_sig", "reason", "reject_reason", "request_id", "version",
+            ]
+        );
+
+        let Value::Map(map) = &encoded else { unreachable!() };
+        let file_list = &map[&key("file_list")];
+        assert_eq!(field_names(file_list), ["files", "total_size", "unverified"]);
+
+        let Value::Map(file_list) = file_list else { unreachable!() };
+        let Value::Array(files) = &file_list[&key("files")] else {
+            panic!("files should be an array");
+        };
+        assert_eq!(
+            field_names(&files[0]),
+            ["duplicate_of", "hash", "hash_algo", "mode", "name", "relative_path", "size"]
+        );
+        assert_eq!(map[&key("compression")], serde_cbor::value::to_value(CompressionAlgo::Zstd { level: 3 }).unwrap());
+        assert_eq!(file_list[&key("total_size")], Value::Integer(70_010));
+    }
+
+    #[test]
+    fn rejection_from_an_older_sender_decodes() {
+        let bare = edited(&response(), |map| {
+            map.retain(|k, _| [key("request_id"), key("file_list"), key("accepted")].contains(k));
+            map.insert(key("accepted"), Value::Bool(false));
+        });
+        let decoded: TransferResponse = serde_cbor::from_slice(&bare).unwrap();
+        assert!(!decoded.accepted);
+        assert_eq!(decoded.manifest_sig, unsigned());
+        assert_eq!(decoded.compression, CompressionAlgo::None);
+        assert_eq!(decoded.chunk_size, 0);
+    }
+
+    /// Error codes travel as their names; renaming one breaks older peers
+    #[test]
+    fn error_codes_are_encoded_by_name() {
+        let codes = [
+            (ErrorCode::InvalidRequest, "InvalidRequest"),
+            (ErrorCode::FileUnavailable, "FileUnavailable"),
+            (ErrorCode::WriteFailed, "WriteFailed"),
+            (ErrorCode::Internal, "Internal"),
+            (ErrorCode::Busy, "Busy"),
+        ];
+        for (code, name) in codes {
+            let encoded = serde_cbor::to_vec(&code).unwrap();
+            assert_eq!(serde_cbor::from_slice::<Value>(&encoded).unwrap(), key(name));
+            assert_eq!(serde_cbor::from_slice::<ErrorCode>(&encoded).unwrap(), code);
+        }
+    }
+
+    #[test]
+    fn protocol_error_encoding_is_pinned() {
+        let error = ProtocolError {
+            code: ErrorCode::FileUnavailable,
+            message: "gone".to_string(),
+            file_index: Some(2),
+        };
+        let encoded: Value = serde_cbor::from_slice(&serde_cbor::to_vec(&error).unwrap()).unwrap();
+        assert_eq!(field_names(&encoded), ["code", "file_index", "message"]);
+
+        let Value::Map(map) = &encoded else { unreachable!() };
+        assert_eq!(map[&key("code")], key("FileUnavailable"));
+        assert_eq!(map[&key("file_index")], Value::Integer(2));
+        assert_eq!(map[&key("message")], key("gone"));
+    }
+
+    #[test]
+    fn unknown_error_codes_decode_as_other() {
+        let error = ProtocolError { code: ErrorCode::Busy, message: "later".to_string(), file_index: None };
+        let newer = edited(&error, |map| {
+            map.insert(key("code"), key("AddedInALaterRelease"));
+            map.remove(&key("file_index"));
+        });
+        let decoded: ProtocolError = serde_cbor::from_slice(&newer).unwrap();
+        assert_eq!(decoded.code, ErrorCode::Other);
+        assert_eq!(decoded.message, "later");
+        assert_eq!(decoded.file_index, None);
+        assert_eq!(decoded.to_string(), "error: later");
+    }
+
+    #[test]
+    fn every_uuid_parses() {
+        let all = SCANNED_SERVICE_UUIDS.iter().chain(SCANNED_CHAR_UUIDS);
+        let parsed: Vec<uuid::Uuid> = all.map(|uuid| uuid::Uuid::parse_str(uuid).unwrap()).collect();
+        let distinct: std::collections::HashSet<_> = parsed.iter().collect();
+        assert_eq!(distinct.len(), parsed.len());
+
+        for transport in [TransportProtocol::Quic, TransportProtocol::Tcp, TransportProtocol::Both] {
+            assert!(SCANNED_SERVICE_UUIDS.contains(&transport.service_uuid()));
+            assert!(SCANNED_CHAR_UUIDS.contains(&transport.char_uuid()));
+        }
+    }
+
+    #[test]
+    fn read_responses_keep_the_file_list_as_sent() {
+        let sent = response();
+        let frame = Frame::from_cbor(FrameKind::Response, &serde_cbor::to_vec(&sent).unwrap()).unwrap();
+        let Frame::Response(decoded) = frame else { unreachable!() };
+        assert_eq!(decoded.signed_file_list, serde_cbor::to_vec(&sent.file_list).unwrap());
+
+        // Including fields this version doesn't know, which decoding drops
+        let newer = edited(&sent, |map| {
+            let Some(Value::Map(file_list)) = map.get_mut(&key("file_list")) else { unreachable!() };
+            file_list.insert(key("added_in_a_later_release"), Value::Array(vec![Value::Integer(1)]));
+        });
+        let Frame::Response(decoded) = Frame::from_cbor(FrameKind::Response, &newer).unwrap() else { unreachable!() };
+        let raw: Value = serde_cbor::from_slice(&decoded.signed_file_list).unwrap();
+        assert!(field_names(&raw).contains(&"added_in_a_later_release".to_string()));
+        assert_ne!(decoded.signed_file_list, serde_cbor::to_vec(&decoded.file_list).unwrap());
+    }
+
+    #[test]
+    fn cbor_items_are_measured_whole() {
+        let value = Value::Array(vec![
+            Value::Integer(-70_000),
+            Value::Bytes(vec![1; 300]),
+            Value::Map([(key("a"), Value::Float(1.5)), (key("b"), Value::Null)].into()),
+            Value::Tag(2, Box::new(Value::Text("x".repeat(30)))),
+        ]);
+        let encoded = serde_cbor::to_vec(&value).unwrap();
+        assert_eq!(cbor_item_len(&encoded), Some(encoded.len()));
+        assert_eq!(cbor_item_len(&encoded[..encoded.len() - 1]), None);
+
+        // An indefinite array of two items
+        assert_eq!(cbor_item_len(&[0x9f, 0x01, 0x02, 0xff, 0x00]), Some(4));
+        assert_eq!(cbor_map_value(&encoded, "a"), None);
+    }
+}
@@ -0,0 +1,128 @@
+// Token-bucket bandwidth limiter for chunk data
+
+use crate::transfer;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Limits the average rate of chunk bytes, shared by every stream of a sender
+/// (or, as a safety net, of a receiver)
+///
+/// The bucket holds at most one second worth of tokens. A write larger than the
+/// tokens available puts the bucket into debt and sleeps it off, so chunks
+/// bigger than the per-second budget still hold the average near the target.
+pub struct RateLimiter {
+    bytes_per_sec: u64,
+    bucket: Mutex<Bucket>,
+}
+
+struct Bucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(bytes_per_sec: u64) -> Self {
+        Self {
+            bytes_per_sec,
+            bucket: Mutex::new(Bucket {
+                tokens: bytes_per_sec as f64,
+                last_refill: Instant::now(),
+            }),
+        }
+    }
+
+    /// The configured rate
+    pub fn bytes_per_sec(&self) -> u64 {
+        self.bytes_per_sec
+    }
+
+    /// Take `bytes` tokens, sleeping until the bucket can cover them
+    pub async fn acquire(&self, bytes: usize) {
+        let wait = {
+            let mut bucket = self.bucket.lock().unwrap();
+            let rate = self.bytes_per_sec as f64;
+
+            let now = Instant::now();
+            let elapsed = now.duration_since(bucket.last_refill).as_secs_f64();
+            bucket.tokens = (bucket.tokens + elapsed * rate).min(rate);
+            bucket.last_refill = now;
+
+            bucket.tokens -= bytes as f64;
+            if bucket.tokens < 0.0 {
+                Duration::from_secs_f64(-bucket.tokens / rate)
+            } else {
+                Duration::ZERO
+            }
+        };
+
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+/// Parse a rate such as `500000`, `512K`, `10M` or `5MB/s` (binary multiples) into bytes per second
+pub fn parse_rate(value: &str) -> Result<u64, String> {
+    let rate = transfer::parse_size(value.trim().trim_end_matches("/s"))
+        .ok_or_else(|| format!("Invalid rate: {} (expected bytes per second, e.g. 500000, 10M or 5MB)", value))?;
+
+    if rate == 0 {
+        return Err("Rate must be greater than zero".to_string());
+    }
+    Ok(rate)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn holds_the_average_rate() {
+        let limiter = RateLimiter::new(100_000);
+        let start = Instant::now();
+        // 1 MB in 16 KiB writes: the first second's worth goes out at once
+        let mut sent = 0;
+        while sent < 1_000_000 {
+            limiter.acquire(16 * 1024).await;
+            sent += 16 * 1024;
+        }
+        let elapsed = start.elapsed().as_secs_f64();
+        let expected = (sent - 100_000) as f64 / 100_000.0;
+        assert!(elapsed >= expected - 0.01, "{}s, expected at least {}s", elapsed, expected);
+        assert!(elapsed <= expected * 1.05, "{}s, expected about {}s", elapsed, expected);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn writes_larger_than_the_bucket_are_paid_off() {
+        let limiter = RateLimiter::new(10_000);
+        let start = Instant::now();
+        for _ in 0..4 {
+            limiter.acquire(25_000).await;
+        }
+        let elapsed = start.elapsed().as_secs_f64();
+        // 100 KB at 10 KB/s with a 10 KB head start
+        assert!((elapsed - 9.0).abs() < 0.05, "{}s", elapsed);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn idle_time_refills_at_most_one_second() {
+        let limiter = RateLimiter::new(10_000);
+        limiter.acquire(10_000).await;
+        tokio::time::sleep(Duration::from_secs(60)).await;
+
+        let start = Instant::now();
+        limiter.acquire(30_000).await;
+        let elapsed = start.elapsed().as_secs_f64();
+        assert!((elapsed - 2.0).abs() < 0.05, "{}s", elapsed);
+    }
+
+    #[test]
+    fn parses_rates() {
+        assert_eq!(parse_rate("500000"), Ok(500_000));
+        assert_eq!(parse_rate("512K"), Ok(512 * 1024));
+        assert_eq!(parse_rate("5MB/s"), Ok(5 * 1024 * 1024));
+        assert!(parse_rate("0").is_err());
+        assert!(parse_rate("fast").is_err());
+    }
+}
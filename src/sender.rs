@@ -1,56 +1,121 @@
 // Sender: Advertises via BLE and sends files via libp2p
 
-mod network;
-mod protocol;
-mod transfer;
+#[macro_use]
+extern crate fastdrop;
+
+use fastdrop::{codec, crypto, events, network, protocol, ratelimit, stats, ticket, transfer};
 
 use anyhow::{Context, Result};
 use ble_peripheral_rust::gatt::{characteristic, properties, service};
 use ble_peripheral_rust::{Peripheral, PeripheralImpl};
-use futures::StreamExt;
+use futures::{AsyncWriteExt, StreamExt, TryStreamExt};
 use libp2p::identity::Keypair;
-use libp2p::swarm::SwarmEvent;
-use libp2p::{PeerId, StreamProtocol};
-use protocol::{SessionTicket, TransferResponse};
-use std::collections::HashMap;
+use libp2p::multiaddr::Protocol;
+use libp2p::swarm::{ConnectionId, SwarmEvent};
+use libp2p::{upnp, Multiaddr, PeerId, Stream, StreamProtocol};
+use protocol::{
+    Capability, CompressionAlgo, ErrorCode, FileList, FileStatus, HashAlgo, RejectReason, ResumeRequest, SessionTicket, TransferComplete,
+    TransferResponse, TransportProtocol,
+};
+use events::Event;
+use network::{Admission, FileTransferBehaviourEvent, Heartbeat, NetworkEvent, StreamLimits, StreamSlot};
+use ratelimit::RateLimiter;
+use stats::{StatsReport, TransferStats};
+use ticket::SessionRegistry;
+use std::collections::{HashMap, HashSet};
 use std::env;
-use std::path::PathBuf;
+use std::io::{self, Write};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
 use tokio::signal;
-use tokio::sync::mpsc;
-use tokio::time::{sleep, Duration};
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::{sleep, sleep_until, Duration, Instant};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, trace, warn};
+use transfer::SizeLimits;
 use uuid::Uuid;
 
+/// How long `--broadcast` takes on new receivers without `--advertise-timeout`
+const BROADCAST_WINDOW: Duration = Duration::from_secs(30);
+
+/// Upper bound on waiting for listen addresses
+const ADDR_MAX_WAIT: Duration = Duration::from_secs(5);
+
+/// How long Ctrl+C waits for transfers to notify their receivers before exiting
+const CANCEL_GRACE: Duration = Duration::from_millis(500);
+
+/// How long Ctrl+C lets running transfers finish before cancelling them
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(30);
+
+/// Room left for the local name in a 31-byte advertisement carrying the
+/// flags and a 128-bit service UUID
+const MAX_ADVERTISED_NAME_BYTES: usize = 8;
+
 #[tokio::main]
 async fn main() -> Result<()> {
-    println!("🚀 Fastdrop Sender");
-    println!("==================\n");
-
-    // 1. Get file paths from command line
-    let args: Vec<String> = env::args().skip(1).collect();
-    if args.is_empty() {
-        eprintln!("Usage: sender <file1> [file2] [file3] ...");
-        eprintln!("\nExample: sender document.pdf photo.jpg video.mp4");
-        std::process::exit(1);
+    events::init_tracing();
+
+    // 1. Get options and file paths from command line
+    let options = match Options::parse() {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("❌ {}\n", e);
+            print_usage();
+            std::process::exit(1);
+        }
+    };
+
+    if options.json {
+        events::enable_json();
     }
+    network::set_timeouts(options.control_timeout, options.idle_timeout);
+    say!("🚀 Fastdrop Sender");
+    say!("==================\n");
 
-    let file_paths: Vec<PathBuf> = args.iter().map(PathBuf::from).collect();
-    println!("📁 Files to send: {}", file_paths.len());
+    let file_paths = options.files.clone();
+    say!("📁 Files to send: {}", file_paths.len());
     for path in &file_paths {
-        println!("   - {}", path.display());
+        say!("   - {}", path.display());
     }
-    println!();
+    say!();
 
     // 2. Analyze files and determine protocol
-    let (protocol, file_list) = transfer::analyze_files(&file_paths)
+    let (suggested, mut file_list) = transfer::analyze_files(&file_paths, options.hash_algo, options.limits)
         .await
         .context("Failed to analyze files")?;
+    let protocol = options.transport.unwrap_or(suggested);
 
-    println!(
+    if options.no_verify {
+        file_list.unverified = true;
+        say!("⚠️  Verification disabled (--no-verify): this transfer will be UNVERIFIED\n");
+    }
+
+    say!(
         "📊 Total size: {} ({})\n",
         transfer::format_bytes(file_list.total_size),
         file_list.total_size
     );
 
+    if let Some(rate) = options.rate {
+        say!("🐢 Upload rate limited to {}/s\n", transfer::format_bytes(rate));
+    }
+
+    // Shared redundancy between many small files is lost when each is compressed alone
+    let dictionary = match options.compression {
+        CompressionAlgo::Zstd { level } | CompressionAlgo::ZstdStream { level } if options.dict => {
+            transfer::train_dictionary(&file_paths, level)
+                .await
+                .context("Failed to train compression dictionary")?
+        }
+        _ => None,
+    };
+
+    if options.dry_run {
+        print_dry_run(protocol, &file_list);
+        return Ok(());
+    }
+
     // Note: We don't load file contents into memory anymore
     // Files will be sent as chunks on-demand
 
@@ -58,90 +123,124 @@ async fn main() -> Result<()> {
     let keypair = Keypair::generate_ed25519();
     let peer_id = keypair.public().to_peer_id();
     
-    let mut swarm = network::build_swarm(keypair.clone(), protocol)
+    let mut swarm = network::build_swarm(keypair.clone(), protocol, options.upnp)
         .context("Failed to build swarm")?;
 
-    // 4. Start listening on appropriate transport
-    let listen_addr = match protocol {
-        protocol::TransportProtocol::Quic => "/ip4/0.0.0.0/udp/0/quic-v1".parse()?,
-        protocol::TransportProtocol::Tcp => "/ip4/0.0.0.0/tcp/0".parse()?,
+    // 4. Start listening on appropriate transport (port 0 picks an ephemeral one)
+    let port = options.port.unwrap_or(0);
+    let mut families = Vec::new();
+    if let Some(interface) = &options.interface {
+        // Only addresses on that interface, so only those end up in the ticket
+        for ip in network::interface_ips(interface)? {
+            match ip {
+                IpAddr::V4(ip) if !options.no_ipv4 => families.push(format!("/ip4/{}", ip)),
+                IpAddr::V6(ip) if !options.no_ipv6 => families.push(format!("/ip6/{}", ip)),
+                _ => {}
+            }
+        }
+        if families.is_empty() {
+            anyhow::bail!("Interface {} has no address of an enabled family", interface);
+        }
+    } else {
+        if !options.no_ipv4 {
+            families.push("/ip4/0.0.0.0".to_string());
+        }
+        if !options.no_ipv6 {
+            families.push("/ip6/::".to_string());
+        }
+    }
+
+    let wanted: Vec<String> = families
+        .iter()
+        .flat_map(|family| network::listen_addrs_for(protocol, family, port))
+        .collect();
+
+    let mut listening = 0;
+    for listen_addr in &wanted {
+        let listen_addr: Multiaddr = listen_addr.parse()?;
+
+        match swarm.listen_on(listen_addr.clone()) {
+            Ok(_) => listening += 1,
+            // A host without IPv6 (or with UDP blocked) shouldn't stop us from serving the rest
+            Err(e) if wanted.len() > 1 => {
+                eprintln!("⚠️  Not listening on {}: {}", listen_addr, e);
+            }
+            Err(e) => {
+                let message = match options.port {
+                    Some(port) => format!("Failed to listen on port {} (is it already in use?)", port),
+                    None => "Failed to start listening".to_string(),
+                };
+                return Err(anyhow::Error::new(e).context(message));
+            }
+        }
+    }
+    if listening == 0 {
+        anyhow::bail!("Failed to listen on any address");
+    }
+
+    // Reserve a slot on the relay so receivers on other networks can reach us
+    let relay_circuit = options.relay.clone().map(|relay| relay.with(Protocol::P2pCircuit));
+    let relay_peer = relay_circuit.as_ref().and_then(network::relay_peer_of);
+    let relay_listener = match relay_circuit {
+        Some(circuit) => {
+            say!("🛰️  Reserving a slot on relay {}", circuit);
+            Some(swarm.listen_on(circuit).context("Failed to listen through the relay")?)
+        }
+        None => None,
     };
-    
-    swarm.listen_on(listen_addr)
-        .context("Failed to start listening")?;
 
-    println!("⏳ Waiting for network to bind...\n");
+    say!("⏳ Waiting for network to bind...\n");
 
-    // Wait for NewListenAddr events to get actual bound addresses
-    // We need to collect multiple addresses and filter out localhost
-    let mut listen_addrs = Vec::new();
-    let mut addr_count = 0;
-    
-    loop {
-        tokio::select! {
-            event = swarm.select_next_some() => {
-                match event {
-                    SwarmEvent::NewListenAddr { address, .. } => {
-                        println!("🎧 Listening on: {}", address);
-                        
-                        // Filter out localhost addresses for the ticket
-                        let addr_str = address.to_string();
-                        if !addr_str.contains("127.0.0.1") && !addr_str.contains("::1") {
-                            listen_addrs.push(address);
-                            println!("   ✅ Added to ticket (non-localhost)");
-                        } else {
-                            println!("   ⚠️  Skipped (localhost)");
-                        }
-                        
-                        addr_count += 1;
-                        
-                        // Wait for a short time to collect all addresses
-                        // Usually we get 3-4 addresses (localhost, LAN, etc.)
-                        if addr_count >= 3 {
-                            break;
-                        }
-                    }
-                    _ => {}
+    let (network_events, mut reports) = mpsc::unbounded_channel();
+    let collected = network::listen_and_wait(&mut swarm, relay_listener, 1, ADDR_MAX_WAIT, Some(&network_events)).await;
+    drop(network_events);
+    while let Ok(event) = reports.try_recv() {
+        match event {
+            NetworkEvent::Listening { address, usable } => {
+                say!("🎧 Listening on: {}", address);
+                if usable {
+                    say!("   ✅ Added to ticket");
+                } else {
+                    say!("   ⚠️  Skipped (loopback, link-local or bridge)");
                 }
             }
-            _ = tokio::time::sleep(Duration::from_secs(2)) => {
-                // Timeout after 2 seconds even if we haven't gotten 3 addresses
-                if !listen_addrs.is_empty() {
-                    break;
-                }
+            NetworkEvent::PortMapped { address } => {
+                say!("🌍 Router forwards {} to us", address);
+                say!("   ✅ Added to ticket");
             }
+            NetworkEvent::Error { message, .. } => eprintln!("⚠️  {}", message),
+            _ => {}
         }
     }
-    
-    if listen_addrs.is_empty() {
-        anyhow::bail!("No valid listen addresses obtained (all were localhost)");
+    let mut listen_addrs = collected?;
+    if options.lan_only {
+        listen_addrs.retain(network::is_private_lan_addr);
+        if listen_addrs.is_empty() {
+            anyhow::bail!("No private LAN address to advertise with --lan-only");
+        }
+        say!("🏠 Only advertising private LAN addresses");
     }
+    network::sort_by_preference(&mut listen_addrs);
 
-    println!();
+    say!();
 
-    // 5. Create session ticket
-    let nonce = rand::random::<u64>();
-    
-    // Create signature (simplified - in production should sign actual data)
-    let mut sig = [0u8; 64];
-    sig[..8].copy_from_slice(&nonce.to_le_bytes());
-    
-    let ticket = SessionTicket {
-        peer_id,
-        addrs: listen_addrs.clone(),
-        protocol,
-        nonce,
-        sig,
-    };
+    // 5. Create session ticket, signed with our identity key, and encode it as CBOR
+    let identity = SenderIdentity::new(options.name.clone());
+    let sessions = Arc::new(SessionRegistry::default());
+    let ticket_cbor = build_ticket(&keypair, &listen_addrs, protocol, &identity, &file_list, &sessions)?;
 
-    // 6. Encode ticket as CBOR
-    let ticket_cbor = serde_cbor::to_vec(&ticket)
-        .context("Failed to encode session ticket")?;
+    if let Some(target) = &options.emit_ticket {
+        emit_ticket(target, &ticket_cbor)?;
+    }
+    if options.qr {
+        show_ticket_qr(&keypair, &listen_addrs, protocol, &identity, &file_list, &sessions)?;
+    }
 
-    println!("🎫 Session ticket created ({} bytes)", ticket_cbor.len());
-    println!("   Protocol: {:?}", protocol);
-    println!("   PeerId: {}", peer_id);
-    println!();
+    say!("🎫 Session ticket created ({} bytes)", ticket_cbor.len());
+    say!("   Sender: {} ({})", identity.name, identity.hostname);
+    say!("   Protocol: {:?}", protocol);
+    say!("   PeerId: {}", peer_id);
+    say!();
 
     // 7. Setup BLE advertising
     let (tx, _rx) = mpsc::channel::<_>(256);
@@ -149,11 +248,11 @@ async fn main() -> Result<()> {
         .await
         .context("Failed to create BLE peripheral")?;
 
-    println!("⏳ Waiting for Bluetooth adapter to power on...");
+    say!("⏳ Waiting for Bluetooth adapter to power on...");
     while !peripheral.is_powered().await? {
         sleep(Duration::from_millis(100)).await;
     }
-    println!("✅ Bluetooth adapter powered on\n");
+    say!("✅ Bluetooth adapter powered on\n");
 
     // 8. Setup GATT service with protocol-specific UUIDs
     let service_uuid = Uuid::parse_str(protocol.service_uuid())
@@ -180,14 +279,14 @@ async fn main() -> Result<()> {
         .await
         .context("Failed to add GATT service")?;
 
-    println!("📡 GATT service configured:");
-    println!("   Service UUID: {}", service_uuid);
-    println!("   Char UUID: {}", char_uuid);
-    println!();
+    say!("📡 GATT service configured:");
+    say!("   Service UUID: {}", service_uuid);
+    say!("   Char UUID: {}", char_uuid);
+    say!();
 
     // 9. Start advertising
     peripheral
-        .start_advertising("Fastdrop", &[service_uuid])
+        .start_advertising(&identity.advertised_name(), &[service_uuid])
         .await
         .context("Failed to start advertising")?;
 
@@ -197,143 +296,1833 @@ async fn main() -> Result<()> {
         anyhow::bail!("Advertising failed to start");
     }
 
-    println!("🔵 BLE advertising active!");
-    println!("🔍 Receivers can now discover this device\n");
-    println!("📦 Waiting for transfer requests...");
-    println!("   (Press Ctrl+C to cancel)\n");
+    say!("🔵 BLE advertising active!");
+    say!("🔍 Receivers can now discover this device\n");
+    say!("📦 Waiting for transfer requests...");
+    match (options.broadcast, options.advertise_timeout) {
+        (true, timeout) => say!(
+            "   (Broadcasting to everyone who connects within {}s)",
+            timeout.unwrap_or(BROADCAST_WINDOW).as_secs()
+        ),
+        (false, Some(timeout)) => say!("   (Giving up after {}s without a transfer)", timeout.as_secs()),
+        (false, None) => {}
+    }
+    say!("   (Press Ctrl+C to cancel)\n");
 
     // 10. Setup stream acceptor
-    println!("🔍 Debug: Setting up stream acceptor...");
+    debug!("Setting up stream acceptor...");
     let mut control = network::get_stream_control(&swarm);
     let protocol_stream = StreamProtocol::new(network::TRANSFER_PROTOCOL);
-    println!("🔍 Debug: Protocol: {}", network::TRANSFER_PROTOCOL);
+    debug!("Protocol: {}", network::TRANSFER_PROTOCOL);
     
     let mut incoming = control.accept(protocol_stream)
         .context("Failed to accept incoming streams")?;
+
+    // Receivers that agreed to a data stream open it once they accept
+    let mut incoming_data = control
+        .accept(StreamProtocol::new(network::DATA_PROTOCOL))
+        .context("Failed to accept incoming data streams")?;
+    let data_streams = Arc::new(DataStreams::default());
+    let data_streams_clone = data_streams.clone();
+    tokio::spawn(async move {
+        while let Some((peer, stream)) = incoming_data.next().await {
+            data_streams_clone.deliver(peer, stream);
+        }
+    });
     
-    println!("✅ Stream acceptor configured");
+    say!("✅ Stream acceptor configured");
 
     // Clone data for the stream handler task
     let file_list_clone = file_list.clone();
     let file_paths_clone = file_paths.clone();
+    let control_clone = control.clone();
+    // Transport of each connection to a receiver, for the parallel-files decision
+    let transports: Arc<Mutex<Transports>> = Arc::default();
+    let transports_clone = transports.clone();
+    let cancel = CancellationToken::new();
+    let max_receivers = match options.max_receivers {
+        Some(max) => max,
+        None if options.broadcast => usize::MAX,
+        None => 1,
+    };
+    let receivers = Arc::new(Receivers::new(max_receivers));
+    let stream_limits = Arc::new(StreamLimits::new(options.max_streams_per_peer.unwrap_or(network::DEFAULT_STREAMS_PER_PEER)));
+    let settings = SendSettings {
+        hash_algo: (!options.no_verify).then_some(options.hash_algo),
+        // One limiter shared by every transfer so the cap applies to the sender as a whole
+        rate_limiter: options.rate.map(|rate| Arc::new(RateLimiter::new(rate))),
+        ack_window: options.ack_window,
+        retransmit: false,
+        binary_chunks: false,
+        chunk_crc: !options.no_chunk_crc,
+        compression: options.compression,
+        dictionary: dictionary.map(Arc::new),
+        chunk_size: options.chunk_size,
+        parallel_files: network::DEFAULT_PARALLEL_FILES,
+        confirm: options.confirm,
+        peer_id,
+        keypair: keypair.clone(),
+        verify_code: !options.no_verify,
+        sessions: sessions.clone(),
+        receivers: receivers.clone(),
+        data_streams: data_streams.clone(),
+        cancel: cancel.clone(),
+        cipher: None,
+        stats: Arc::default(),
+        events: None,
+    };
     
+    // Transfers report to the main loop so it knows when to exit
+    let (updates_tx, mut updates) = mpsc::unbounded_channel();
+
     // Spawn task to handle incoming streams
-    println!("🔍 Debug: Spawning incoming stream handler...");
+    debug!("Spawning incoming stream handler...");
     tokio::spawn(async move {
-        println!("🔍 Debug: Stream handler task started, waiting for incoming streams...");
-        while let Some((peer, mut stream)) = incoming.next().await {
-            println!("📨 Received stream from {}", peer);
+        debug!("Stream handler task started, waiting for incoming streams...");
+        while let Some((peer, stream)) = incoming.next().await {
+            say!("📨 Received stream from {}", peer);
+            // A peer that can't start a transfer now only counts as a refusal,
+            // so it can't take the open streams of the receivers being served
+            let room = settings.receivers.has_room_for(peer);
+            let admission = if room { stream_limits.admit(peer) } else { stream_limits.refuse() };
+            // Held by whichever task ends up with the stream
+            let slot = match admission {
+                Admission::Open(slot) => slot,
+                Admission::Refuse(slot) if !room => {
+                    say!("🚦 Turning {} away, already sending to {} receiver(s)", peer, max_receivers);
+                    tokio::spawn(async move {
+                        if let Err(e) = turn_away(peer, stream, max_receivers).await {
+                            debug!("{:#}", e);
+                        }
+                        drop(slot);
+                    });
+                    continue;
+                }
+                Admission::Refuse(slot) => {
+                    say!("🚦 Refusing a stream from {}, too many are open", peer);
+                    tokio::spawn(refuse_stream(peer, stream, slot));
+                    continue;
+                }
+                Admission::Reset => {
+                    debug!("Resetting a stream from {}, too many refusals under way", peer);
+                    drop(stream);
+                    continue;
+                }
+            };
+            // Only this loop takes slots, so the room found above is still there
+            let started = settings.receivers.try_start(peer);
+            debug_assert!(started);
             
             let file_list = file_list_clone.clone();
             let file_paths = file_paths_clone.clone();
+            let control = control_clone.clone();
+            let settings = settings.clone();
+            let receivers = settings.receivers.clone();
+            let updates = updates_tx.clone();
+            let _ = updates.send(TransferUpdate::Started);
+            let transport = transport_to(&transports_clone.lock().unwrap(), peer).unwrap_or(protocol);
             
-            tokio::spawn(async move {
-                println!("🔍 Debug: Spawned handler for stream from {}", peer);
-                // Read request
-                println!("🔍 Debug: Reading request from stream...");
-                match network::read_request(&mut stream).await {
-                    Ok(request) => {
-                        println!("📨 Transfer request from {}", peer);
-                        println!("   Request ID: {}", request.request_id);
-                        
-                        if request.ready {
-                            println!("🔍 Debug: Creating transfer response...");
-                            let response = TransferResponse {
-                                request_id: request.request_id,
-                                file_list: file_list.clone(),
-                                accepted: true,
-                            };
-                            
-                            // Send response with metadata
-                            println!("🔍 Debug: Sending response with metadata...");
-                            if let Err(e) = network::write_response(&mut stream, response).await {
-                                eprintln!("❌ Failed to send response: {}", e);
-                                return;
+            tokio::spawn(events::transfer(async move {
+                debug!("Spawned handler for stream from {}", peer);
+                let result = handle_transfer_stream(
+                    peer,
+                    stream,
+                    control,
+                    transport,
+                    settings,
+                    file_list,
+                    file_paths,
+                )
+                .await;
+                let outcome = match result {
+                    Ok(Some(stats)) => TransferOutcome::Sent(stats),
+                    Ok(None) => TransferOutcome::NotSent,
+                    Err(e) => {
+                        // A receiver stopping the transfer on purpose is not a failure
+                        match e.downcast_ref::<network::CancelledByPeer>() {
+                            Some(cancelled) => {
+                                say!("🛑 {}", cancelled);
+                                events::emit(Event::TransferCancelled {
+                                    peer_id: Some(peer.to_string()),
+                                    reason: cancelled.reason.clone(),
+                                });
+                                TransferOutcome::Cancelled(cancelled.reason.clone())
                             }
-                            
-                            println!("✅ Sent file list metadata to receiver");
-                            println!("📤 Starting to send file chunks...");
-                            
-                            // Now send all files as chunks
-                            for (file_index, path) in file_paths.iter().enumerate() {
-                                println!("📄 Sending file {}/{}: {}", 
-                                    file_index + 1, 
-                                    file_paths.len(), 
-                                    path.display()
-                                );
-                                
-                                match transfer::send_file(path, file_index).await {
-                                    Ok(chunks) => {
-                                        println!("   📦 Sending {} chunks...", chunks.len());
-                                        
-                                        // Send each chunk
-                                        if let Err(e) = network::send_chunks_over_stream(&mut stream, chunks).await {
-                                            eprintln!("   ❌ Failed to send chunks: {}", e);
-                                            return;
-                                        }
-                                        
-                                        println!("   ✅ All chunks sent for file {}", file_index);
-                                    }
-                                    Err(e) => {
-                                        eprintln!("   ❌ Failed to prepare file: {}", e);
-                                        return;
-                                    }
-                                }
+                            None => {
+                                eprintln!("❌ Transfer to {} failed: {:#}", peer, e);
+                                events::emit(Event::TransferFailed {
+                                    peer_id: Some(peer.to_string()),
+                                    error: format!("{:#}", e),
+                                });
+                                TransferOutcome::Failed(format!("{:#}", e))
                             }
-                            
-                            println!("✅ All files sent successfully to {}", peer);
                         }
                     }
-                    Err(e) => {
-                        eprintln!("❌ Failed to read request: {}", e);
-                    }
-                }
-            });
+                };
+                receivers.finish(peer, &outcome);
+                drop(slot);
+                let _ = updates.send(TransferUpdate::Finished { peer, outcome });
+            }));
         }
     });
 
     // 11. Handle P2P connection events
-    let mut pending_transfers: HashMap<PeerId, Vec<PathBuf>> = HashMap::new();
+    let mut heartbeat = Heartbeat::default();
+
+    // Re-issue the ticket well before receivers would consider it expired
+    let refresh_every = Duration::from_secs(u64::from(ticket::TICKET_TTL_SECS) / 2);
+    let mut ticket_refresh = tokio::time::interval_at(Instant::now() + refresh_every, refresh_every);
+
+    // Give up once the window passes without a transfer, if asked to. A
+    // broadcast serves everyone who turns up within its window instead.
+    let window = if options.broadcast {
+        Some(options.advertise_timeout.unwrap_or(BROADCAST_WINDOW))
+    } else {
+        options.advertise_timeout
+    };
+    let mut advertise_until = window.map(|window| Instant::now() + window);
+    let mut timed_out = false;
+    let mut active_transfers = 0usize;
+    let mut outcomes: Vec<(PeerId, TransferOutcome)> = Vec::new();
+    // Done serving new receivers, waiting for the running transfers
+    let mut closing = false;
+    // Set by Ctrl+C: running transfers are cancelled if still going by then
+    let mut shutdown_by: Option<Instant> = None;
 
-    println!("🔍 Debug: Entering main event loop...");
+    debug!("Entering main event loop...");
     loop {
         tokio::select! {
+            _ = sleep_until(advertise_until.unwrap_or_else(Instant::now)), if advertise_until.is_some() => {
+                advertise_until = None;
+                stop_serving(&mut peripheral, &sessions).await;
+                if active_transfers == 0 {
+                    timed_out = !outcomes.iter().any(|(_, outcome)| outcome.is_sent());
+                    break;
+                }
+                say!("⌛ Advertising window is over, finishing the running transfer(s)...");
+                closing = true;
+            }
+            Some(update) = updates.recv() => match update {
+                TransferUpdate::Started => {
+                    active_transfers += 1;
+                    print_receivers(&receivers, &file_paths);
+                }
+                TransferUpdate::Finished { peer, outcome } => {
+                    active_transfers -= 1;
+                    print_receivers(&receivers, &file_paths);
+                    let sent = outcome.is_sent();
+                    outcomes.push((peer, outcome));
+                    if sent && !options.broadcast {
+                        // One completed transfer is all the window asks for
+                        advertise_until = None;
+                        if options.once && !closing {
+                            say!("☝️  Files sent once, not serving anyone else");
+                            stop_serving(&mut peripheral, &sessions).await;
+                            closing = true;
+                        }
+                    }
+                    if closing && active_transfers == 0 {
+                        break;
+                    }
+                }
+            },
+            _ = sleep_until(shutdown_by.unwrap_or_else(Instant::now)), if shutdown_by.is_some() => {
+                eprintln!(
+                    "⌛ {} transfer(s) still running after {}s, cancelling",
+                    active_transfers,
+                    SHUTDOWN_GRACE.as_secs()
+                );
+                cancel.cancel();
+                sleep(CANCEL_GRACE).await;
+                break;
+            }
+            // A closing sender hands out no fresh tickets
+            _ = ticket_refresh.tick(), if !closing => {
+                match build_ticket(&keypair, &listen_addrs, protocol, &identity, &file_list, &sessions) {
+                    Ok(ticket_cbor) => {
+                        if let Some(target) = &options.emit_ticket
+                            && let Err(e) = emit_ticket(target, &ticket_cbor)
+                        {
+                            eprintln!("⚠️  {:#}", e);
+                        }
+                        // The code on screen goes stale with the ticket it carries
+                        if options.qr
+                            && let Err(e) = show_ticket_qr(&keypair, &listen_addrs, protocol, &identity, &file_list, &sessions)
+                        {
+                            eprintln!("⚠️  {:#}", e);
+                        }
+                        if let Err(e) = peripheral.update_characteristic(char_uuid, ticket_cbor.into()).await {
+                            eprintln!("⚠️  Failed to refresh session ticket: {}", e);
+                        } else {
+                            say!("🎫 Session ticket refreshed");
+                        }
+                    }
+                    Err(e) => eprintln!("⚠️  Failed to build session ticket: {}", e),
+                }
+            }
             event = swarm.select_next_some() => {
-                println!("🔍 Debug: Received swarm event: {:?}", std::mem::discriminant(&event));
+                trace!("Received swarm event: {:?}", std::mem::discriminant(&event));
                 match event {
                     SwarmEvent::NewListenAddr { address, .. } => {
-                        println!("🎧 New listen address: {}", address);
+                        say!("🎧 New listen address: {}", address);
+                        // A relay reservation that came through late, or was renewed
+                        if network::is_relayed(&address) && !listen_addrs.contains(&address) {
+                            listen_addrs.push(address);
+                            ticket_refresh.reset_immediately();
+                        }
+                    }
+                    SwarmEvent::ExpiredListenAddr { address, .. } if network::is_relayed(&address) => {
+                        eprintln!("⚠️  Relayed address expired: {}", address);
+                        listen_addrs.retain(|addr| *addr != address);
+                        ticket_refresh.reset_immediately();
+                    }
+                    SwarmEvent::ListenerClosed { listener_id, reason, .. } if Some(listener_id) == relay_listener => {
+                        eprintln!("⚠️  Lost the relay reservation: {:?}", reason);
+                    }
+                    SwarmEvent::ConnectionEstablished { peer_id, .. } if Some(peer_id) == relay_peer => {
+                        debug!("Connected to relay {}", peer_id);
+                    }
+                    SwarmEvent::Behaviour(FileTransferBehaviourEvent::Upnp(event)) => match event {
+                        upnp::Event::NewExternalAddr(address) if !listen_addrs.contains(&address) => {
+                            say!("🌍 Router forwards {} to us", address);
+                            listen_addrs.push(address);
+                            network::sort_by_preference(&mut listen_addrs);
+                            ticket_refresh.reset_immediately();
+                        }
+                        upnp::Event::ExpiredExternalAddr(address) => {
+                            eprintln!("⚠️  Port mapping expired: {}", address);
+                            listen_addrs.retain(|addr| *addr != address);
+                            ticket_refresh.reset_immediately();
+                        }
+                        event => {
+                            if let Some(message) = network::upnp_failure(&event) {
+                                eprintln!("⚠️  {}", message);
+                            }
+                        }
+                    },
+                    SwarmEvent::ConnectionEstablished { peer_id, connection_id, endpoint, .. } => {
+                        say!("🤝 Connection established with {}", peer_id);
+                        debug!("Endpoint: {:?}", endpoint);
+                        events::emit(Event::Connected {
+                            peer_id: peer_id.to_string(),
+                            address: endpoint.get_remote_address().to_string(),
+                        });
+                        transports
+                            .lock()
+                            .unwrap()
+                            .insert((peer_id, connection_id), network::transport_of(endpoint.get_remote_address()));
+                        // Receivers won't reuse a nonce, so hand out a fresh ticket
+                        ticket_refresh.reset_immediately();
                     }
-                    SwarmEvent::ConnectionEstablished { peer_id, endpoint, .. } => {
-                        println!("🤝 Connection established with {}", peer_id);
-                        println!("   Endpoint: {:?}", endpoint);
-                        pending_transfers.insert(peer_id, file_paths.clone());
+                    SwarmEvent::ConnectionClosed { peer_id, connection_id, cause, num_established, .. } => {
+                        say!("❌ Connection closed with {}: {:?}", peer_id, cause);
+                        heartbeat.forget(connection_id);
+                        transports.lock().unwrap().remove(&(peer_id, connection_id));
+                        if num_established == 0 {
+                            receivers.forget(peer_id);
+                        }
                     }
-                    SwarmEvent::ConnectionClosed { peer_id, cause, .. } => {
-                        println!("❌ Connection closed with {}: {:?}", peer_id, cause);
-                        pending_transfers.remove(&peer_id);
+                    SwarmEvent::Behaviour(FileTransferBehaviourEvent::Ping(event)) => {
+                        let first = heartbeat.rtt(event.connection).is_none();
+                        if heartbeat.on_ping(&event) {
+                            // Closing the connection fails the transfer's pending reads right away
+                            eprintln!(
+                                "❌ Receiver {} unreachable: {} heartbeats missed",
+                                event.peer,
+                                network::MAX_MISSED_PINGS
+                            );
+                            swarm.close_connection(event.connection);
+                        } else if first && let Some(rtt) = heartbeat.rtt(event.connection) {
+                            debug!("Round trip to {}: {} ms", event.peer, rtt.as_millis());
+                        }
                     }
+                    SwarmEvent::Behaviour(FileTransferBehaviourEvent::Dcutr(event)) => match event.result {
+                        Ok(_) => say!("🕳️  Direct connection to {} through hole punching", event.remote_peer_id),
+                        Err(e) => warn!("Hole punching to {} failed: {}", event.remote_peer_id, e),
+                    },
                     SwarmEvent::IncomingConnection { send_back_addr, .. } => {
-                        println!("📥 Incoming connection from: {:?}", send_back_addr);
+                        debug!("Incoming connection from {:?}", send_back_addr);
                     }
                     SwarmEvent::IncomingConnectionError { send_back_addr, error, .. } => {
-                        eprintln!("❌ Incoming connection error from {:?}: {}", send_back_addr, error);
+                        warn!("Incoming connection error from {:?}: {}", send_back_addr, error);
                     }
                     SwarmEvent::OutgoingConnectionError { peer_id, error, .. } => {
-                        eprintln!("❌ Outgoing connection error to {:?}: {}", peer_id, error);
+                        warn!("Outgoing connection error to {:?}: {}", peer_id, error);
                     }
                     event => {
-                        println!("🔍 Debug: Other event: {:?}", event);
+                        debug!("Other event: {:?}", event);
                     }
                 }
             }
             _ = signal::ctrl_c() => {
-                println!("\n\n🛑 Received Ctrl+C, shutting down...");
+                if active_transfers > 0 && shutdown_by.is_none() {
+                    say!(
+                        "\n\n🛑 Received Ctrl+C, letting {} transfer(s) finish for up to {}s (Ctrl+C again to stop now)...",
+                        active_transfers,
+                        SHUTDOWN_GRACE.as_secs()
+                    );
+                    stop_serving(&mut peripheral, &sessions).await;
+                    closing = true;
+                    shutdown_by = Some(Instant::now() + SHUTDOWN_GRACE);
+                    continue;
+                }
+                say!("\n\n🛑 Received Ctrl+C, shutting down...");
+                cancel.cancel();
+                // Give running transfers a moment to tell their receivers
+                sleep(CANCEL_GRACE).await;
                 break;
             }
         }
     }
 
-    println!("👋 Goodbye!");
+    if options.broadcast {
+        print_broadcast_summary(&outcomes);
+    }
+    if timed_out {
+        let timeout = window.unwrap_or_default();
+        let error = format!("No transfer completed within {}s", timeout.as_secs());
+        eprintln!("⌛ {}", error);
+        events::emit(Event::TransferFailed { peer_id: None, error });
+        std::process::exit(1);
+    }
+
+    say!("👋 Goodbye!");
+    Ok(())
+}
+
+/// What the stream handler tells the main loop about each transfer stream
+enum TransferUpdate {
+    Started,
+    Finished { peer: PeerId, outcome: TransferOutcome },
+}
+
+/// How a transfer stream ended
+enum TransferOutcome {
+    Sent(StatsReport),
+    /// The receiver declined, or the verification codes didn't match
+    NotSent,
+    Cancelled(String),
+    Failed(String),
+}
+
+impl TransferOutcome {
+    fn is_sent(&self) -> bool {
+        matches!(self, TransferOutcome::Sent(_))
+    }
+}
+
+/// One line per receiver at the end of a broadcast
+fn print_broadcast_summary(outcomes: &[(PeerId, TransferOutcome)]) {
+    let sent = outcomes.iter().filter(|(_, outcome)| outcome.is_sent()).count();
+    say!("\n📋 Broadcast summary: sent to {} of {} receiver(s)", sent, outcomes.len());
+    for (peer, outcome) in outcomes {
+        match outcome {
+            TransferOutcome::Sent(stats) => say!(
+                "   ✅ {}: {} in {} ({})",
+                peer,
+                transfer::format_bytes(stats.bytes),
+                transfer::format_duration(Duration::from_millis(stats.duration_ms)),
+                transfer::format_speed(stats.average_bytes_per_sec)
+            ),
+            TransferOutcome::NotSent => say!("   🚫 {}: declined", peer),
+            TransferOutcome::Cancelled(reason) => say!("   🛑 {}: cancelled ({})", peer, reason),
+            TransferOutcome::Failed(error) => say!("   ❌ {}: {}", peer, error),
+        }
+    }
+}
+
+/// Stop advertising and turn away tickets already handed out, for a sender
+/// that only finishes the transfers it has running
+async fn stop_serving(peripheral: &mut Peripheral, sessions: &SessionRegistry) {
+    sessions.revoke_all();
+    if let Err(e) = peripheral.stop_advertising().await {
+        warn!("Failed to stop advertising: {}", e);
+    }
+}
+
+/* ========== Receivers ========== */
+
+/// Where one receiver's transfer stands
+#[derive(Debug, Clone)]
+enum ReceiverState {
+    /// Stream open, the handshake is running or the user is being asked
+    Negotiating,
+
+    /// Chunks are going out; `bytes` counts every file of the transfer so far
+    Sending { file_index: usize, bytes: u64 },
+
+    /// The transfer ended: sent or declined, or cancelled or failed
+    Done,
+    Failed,
+}
+
+impl ReceiverState {
+    fn is_active(&self) -> bool {
+        matches!(self, ReceiverState::Negotiating | ReceiverState::Sending { .. })
+    }
+}
+
+/// Every receiver currently connected, shared by the stream handler, the
+/// transfers and the main loop
+///
+/// A receiver holds one of `max_active` slots from the moment its transfer
+/// stream arrives until the transfer ends; anyone arriving while they are all
+/// taken is turned away as busy.
+struct Receivers {
+    states: Mutex<HashMap<PeerId, ReceiverState>>,
+    max_active: usize,
+}
+
+impl Receivers {
+    fn new(max_active: usize) -> Self {
+        Self { states: Mutex::default(), max_active }
+    }
+
+    /// Take a slot for `peer`, or `false` if none is free or it already has one
+    fn try_start(&self, peer: PeerId) -> bool {
+        let mut states = self.states.lock().unwrap();
+        if !Self::has_room(&states, self.max_active, peer) {
+            return false;
+        }
+        states.insert(peer, ReceiverState::Negotiating);
+        true
+    }
+
+    /// Whether `peer` could take a slot now, without taking it
+    fn has_room_for(&self, peer: PeerId) -> bool {
+        Self::has_room(&self.states.lock().unwrap(), self.max_active, peer)
+    }
+
+    fn has_room(states: &HashMap<PeerId, ReceiverState>, max_active: usize, peer: PeerId) -> bool {
+        let active = states.values().filter(|state| state.is_active()).count();
+        active < max_active && !states.get(&peer).is_some_and(ReceiverState::is_active)
+    }
+
+    /// Count `bytes` more sent to `peer`, most recently of file `file_index`
+    fn sent(&self, peer: PeerId, file_index: usize, bytes: u64) {
+        // Events still being rendered after the transfer ended change nothing
+        if let Some(state) = self.states.lock().unwrap().get_mut(&peer)
+            && state.is_active()
+        {
+            let total = match state {
+                ReceiverState::Sending { bytes: total, .. } => *total,
+                _ => 0,
+            };
+            *state = ReceiverState::Sending { file_index, bytes: total + bytes };
+        }
+    }
+
+    /// Free `peer`'s slot, keeping how the transfer ended
+    fn finish(&self, peer: PeerId, outcome: &TransferOutcome) {
+        let state = match outcome {
+            TransferOutcome::Sent(_) | TransferOutcome::NotSent => ReceiverState::Done,
+            TransferOutcome::Cancelled(_) | TransferOutcome::Failed(_) => ReceiverState::Failed,
+        };
+        self.states.lock().unwrap().insert(peer, state);
+    }
+
+    /// Drop a receiver that disconnected; a transfer still running ends on its own
+    fn forget(&self, peer: PeerId) {
+        let mut states = self.states.lock().unwrap();
+        if states.get(&peer).is_some_and(|state| !state.is_active()) {
+            states.remove(&peer);
+        }
+    }
+
+    /// One line naming the receivers with a transfer running, `None` if there are none
+    fn status_line(&self, file_paths: &[PathBuf]) -> Option<String> {
+        let states = self.states.lock().unwrap();
+        let active: Vec<String> = states
+            .iter()
+            .filter_map(|(peer, state)| match state {
+                ReceiverState::Negotiating => Some(format!("{} (negotiating)", peer)),
+                ReceiverState::Sending { file_index, bytes } => Some(format!(
+                    "{} (sending {}, {} so far)",
+                    peer,
+                    file_paths.get(*file_index).map_or_else(|| file_index.to_string(), |path| path.display().to_string()),
+                    transfer::format_bytes(*bytes)
+                )),
+                ReceiverState::Done | ReceiverState::Failed => None,
+            })
+            .collect();
+        (!active.is_empty()).then(|| format!("{} receiver(s): {}", active.len(), active.join(", ")))
+    }
+}
+
+/// Show who is being served, after a transfer starts or ends
+fn print_receivers(receivers: &Receivers, file_paths: &[PathBuf]) {
+    if let Some(status) = receivers.status_line(file_paths) {
+        say!("👥 Serving {}", status);
+    }
+}
+
+/// Tell a receiver that arrived while every slot is taken to try again later
+async fn turn_away(peer: PeerId, mut stream: Stream, max_active: usize) -> Result<()> {
+    let request = network::read_request(&mut stream).await?;
+    reject(
+        &mut stream,
+        request.request_id,
+        RejectReason::Busy,
+        Some(format!("Already sending to {} receiver(s), try again once one is done", max_active)),
+    )
+    .await
+    .with_context(|| format!("Failed to turn away {}", peer))
+}
+
+/// Answer a stream over the limit with a busy error, giving up after the control timeout
+async fn refuse_stream(peer: PeerId, mut stream: Stream, _slot: StreamSlot) {
+    match tokio::time::timeout(network::control_timeout(), network::refuse_busy(&mut stream)).await {
+        Ok(Ok(())) => {}
+        Ok(Err(e)) => debug!("Failed to refuse stream from {}: {:#}", peer, e),
+        Err(_) => debug!("Gave up refusing stream from {}", peer),
+    }
+}
+
+/* ========== Session Ticket ========== */
+
+/// Report the analysis without touching the network or BLE
+fn print_dry_run(protocol: TransportProtocol, file_list: &FileList) {
+    say!("🧪 Dry run - nothing will be advertised or sent\n");
+    say!("   Transport: {:?}", protocol);
+    say!("   Files:");
+    for file in &file_list.files {
+        say!(
+            "      - {} ({})",
+            transfer::display_path(file),
+            transfer::format_bytes(file.size)
+        );
+    }
+    say!(
+        "   Total: {} in {} file(s)",
+        transfer::format_bytes(file_list.total_size),
+        file_list.files.len()
+    );
+}
+
+/// How the sender introduces itself to receivers
+struct SenderIdentity {
+    /// Display name from `--name`, or the hostname
+    name: String,
+    hostname: String,
+}
+
+impl SenderIdentity {
+    fn new(name: Option<String>) -> Self {
+        let hostname = hostname::get()
+            .map(|h| h.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let name = name.unwrap_or_else(|| hostname.clone());
+        Self { name, hostname }
+    }
+
+    /// Name for the BLE advertisement, cut to what fits next to the service UUID
+    fn advertised_name(&self) -> String {
+        if self.name.is_empty() {
+            return "Fastdrop".to_string();
+        }
+        let mut end = self.name.len().min(MAX_ADVERTISED_NAME_BYTES);
+        while !self.name.is_char_boundary(end) {
+            end -= 1;
+        }
+        self.name[..end].to_string()
+    }
+}
+
+/// Issue a fresh signed ticket and encode it for the GATT characteristic
+fn build_ticket(
+    keypair: &Keypair,
+    listen_addrs: &[Multiaddr],
+    protocol: TransportProtocol,
+    identity: &SenderIdentity,
+    file_list: &FileList,
+    sessions: &SessionRegistry,
+) -> Result<Vec<u8>> {
+    let nonce = rand::random::<u64>();
+    let mut ticket = SessionTicket {
+        version: protocol::PROTOCOL_VERSION,
+        peer_id: keypair.public().to_peer_id(),
+        addrs: Vec::new(),
+        protocol,
+        nonce,
+        issued_at: ticket::unix_now(),
+        ttl_secs: ticket::TICKET_TTL_SECS,
+        sender_name: identity.name.clone(),
+        hostname: identity.hostname.clone(),
+        app_version: env!("CARGO_PKG_VERSION").to_string(),
+        file_count: file_list.files.len() as u32,
+        total_size: file_list.total_size,
+        session_id: sessions.issue(nonce),
+        sig: [0u8; 64],
+    };
+
+    // Receivers may read no more than `MAX_TICKET_BYTES`, so leave out the
+    // least preferred addresses until it fits
+    let mut addrs = listen_addrs.to_vec();
+    let ticket_cbor = loop {
+        ticket.addrs = addrs;
+        ticket::sign_ticket(&mut ticket, keypair)?;
+        let ticket_cbor = serde_cbor::to_vec(&ticket).context("Failed to encode session ticket")?;
+        if ticket_cbor.len() <= ticket::MAX_TICKET_BYTES {
+            break ticket_cbor;
+        }
+        addrs = network::fewer_addrs(&ticket.addrs).with_context(|| {
+            format!(
+                "Session ticket is {} bytes even with one address, over the {} byte limit (try a shorter --name)",
+                ticket_cbor.len(),
+                ticket::MAX_TICKET_BYTES
+            )
+        })?;
+    };
+    if ticket.addrs.len() < listen_addrs.len() {
+        say!(
+            "   ⚠️  Ticket lists {} of {} addresses to stay within {} bytes",
+            ticket.addrs.len(),
+            listen_addrs.len(),
+            ticket::MAX_TICKET_BYTES
+        );
+    }
+
+    events::emit(Event::TicketCreated {
+        peer_id: ticket.peer_id.to_string(),
+        protocol: format!("{:?}", protocol).to_lowercase(),
+        session: ticket::format_session_id(&ticket.session_id),
+        addrs: ticket.addrs.iter().map(Multiaddr::to_string).collect(),
+    });
+    Ok(ticket_cbor)
+}
+
+/// Transport of every open connection, per receiver
+type Transports = HashMap<(PeerId, ConnectionId), TransportProtocol>;
+
+/// The transport `peer` is connected over, `None` without a connection
+///
+/// While a receiver racing both transports still holds one of each, its
+/// stream may be on either, so it counts as TCP and gets no parallel files.
+fn transport_to(transports: &Transports, peer: PeerId) -> Option<TransportProtocol> {
+    transports
+        .iter()
+        .filter(|((connected, _), _)| *connected == peer)
+        .map(|(_, transport)| *transport)
+        .reduce(|a, b| if a == b { a } else { TransportProtocol::Tcp })
+}
+
+/// Hand the ticket out for `--emit-ticket`, to a file or (`-`) stdout
+///
+/// The file is rewritten with every refreshed ticket, so a receiver reading
+/// it always gets one that hasn't expired or been used.
+fn emit_ticket(target: &Path, ticket_cbor: &[u8]) -> Result<()> {
+    let text = ticket::encode_ticket_text(ticket_cbor);
+    if target == Path::new("-") {
+        println!("{}", text);
+        return Ok(());
+    }
+    std::fs::write(target, text + "\n")
+        .with_context(|| format!("Failed to write session ticket to {}", target.display()))?;
+    say!("📄 Session ticket written to {}", target.display());
+    Ok(())
+}
+
+/// Print a QR code of a fresh ticket for `--qr`
+///
+/// The ticket in it only lists the preferred address of each kind, so the
+/// code stays small enough to scan off a terminal.
+fn show_ticket_qr(
+    keypair: &Keypair,
+    listen_addrs: &[Multiaddr],
+    protocol: TransportProtocol,
+    identity: &SenderIdentity,
+    file_list: &FileList,
+    sessions: &SessionRegistry,
+) -> Result<()> {
+    let addrs = network::compact_addrs(listen_addrs);
+    let ticket_cbor = build_ticket(keypair, &addrs, protocol, identity, file_list, sessions)?;
+    let qr = ticket::ticket_qr(&ticket_cbor)?;
+    say!("📱 Scan to connect (receiver --ticket with the scanned text):\n{}", qr);
+    Ok(())
+}
+
+/* ========== Command Line ========== */
+
+/// Sender command line options
+#[derive(Debug, Clone, Default)]
+struct Options {
+    /// Files to offer
+    files: Vec<PathBuf>,
+
+    /// Hash algorithm used for end-to-end verification
+    hash_algo: HashAlgo,
+
+    /// Skip hashing and the verification code (trusted networks only)
+    no_verify: bool,
+
+    /// Send chunks without their CRC32
+    no_chunk_crc: bool,
+
+    /// Cap on outgoing throughput in bytes per second
+    rate: Option<u64>,
+
+    /// Chunks in flight before waiting for an ack
+    ack_window: u64,
+
+    /// Compression to use with receivers that support it
+    compression: CompressionAlgo,
+
+    /// Ask before serving each transfer request
+    confirm: bool,
+
+    /// Fixed listen port instead of an ephemeral one
+    port: Option<u16>,
+
+    /// Interface name or local IP to listen on instead of every interface
+    interface: Option<String>,
+
+    /// Don't listen on IPv4
+    no_ipv4: bool,
+
+    /// Don't listen on IPv6
+    no_ipv6: bool,
+
+    /// Only analyze the files, don't advertise or send
+    dry_run: bool,
+
+    /// Display name shown to receivers instead of the hostname
+    name: Option<String>,
+
+    /// Train a zstd dictionary on the files and compress with it
+    dict: bool,
+
+    /// Largest file and selection we agree to offer
+    limits: SizeLimits,
+
+    /// Chunk size without a receiver preference, and the most a receiver may ask for
+    chunk_size: Option<usize>,
+
+    /// Transports to listen on, `None` to pick one from the files
+    transport: Option<TransportProtocol>,
+
+    /// Print JSON events instead of decorative text
+    json: bool,
+
+    /// Relay to reserve a slot on, for receivers on other networks
+    relay: Option<Multiaddr>,
+
+    /// Ask the router to forward our ports and advertise the external address
+    upnp: bool,
+
+    /// Only put private LAN addresses (RFC 1918, IPv6 ULA) in the ticket
+    lan_only: bool,
+
+    /// Where to write the base64 session ticket, `-` for stdout
+    emit_ticket: Option<PathBuf>,
+
+    /// Show the session ticket as a QR code in the terminal
+    qr: bool,
+
+    /// Exit if no transfer completes within this long
+    advertise_timeout: Option<Duration>,
+
+    /// Exit after the first completed transfer
+    once: bool,
+
+    /// Serve everyone who connects within the advertising window, then exit
+    broadcast: bool,
+
+    /// Transfers running at once, `None` for one (every receiver with `--broadcast`)
+    max_receivers: Option<usize>,
+
+    /// Transfer streams one receiver may hold open at once
+    max_streams_per_peer: Option<usize>,
+
+    /// Limit on reading or writing one control message
+    control_timeout: Duration,
+
+    /// Limit on a chunk stream going without a chunk or an ack
+    idle_timeout: Duration,
+}
+
+impl Options {
+    fn parse() -> Result<Self, String> {
+        let mut options = Options {
+            ack_window: network::DEFAULT_ACK_WINDOW,
+            transport: Some(TransportProtocol::default()),
+            control_timeout: network::DEFAULT_CONTROL_TIMEOUT,
+            idle_timeout: network::DEFAULT_IDLE_TIMEOUT,
+            ..Default::default()
+        };
+        let mut args = env::args().skip(1);
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--hash" => options.hash_algo = next_value(&mut args, &arg)?.parse()?,
+                "--no-verify" | "--fast" => options.no_verify = true,
+                "--no-chunk-crc" => options.no_chunk_crc = true,
+                "--rate" | "--rate-limit" => options.rate = Some(ratelimit::parse_rate(&next_value(&mut args, &arg)?)?),
+                "--confirm" => options.confirm = true,
+                "--no-ipv4" => options.no_ipv4 = true,
+                "--no-ipv6" => options.no_ipv6 = true,
+                "--dry-run" => options.dry_run = true,
+                "--json" => options.json = true,
+                "--name" => options.name = Some(next_value(&mut args, &arg)?),
+                "--qr" => options.qr = true,
+                "--once" => options.once = true,
+                "--broadcast" => options.broadcast = true,
+                "--max-receivers" => {
+                    let max = next_value(&mut args, &arg)?
+                        .parse::<usize>()
+                        .ok()
+                        .filter(|&max| max > 0)
+                        .ok_or("--max-receivers needs a number of receivers")?;
+                    options.max_receivers = Some(max);
+                }
+                "--max-streams-per-peer" => {
+                    let max = next_value(&mut args, &arg)?
+                        .parse::<usize>()
+                        .ok()
+                        .filter(|&max| max > 0)
+                        .ok_or("--max-streams-per-peer needs a number of streams")?;
+                    options.max_streams_per_peer = Some(max);
+                }
+                "--advertise-timeout" | "--timeout" | "--control-timeout" | "--idle-timeout" => {
+                    let secs = next_value(&mut args, &arg)?
+                        .parse::<u64>()
+                        .ok()
+                        .filter(|&secs| secs > 0)
+                        .ok_or_else(|| format!("{} needs a number of seconds", arg))?;
+                    let timeout = Duration::from_secs(secs);
+                    match arg.as_str() {
+                        "--control-timeout" => options.control_timeout = timeout,
+                        "--idle-timeout" => options.idle_timeout = timeout,
+                        _ => options.advertise_timeout = Some(timeout),
+                    }
+                }
+                "--emit-ticket" => options.emit_ticket = Some(PathBuf::from(next_value(&mut args, &arg)?)),
+                "--relay" => options.relay = Some(network::parse_relay_addr(&next_value(&mut args, &arg)?)?),
+                "--upnp" => options.upnp = true,
+                "--lan-only" => options.lan_only = true,
+                "--compress" | "--dict" => {
+                    if arg == "--dict" {
+                        options.dict = true;
+                    }
+                    if options.compression == CompressionAlgo::None {
+                        options.compression = CompressionAlgo::Zstd { level: codec::DEFAULT_ZSTD_LEVEL };
+                    }
+                }
+                "--compress-level" => {
+                    let level = next_value(&mut args, &arg)?
+                        .parse::<i32>()
+                        .ok()
+                        .filter(|level| zstd::compression_level_range().contains(level))
+                        .ok_or_else(|| {
+                            let levels = zstd::compression_level_range();
+                            format!(
+                                "--compress-level needs a level between {} and {}",
+                                levels.start(),
+                                levels.end()
+                            )
+                        })?;
+                    options.compression = CompressionAlgo::Zstd { level };
+                }
+                "--max-file-size" | "--max-total-size" => {
+                    let size = transfer::parse_size(&next_value(&mut args, &arg)?)
+                        .ok_or_else(|| format!("{} needs a size such as 500M or 2G", arg))?;
+                    if arg == "--max-file-size" {
+                        options.limits.max_file_size = Some(size);
+                    } else {
+                        options.limits.max_total_size = Some(size);
+                    }
+                }
+                "--no-limit" => options.limits = SizeLimits::NONE,
+                "--chunk-size" => {
+                    options.chunk_size = Some(transfer::parse_chunk_size(&next_value(&mut args, &arg)?)?);
+                }
+                "--transport" => {
+                    options.transport = match next_value(&mut args, &arg)?.as_str() {
+                        "auto" => None,
+                        transport => Some(transport.parse()?),
+                    };
+                }
+                "--interface" => options.interface = Some(next_value(&mut args, &arg)?),
+                "--port" => {
+                    let port = next_value(&mut args, &arg)?
+                        .parse::<u16>()
+                        .ok()
+                        .filter(|&port| port != 0)
+                        .ok_or("--port needs a port number between 1 and 65535")?;
+                    options.port = Some(port);
+                }
+                "--window" => {
+                    options.ack_window = next_value(&mut args, &arg)?
+                        .parse()
+                        .map_err(|_| "--window needs a number of chunks".to_string())?;
+                }
+                "--" => options.files.extend(args.by_ref().map(PathBuf::from)),
+                flag if flag.starts_with("--") => {
+                    return Err(format!("Unknown option: {}", flag));
+                }
+                _ => options.files.push(PathBuf::from(arg)),
+            }
+        }
+
+        options.files = expand_patterns(options.files)?;
+        if options.files.is_empty() {
+            return Err("No files given".to_string());
+        }
+        if options.no_ipv4 && options.no_ipv6 {
+            return Err("--no-ipv4 and --no-ipv6 can't be used together".to_string());
+        }
+        if options.json && options.emit_ticket.as_deref() == Some(Path::new("-")) {
+            return Err("--emit-ticket - would mix the ticket into the JSON events, write it to a file instead".to_string());
+        }
+        if options.lan_only && (options.relay.is_some() || options.upnp) {
+            return Err("--lan-only can't be used with --relay or --upnp, they advertise public addresses".to_string());
+        }
+        if options.once && options.broadcast {
+            return Err("--once and --broadcast can't be used together".to_string());
+        }
+        if options.json && options.qr {
+            return Err("--qr can't be shown with --json, use --emit-ticket instead".to_string());
+        }
+
+        Ok(options)
+    }
+}
+
+/// Expand arguments with wildcards into the files they match
+///
+/// The shell usually does this, but not on Windows or when arguments are
+/// quoted. Arguments naming an existing path are taken literally, so a file
+/// called `photo[1].jpg` is still sent as is.
+fn expand_patterns(args: Vec<PathBuf>) -> Result<Vec<PathBuf>, String> {
+    let mut files = Vec::new();
+    for arg in args {
+        let pattern = arg.to_string_lossy().into_owned();
+        if arg.exists() || !pattern.contains(['*', '?', '[']) {
+            files.push(arg);
+            continue;
+        }
+
+        let matches: Vec<PathBuf> = glob::glob(&pattern)
+            .map_err(|e| format!("Invalid pattern '{}': {}", pattern, e))?
+            .filter_map(Result::ok)
+            .filter(|path| path.is_file())
+            .collect();
+        if matches.is_empty() {
+            return Err(format!("'{}' doesn't match any files", pattern));
+        }
+        files.extend(matches);
+    }
+    Ok(files)
+}
+
+/// Take the value following a flag
+fn next_value(args: &mut impl Iterator<Item = String>, flag: &str) -> Result<String, String> {
+    args.next().ok_or_else(|| format!("{} needs a value", flag))
+}
+
+fn print_usage() {
+    eprintln!("Usage: sender [options] <file1> [file2] [file3] ...");
+    eprintln!("\nOptions:");
+    eprintln!("   --hash <sha256|blake3>   Hash algorithm for verification (default: sha256)");
+    eprintln!("   --no-verify, --fast      Skip hashing and the code check; the receiver can't verify anything");
+    eprintln!("   --no-chunk-crc           Send chunks without a CRC32; corruption then only shows in the file hash");
+    eprintln!("   --rate, --rate-limit <R> Limit total upload speed across receivers, e.g. 500000, 512K or 5MB");
+    eprintln!(
+        "   --window <CHUNKS>        Unacknowledged chunks in flight (default: {}, min: {})",
+        network::DEFAULT_ACK_WINDOW,
+        network::ACK_INTERVAL
+    );
+    eprintln!("   --compress               Compress chunks with zstd when the receiver supports it");
+    eprintln!(
+        "   --compress-level <N>     zstd level, implies --compress (default: {})",
+        codec::DEFAULT_ZSTD_LEVEL
+    );
+    eprintln!("   --dict                   Compress with a dictionary trained on the files, implies --compress");
+    eprintln!(
+        "   --chunk-size <SIZE>      Chunk size from {} to {}, e.g. 4M; caps what receivers may ask for (default: scaled to each file)",
+        transfer::format_bytes(transfer::MIN_CHUNK_SIZE_OVERRIDE as u64),
+        transfer::format_bytes(transfer::MAX_CHUNK_SIZE_OVERRIDE as u64)
+    );
+    eprintln!("   --max-file-size <SIZE>   Refuse to offer a file larger than SIZE (default: 16G)");
+    eprintln!("   --max-total-size <SIZE>  Refuse to offer more than SIZE in total (default: 64G)");
+    eprintln!("   --no-limit               Offer files of any size");
+    eprintln!("   --confirm                Ask before serving each transfer request");
+    eprintln!("   --transport <MODE>       both, quic, tcp or auto to pick by the files (default: both)");
+    eprintln!("   --port <N>               Listen on a fixed port (for firewall rules)");
+    eprintln!("   --interface <NAME|IP>    Only listen on (and advertise) this network interface or address");
+    eprintln!("   --no-ipv4, --no-ipv6     Only listen on the other address family");
+    eprintln!("   --qr                     Show the session ticket as a QR code to scan instead of using BLE");
+    eprintln!("   --emit-ticket <FILE>     Also write the session ticket as base64 to FILE (- for stdout), for receivers without BLE");
+    eprintln!("   --relay <MULTIADDR>      Also listen through a relay (ending in /p2p/<peer ID>) for receivers elsewhere");
+    eprintln!("   --upnp                   Have the router forward our port (UPnP) and advertise the external address");
+    eprintln!("   --lan-only               Only advertise private LAN addresses (10.x, 172.16-31.x, 192.168.x, fc00::/7)");
+    eprintln!("   --advertise-timeout <S>  Exit if no transfer completes within S seconds (default: wait forever)");
+    eprintln!("   --once                   Exit after the first completed transfer");
+    eprintln!(
+        "   --control-timeout <S>    Give up on a receiver that takes longer to answer a message (default: {}s)",
+        network::DEFAULT_CONTROL_TIMEOUT.as_secs()
+    );
+    eprintln!(
+        "   --idle-timeout <S>       Give up on a receiver that acks nothing for this long (default: {}s)",
+        network::DEFAULT_IDLE_TIMEOUT.as_secs()
+    );
+    eprintln!(
+        "   --broadcast              Send to everyone who connects within --advertise-timeout (default: {}s), then exit",
+        BROADCAST_WINDOW.as_secs()
+    );
+    eprintln!("   --max-receivers <N>      Send to at most N receivers at once, turning others away as busy (default: 1, all with --broadcast)");
+    eprintln!("   --max-streams-per-peer <N> Refuse transfer streams past N open at once from one receiver (default: 4)");
+    eprintln!("   --dry-run                Show what would be sent and exit");
+    eprintln!("   --name <NAME>            Name shown to receivers (default: hostname)");
+    eprintln!("   --json                   Print newline-delimited JSON events instead of text");
+    eprintln!("\nExample: sender document.pdf photo.jpg video.mp4");
+    eprintln!("         sender \"photos/*.jpg\"   (patterns are expanded even when the shell doesn't)");
+}
+
+/* ========== Transfer Handling ========== */
+
+/// How each transfer sends its chunks, shared by all receivers
+#[derive(Clone)]
+struct SendSettings {
+    /// Hash algorithm, or `None` when verification is disabled
+    hash_algo: Option<HashAlgo>,
+
+    /// Optional cap on total outgoing throughput
+    rate_limiter: Option<Arc<RateLimiter>>,
+
+    /// Chunks in flight before waiting for an ack
+    ack_window: u64,
+
+    /// Resend chunks this receiver reports corrupted
+    retransmit: bool,
+
+    /// Send chunks as binary frames instead of CBOR
+    binary_chunks: bool,
+
+    /// Give every chunk a CRC32
+    chunk_crc: bool,
+
+    /// Compression offered to receivers that support it
+    compression: CompressionAlgo,
+
+    /// Dictionary trained on the offered files, if it helped
+    dictionary: Option<Arc<Vec<u8>>>,
+
+    /// Chunk size without a receiver preference, and the most a receiver may ask for
+    chunk_size: Option<usize>,
+
+    /// Files streamed at once when parallel transfer is negotiated
+    parallel_files: usize,
+
+    /// Ask the user before serving each request
+    confirm: bool,
+
+    /// Our own identity, part of the verification code
+    peer_id: PeerId,
+
+    /// Identity key that signed the ticket, also used to sign file lists
+    keypair: Keypair,
+
+    /// Have receivers compare a verification code before files are sent
+    verify_code: bool,
+
+    /// Session IDs of the tickets we advertised
+    sessions: Arc<SessionRegistry>,
+
+    /// Receivers being served, and how many may be at once
+    receivers: Arc<Receivers>,
+
+    /// Data streams receivers open after accepting, handed to their transfers
+    data_streams: Arc<DataStreams>,
+
+    /// Cancelled when the sender shuts down, aborting transfers in progress
+    cancel: CancellationToken,
+
+    /// End-to-end chunk encryption agreed with this receiver, if any
+    cipher: Option<crypto::ChunkCipher>,
+
+    /// Statistics of this transfer, shared by all of its file streams
+    stats: Arc<Mutex<TransferStats>>,
+
+    /// Where this transfer's chunk streams report what happens
+    events: Option<mpsc::UnboundedSender<NetworkEvent>>,
+}
+
+impl SendSettings {
+    /// How chunks go on the wire to this receiver
+    fn chunk_wire(&self) -> network::ChunkWire {
+        network::ChunkWire { window: self.ack_window, retransmit: self.retransmit, binary: self.binary_chunks }
+    }
+}
+
+/// Serve a single transfer stream: answer the request, wait for the
+/// receiver's decision and stream every file as acknowledged chunks
+///
+/// Returns the transfer's statistics, or `None` when the receiver declined.
+async fn handle_transfer_stream(
+    peer: PeerId,
+    mut stream: Stream,
+    control: libp2p_stream::Control,
+    transport: TransportProtocol,
+    settings: SendSettings,
+    mut file_list: FileList,
+    file_paths: Vec<PathBuf>,
+) -> Result<Option<StatsReport>> {
+    // Read request
+    debug!("Reading request from stream...");
+    let request = match network::read_request(&mut stream).await {
+        Ok(request) => request,
+        Err(e) => {
+            let _ = network::write_error(&mut stream, ErrorCode::InvalidRequest, format!("{:#}", e), None).await;
+            return Err(e.context("Failed to read request"));
+        }
+    };
+    let session = ticket::format_session_id(&request.session_id);
+    events::set_session(&session);
+    say!("📨 Transfer request from {} (session {})", peer, session);
+    say!("   Request ID: {}", request.request_id);
+
+    // Answered all the same, so the receiver isn't left waiting for a response
+    if !request.ready {
+        reject(&mut stream, request.request_id, RejectReason::NotReady, None).await?;
+        return Ok(None);
+    }
+
+    // Only receivers holding one of our current tickets may ask for files
+    let Some(nonce) = settings.sessions.nonce_of(&request.session_id) else {
+        reject(
+            &mut stream,
+            request.request_id,
+            RejectReason::Unauthorized,
+            Some("Unknown or expired session, scan for the sender again".to_string()),
+        )
+        .await?;
+        anyhow::bail!("Refused session {}: not issued by this sender or expired", session);
+    };
+    // The verification code and the encryption key use the ticket's nonce, so
+    // a request can't pick its own
+    if request.nonce != nonce {
+        reject(
+            &mut stream,
+            request.request_id,
+            RejectReason::Unauthorized,
+            Some("Nonce doesn't match the session's ticket, scan for the sender again".to_string()),
+        )
+        .await?;
+        anyhow::bail!("Refused session {}: nonce doesn't match the ticket", session);
+    }
+
+    // Refuse peers speaking an incompatible wire format
+    let Some(version) = protocol::negotiate_version(protocol::PROTOCOL_VERSION, request.version)
+    else {
+        let reason = format!(
+            "Unsupported protocol version {} (sender speaks {})",
+            protocol::format_version(request.version),
+            protocol::format_version(protocol::PROTOCOL_VERSION)
+        );
+        reject(
+            &mut stream,
+            request.request_id,
+            RejectReason::UnsupportedVersion,
+            Some(reason.clone()),
+        )
+        .await?;
+        anyhow::bail!(reason);
+    };
+    let mut capabilities = protocol::common_capabilities(&request.capabilities);
+
+    // Separate streams only pay off where they are cheap and independent
+    if transport != TransportProtocol::Quic {
+        protocol::remove_capability(&mut capabilities, Capability::ParallelFiles);
+    }
+    // Per-file streams already keep the chunks off the transfer stream
+    if protocol::has_capability(&capabilities, Capability::ParallelFiles) {
+        protocol::remove_capability(&mut capabilities, Capability::DataStream);
+    }
+    if !settings.verify_code {
+        protocol::remove_capability(&mut capabilities, Capability::Sas);
+    }
+    // Without the receiver's half of the key exchange there is nothing to encrypt with
+    if request.key_share.is_none() {
+        protocol::remove_capability(&mut capabilities, Capability::Encrypt);
+    }
+    let has = |capability| protocol::has_capability(&capabilities, capability);
+    let verify_code = has(Capability::Sas);
+    let parallel = has(Capability::ParallelFiles);
+    let completion = has(Capability::TransferComplete);
+    // A stream per file compresses better; independent chunks suit older receivers
+    let compression = match settings.compression {
+        CompressionAlgo::Zstd { level } if has(Capability::ZstdStream) => CompressionAlgo::ZstdStream { level },
+        CompressionAlgo::Zstd { level } if has(Capability::Zstd) => CompressionAlgo::Zstd { level },
+        _ => CompressionAlgo::None,
+    };
+    let chunk_size = transfer::negotiate_chunk_size(request.preferred_chunk_size, settings.chunk_size);
+    let parallel_files = network::negotiate_parallel_files(request.max_parallel_files);
+    say!("   Protocol version: {}", protocol::format_version(version));
+    if let Some(chunk_size) = chunk_size {
+        say!("   Chunk size: {}", transfer::format_bytes(chunk_size as u64));
+    }
+    match compression {
+        CompressionAlgo::None => {}
+        CompressionAlgo::Zstd { level } => say!("   Compression: zstd level {} per chunk", level),
+        CompressionAlgo::ZstdStream { level } => say!("   Compression: zstd level {} per file", level),
+    }
+    let dictionary = settings
+        .dictionary
+        .clone()
+        .filter(|_| compression != CompressionAlgo::None && has(Capability::ZstdDict));
+    if let Some(dictionary) = &dictionary {
+        say!("   Dictionary: {}", transfer::format_bytes(dictionary.len() as u64));
+    }
+    // Receivers that can't copy duplicates themselves get every file in full
+    if !has(Capability::Dedup) {
+        for file in &mut file_list.files {
+            file.duplicate_of = None;
+        }
+    }
+    let mut duplicates: Vec<Option<usize>> = file_list.files.iter().map(|f| f.duplicate_of).collect();
+    let sizes: Vec<u64> = file_list.files.iter().map(|f| f.size).collect();
+
+    // Files may have changed since they were advertised
+    if let Err(e) = transfer::validate_files(&file_paths).await {
+        reject(
+            &mut stream,
+            request.request_id,
+            RejectReason::FilesUnavailable,
+            Some(format!("{:#}", e)),
+        )
+        .await?;
+        return Err(e.context("Files are no longer available"));
+    }
+
+    if settings.confirm {
+        let prompt = format!("📤 Send {} file(s) to {}? [y/N]: ", file_paths.len(), peer);
+        let approved = tokio::task::spawn_blocking(move || confirm(&prompt)).await??;
+        if !approved {
+            reject(&mut stream, request.request_id, RejectReason::Declined, None).await?;
+            return Ok(None);
+        }
+    }
+
+    let (key_share, cipher) = match request.key_share.filter(|_| has(Capability::Encrypt)) {
+        Some(peer_key) => {
+            let exchange = crypto::KeyExchange::new();
+            let key_share = exchange.public_key();
+            let cipher = exchange.finish(peer_key, nonce, &request.session_id)?;
+            say!("   Encryption: ChaCha20-Poly1305, end to end");
+            (Some(key_share), Some(cipher))
+        }
+        None => (None, None),
+    };
+
+    debug!("Creating transfer response...");
+    let manifest_sig = ticket::sign_manifest(&file_list, &settings.keypair)?;
+    let response = TransferResponse {
+        request_id: request.request_id,
+        file_list,
+        accepted: true,
+        reject_reason: None,
+        reason: None,
+        version,
+        capabilities: capabilities.clone(),
+        compression,
+        dictionary: dictionary.as_deref().cloned(),
+        manifest_sig,
+        chunk_size: chunk_size.unwrap_or(0) as u32,
+        key_share,
+        signed_file_list: Vec::new(),
+    };
+
+    // The receiver may open its data stream as soon as it has accepted
+    let data_stream = has(Capability::DataStream).then(|| settings.data_streams.expect(peer));
+
+    // Send response with metadata
+    debug!("Sending response with metadata...");
+    network::write_response(&mut stream, response)
+        .await
+        .context("Failed to send response")?;
+
+    say!("✅ Sent file list metadata to receiver");
+
+    if verify_code {
+        let key_shares = key_share.zip(request.key_share);
+        let code = ticket::verification_code(&settings.peer_id, &peer, nonce, key_shares);
+        say!("🔢 Verification code for {}: {}", peer, code);
+        say!("   The receiver should see the same code");
+        events::emit(Event::VerificationCode { peer_id: peer.to_string(), code });
+
+        let answer = network::read_confirmed(&mut stream)
+            .await
+            .context("Failed to read verification code answer")?;
+        if !answer.confirmed {
+            say!("🚫 Receiver {} reported a different code, not sending", peer);
+            return Ok(None);
+        }
+        say!("🔐 Receiver confirmed the code");
+    }
+
+    // Wait for the receiver to review the file list
+    let decision = network::read_decision(&mut stream)
+        .await
+        .context("Failed to read receiver decision")?;
+    if !decision.accepted {
+        match decision.reject_reason {
+            Some(reason) => say!("🚫 Receiver {} declined the transfer: {}", peer, reason),
+            None => say!("🚫 Receiver {} declined the transfer", peer),
+        }
+        return Ok(None);
+    }
+    say!("👍 Receiver accepted the transfer");
+    let resume = decision.resume.unwrap_or_default();
+    let kept: HashSet<usize> = decision
+        .skip
+        .into_iter()
+        .filter(|&i| i < file_paths.len() && has(Capability::Skip))
+        .collect();
+    for (_, path) in file_paths.iter().enumerate().filter(|(i, _)| kept.contains(i)) {
+        say!("⏭️  Skipping {}, the receiver keeps its own copy", path.display());
+    }
+    // The receiver won't copy from files it kept, so copies of them are sent
+    transfer::reassign_copies(&mut duplicates, |file_index| kept.contains(&file_index));
+    // Neither duplicates nor files the receiver keeps are sent
+    let skip: HashSet<usize> = (0..file_paths.len())
+        .filter(|i| duplicates[*i].is_some() || kept.contains(i))
+        .collect();
+
+    // From here on the settings hold what was agreed with this receiver
+    let (network_events, reports) = mpsc::unbounded_channel();
+    let progress = SendProgress::new(&sizes, &skip, &resume);
+    tokio::spawn(events::in_current_transfer(render_network_events(
+        reports,
+        peer,
+        settings.receivers.clone(),
+        progress,
+    )));
+    let settings = SendSettings {
+        compression,
+        dictionary,
+        chunk_size,
+        parallel_files,
+        cipher,
+        retransmit: has(Capability::Retransmit),
+        binary_chunks: has(Capability::BinaryChunks),
+        stats: Arc::default(),
+        events: Some(network_events),
+        ..settings
+    };
+
+    say!("📤 Starting to send file chunks...");
+
+    let sent = if parallel {
+        send_files_parallel(peer, control, &settings, &file_paths, &sizes, &skip, &resume).await?
+    } else {
+        let mut data = match data_stream {
+            Some(data_stream) => Some(await_data_stream(data_stream, peer).await?),
+            None => None,
+        };
+        // Chunks go on the data stream if there is one, else after the handshake
+        let chunk_stream = match data.as_mut() {
+            Some(data) => data,
+            None => &mut stream,
+        };
+        let mut sent = Vec::with_capacity(file_paths.len());
+        // Now send all files as chunks
+        for (file_index, path) in file_paths.iter().enumerate() {
+            if let Some(original) = duplicates[file_index] {
+                say!("♊ Skipping {}, the receiver copies it from file {}", path.display(), original);
+                continue;
+            }
+            if kept.contains(&file_index) {
+                continue;
+            }
+            say!("📄 Sending file {}/{}: {}",
+                file_index + 1,
+                file_paths.len(),
+                path.display()
+            );
+            emit_file_started(file_index, path, sizes[file_index], &resume);
+
+            let chunks = transfer::ChunkReader::open(
+                path,
+                file_index,
+                settings.hash_algo,
+                resume.get(file_index),
+                settings.compression,
+                settings.dictionary.as_deref().map(Vec::as_slice),
+                settings.chunk_size,
+            )
+                .await;
+            let mut chunks = match chunks {
+                Ok(chunks) => chunks,
+                Err(e) => {
+                    let message = format!("{:#}", e);
+                    let _ = network::write_error(chunk_stream, ErrorCode::FileUnavailable, message, Some(file_index)).await;
+                    return Err(e.context("Failed to prepare file"));
+                }
+            };
+            if !settings.chunk_crc {
+                chunks.without_checksums();
+            }
+            if let Some(cipher) = &settings.cipher {
+                chunks.encrypt_with(cipher.clone());
+            }
+            say!("   📦 Sending {} chunks...", chunks.remaining());
+
+            // Chunks are read as the window allows, so only a few are in memory
+            network::send_chunks_over_stream(
+                chunk_stream,
+                &mut chunks,
+                settings.rate_limiter.as_deref(),
+                settings.chunk_wire(),
+                &settings.cancel,
+                &settings.stats,
+                settings.events.as_ref(),
+            )
+                .await
+                .context("Failed to send chunks")?;
+            sent.push(SentFile::from_reader(&chunks));
+
+            say!("   ✅ All chunks sent for file {}", file_index);
+            emit_file_sent(file_index, path);
+        }
+        if let Some(mut data) = data {
+            data.close().await.context("Failed to close data stream")?;
+        }
+        sent
+    };
+
+    let report = settings.stats.lock().unwrap().report();
+    if completion {
+        finish_transfer(&mut stream, peer, &file_paths, sent, &kept).await?;
+    }
+
+    stream.close().await.context("Failed to close stream")?;
+    say!("✅ All files sent successfully to {} over {:?}", peer, transport);
+    report.print(|file_index| file_paths[file_index].display().to_string());
+    if let Some(limiter) = &settings.rate_limiter {
+        say!("   Rate limited to {}", transfer::format_speed(limiter.bytes_per_sec()));
+    }
+    events::emit(Event::TransferStats { peer_id: peer.to_string(), stats: report.clone() });
+    events::emit(Event::TransferComplete {
+        peer_id: peer.to_string(),
+        files: file_paths.len() - kept.len(),
+        transport: format!("{:?}", transport).to_lowercase(),
+    });
+    Ok(Some(report))
+}
+
+/// What was sent for one file, reported in `TransferComplete`
+struct SentFile {
+    file_index: usize,
+    hash: Option<[u8; 32]>,
+    bytes: u64,
+}
+
+impl SentFile {
+    /// Summary of a file whose chunks have all been sent
+    fn from_reader(chunks: &transfer::ChunkReader) -> Self {
+        Self {
+            file_index: chunks.file_index(),
+            hash: chunks.file_hash(),
+            bytes: chunks.file_bytes(),
+        }
+    }
+}
+
+/// Tell the receiver everything was sent and log its per-file verdict
+async fn finish_transfer(
+    stream: &mut Stream,
+    peer: PeerId,
+    file_paths: &[PathBuf],
+    mut sent: Vec<SentFile>,
+    kept: &HashSet<usize>,
+) -> Result<()> {
+    sent.sort_by_key(|f| f.file_index);
+    // By file index, so skipped files and duplicates leave a gap
+    let mut file_hashes = vec![None; file_paths.len()];
+    for f in &sent {
+        file_hashes[f.file_index] = f.hash;
+    }
+    let complete = TransferComplete {
+        file_count: sent.len(),
+        total_bytes: sent.iter().map(|f| f.bytes).sum(),
+        file_hashes,
+    };
+    network::write_complete(stream, complete)
+        .await
+        .context("Failed to send transfer completion")?;
+
+    let receipt = network::read_receipt(stream)
+        .await
+        .context("Failed to read receipt")?;
+
+    say!("🧾 Receipt from {}:", peer);
+    let mut failed = 0;
+    for file in &receipt.files {
+        let name = file_paths
+            .get(file.file_index)
+            .map_or_else(|| format!("file {}", file.file_index), |p| p.display().to_string());
+        match file.status {
+            FileStatus::Verified => say!("   ✅ {} verified", name),
+            FileStatus::Unverified => say!("   ⚠️  {} received (unverified)", name),
+            FileStatus::Failed => {
+                failed += 1;
+                say!("   ❌ {} failed verification", name);
+            }
+            FileStatus::Missing if kept.contains(&file.file_index) => {
+                say!("   ⏭️  {} kept by the receiver", name);
+            }
+            FileStatus::Missing => {
+                failed += 1;
+                say!("   ❌ {} missing", name);
+            }
+        }
+    }
+
+    if failed > 0 {
+        anyhow::bail!("{} file(s) did not arrive intact at {}", failed, peer);
+    }
     Ok(())
 }
+
+/// Send each file on its own stream, up to `settings.parallel_files` at a time
+async fn send_files_parallel(
+    peer: PeerId,
+    control: libp2p_stream::Control,
+    settings: &SendSettings,
+    file_paths: &[PathBuf],
+    sizes: &[u64],
+    skip: &HashSet<usize>,
+    resume: &ResumeRequest,
+) -> Result<Vec<SentFile>> {
+    // Duplicates and files the receiver keeps get no stream
+    let originals: Vec<_> = file_paths
+        .iter()
+        .enumerate()
+        .filter(|(file_index, _)| !skip.contains(file_index))
+        .collect();
+    say!(
+        "   🔀 Sending {} file(s) over parallel streams (up to {} at once)",
+        originals.len(),
+        settings.parallel_files
+    );
+
+    futures::stream::iter(originals)
+        .map(|(file_index, path)| {
+            let mut control = control.clone();
+            async move {
+                let mut stream = control
+                    .open_stream(peer, StreamProtocol::new(network::FILE_PROTOCOL))
+                    .await
+                    .map_err(|e| anyhow::anyhow!("Failed to open stream for file {}: {}", file_index, e))?;
+                emit_file_started(file_index, path, sizes[file_index], resume);
+
+                let chunks = transfer::ChunkReader::open(
+                    path,
+                    file_index,
+                    settings.hash_algo,
+                    resume.get(file_index),
+                    settings.compression,
+                    settings.dictionary.as_deref().map(Vec::as_slice),
+                    settings.chunk_size,
+                )
+                    .await;
+                let mut chunks = match chunks {
+                    Ok(chunks) => chunks,
+                    Err(e) => {
+                        let message = format!("{:#}", e);
+                        let _ = network::write_error(&mut stream, ErrorCode::FileUnavailable, message, Some(file_index))
+                            .await;
+                        return Err(e.context("Failed to prepare file"));
+                    }
+                };
+                if !settings.chunk_crc {
+                    chunks.without_checksums();
+                }
+                if let Some(cipher) = &settings.cipher {
+                    chunks.encrypt_with(cipher.clone());
+                }
+                network::send_chunks_over_stream(
+                    &mut stream,
+                    &mut chunks,
+                    settings.rate_limiter.as_deref(),
+                    settings.chunk_wire(),
+                    &settings.cancel,
+                    &settings.stats,
+                    settings.events.as_ref(),
+                )
+                    .await
+                    .with_context(|| format!("Failed to send file {}", file_index))?;
+                let sent = SentFile::from_reader(&chunks);
+                stream.close().await.context("Failed to close file stream")?;
+
+                say!("   ✅ All chunks sent for file {} ({})", file_index, path.display());
+                emit_file_sent(file_index, path);
+                anyhow::Ok(sent)
+            }
+        })
+        .buffer_unordered(settings.parallel_files)
+        .try_collect()
+        .await
+}
+
+/// Print what a transfer's chunk streams report, until the transfer is over
+async fn render_network_events(
+    mut reports: mpsc::UnboundedReceiver<NetworkEvent>,
+    peer: PeerId,
+    receivers: Arc<Receivers>,
+    mut progress: SendProgress,
+) {
+    while let Some(event) = reports.recv().await {
+        match event {
+            NetworkEvent::ChunkSent { file_index, chunk_number, bytes, file_bytes } => {
+                trace!("Chunk {} of file {} sent to {} ({} bytes)", chunk_number, file_index, peer, bytes);
+                receivers.sent(peer, file_index, bytes);
+                // As often as the receiver reports its own progress
+                let (sent, total) = progress.chunk(file_index, file_bytes);
+                if (chunk_number + 1).is_multiple_of(network::ACK_INTERVAL) || sent >= total {
+                    events::emit(Event::FileProgress { file_index, bytes: sent, total });
+                    events::emit(Event::TransferProgress { bytes: progress.bytes, total: progress.total });
+                }
+            }
+            NetworkEvent::Retransmit { file_index, chunk_number } => {
+                say!("🔁 Resending file {} from chunk {}, {} got it corrupted", file_index, chunk_number, peer);
+            }
+            NetworkEvent::PeerPaused => say!("⏸️  Receiver {} paused the transfer", peer),
+            NetworkEvent::PeerResumed => say!("▶️  Receiver {} resumed the transfer", peer),
+            NetworkEvent::Error { message, .. } => eprintln!("   ❌ {}", message),
+            event => debug!("Network event: {:?}", event),
+        }
+    }
+}
+
+/// Bytes sent of each file and of the whole transfer, for `--json`
+struct SendProgress {
+    /// Size and bytes sent so far of every file that is sent
+    files: HashMap<usize, (u64, u64)>,
+    bytes: u64,
+    total: u64,
+}
+
+impl SendProgress {
+    /// Progress over the files that are sent, starting where the receiver resumes them
+    fn new(sizes: &[u64], skip: &HashSet<usize>, resume: &ResumeRequest) -> Self {
+        let files: HashMap<usize, (u64, u64)> = sizes
+            .iter()
+            .enumerate()
+            .filter(|(file_index, _)| !skip.contains(file_index))
+            .map(|(file_index, &size)| {
+                let resumed = resume.get(file_index).map_or(0, |point| point.offset.min(size));
+                (file_index, (size, resumed))
+            })
+            .collect();
+        Self {
+            bytes: files.values().map(|(_, sent)| sent).sum(),
+            total: files.values().map(|(size, _)| size).sum(),
+            files,
+        }
+    }
+
+    /// Count `bytes` more of file `file_index` sent, returning its bytes sent and size
+    fn chunk(&mut self, file_index: usize, bytes: u64) -> (u64, u64) {
+        let (size, sent) = self.files.entry(file_index).or_default();
+        *sent += bytes;
+        self.bytes += bytes;
+        (*sent, *size)
+    }
+}
+
+/// Announce a file about to be sent, for `--json`
+fn emit_file_started(file_index: usize, path: &Path, size: u64, resume: &ResumeRequest) {
+    events::emit(Event::FileStarted {
+        file_index,
+        path: path.display().to_string(),
+        size,
+        offset: resume.get(file_index).map_or(0, |point| point.offset),
+    });
+}
+
+/// Announce a file whose chunks were all acknowledged, for `--json`
+fn emit_file_sent(file_index: usize, path: &Path) {
+    events::emit(Event::FileCompleted {
+        file_index,
+        path: path.display().to_string(),
+        status: None,
+    });
+}
+
+/// Ask a yes/no question on stdin, defaulting to no
+fn confirm(prompt: &str) -> io::Result<bool> {
+    if events::json() {
+        events::emit(Event::Prompt { question: prompt.trim().to_string() });
+    } else {
+        print!("{}", prompt);
+    }
+    io::stdout().flush()?;
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer)?;
+    Ok(matches!(answer.trim().to_lowercase().as_str(), "y" | "yes"))
+}
+
+/// Data streams on their way from receivers, by the peer whose transfer waits for one
+#[derive(Default)]
+struct DataStreams {
+    waiting: Mutex<HashMap<PeerId, oneshot::Sender<Stream>>>,
+}
+
+impl DataStreams {
+    /// Wait for `peer`'s next data stream; each receiver runs one transfer at a time
+    fn expect(&self, peer: PeerId) -> oneshot::Receiver<Stream> {
+        let (tx, rx) = oneshot::channel();
+        self.waiting.lock().unwrap().insert(peer, tx);
+        rx
+    }
+
+    /// Hand a newly opened data stream to the transfer waiting for it
+    fn deliver(&self, peer: PeerId, stream: Stream) {
+        let waiting = self.waiting.lock().unwrap().remove(&peer);
+        if waiting.is_none_or(|tx| tx.send(stream).is_err()) {
+            debug!("Dropping data stream from {}: no transfer is waiting for it", peer);
+        }
+    }
+}
+
+/// The data stream a receiver opens after accepting, within the control timeout
+async fn await_data_stream(data_stream: oneshot::Receiver<Stream>, peer: PeerId) -> Result<Stream> {
+    let limit = network::control_timeout();
+    match tokio::time::timeout(limit, data_stream).await {
+        Ok(Ok(stream)) => Ok(stream),
+        Ok(Err(_)) => anyhow::bail!("Data stream from {} was taken by another transfer", peer),
+        Err(_) => anyhow::bail!("Receiver {} didn't open its data stream within {}s", peer, limit.as_secs()),
+    }
+}
+
+/// Decline a transfer request, telling the receiver why
+async fn reject(
+    stream: &mut Stream,
+    request_id: u64,
+    kind: RejectReason,
+    detail: Option<String>,
+) -> Result<()> {
+    match &detail {
+        Some(detail) => eprintln!("🚫 Rejecting transfer ({}): {}", kind, detail),
+        None => eprintln!("🚫 Rejecting transfer: {}", kind),
+    }
+    let response = TransferResponse {
+        request_id,
+        file_list: FileList::default(),
+        accepted: false,
+        reject_reason: Some(kind),
+        reason: detail,
+        version: protocol::PROTOCOL_VERSION,
+        capabilities: Vec::new(),
+        compression: CompressionAlgo::None,
+        dictionary: None,
+        manifest_sig: protocol::unsigned(),
+        chunk_size: 0,
+        key_share: None,
+        signed_file_list: Vec::new(),
+    };
+    network::write_response(stream, response)
+        .await
+        .context("Failed to send rejection")
+}
@@ -0,0 +1,200 @@
+// Transfer statistics: throughput, duration and a per-file breakdown
+
+use crate::transfer;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Window over which the peak rate is measured
+const PEAK_WINDOW: Duration = Duration::from_secs(1);
+
+/// Files listed one by one in the printed summary, the rest are counted
+const MAX_FILE_LINES: usize = 10;
+
+/// Statistics collected while a transfer runs
+///
+/// Shared by every stream of the transfer behind a mutex, which is only held
+/// for the bookkeeping and never across an await.
+#[derive(Debug, Clone)]
+pub struct TransferStats {
+    started: Instant,
+    finished: Option<Instant>,
+
+    /// File bytes sent or written, not counting resumed data
+    bytes: u64,
+
+    /// Chunk data on the wire, after compression and encryption
+    wire_bytes: u64,
+
+    chunks: u64,
+
+    /// Chunks that arrived more than once
+    retransmits: u64,
+
+    /// Start and bytes of the current peak window
+    window: (Instant, u64),
+    peak_bytes_per_sec: u64,
+
+    files: BTreeMap<usize, FileTiming>,
+}
+
+#[derive(Debug, Clone)]
+struct FileTiming {
+    started: Instant,
+    finished: Option<Instant>,
+    bytes: u64,
+    chunks: u64,
+}
+
+impl TransferStats {
+    pub fn new() -> Self {
+        let now = Instant::now();
+        Self {
+            started: now,
+            finished: None,
+            bytes: 0,
+            wire_bytes: 0,
+            chunks: 0,
+            retransmits: 0,
+            window: (now, 0),
+            peak_bytes_per_sec: 0,
+            files: BTreeMap::new(),
+        }
+    }
+
+    /// Count a chunk carrying `bytes` of the file in `wire_bytes` of chunk data
+    pub fn chunk(&mut self, file_index: usize, bytes: u64, wire_bytes: u64) {
+        let now = Instant::now();
+        self.bytes += bytes;
+        self.wire_bytes += wire_bytes;
+        self.chunks += 1;
+
+        let (window_start, window_bytes) = &mut self.window;
+        *window_bytes += bytes;
+        let window = now.duration_since(*window_start);
+        if window >= PEAK_WINDOW {
+            let rate = (*window_bytes as f64 / window.as_secs_f64()) as u64;
+            self.peak_bytes_per_sec = self.peak_bytes_per_sec.max(rate);
+            self.window = (now, 0);
+        }
+
+        let file = self.files.entry(file_index).or_insert_with(|| FileTiming {
+            started: now,
+            finished: None,
+            bytes: 0,
+            chunks: 0,
+        });
+        file.bytes += bytes;
+        file.chunks += 1;
+    }
+
+    /// Count a chunk that was received again
+    pub fn retransmit(&mut self) {
+        self.retransmits += 1;
+    }
+
+    /// Note that every chunk of a file is through
+    pub fn file_done(&mut self, file_index: usize) {
+        if let Some(file) = self.files.get_mut(&file_index) {
+            file.finished.get_or_insert_with(Instant::now);
+        }
+    }
+
+    /// Stop the clock and sum everything up
+    pub fn report(&mut self) -> StatsReport {
+        let finished = *self.finished.get_or_insert_with(Instant::now);
+        let duration = finished.duration_since(self.started);
+        let average_bytes_per_sec = bytes_per_sec(self.bytes, duration);
+        StatsReport {
+            bytes: self.bytes,
+            wire_bytes: self.wire_bytes,
+            chunks: self.chunks,
+            retransmits: self.retransmits,
+            duration_ms: duration.as_millis() as u64,
+            average_bytes_per_sec,
+            // Transfers shorter than one window never closed one
+            peak_bytes_per_sec: self.peak_bytes_per_sec.max(average_bytes_per_sec),
+            files: self
+                .files
+                .iter()
+                .map(|(&file_index, file)| FileReport {
+                    file_index,
+                    bytes: file.bytes,
+                    chunks: file.chunks,
+                    duration_ms: file
+                        .finished
+                        .unwrap_or(finished)
+                        .duration_since(file.started)
+                        .as_millis() as u64,
+                })
+                .collect(),
+        }
+    }
+}
+
+impl Default for TransferStats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn bytes_per_sec(bytes: u64, duration: Duration) -> u64 {
+    (bytes as f64 / duration.as_secs_f64().max(0.001)) as u64
+}
+
+/// Summary of a transfer, printed at the end and emitted with `--json`
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct StatsReport {
+    pub bytes: u64,
+    pub wire_bytes: u64,
+    pub chunks: u64,
+    pub retransmits: u64,
+    pub duration_ms: u64,
+    pub average_bytes_per_sec: u64,
+    pub peak_bytes_per_sec: u64,
+    pub files: Vec<FileReport>,
+}
+
+/// One file's share of a `StatsReport`
+#[derive(Debug, Clone, Serialize)]
+pub struct FileReport {
+    pub file_index: usize,
+    pub bytes: u64,
+    pub chunks: u64,
+    pub duration_ms: u64,
+}
+
+impl StatsReport {
+    /// Print the summary, naming files with `name_of(file_index)`
+    pub fn print(&self, name_of: impl Fn(usize) -> String) {
+        say!("📊 Statistics:");
+        say!(
+            "   {} in {}, average {}, peak {}",
+            transfer::format_bytes(self.bytes),
+            transfer::format_duration(Duration::from_millis(self.duration_ms)),
+            transfer::format_speed(self.average_bytes_per_sec),
+            transfer::format_speed(self.peak_bytes_per_sec)
+        );
+        if self.wire_bytes != self.bytes {
+            say!("   {} on the wire", transfer::format_bytes(self.wire_bytes));
+        }
+        say!("   {} chunk(s), {} retransmitted", self.chunks, self.retransmits);
+
+        if self.files.len() > 1 {
+            for file in self.files.iter().take(MAX_FILE_LINES) {
+                let duration = Duration::from_millis(file.duration_ms);
+                say!(
+                    "      - {}: {} in {} ({})",
+                    name_of(file.file_index),
+                    transfer::format_bytes(file.bytes),
+                    transfer::format_duration(duration),
+                    transfer::format_speed(bytes_per_sec(file.bytes, duration))
+                );
+            }
+            if self.files.len() > MAX_FILE_LINES {
+                say!("      ... and {} more", self.files.len() - MAX_FILE_LINES);
+            }
+        }
+    }
+}
@@ -0,0 +1,603 @@
+// Session ticket signing and verification
+//
+// The sender signs its ticket with the same Ed25519 identity that backs its
+// PeerId, so a receiver can check an advertisement really came from that peer
+// before dialing any of the addresses in it.
+
+use crate::protocol::{FileList, SessionTicket};
+use anyhow::{Context, Result};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use libp2p::identity::{Keypair, PublicKey};
+use libp2p::PeerId;
+use qrcode::render::unicode::Dense1x2;
+use qrcode::{EcLevel, QrCode};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Ticket nonces remembered per receiver, oldest forgotten first
+const MAX_SEEN_NONCES: usize = 256;
+
+/// Multihash code used by PeerIds that embed the public key directly
+const IDENTITY_MULTIHASH_CODE: u64 = 0x00;
+
+/// Encoded tickets larger than this may not fit a single GATT read on
+/// every platform
+pub const MAX_TICKET_BYTES: usize = 512;
+
+/// How long a freshly issued ticket stays valid
+pub const TICKET_TTL_SECS: u32 = 300;
+
+/// Tickets issued further than this in the future point at a skewed clock
+pub const MAX_CLOCK_SKEW_SECS: u64 = 30;
+
+/// Expired by more than this many TTLs, the clocks are more likely wrong than the ticket stale
+const SKEW_SUSPECT_TTL_MULTIPLE: u64 = 10;
+
+/// How long past its TTL a session ID is still honoured, for receivers that
+/// read the ticket just before it expired and are still dialing
+const SESSION_GRACE_SECS: u64 = 60;
+
+/// Canonical bytes covered by the ticket signature
+///
+/// Encodes every ticket field except `sig` as a CBOR array in a fixed order.
+/// Tickets without any sender, offer or session details use the shorter
+/// array older senders signed, so their signatures still verify.
+fn signing_payload(ticket: &SessionTicket) -> Result<Vec<u8>> {
+    let has_details = !ticket.sender_name.is_empty()
+        || !ticket.hostname.is_empty()
+        || !ticket.app_version.is_empty()
+        || ticket.file_count > 0
+        || ticket.total_size > 0
+        || ticket.session_id != SessionId::default();
+
+    let encoded = if has_details {
+        serde_cbor::to_vec(&(
+            ticket.version,
+            &ticket.peer_id,
+            &ticket.addrs,
+            ticket.protocol,
+            ticket.nonce,
+            ticket.issued_at,
+            ticket.ttl_secs,
+            &ticket.sender_name,
+            &ticket.hostname,
+            &ticket.app_version,
+            ticket.file_count,
+            ticket.total_size,
+            ticket.session_id,
+        ))
+    } else {
+        serde_cbor::to_vec(&(
+            ticket.version,
+            &ticket.peer_id,
+            &ticket.addrs,
+            ticket.protocol,
+            ticket.nonce,
+            ticket.issued_at,
+            ticket.ttl_secs,
+        ))
+    };
+    encoded.context("Failed to encode ticket for signing")
+}
+
+/// Sign the ticket in place with the sender's identity keypair
+pub fn sign_ticket(ticket: &mut SessionTicket, keypair: &Keypair) -> Result<()> {
+    let payload = signing_payload(ticket)?;
+    let sig = keypair.sign(&payload).context("Failed to sign session ticket")?;
+
+    ticket.sig = sig
+        .as_slice()
+        .try_into()
+        .map_err(|_| anyhow::anyhow!("Unexpected signature length {} (need an Ed25519 key)", sig.len()))?;
+    Ok(())
+}
+
+/// Check the ticket was signed by the key behind `ticket.peer_id`
+pub fn verify_ticket(ticket: &SessionTicket) -> Result<()> {
+    let public_key = public_key_from_peer_id(&ticket.peer_id)?;
+    let payload = signing_payload(ticket)?;
+
+    if !public_key.verify(&payload, &ticket.sig) {
+        anyhow::bail!("ticket signature invalid — possible spoofed advertisement");
+    }
+    Ok(())
+}
+
+/* ========== Manifest Signing ========== */
+
+/// Bytes covered by the manifest signature: the file list's CBOR as sent
+///
+/// Prefixed so a manifest signature can never pass for a ticket signature.
+fn manifest_payload(file_list_cbor: &[u8]) -> Vec<u8> {
+    [b"fastdrop-manifest".as_slice(), file_list_cbor].concat()
+}
+
+/// Sign the file list offered in a `TransferResponse`
+///
+/// The list is encoded just as it is inside the response, so the receiver
+/// can check the bytes it got without decoding and re-encoding them.
+pub fn sign_manifest(file_list: &FileList, keypair: &Keypair) -> Result<[u8; 64]> {
+    let file_list_cbor = serde_cbor::to_vec(file_list).context("Failed to encode file list for signing")?;
+    let payload = manifest_payload(&file_list_cbor);
+    let sig = keypair.sign(&payload).context("Failed to sign file list")?;
+
+    sig.as_slice()
+        .try_into()
+        .map_err(|_| anyhow::anyhow!("Unexpected signature length {} (need an Ed25519 key)", sig.len()))
+}
+
+/// Check the file list, as received (`TransferResponse::signed_file_list`),
+/// was signed by `peer_id`, the identity from the ticket
+pub fn verify_manifest(file_list_cbor: &[u8], sig: &[u8; 64], peer_id: &PeerId) -> Result<()> {
+    let public_key = public_key_from_peer_id(peer_id)?;
+    let payload = manifest_payload(file_list_cbor);
+
+    if !public_key.verify(&payload, sig) {
+        anyhow::bail!("file list signature invalid — the manifest was altered in transit");
+    }
+    Ok(())
+}
+
+/// Whether the ticket comes from a sender that signs its file lists
+///
+/// Such a sender's responses are never unsigned, so one that is has been
+/// tampered with rather than come from an older version.
+pub fn signs_manifests(ticket: &SessionTicket) -> bool {
+    ticket.session_id != SessionId::default() || !ticket.app_version.is_empty()
+}
+
+/// Recover the public key embedded in an (Ed25519) PeerId
+fn public_key_from_peer_id(peer_id: &PeerId) -> Result<PublicKey> {
+    let multihash = peer_id.as_ref();
+    if multihash.code() != IDENTITY_MULTIHASH_CODE {
+        anyhow::bail!("PeerId {} does not embed its public key", peer_id);
+    }
+
+    PublicKey::try_decode_protobuf(multihash.digest())
+        .with_context(|| format!("Failed to decode public key from PeerId {}", peer_id))
+}
+
+/* ========== Expiry ========== */
+
+/// Current time in unix seconds
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Ticket age relative to the local clock
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Freshness {
+    /// Within its time-to-live (or issued without one)
+    Fresh,
+
+    /// Older than its time-to-live
+    Expired { age_secs: u64 },
+
+    /// Issued noticeably in the future - the two clocks disagree
+    FromFuture { skew_secs: u64 },
+}
+
+/// Compare a ticket's issue time and TTL against `now` (unix seconds)
+///
+/// Tickets with `ttl_secs == 0` predate expiry support and never expire.
+pub fn check_freshness(ticket: &SessionTicket, now: u64) -> Freshness {
+    if ticket.issued_at > now + MAX_CLOCK_SKEW_SECS {
+        return Freshness::FromFuture {
+            skew_secs: ticket.issued_at - now,
+        };
+    }
+    if ticket.ttl_secs == 0 {
+        return Freshness::Fresh;
+    }
+
+    let age_secs = now.saturating_sub(ticket.issued_at);
+    if age_secs > u64::from(ticket.ttl_secs) {
+        Freshness::Expired { age_secs }
+    } else {
+        Freshness::Fresh
+    }
+}
+
+/// Whether an expired ticket is so old that clock skew is the likelier cause
+pub fn expiry_looks_like_skew(ticket: &SessionTicket, age_secs: u64) -> bool {
+    age_secs > u64::from(ticket.ttl_secs) * SKEW_SUSPECT_TTL_MULTIPLE
+}
+
+/* ========== Sessions ========== */
+
+/// Random ID tying a ticket to the transfer request made with it
+pub type SessionId = [u8; 16];
+
+/// Short form of a session ID for log lines
+pub fn format_session_id(id: &SessionId) -> String {
+    id[..4].iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Session IDs of the tickets a sender has handed out
+///
+/// A request must echo one of these, so a peer that found the listening port
+/// without reading a current ticket is turned away. Each ID remembers the
+/// nonce of its ticket, which the verification code is derived from.
+#[derive(Debug, Default)]
+pub struct SessionRegistry {
+    /// Issue time (unix seconds) and ticket nonce of each live session ID
+    issued: Mutex<HashMap<SessionId, (u64, u64)>>,
+}
+
+impl SessionRegistry {
+    /// Create a session ID for a new ticket carrying `nonce`, forgetting expired ones
+    pub fn issue(&self, nonce: u64) -> SessionId {
+        let id = rand::random::<SessionId>();
+        let now = unix_now();
+        let mut issued = self.issued.lock().unwrap();
+        issued.retain(|_, (issued_at, _)| !session_expired(*issued_at, now));
+        issued.insert(id, (now, nonce));
+        id
+    }
+
+    /// Whether `id` belongs to a ticket issued by us that hasn't expired
+    pub fn is_valid(&self, id: &SessionId) -> bool {
+        self.nonce_of(id).is_some()
+    }
+
+    /// Nonce of the ticket `id` was issued with, if it is ours and hasn't expired
+    pub fn nonce_of(&self, id: &SessionId) -> Option<u64> {
+        let issued = self.issued.lock().unwrap();
+        issued
+            .get(id)
+            .filter(|(issued_at, _)| !session_expired(*issued_at, unix_now()))
+            .map(|&(_, nonce)| nonce)
+    }
+
+    /// Forget every session, so tickets already handed out start no new transfer
+    pub fn revoke_all(&self) {
+        self.issued.lock().unwrap().clear();
+    }
+}
+
+fn session_expired(issued_at: u64, now: u64) -> bool {
+    now.saturating_sub(issued_at) > u64::from(TICKET_TTL_SECS) + SESSION_GRACE_SECS
+}
+
+/* ========== Out-of-Band Tickets ========== */
+
+/// A ticket's CBOR as one line of base64, for handing it over without BLE
+pub fn encode_ticket_text(ticket_cbor: &[u8]) -> String {
+    BASE64.encode(ticket_cbor)
+}
+
+/// Render a ticket as a terminal QR code, for `sender --qr`
+///
+/// Scanning it yields the same base64 text `--emit-ticket` writes.
+pub fn ticket_qr(ticket_cbor: &[u8]) -> Result<String> {
+    // Low error correction keeps the code small enough to scan off a screen
+    let code = QrCode::with_error_correction_level(encode_ticket_text(ticket_cbor), EcLevel::L)
+        .context("Session ticket is too large for a QR code")?;
+    Ok(code
+        .render::<Dense1x2>()
+        .dark_color(Dense1x2::Light)
+        .light_color(Dense1x2::Dark)
+        .build())
+}
+
+/// Read a ticket file as written by `sender --emit-ticket`, `-` for stdin
+///
+/// Accepts base64 (whitespace anywhere is ignored, so line-wrapped pastes
+/// and QR scanner output work) as well as raw CBOR.
+pub fn read_ticket_file(path: &Path) -> Result<Vec<u8>> {
+    let data = if path == Path::new("-") {
+        let mut data = Vec::new();
+        std::io::Read::read_to_end(&mut std::io::stdin(), &mut data).context("Failed to read ticket from stdin")?;
+        data
+    } else {
+        std::fs::read(path).with_context(|| format!("Failed to read ticket file {}", path.display()))?
+    };
+    let decoded = std::str::from_utf8(&data)
+        .ok()
+        .and_then(|text| BASE64.decode(text.split_whitespace().collect::<String>()).ok());
+    Ok(decoded.unwrap_or(data))
+}
+
+/* ========== Verification Code ========== */
+
+/// Six-digit code both users compare after connecting, e.g. `"042 917"`
+///
+/// Each side derives it from the two PeerIds it sees on the connection and
+/// the ticket nonce, so a device in the middle shows a different code. When
+/// chunks are encrypted, the sender's and receiver's key shares go in too, so
+/// matching codes also vouch for the encryption key.
+pub fn verification_code(
+    sender: &PeerId,
+    receiver: &PeerId,
+    nonce: u64,
+    key_shares: Option<([u8; 32], [u8; 32])>,
+) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(b"fastdrop-sas");
+    hasher.update(sender.to_bytes());
+    hasher.update(receiver.to_bytes());
+    hasher.update(nonce.to_be_bytes());
+    if let Some((sender_key, receiver_key)) = key_shares {
+        hasher.update(sender_key);
+        hasher.update(receiver_key);
+    }
+    let digest = hasher.finalize();
+
+    let value = u32::from_be_bytes([digest[0], digest[1], digest[2], digest[3]]) % 1_000_000;
+    format!("{:03} {:03}", value / 1000, value % 1000)
+}
+
+/* ========== Replay Protection ========== */
+
+/// A ticket the receiver has already acted on
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+struct SeenNonce {
+    peer_id: String,
+    nonce: u64,
+}
+
+/// Recently used `(peer_id, nonce)` pairs, kept on disk across runs
+///
+/// A signed ticket stays valid until it expires, so without this a captured
+/// advertisement could be replayed to send the receiver to the same sender
+/// again. Senders issue a new nonce every time they rebuild their ticket.
+pub struct SeenNonces {
+    path: Option<PathBuf>,
+    /// Least recently seen first
+    entries: Vec<SeenNonce>,
+}
+
+impl SeenNonces {
+    /// Load the list from the config directory (empty if there is none yet)
+    pub fn load() -> Self {
+        let path = dirs::config_dir().map(|dir| dir.join("fastdrop").join("seen_nonces.json"));
+        let entries = path
+            .as_ref()
+            .and_then(|path| std::fs::read(path).ok())
+            .and_then(|data| match serde_json::from_slice(&data) {
+                Ok(entries) => Some(entries),
+                Err(e) => {
+                    eprintln!("⚠️  Ignoring unreadable nonce history: {}", e);
+                    None
+                }
+            })
+            .unwrap_or_default();
+
+        Self { path, entries }
+    }
+
+    /// Whether a ticket of `peer_id` with this nonce was already used
+    pub fn contains(&self, peer_id: &PeerId, nonce: u64) -> bool {
+        self.entries.contains(&Self::entry(peer_id, nonce))
+    }
+
+    /// Remember the nonce of a ticket that led to a transfer and save the list
+    pub fn record(&mut self, peer_id: &PeerId, nonce: u64) -> Result<()> {
+        let entry = Self::entry(peer_id, nonce);
+        self.entries.retain(|seen| *seen != entry);
+        self.entries.push(entry);
+        if self.entries.len() > MAX_SEEN_NONCES {
+            let excess = self.entries.len() - MAX_SEEN_NONCES;
+            self.entries.drain(..excess);
+        }
+
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)
+                .with_context(|| format!("Failed to create {}", parent.display()))?;
+        }
+        let data = serde_json::to_vec(&self.entries).context("Failed to encode nonce history")?;
+        std::fs::write(path, data).with_context(|| format!("Failed to write {}", path.display()))
+    }
+
+    fn entry(peer_id: &PeerId, nonce: u64) -> SeenNonce {
+        SeenNonce {
+            peer_id: peer_id.to_string(),
+            nonce,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::{TransportProtocol, PROTOCOL_VERSION};
+
+    fn ticket(keypair: &Keypair) -> SessionTicket {
+        SessionTicket {
+            version: PROTOCOL_VERSION,
+            peer_id: keypair.public().to_peer_id(),
+            addrs: vec![
+                "/ip4/192.168.1.20/udp/4001/quic-v1".parse().unwrap(),
+                "/ip4/192.168.1.20/tcp/4001".parse().unwrap(),
+            ],
+            protocol: TransportProtocol::Quic,
+            nonce: 0x1234_5678_9abc_def0,
+            issued_at: 1_700_000_000,
+            ttl_secs: TICKET_TTL_SECS,
+            sender_name: "laptop".to_string(),
+            hostname: "laptop.local".to_string(),
+            app_version: "0.1.0".to_string(),
+            file_count: 3,
+            total_size: 12_345,
+            session_id: [7; 16],
+            sig: [0; 64],
+        }
+    }
+
+    fn signed(keypair: &Keypair) -> SessionTicket {
+        let mut ticket = ticket(keypair);
+        sign_ticket(&mut ticket, keypair).unwrap();
+        ticket
+    }
+
+    #[test]
+    fn signed_ticket_verifies() {
+        let keypair = Keypair::generate_ed25519();
+        verify_ticket(&signed(&keypair)).unwrap();
+    }
+
+    #[test]
+    fn ticket_without_details_verifies() {
+        let keypair = Keypair::generate_ed25519();
+        let mut ticket = ticket(&keypair);
+        ticket.sender_name.clear();
+        ticket.hostname.clear();
+        ticket.app_version.clear();
+        ticket.file_count = 0;
+        ticket.total_size = 0;
+        ticket.session_id = SessionId::default();
+        sign_ticket(&mut ticket, &keypair).unwrap();
+        verify_ticket(&ticket).unwrap();
+    }
+
+    #[test]
+    fn encoded_ticket_fits_a_gatt_read() {
+        // A dual-stack LAN sender on both transports, with long names and
+        // wide numbers. Signature bytes of 24 and up take two bytes in CBOR,
+        // so the signature is set to its largest encoding.
+        let keypair = Keypair::generate_ed25519();
+        let mut ticket = ticket(&keypair);
+        ticket.addrs = [
+            "/ip4/192.168.178.123/udp/54321/quic-v1",
+            "/ip4/192.168.178.123/tcp/54321",
+            "/ip6/fd12:3456:789a:1::123/udp/54321/quic-v1",
+            "/ip6/fd12:3456:789a:1::123/tcp/54321",
+        ]
+        .iter()
+        .map(|addr| addr.parse().unwrap())
+        .collect();
+        ticket.protocol = TransportProtocol::Both;
+        ticket.sender_name = "Alice's MacBook Pro".to_string();
+        ticket.hostname = "alices-macbook-pro.local".to_string();
+        ticket.app_version = "10.12.3".to_string();
+        ticket.file_count = u32::MAX;
+        ticket.total_size = u64::MAX;
+        ticket.nonce = u64::MAX;
+        ticket.issued_at = u64::MAX;
+        ticket.session_id = [0xff; 16];
+        ticket.sig = [0xff; 64];
+
+        let encoded = serde_cbor::to_vec(&ticket).unwrap();
+        assert!(
+            encoded.len() <= MAX_TICKET_BYTES,
+            "{} byte ticket, over the {} byte limit",
+            encoded.len(),
+            MAX_TICKET_BYTES
+        );
+    }
+
+    #[test]
+    fn used_nonces_are_remembered_up_to_the_limit() {
+        let mut seen = SeenNonces { path: None, entries: Vec::new() };
+        let peer_id = Keypair::generate_ed25519().public().to_peer_id();
+        let other = Keypair::generate_ed25519().public().to_peer_id();
+        seen.record(&peer_id, 1).unwrap();
+        assert!(seen.contains(&peer_id, 1));
+        assert!(!seen.contains(&peer_id, 2));
+        assert!(!seen.contains(&other, 1));
+
+        for nonce in 2..=MAX_SEEN_NONCES as u64 {
+            seen.record(&other, nonce).unwrap();
+        }
+        // Using it again makes it the most recent, so the next one pushes out another
+        seen.record(&peer_id, 1).unwrap();
+        seen.record(&other, 0).unwrap();
+        assert!(seen.contains(&peer_id, 1));
+        assert!(!seen.contains(&other, 2));
+        assert_eq!(seen.entries.len(), MAX_SEEN_NONCES);
+    }
+
+    #[test]
+    fn sessions_remember_their_ticket_nonce() {
+        let sessions = SessionRegistry::default();
+        let first = sessions.issue(11);
+        let second = sessions.issue(22);
+        assert_eq!(sessions.nonce_of(&first), Some(11));
+        assert_eq!(sessions.nonce_of(&second), Some(22));
+        assert_eq!(sessions.nonce_of(&[0; 16]), None);
+
+        sessions.revoke_all();
+        assert!(!sessions.is_valid(&first));
+        assert_eq!(sessions.nonce_of(&second), None);
+    }
+
+    #[test]
+    fn tampered_addrs_are_rejected() {
+        let keypair = Keypair::generate_ed25519();
+        let mut ticket = signed(&keypair);
+        ticket.addrs[0] = "/ip4/203.0.113.9/udp/4001/quic-v1".parse().unwrap();
+        let error = verify_ticket(&ticket).unwrap_err().to_string();
+        assert!(error.contains("ticket signature invalid"), "{}", error);
+
+        let mut ticket = signed(&keypair);
+        ticket.addrs.pop();
+        assert!(verify_ticket(&ticket).is_err());
+    }
+
+    #[test]
+    fn tampered_nonce_is_rejected() {
+        let keypair = Keypair::generate_ed25519();
+        let mut ticket = signed(&keypair);
+        ticket.nonce += 1;
+        assert!(verify_ticket(&ticket).is_err());
+    }
+
+    #[test]
+    fn tampered_details_are_rejected() {
+        let keypair = Keypair::generate_ed25519();
+        let mut ticket = signed(&keypair);
+        ticket.sender_name = "someone else".to_string();
+        assert!(verify_ticket(&ticket).is_err());
+    }
+
+    #[test]
+    fn signature_from_another_key_is_rejected() {
+        let keypair = Keypair::generate_ed25519();
+        let impostor = Keypair::generate_ed25519();
+        let mut ticket = ticket(&keypair);
+        sign_ticket(&mut ticket, &impostor).unwrap();
+        assert!(verify_ticket(&ticket).is_err());
+
+        // Nor can the impostor's own ticket be relabelled with the real sender's ID
+        let mut ticket = signed(&impostor);
+        ticket.peer_id = keypair.public().to_peer_id();
+        assert!(verify_ticket(&ticket).is_err());
+    }
+
+    #[test]
+    fn manifest_signature_covers_the_list_as_sent() {
+        let keypair = Keypair::generate_ed25519();
+        let peer_id = keypair.public().to_peer_id();
+        let file_list = FileList { total_size: 5, ..Default::default() };
+        let sig = sign_manifest(&file_list, &keypair).unwrap();
+
+        let sent = serde_cbor::to_vec(&file_list).unwrap();
+        verify_manifest(&sent, &sig, &peer_id).unwrap();
+
+        let altered = serde_cbor::to_vec(&FileList { total_size: 6, ..Default::default() }).unwrap();
+        assert!(verify_manifest(&altered, &sig, &peer_id).is_err());
+        assert!(verify_manifest(&[], &sig, &peer_id).is_err());
+    }
+
+    #[test]
+    fn senders_with_session_ids_sign_manifests() {
+        let keypair = Keypair::generate_ed25519();
+        assert!(signs_manifests(&ticket(&keypair)));
+
+        let mut old = ticket(&keypair);
+        old.session_id = SessionId::default();
+        old.app_version.clear();
+        assert!(!signs_manifests(&old));
+    }
+}
@@ -1,16 +1,49 @@
 // File transfer operations and protocol decision logic
 
-use crate::protocol::{FileChunk, FileList, FileMetadata, TransportProtocol};
+use crate::codec;
+use crate::crypto::ChunkCipher;
+use crate::network::{report, NetworkEvent};
+use crate::protocol::{
+    CompressionAlgo, FileChunk, FileList, FileMetadata, FileStatus, HashAlgo, ResumeFile, TransportProtocol, CHUNK_SIZE,
+    MAX_FILE_SIZE, MAX_FRAME_SIZE, MAX_TOTAL_SIZE,
+};
 use anyhow::{Context, Result};
+use futures::StreamExt;
 use sha2::{Digest, Sha256};
-use std::path::{Path, PathBuf};
+use std::borrow::Cow;
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet};
+use std::path::{Component, Path, PathBuf};
+use std::time::Duration;
 use tokio::fs::{self, File};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWriteExt, SeekFrom};
+use tokio::sync::mpsc;
 
 /* ========== Constants ========== */
 
-/// Size of each chunk for file transfer (64KB)
-pub const CHUNK_SIZE: usize = 64 * 1024;
+/// Chunk size for small files, where a big buffer buys nothing
+pub const MIN_CHUNK_SIZE: usize = 16 * 1024;
+
+/// Chunk size for multi-GB files, to keep the number of frames down
+pub const MAX_CHUNK_SIZE: usize = 1024 * 1024;
+
+/// Smallest chunk size accepted by `--chunk-size` or negotiated with a receiver
+pub const MIN_CHUNK_SIZE_OVERRIDE: usize = 4 * 1024;
+
+/// Largest chunk size accepted by `--chunk-size` or negotiated with a receiver
+///
+/// A chunk travels as a single frame, so this stays well below
+/// `MAX_FRAME_SIZE` to leave room for the CBOR encoding around the data.
+pub const MAX_CHUNK_SIZE_OVERRIDE: usize = 8 * 1024 * 1024;
+
+/// Most files hashed at once, to bound open files and memory
+const MAX_HASH_TASKS: usize = 8;
+
+/// Files at least this large are read through a memory map when possible
+const MMAP_THRESHOLD: u64 = 64 * 1024 * 1024;
+
+/// Chunk buffers kept for reuse once written, about one ack's worth
+pub const SPARE_CHUNK_BUFFERS: usize = 16;
 
 /// Threshold for protocol selection
 const MANY_FILES_THRESHOLD: usize = 5;
@@ -19,49 +52,60 @@ const SMALL_TOTAL_SIZE_THRESHOLD: u64 = 100 * 1024 * 1024; // 100 MB
 /* ========== Protocol Decision ========== */
 
 /// Analyzes files and decides optimal transport protocol
+///
+/// Senders listen on both transports by default; this pick is only used
+/// with `--transport auto`, which lets the files choose one of them.
 /// 
 /// Rules:
 /// - QUIC: Many files (>5) OR small total size (<100MB)
 ///   Benefits: multiplexing, lower latency, parallel streams
 /// - TCP: Few files (≤5) AND large total size (≥100MB)
 ///   Benefits: simpler, reliable, better congestion control for large transfers
+///
+/// Selections over `limits` are refused, naming the offending file.
 pub async fn analyze_files<P: AsRef<Path>>(
     file_paths: &[P],
+    hash_algo: HashAlgo,
+    limits: SizeLimits,
 ) -> Result<(TransportProtocol, FileList)> {
     if file_paths.is_empty() {
         anyhow::bail!("No files provided for analysis");
     }
 
+    // Fail fast on unreadable inputs before anything is advertised
+    validate_files(file_paths).await?;
+
     let mut files = Vec::new();
     let mut total_size = 0u64;
+    let relative_paths = relative_paths(file_paths);
 
     // Gather metadata for each file
-    for path in file_paths {
+    for (path, relative_path) in file_paths.iter().zip(relative_paths) {
         let path = path.as_ref();
         
         // Get file metadata
         let metadata = fs::metadata(path)
             .await
             .with_context(|| format!("Failed to read metadata for {:?}", path))?;
-        
-        if !metadata.is_file() {
-            anyhow::bail!("{:?} is not a regular file", path);
-        }
 
         let size = metadata.len();
         total_size += size;
 
-        // Calculate SHA256 hash
-        let hash = calculate_file_hash(path).await?;
+        let name = path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .unwrap_or("unknown")
+            .to_string();
 
         let file_meta = FileMetadata {
-            name: path
-                .file_name()
-                .and_then(|n| n.to_str())
-                .unwrap_or("unknown")
-                .to_string(),
+            relative_path: relative_path.unwrap_or_else(|| name.clone()),
+            name,
             size,
-            hash: Some(hash),
+            // Hashed while chunking in `ChunkReader` to avoid a second pass
+            hash: None,
+            hash_algo,
+            mode: file_mode(&metadata),
+            duplicate_of: None,
         };
 
         files.push(file_meta);
@@ -76,14 +120,20 @@ pub async fn analyze_files<P: AsRef<Path>>(
         TransportProtocol::Tcp
     };
 
-    let file_list = FileList { 
+    let mut file_list = FileList { 
         files, 
         total_size,
-        file_data: Vec::new(), // Will be filled by sender
+        unverified: false,
     };
 
-    println!(
-        "📊 Analysis: {} files, {} bytes total → Using {:?}",
+    if let Some(problem) = limits.exceeded_by(&file_list) {
+        anyhow::bail!("{} (raise it with --max-file-size/--max-total-size or use --no-limit)", problem);
+    }
+
+    mark_duplicates(&mut file_list, file_paths, hash_algo).await?;
+
+    say!(
+        "📊 Analysis: {} files, {} bytes total → Best suited to {:?}",
         file_list.files.len(),
         file_list.total_size,
         protocol
@@ -92,107 +142,1351 @@ pub async fn analyze_files<P: AsRef<Path>>(
     Ok((protocol, file_list))
 }
 
+/* ========== Deduplication ========== */
+
+/// Point every file whose contents match an earlier one at that file
+///
+/// Only files sharing a size with another file are hashed, so selections
+/// without duplicates cost no extra reads. Empty files are left alone.
+async fn mark_duplicates<P: AsRef<Path>>(
+    file_list: &mut FileList,
+    file_paths: &[P],
+    hash_algo: HashAlgo,
+) -> Result<()> {
+    let mut by_size: HashMap<u64, Vec<usize>> = HashMap::new();
+    for (file_index, meta) in file_list.files.iter().enumerate() {
+        if meta.size > 0 {
+            by_size.entry(meta.size).or_default().push(file_index);
+        }
+    }
+    let mut candidates: Vec<usize> = by_size.into_values().filter(|group| group.len() > 1).flatten().collect();
+    if candidates.is_empty() {
+        return Ok(());
+    }
+    candidates.sort_unstable();
+
+    let tasks = std::thread::available_parallelism()
+        .map_or(1, |cores| cores.get())
+        .min(MAX_HASH_TASKS);
+    let hashes: Vec<_> = futures::stream::iter(candidates.iter().copied())
+        .map(|file_index| {
+            let path = file_paths[file_index].as_ref().to_path_buf();
+            tokio::spawn(async move { calculate_file_hash(&path, hash_algo).await })
+        })
+        .buffered(tasks)
+        .collect()
+        .await;
+
+    // Candidates are in file order, so the first file with a hash is the original
+    let mut originals: HashMap<(u64, [u8; 32]), usize> = HashMap::new();
+    let mut duplicates = 0;
+    for (file_index, hash) in candidates.into_iter().zip(hashes) {
+        let hash = hash.context("Hashing task panicked")??;
+        let meta = &mut file_list.files[file_index];
+        match originals.entry((meta.size, hash)) {
+            Entry::Occupied(original) => {
+                meta.duplicate_of = Some(*original.get());
+                duplicates += 1;
+            }
+            Entry::Vacant(slot) => {
+                slot.insert(file_index);
+            }
+        }
+    }
+
+    if duplicates > 0 {
+        say!("♊ {} duplicate file(s) will only be sent once", duplicates);
+    }
+    Ok(())
+}
+
+/* ========== File Validation ========== */
+
+/// Largest file and transfer size either side is willing to handle
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SizeLimits {
+    pub max_file_size: Option<u64>,
+    pub max_total_size: Option<u64>,
+}
+
+impl Default for SizeLimits {
+    fn default() -> Self {
+        Self {
+            max_file_size: Some(MAX_FILE_SIZE),
+            max_total_size: Some(MAX_TOTAL_SIZE),
+        }
+    }
+}
+
+impl SizeLimits {
+    /// No limits at all (`--no-limit`)
+    pub const NONE: SizeLimits = SizeLimits {
+        max_file_size: None,
+        max_total_size: None,
+    };
+
+    /// Describe the first limit the file list breaks, if any
+    ///
+    /// The total is added up from the files, so a list understating its
+    /// `total_size` gets no further.
+    pub fn exceeded_by(&self, file_list: &FileList) -> Option<String> {
+        if let Some(max) = self.max_file_size
+            && let Some(file) = file_list.files.iter().find(|f| f.size > max)
+        {
+            return Some(format!(
+                "{} is {}, over the {} per-file limit",
+                display_path(file),
+                format_bytes(file.size),
+                format_bytes(max)
+            ));
+        }
+
+        let total = file_list.files_size();
+        match self.max_total_size {
+            Some(max) if total > max => Some(format!(
+                "The files total {}, over the {} limit",
+                format_bytes(total),
+                format_bytes(max)
+            )),
+            _ => None,
+        }
+    }
+}
+
+/// Check that every path is a regular file that can be opened for reading
+///
+/// All problems are collected so they can be reported in a single error.
+pub async fn validate_files<P: AsRef<Path>>(file_paths: &[P]) -> Result<()> {
+    let mut problems = Vec::new();
+
+    for path in file_paths {
+        let path = path.as_ref();
+        if let Err(problem) = check_readable(path).await {
+            problems.push(format!("{}: {}", path.display(), problem));
+        }
+    }
+
+    if !problems.is_empty() {
+        anyhow::bail!(
+            "{} file(s) cannot be sent:\n   - {}",
+            problems.len(),
+            problems.join("\n   - ")
+        );
+    }
+
+    Ok(())
+}
+
+/// Describe why a single path can't be sent, if it can't
+async fn check_readable(path: &Path) -> std::result::Result<(), String> {
+    let metadata = fs::metadata(path).await.map_err(|e| e.to_string())?;
+
+    // Directories can be opened on some platforms, so check the type first
+    if !metadata.is_file() {
+        return Err("not a regular file".to_string());
+    }
+
+    File::open(path).await.map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+/* ========== Permissions ========== */
+
+/// Permission bits worth carrying over (no setuid/setgid/sticky)
+#[cfg(unix)]
+const PRESERVED_MODE_BITS: u32 = 0o777;
+
+#[cfg(unix)]
+fn file_mode(metadata: &std::fs::Metadata) -> Option<u32> {
+    use std::os::unix::fs::PermissionsExt;
+    Some(metadata.permissions().mode() & PRESERVED_MODE_BITS)
+}
+
+#[cfg(not(unix))]
+fn file_mode(_metadata: &std::fs::Metadata) -> Option<u32> {
+    None
+}
+
+/// Apply the sender's permission bits to a received file (no-op off Unix)
+#[cfg(unix)]
+pub async fn apply_mode(path: &Path, mode: u32) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    let permissions = std::fs::Permissions::from_mode(mode & PRESERVED_MODE_BITS);
+    fs::set_permissions(path, permissions)
+        .await
+        .with_context(|| format!("Failed to set permissions on {}", path.display()))
+}
+
+#[cfg(not(unix))]
+pub async fn apply_mode(_path: &Path, _mode: u32) -> Result<()> {
+    Ok(())
+}
+
+/* ========== Resuming ========== */
+
+/// Where a file is written while it is still being received
+pub fn partial_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".part");
+    PathBuf::from(name)
+}
+
+/// How much of a file a previous session already left in its `.part` file
+///
+/// The offset is rounded down to whole chunks of `chunk_size`, or of the
+/// size `chunk_size_for` picks when the sender scales chunks to each file.
+/// The prefix is hashed when the sender hashes files, so a mismatched
+/// partial copy is not continued.
+async fn find_resume_point(
+    file_index: usize,
+    meta: &FileMetadata,
+    hashed: bool,
+    chunk_size: Option<usize>,
+) -> Result<Option<ResumeFile>> {
+    let partial = partial_path(&output_path(meta)?);
+    let Ok(metadata) = fs::metadata(&partial).await else {
+        return Ok(None);
+    };
+
+    let chunk_size = chunk_size.unwrap_or_else(|| chunk_size_for(meta.size)) as u64;
+    let offset = metadata.len() - metadata.len() % chunk_size;
+    if offset == 0 || offset >= meta.size {
+        return Ok(None);
+    }
+
+    let prefix_hash = if hashed {
+        Some(hash_prefix(&partial, offset, meta.hash_algo).await?)
+    } else {
+        None
+    };
+    Ok(Some(ResumeFile { file_index, offset, prefix_hash }))
+}
+
+/// `find_resume_point` for every file in the list, in file list order
+///
+/// Hashing partial files is CPU-bound, so up to one file per core (at most
+/// `MAX_HASH_TASKS`) is hashed at a time on its own task.
+pub async fn find_resume_points(file_list: &FileList, chunk_size: Option<usize>) -> Result<Vec<ResumeFile>> {
+    let hashed = !file_list.unverified;
+    let tasks = std::thread::available_parallelism()
+        .map_or(1, |cores| cores.get())
+        .min(MAX_HASH_TASKS);
+
+    let results: Vec<_> = futures::stream::iter(file_list.files.iter().cloned().enumerate())
+        // Duplicates are copied, never resumed
+        .filter(|(_, meta)| std::future::ready(meta.duplicate_of.is_none()))
+        .map(|(file_index, meta)| {
+            tokio::spawn(async move { find_resume_point(file_index, &meta, hashed, chunk_size).await })
+        })
+        .buffered(tasks)
+        .collect()
+        .await;
+
+    let mut points = Vec::new();
+    for result in results {
+        if let Some(point) = result.context("Hashing task panicked")?? {
+            points.push(point);
+        }
+    }
+    Ok(points)
+}
+
+/// What to do with the `.part` files of a transfer that didn't finish
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PartialPolicy {
+    /// Leave them for the next attempt to resume from
+    Keep,
+
+    /// Remove them
+    Delete,
+
+    /// Rename them to `.partial` so they are kept but never resumed, and
+    /// nobody mistakes them for finished files
+    #[default]
+    Rename,
+}
+
+impl std::str::FromStr for PartialPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "keep" => Ok(PartialPolicy::Keep),
+            "delete" => Ok(PartialPolicy::Delete),
+            "rename" => Ok(PartialPolicy::Rename),
+            other => Err(format!("Unknown failure policy '{}' (expected keep, delete or rename)", other)),
+        }
+    }
+}
+
+/// Apply `policy` to whatever `.part` files the files in `file_list` left behind
+pub async fn handle_partial_files(file_list: &FileList, policy: PartialPolicy) -> Result<()> {
+    for meta in &file_list.files {
+        let partial = partial_path(&output_path(meta)?);
+        if !fs::try_exists(&partial).await.unwrap_or(false) {
+            continue;
+        }
+
+        match policy {
+            PartialPolicy::Keep => {
+                say!("   ⏸️  Kept {} (run again to resume)", partial.display());
+            }
+            PartialPolicy::Delete => {
+                fs::remove_file(&partial)
+                    .await
+                    .with_context(|| format!("Failed to delete {}", partial.display()))?;
+                say!("   🗑️  Deleted {}", partial.display());
+            }
+            PartialPolicy::Rename => {
+                let renamed = unused_partial_path(&partial).await;
+                fs::rename(&partial, &renamed)
+                    .await
+                    .with_context(|| format!("Failed to rename {}", partial.display()))?;
+                say!("   📝 Renamed {} to {}", partial.display(), renamed.display());
+            }
+        }
+    }
+    Ok(())
+}
+
+/// `name.partial` for the `name.part` file `partial`, numbered the way
+/// `ConflictPolicy::Rename` numbers files if that is taken, so an earlier
+/// failure's copy isn't overwritten
+async fn unused_partial_path(partial: &Path) -> PathBuf {
+    let renamed = partial.with_extension("partial");
+    let name = renamed.file_name().unwrap_or_default().to_string_lossy().into_owned();
+    let mut candidate = renamed.clone();
+    let mut n = 1;
+    while fs::try_exists(&candidate).await.unwrap_or(false) {
+        candidate = renamed.with_file_name(numbered_path(&name, n));
+        n += 1;
+    }
+    candidate
+}
+
+/* ========== Conflicts ========== */
+
+/// What to do when a received file's output path already exists
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ConflictPolicy {
+    /// Replace the existing file
+    Overwrite,
+
+    /// Keep the existing file and don't receive this one
+    Skip,
+
+    /// Receive it as `name (1).ext`, `name (2).ext`, ...
+    #[default]
+    Rename,
+}
+
+impl std::str::FromStr for ConflictPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "overwrite" => Ok(ConflictPolicy::Overwrite),
+            "skip" => Ok(ConflictPolicy::Skip),
+            "rename" => Ok(ConflictPolicy::Rename),
+            other => Err(format!("Unknown conflict policy '{}' (expected overwrite, skip or rename)", other)),
+        }
+    }
+}
+
+/// Apply `policy` to every file whose output path already exists
+///
+/// Renamed files get a new `relative_path`; the indices of skipped files are
+/// returned. Copies of a skipped file are only skipped when their own path
+/// exists; the others are received as `reassign_copies` says.
+pub async fn resolve_conflicts(file_list: &mut FileList, policy: ConflictPolicy) -> Result<Vec<usize>> {
+    let mut taken = file_list
+        .files
+        .iter()
+        .map(output_path)
+        .collect::<Result<HashSet<_>>>()?;
+    let mut skip = Vec::new();
+
+    for file_index in 0..file_list.files.len() {
+        let meta = &file_list.files[file_index];
+        let path = output_path(meta)?;
+        if !fs::try_exists(&path).await.unwrap_or(false) {
+            continue;
+        }
+
+        match policy {
+            ConflictPolicy::Overwrite => say!("   ⚠️  Overwriting {}", path.display()),
+            ConflictPolicy::Skip => {
+                say!("   ⏭️  Skipping {}, it already exists", path.display());
+                skip.push(file_index);
+            }
+            ConflictPolicy::Rename => {
+                let relative = display_path(meta).to_string();
+                let mut n = 1;
+                let renamed = loop {
+                    let candidate = numbered_path(&relative, n);
+                    let candidate_path = output_path_of(&candidate)?;
+                    if !taken.contains(&candidate_path) && !fs::try_exists(&candidate_path).await.unwrap_or(false) {
+                        taken.insert(candidate_path);
+                        break candidate;
+                    }
+                    n += 1;
+                };
+                say!("   📝 {} exists, receiving as {}", path.display(), renamed);
+                file_list.files[file_index].relative_path = renamed;
+            }
+        }
+    }
+
+    let mut duplicates: Vec<_> = file_list.files.iter().map(|meta| meta.duplicate_of).collect();
+    reassign_copies(&mut duplicates, |file_index| skip.contains(&file_index));
+    for (meta, duplicate_of) in file_list.files.iter_mut().zip(duplicates) {
+        meta.duplicate_of = duplicate_of;
+    }
+    Ok(skip)
+}
+
+/// Find the copies of skipped files a new original, as both sides do once
+/// the receiver said which files it skips
+///
+/// The receiver's own version of a skipped file may differ, so nothing is
+/// copied from it: the first copy that isn't skipped itself is sent in full,
+/// and the later ones are copied from that.
+pub fn reassign_copies(duplicates: &mut [Option<usize>], skipped: impl Fn(usize) -> bool) {
+    let mut sent_instead = HashMap::new();
+    for (file_index, duplicate_of) in duplicates.iter_mut().enumerate() {
+        let Some(original) = *duplicate_of else {
+            continue;
+        };
+        if !skipped(original) || skipped(file_index) {
+            continue;
+        }
+        *duplicate_of = match sent_instead.entry(original) {
+            Entry::Occupied(entry) => Some(*entry.get()),
+            Entry::Vacant(entry) => {
+                entry.insert(file_index);
+                None
+            }
+        };
+    }
+}
+
+/// `dir/name.ext` as `dir/name (n).ext`
+fn numbered_path(relative: &str, n: u32) -> String {
+    let (dir, name) = match relative.rsplit_once('/') {
+        Some((dir, name)) => (Some(dir), name),
+        None => (None, relative),
+    };
+    // A leading dot marks a hidden file, not an extension
+    let name = match name.rfind('.').filter(|&dot| dot > 0) {
+        Some(dot) => format!("{} ({}){}", &name[..dot], n, &name[dot..]),
+        None => format!("{} ({})", name, n),
+    };
+    match dir {
+        Some(dir) => format!("{}/{}", dir, name),
+        None => name,
+    }
+}
+
+/* ========== Relative Paths ========== */
+
+/// Forward-slash relative paths to send for the files given to the sender
+///
+/// Plain relative arguments are sent as given. Once any argument is absolute
+/// or climbs out with `..`, every file is sent relative to the deepest
+/// directory holding all of them instead, so `/a/x.txt` and `/b/x.txt`
+/// arrive as `a/x.txt` and `b/x.txt` rather than colliding. `None` where a
+/// path isn't valid UTF-8, for the caller to fall back to the basename.
+fn relative_paths<P: AsRef<Path>>(paths: &[P]) -> Vec<Option<String>> {
+    let plain: Option<Vec<String>> = paths.iter().map(|path| plain_relative_path(path.as_ref())).collect();
+    if let Some(plain) = plain {
+        return plain.into_iter().map(Some).collect();
+    }
+
+    let absolute: Vec<PathBuf> = paths.iter().map(|path| normalize_path(path.as_ref())).collect();
+    let mut root: Vec<Component> = Vec::new();
+    for (i, path) in absolute.iter().enumerate() {
+        let parent = path.parent().unwrap_or(path).components();
+        if i == 0 {
+            root = parent.collect();
+        } else {
+            let common = root.iter().zip(parent).take_while(|(a, b)| **a == *b).count();
+            root.truncate(common);
+        }
+    }
+
+    absolute
+        .iter()
+        .map(|path| {
+            let parts = path
+                .components()
+                .skip(root.len())
+                .filter_map(|component| match component {
+                    Component::Normal(part) => Some(part.to_str()),
+                    _ => None,
+                })
+                .collect::<Option<Vec<&str>>>()?;
+            (!parts.is_empty()).then(|| parts.join("/"))
+        })
+        .collect()
+}
+
+/// `path` as a forward-slash string, if it is a plain relative path
+fn plain_relative_path(path: &Path) -> Option<String> {
+    let mut parts = Vec::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(part) => parts.push(part.to_str()?),
+            Component::CurDir => {}
+            _ => return None,
+        }
+    }
+
+    if parts.is_empty() {
+        None
+    } else {
+        Some(parts.join("/"))
+    }
+}
+
+/// Absolute form of `path` with `.` and `..` resolved lexically
+///
+/// Symlinks are left alone, so a linked file keeps the name it was given by.
+fn normalize_path(path: &Path) -> PathBuf {
+    let absolute = std::path::absolute(path).unwrap_or_else(|_| path.to_path_buf());
+    let mut normalized = PathBuf::new();
+    for component in absolute.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            other => normalized.push(other),
+        }
+    }
+    normalized
+}
+
+/// Path a received file is shown and tracked under
+pub fn display_path(meta: &FileMetadata) -> &str {
+    if meta.relative_path.is_empty() {
+        &meta.name
+    } else {
+        &meta.relative_path
+    }
+}
+
+/// Local path a received file is written to, relative to the current directory
+///
+/// Rejects absolute paths, `..` components and anything else that could
+/// escape the output directory.
+pub fn output_path(meta: &FileMetadata) -> Result<PathBuf> {
+    output_path_of(display_path(meta))
+}
+
+/// `output_path` for a relative path given as a `/`-separated string
+fn output_path_of(relative: &str) -> Result<PathBuf> {
+    if relative.starts_with('/') || relative.starts_with('\\') {
+        anyhow::bail!("unsafe path {:?}: absolute paths are not allowed", relative);
+    }
+
+    let mut path = PathBuf::new();
+    for part in relative.split('/') {
+        match part {
+            "" | "." => continue,
+            ".." => anyhow::bail!("unsafe path {:?}: parent directory components are not allowed", relative),
+            _ => {}
+        }
+
+        // Catches backslashes and drive prefixes on Windows
+        let mut components = Path::new(part).components();
+        match (components.next(), components.next()) {
+            (Some(Component::Normal(_)), None) => path.push(part),
+            _ => anyhow::bail!("unsafe path {:?}: invalid component {:?}", relative, part),
+        }
+    }
+
+    if path.as_os_str().is_empty() {
+        anyhow::bail!("empty file path");
+    }
+    Ok(path)
+}
+
+/// Check every offered file maps to a safe, distinct output path, and every
+/// duplicate refers to an earlier file that is actually sent
+pub fn validate_output_paths(file_list: &FileList) -> Result<()> {
+    let mut seen = HashSet::new();
+    for (file_index, meta) in file_list.files.iter().enumerate() {
+        let path = output_path(meta)?;
+        if !seen.insert(path) {
+            anyhow::bail!("duplicate file path {:?}", display_path(meta));
+        }
+        if let Some(original) = meta.duplicate_of
+            && (original >= file_index || file_list.files[original].duplicate_of.is_some())
+        {
+            anyhow::bail!("{:?} is marked as a copy of an invalid file", display_path(meta));
+        }
+    }
+    Ok(())
+}
+
+/// Check the list's stated total against its files, and against the file
+/// count and total the ticket announced (`None` from senders that don't)
+pub fn validate_sizes(file_list: &FileList, announced: Option<(u32, u64)>) -> Result<()> {
+    let size = file_list.files_size();
+    if size != file_list.total_size {
+        anyhow::bail!(
+            "the files add up to {}, not the {} the list states",
+            format_bytes(size),
+            format_bytes(file_list.total_size)
+        );
+    }
+    if let Some((count, total)) = announced
+        && (file_list.files.len() as u64 != u64::from(count) || size != total)
+    {
+        anyhow::bail!(
+            "{} file(s) of {} offered, but the ticket announced {} of {}",
+            file_list.files.len(),
+            format_bytes(size),
+            count,
+            format_bytes(total)
+        );
+    }
+    Ok(())
+}
+
 /* ========== File Hashing ========== */
 
-/// Calculate SHA256 hash of a file
-async fn calculate_file_hash(path: &Path) -> Result<[u8; 32]> {
-    let mut file = File::open(path)
+/// Incremental hasher for any supported `HashAlgo`
+#[derive(Clone)]
+pub enum FileHasher {
+    Sha256(Sha256),
+    Blake3(Box<blake3::Hasher>),
+}
+
+impl FileHasher {
+    pub fn new(algo: HashAlgo) -> Self {
+        match algo {
+            HashAlgo::Sha256 => FileHasher::Sha256(Sha256::new()),
+            HashAlgo::Blake3 => FileHasher::Blake3(Box::new(blake3::Hasher::new())),
+        }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            FileHasher::Sha256(hasher) => hasher.update(data),
+            FileHasher::Blake3(hasher) => {
+                hasher.update(data);
+            }
+        }
+    }
+
+    pub fn finalize(self) -> [u8; 32] {
+        match self {
+            FileHasher::Sha256(hasher) => hasher.finalize().into(),
+            FileHasher::Blake3(hasher) => *hasher.finalize().as_bytes(),
+        }
+    }
+}
+
+/// Calculate the hash of a file with the given algorithm
+async fn calculate_file_hash(path: &Path, algo: HashAlgo) -> Result<[u8; 32]> {
+    let file = File::open(path)
         .await
         .with_context(|| format!("Failed to open {:?} for hashing", path))?;
 
-    let mut hasher = Sha256::new();
-    let mut buffer = vec![0u8; CHUNK_SIZE];
+    let mut hasher = FileHasher::new(algo);
+    hash_reader(file, &mut hasher).await?;
+    Ok(hasher.finalize())
+}
 
+/// Hash the first `len` bytes of a file
+pub async fn hash_prefix(path: &Path, len: u64, algo: HashAlgo) -> Result<[u8; 32]> {
+    let file = File::open(path)
+        .await
+        .with_context(|| format!("Failed to open {:?} for hashing", path))?;
+
+    let mut hasher = FileHasher::new(algo);
+    hash_reader(file.take(len), &mut hasher).await?;
+    Ok(hasher.finalize())
+}
+
+/// Feed everything `reader` yields into `hasher`
+async fn hash_reader<R: AsyncRead + Unpin>(mut reader: R, hasher: &mut FileHasher) -> Result<()> {
+    let mut buffer = vec![0u8; CHUNK_SIZE];
     loop {
-        let n = file
+        let n = reader
             .read(&mut buffer)
             .await
             .context("Failed to read file for hashing")?;
         if n == 0 {
-            break;
+            return Ok(());
         }
         hasher.update(&buffer[..n]);
     }
+}
 
-    let result = hasher.finalize();
-    let mut hash = [0u8; 32];
-    hash.copy_from_slice(&result);
-    Ok(hash)
+/// Check a received file against the hash announced by the sender
+pub async fn verify_file_hash(path: &Path, expected_hash: [u8; 32], algo: HashAlgo) -> Result<()> {
+    let actual_hash = calculate_file_hash(path, algo).await?;
+    if actual_hash != expected_hash {
+        anyhow::bail!(
+            "{:?} hash mismatch for {:?}: expected {:x?}, got {:x?}",
+            algo,
+            path,
+            expected_hash,
+            actual_hash
+        );
+    }
+    Ok(())
+}
+
+/* ========== Compression ========== */
+
+/// Chunks compressed at the start of a file to judge whether it compresses at all
+const COMPRESSION_SAMPLE_CHUNKS: u64 = 4;
+
+/// The sampled chunks must shrink below this fraction of their size, otherwise
+/// the rest of the file is sent raw (media, archives and the like)
+const MIN_COMPRESSION_RATIO: f64 = 0.9;
+
+/// Compresses the chunks of one file, giving up on files that don't compress
+struct ChunkCompressor {
+    mode: CompressorMode,
+    sampled: u64,
+    raw_bytes: u64,
+    compressed_bytes: u64,
+}
+
+enum CompressorMode {
+    /// Chunks compressed independently
+    PerChunk(codec::Compressor),
+
+    /// All compressed chunks of the file form one stream
+    Stream(codec::StreamEncoder),
+}
+
+impl ChunkCompressor {
+    /// Compressor for `algo`, or `None` when chunks are sent raw
+    fn new(algo: CompressionAlgo, dictionary: Option<&[u8]>) -> Result<Option<Self>> {
+        let mode = match algo {
+            CompressionAlgo::None => return Ok(None),
+            CompressionAlgo::Zstd { level } => CompressorMode::PerChunk(codec::Compressor::new(level, dictionary)?),
+            CompressionAlgo::ZstdStream { level } => {
+                CompressorMode::Stream(codec::StreamEncoder::new(level, dictionary)?)
+            }
+        };
+        Ok(Some(Self {
+            mode,
+            sampled: 0,
+            raw_bytes: 0,
+            compressed_bytes: 0,
+        }))
+    }
+
+    /// Whether the file still looks worth compressing
+    ///
+    /// Once this turns false it stays false, which keeps a stream's compressed
+    /// chunks a contiguous run the receiver can decode in order.
+    fn enabled(&self) -> bool {
+        self.sampled < COMPRESSION_SAMPLE_CHUNKS
+            || (self.compressed_bytes as f64) < self.raw_bytes as f64 * MIN_COMPRESSION_RATIO
+    }
+
+    /// Compressed form of `data`, or `None` to send it raw
+    fn compress(&mut self, data: &[u8]) -> Result<Option<Vec<u8>>> {
+        let (compressed, keep) = match &mut self.mode {
+            CompressorMode::PerChunk(compressor) => {
+                let compressed = compressor.compress(data).context("Failed to compress chunk")?;
+                let smaller = compressed.len() < data.len();
+                (compressed, smaller)
+            }
+            // The encoder has already taken this chunk into its history, so
+            // it has to be sent compressed even if it came out larger
+            CompressorMode::Stream(encoder) => {
+                let compressed = encoder.compress_chunk(data).context("Failed to compress chunk")?;
+                (compressed, true)
+            }
+        };
+
+        if self.sampled < COMPRESSION_SAMPLE_CHUNKS {
+            self.sampled += 1;
+            self.raw_bytes += data.len() as u64;
+            self.compressed_bytes += if keep { compressed.len() } else { data.len() } as u64;
+        }
+
+        Ok(keep.then_some(compressed))
+    }
+}
+
+/// Undoes the sender's compression, tracking one stream per file when needed
+pub struct ChunkDecompressor {
+    compression: CompressionAlgo,
+    dictionary: Option<Vec<u8>>,
+    chunks: codec::Decompressor,
+    streams: HashMap<usize, codec::StreamDecoder>,
+}
+
+impl ChunkDecompressor {
+    /// `dictionary` is the one shipped in the `TransferResponse`, if any
+    pub fn new(compression: CompressionAlgo, dictionary: Option<&[u8]>) -> Result<Self> {
+        Ok(Self {
+            compression,
+            dictionary: dictionary.map(<[u8]>::to_vec),
+            chunks: codec::Decompressor::new(dictionary)?,
+            streams: HashMap::new(),
+        })
+    }
+
+    /// The file bytes carried by a chunk; chunks must arrive in order per file
+    pub fn decompress<'a>(&mut self, chunk: &'a FileChunk) -> Result<Cow<'a, [u8]>> {
+        if !chunk.compressed {
+            return Ok(Cow::Borrowed(&chunk.data));
+        }
+
+        let expected = checked_uncompressed_len(chunk)?;
+        let data = if matches!(self.compression, CompressionAlgo::ZstdStream { .. }) {
+            let decoder = match self.streams.entry(chunk.file_index) {
+                Entry::Occupied(entry) => entry.into_mut(),
+                Entry::Vacant(entry) => entry.insert(codec::StreamDecoder::new(self.dictionary.as_deref())?),
+            };
+            decoder.decompress_chunk(&chunk.data, expected)
+        } else {
+            self.chunks.decompress(&chunk.data, expected)
+        }
+        .with_context(|| {
+            format!("Failed to decompress chunk {} of file {}", chunk.chunk_number, chunk.file_index)
+        })?;
+        check_decompressed_len(chunk, data.len())?;
+        Ok(Cow::Owned(data))
+    }
+
+    /// Drop the stream state of a finished file
+    pub fn finish(&mut self, file_index: usize) {
+        self.streams.remove(&file_index);
+    }
+}
+
+/// A chunk's claimed uncompressed size, refused if it exceeds the chunk size
+///
+/// A chunk never holds more than its chunk size of the file, which also
+/// bounds the memory a malicious sender can make us allocate.
+fn checked_uncompressed_len(chunk: &FileChunk) -> Result<usize> {
+    let expected = chunk.uncompressed_len as usize;
+    let limit = (chunk.chunk_size as usize).min(MAX_CHUNK_SIZE_OVERRIDE);
+    if expected > limit {
+        anyhow::bail!(
+            "Chunk {} of file {} claims {} bytes uncompressed (limit {})",
+            chunk.chunk_number,
+            chunk.file_index,
+            expected,
+            limit
+        );
+    }
+    Ok(expected)
+}
+
+fn check_decompressed_len(chunk: &FileChunk, actual: usize) -> Result<()> {
+    if actual != chunk.uncompressed_len as usize {
+        anyhow::bail!(
+            "Chunk {} of file {} decompressed to {} bytes, expected {}",
+            chunk.chunk_number,
+            chunk.file_index,
+            actual,
+            chunk.uncompressed_len
+        );
+    }
+    Ok(())
+}
+
+/// The file bytes carried by an independently compressed (or raw) chunk
+pub fn chunk_data(chunk: &FileChunk) -> Result<Cow<'_, [u8]>> {
+    if !chunk.compressed {
+        return Ok(Cow::Borrowed(&chunk.data));
+    }
+
+    let expected = checked_uncompressed_len(chunk)?;
+    let data = codec::decompress(&chunk.data, expected).with_context(|| {
+        format!("Failed to decompress chunk {} of file {}", chunk.chunk_number, chunk.file_index)
+    })?;
+    check_decompressed_len(chunk, data.len())?;
+    Ok(Cow::Owned(data))
+}
+
+/* ========== Dictionary ========== */
+
+/// Upper bound on the trained dictionary, which is shipped to every receiver
+const DICT_MAX_SIZE: usize = 112 * 1024;
+
+/// Bytes taken from the start of each file for training
+const DICT_SAMPLE_BYTES: u64 = 16 * 1024;
+
+/// Files sampled for training at most
+const DICT_MAX_SAMPLES: usize = 2000;
+
+/// Training needs a reasonable number of samples to find anything shared
+const DICT_MIN_SAMPLES: usize = 8;
+
+/// Train a zstd dictionary on the start of the offered files
+///
+/// Returns `None` when there are too few files or when the dictionary
+/// (including its own size, since it is sent once) doesn't clearly beat
+/// compressing without one at `level`.
+pub async fn train_dictionary<P: AsRef<Path>>(file_paths: &[P], level: i32) -> Result<Option<Vec<u8>>> {
+    let mut samples = Vec::new();
+    for path in file_paths.iter().take(DICT_MAX_SAMPLES) {
+        let path = path.as_ref();
+        let file = File::open(path)
+            .await
+            .with_context(|| format!("Failed to open {:?} for sampling", path))?;
+        let mut sample = Vec::new();
+        file.take(DICT_SAMPLE_BYTES)
+            .read_to_end(&mut sample)
+            .await
+            .with_context(|| format!("Failed to sample {:?}", path))?;
+        if !sample.is_empty() {
+            samples.push(sample);
+        }
+    }
+
+    if samples.len() < DICT_MIN_SAMPLES {
+        say!("📖 Too few files to train a dictionary, compressing without one");
+        return Ok(None);
+    }
+
+    let dictionary = match codec::train_dictionary(&samples, DICT_MAX_SIZE) {
+        Ok(dictionary) => dictionary,
+        Err(e) => {
+            say!("📖 Dictionary training failed ({:#}), compressing without one", e);
+            return Ok(None);
+        }
+    };
+
+    let mut plain = codec::Compressor::new(level, None)?;
+    let mut primed = codec::Compressor::new(level, Some(&dictionary))?;
+    let mut plain_bytes = 0;
+    let mut primed_bytes = dictionary.len();
+    for sample in &samples {
+        plain_bytes += plain.compress(sample)?.len();
+        primed_bytes += primed.compress(sample)?.len();
+    }
+
+    if (primed_bytes as f64) < plain_bytes as f64 * MIN_COMPRESSION_RATIO {
+        say!(
+            "📖 Trained a {} dictionary: samples compress to {} instead of {}",
+            format_bytes(dictionary.len() as u64),
+            format_bytes(primed_bytes as u64),
+            format_bytes(plain_bytes as u64)
+        );
+        Ok(Some(dictionary))
+    } else {
+        say!("📖 A dictionary doesn't help these files, compressing without one");
+        Ok(None)
+    }
 }
 
 /* ========== File Sending ========== */
 
-/// Send a file as chunks
-/// Returns async stream of FileChunk
-pub async fn send_file<P: AsRef<Path>>(
-    path: P,
+/// Reads a file as chunks, one at a time, so memory use stays flat whatever the file size
+///
+/// The file is hashed in the same pass and the hash is attached to the last
+/// chunk, unless `hash_algo` is `None` (verification disabled).
+///
+/// With `resume`, chunks start at the receiver's offset. The skipped prefix is
+/// still hashed so the final hash covers the whole file; if it doesn't match
+/// the receiver's prefix hash the file is sent from the start instead.
+///
+/// With `compression`, each chunk is sent compressed when that makes it
+/// smaller; files whose first chunks barely shrink are sent raw. A
+/// `dictionary` must be the one announced to the receiver.
+///
+/// `chunk_size` overrides the size picked by `chunk_size_for` and should
+/// come from `parse_chunk_size`.
+///
+/// Files of `MMAP_THRESHOLD` and up are copied straight out of a memory map
+/// instead of being read through the file. Chunk data goes into buffers
+/// handed back with `recycle`, so a steady transfer allocates none.
+pub struct ChunkReader {
+    path: PathBuf,
+    file: File,
+    mapped: Option<memmap2::Mmap>,
+    /// Buffers of chunks already written, for the next chunks to be read into
+    spare: Vec<Vec<u8>>,
     file_index: usize,
-) -> Result<Vec<FileChunk>> {
-    let path = path.as_ref();
-    let mut file = File::open(path)
-        .await
-        .with_context(|| format!("Failed to open {:?} for sending", path))?;
+    chunk_size: usize,
+    chunk_number: u64,
+    total_chunks: u64,
+    /// The file was empty when opened and goes as a single empty chunk
+    empty: bool,
+    hasher: Option<FileHasher>,
+    compressor: Option<ChunkCompressor>,
+    compressed_chunks: u64,
+    chunks_read: u64,
+    cipher: Option<ChunkCipher>,
 
-    let file_size = file
-        .metadata()
-        .await
-        .context("Failed to get file metadata")?
-        .len();
+    /// Give every chunk a CRC32 so the receiver catches corruption early
+    checksums: bool,
 
-    // Calculate total chunks
-    let total_chunks = (file_size + CHUNK_SIZE as u64 - 1) / CHUNK_SIZE as u64;
+    /// File and wire bytes of the chunks read so far
+    file_bytes: u64,
+    wire_bytes: u64,
 
-    let mut chunks = Vec::new();
-    let mut buffer = vec![0u8; CHUNK_SIZE];
-    let mut chunk_number = 0u64;
+    /// Hash of the whole file, once the last chunk has been read
+    file_hash: Option<[u8; 32]>,
+}
 
-    loop {
-        let n = file
-            .read(&mut buffer)
+impl ChunkReader {
+    pub async fn open<P: AsRef<Path>>(
+        path: P,
+        file_index: usize,
+        hash_algo: Option<HashAlgo>,
+        resume: Option<&ResumeFile>,
+        compression: CompressionAlgo,
+        dictionary: Option<&[u8]>,
+        chunk_size: Option<usize>,
+    ) -> Result<Self> {
+        let path = path.as_ref();
+        let mut file = File::open(path)
             .await
-            .context("Failed to read file chunk")?;
-        
-        if n == 0 {
-            break;
+            .with_context(|| format!("Failed to open {:?} for sending", path))?;
+
+        let file_size = file
+            .metadata()
+            .await
+            .context("Failed to get file metadata")?
+            .len();
+
+        // Calculate total chunks; an empty file still takes one, or the
+        // receiver would never hear of it
+        let chunk_size = chunk_size.unwrap_or_else(|| chunk_size_for(file_size));
+        let total_chunks = file_size.div_ceil(chunk_size as u64).max(1);
+
+        let mapped = if file_size >= MMAP_THRESHOLD { map_file(path) } else { None };
+        let mut chunk_number = 0u64;
+        let mut hasher = hash_algo.map(FileHasher::new);
+
+        if let Some(resume) = resume.filter(|r| r.offset > 0 && r.offset < file_size) {
+            let offset = resume.offset - resume.offset % chunk_size as u64;
+            match hasher.as_mut() {
+                Some(hasher) => hash_reader((&mut file).take(offset), hasher).await?,
+                None => {
+                    file.seek(SeekFrom::Start(offset)).await.context("Failed to seek")?;
+                }
+            }
+
+            let prefix_matches = match (&hasher, resume.prefix_hash) {
+                (Some(hasher), Some(expected)) => hasher.clone().finalize() == expected,
+                _ => true,
+            };
+            if prefix_matches {
+                say!("   ♻️  Resuming file {} at {}", file_index, format_bytes(offset));
+                chunk_number = offset / chunk_size as u64;
+            } else {
+                say!("   ⚠️  Receiver's partial copy of file {} differs, sending it again", file_index);
+                file.seek(SeekFrom::Start(0)).await.context("Failed to seek")?;
+                hasher = hash_algo.map(FileHasher::new);
+            }
         }
 
-        let chunk = FileChunk {
+        Ok(Self {
+            path: path.to_path_buf(),
+            file,
+            spare: Vec::new(),
+            mapped,
             file_index,
+            chunk_size,
             chunk_number,
             total_chunks,
-            data: buffer[..n].to_vec(),
+            empty: file_size == 0,
+            hasher,
+            compressor: ChunkCompressor::new(compression, dictionary)?,
+            compressed_chunks: 0,
+            chunks_read: 0,
+            cipher: None,
+            checksums: true,
+            file_bytes: 0,
+            wire_bytes: 0,
+            file_hash: None,
+        })
+    }
+
+    pub fn file_index(&self) -> usize {
+        self.file_index
+    }
+
+    /// Number of the next chunk to be read
+    pub fn position(&self) -> u64 {
+        self.chunk_number
+    }
+
+    /// Chunks left to read
+    pub fn remaining(&self) -> u64 {
+        self.total_chunks - self.chunk_number
+    }
+
+    /// File bytes carried by the chunks read so far
+    pub fn file_bytes(&self) -> u64 {
+        self.file_bytes
+    }
+
+    /// Hash of the whole file, known once the last chunk has been read
+    pub fn file_hash(&self) -> Option<[u8; 32]> {
+        self.file_hash
+    }
+
+    /// Encrypt every chunk read from now on, after compressing it
+    pub fn encrypt_with(&mut self, cipher: ChunkCipher) {
+        self.cipher = Some(cipher);
+    }
+
+    /// Leave the CRC32 out of every chunk read from now on
+    pub fn without_checksums(&mut self) {
+        self.checksums = false;
+    }
+
+    /// Hand back a sent chunk's data for a later chunk to be read into
+    pub fn recycle(&mut self, buffer: Vec<u8>) {
+        if self.spare.len() < SPARE_CHUNK_BUFFERS {
+            self.spare.push(buffer);
+        }
+    }
+
+    /// Read, compress and encrypt the next chunk, or `None` after the last one
+    pub async fn next_chunk(&mut self) -> Result<Option<FileChunk>> {
+        if self.chunk_number >= self.total_chunks {
+            return Ok(None);
+        }
+
+        let chunk_size = self.chunk_size;
+        let mut raw = self.spare.pop().unwrap_or_default();
+        match &self.mapped {
+            Some(map) => {
+                let start = (self.chunk_number * chunk_size as u64).min(map.len() as u64) as usize;
+                raw.clear();
+                raw.extend_from_slice(&map[start..(start + chunk_size).min(map.len())]);
+            }
+            None => {
+                // Whatever the buffer held is read over, so only growth is zeroed
+                raw.resize(chunk_size, 0);
+                let n = read_full(&mut self.file, &mut raw)
+                    .await
+                    .context("Failed to read file chunk")?;
+                raw.truncate(n);
+            }
+        }
+
+        // The receiver expects the size announced in the file list
+        if raw.is_empty() && !self.empty {
+            anyhow::bail!("{} shrank while it was being sent", self.path.display());
+        }
+
+        if let Some(hasher) = self.hasher.as_mut() {
+            hasher.update(&raw);
+        }
+
+        let n = raw.len();
+        let compressed = match self.compressor.as_mut().filter(|c| c.enabled()) {
+            Some(compressor) => compressor.compress(&raw)?,
+            None => None,
+        };
+        let (data, compressed) = match compressed {
+            Some(data) => {
+                self.compressed_chunks += 1;
+                self.recycle(raw);
+                (data, true)
+            }
+            None => (raw, false),
+        };
+
+        let chunk_number = self.chunk_number;
+        let last = chunk_number + 1 == self.total_chunks;
+        if last {
+            self.file_hash = self.hasher.take().map(FileHasher::finalize);
+        }
+
+        let mut chunk = FileChunk {
+            file_index: self.file_index,
+            chunk_number,
+            total_chunks: self.total_chunks,
+            crc32: self.checksums.then(|| crc32fast::hash(&data)),
+            data,
+            file_hash: self.file_hash.filter(|_| last),
+            compressed,
+            uncompressed_len: n as u32,
+            chunk_size: chunk_size as u32,
+            offset: Some(chunk_number * chunk_size as u64),
+            nonce: None,
         };
 
-        chunks.push(chunk);
-        chunk_number += 1;
+        self.chunk_number += 1;
+        self.chunks_read += 1;
+        self.file_bytes += chunk.file_bytes();
+        self.wire_bytes += chunk.data.len() as u64;
+        if last && self.compressed_chunks > 0 {
+            say!(
+                "   🗜️  Compressed {}/{} chunks: {} -> {}",
+                self.compressed_chunks,
+                self.chunks_read,
+                format_bytes(self.file_bytes),
+                format_bytes(self.wire_bytes)
+            );
+        }
+
+        if let Some(cipher) = &self.cipher {
+            cipher.seal(&mut chunk)?;
+        }
+        Ok(Some(chunk))
     }
+}
 
-    println!(
-        "📤 Prepared {} chunks for file {} ({})",
-        chunks.len(),
-        file_index,
-        path.display()
-    );
+/// Map a file for reading, or `None` to fall back to buffered reads
+fn map_file(path: &Path) -> Option<memmap2::Mmap> {
+    let file = std::fs::File::open(path).ok()?;
+    // SAFETY: the mapping is only read. If another process truncates the file
+    // while it is being sent, reads past the new end fault, the same hazard
+    // every mmap-based reader accepts.
+    match unsafe { memmap2::Mmap::map(&file) } {
+        Ok(map) => Some(map),
+        Err(e) => {
+            say!("   ⚠️  Could not map {}, reading it normally: {}", path.display(), e);
+            None
+        }
+    }
+}
+
+/// Chunk size for a file, scaled so big files don't turn into huge numbers of frames
+pub fn chunk_size_for(file_size: u64) -> usize {
+    const MB: u64 = 1024 * 1024;
+    match file_size {
+        size if size < MB => MIN_CHUNK_SIZE,
+        size if size < 256 * MB => CHUNK_SIZE,
+        size if size < 1024 * MB => 256 * 1024,
+        _ => MAX_CHUNK_SIZE,
+    }
+}
+
+/// Parse a `--chunk-size` value such as 256K or 4M
+///
+/// Larger chunks mean fewer frames and syscalls on fast links, but each one
+/// is a single frame, so the size is capped well below `MAX_FRAME_SIZE`.
+pub fn parse_chunk_size(value: &str) -> Result<usize, String> {
+    let size = parse_size(value)
+        .and_then(|size| usize::try_from(size).ok())
+        .ok_or_else(|| format!("Invalid chunk size '{}' (expected e.g. 256K or 4M)", value))?;
+    if !(MIN_CHUNK_SIZE_OVERRIDE..=MAX_CHUNK_SIZE_OVERRIDE).contains(&size) {
+        return Err(format!(
+            "Chunk size must be between {} and {} (frames are limited to {})",
+            format_bytes(MIN_CHUNK_SIZE_OVERRIDE as u64),
+            format_bytes(MAX_CHUNK_SIZE_OVERRIDE as u64),
+            format_bytes(MAX_FRAME_SIZE as u64)
+        ));
+    }
+    Ok(size)
+}
 
-    Ok(chunks)
+/// Chunk size for a transfer, or `None` to scale it to each file
+///
+/// A receiver's preference is honoured between `MIN_CHUNK_SIZE_OVERRIDE` and
+/// the sender's `--chunk-size` (`MAX_CHUNK_SIZE_OVERRIDE` without one).
+/// Without a preference the sender's `--chunk-size`, if any, is used as is.
+pub fn negotiate_chunk_size(preferred: u32, limit: Option<usize>) -> Option<usize> {
+    if preferred == 0 {
+        return limit;
+    }
+    let max = limit.unwrap_or(MAX_CHUNK_SIZE_OVERRIDE);
+    Some((preferred as usize).clamp(MIN_CHUNK_SIZE_OVERRIDE, max))
+}
+
+/// Fill `buffer` from `reader`, short only at end of file
+///
+/// Every chunk but the last must be exactly the chunk size, since chunk
+/// numbers are turned back into file offsets when resuming.
+async fn read_full<R: AsyncRead + Unpin>(reader: &mut R, buffer: &mut [u8]) -> std::io::Result<usize> {
+    let mut filled = 0;
+    while filled < buffer.len() {
+        let n = reader.read(&mut buffer[filled..]).await?;
+        if n == 0 {
+            break;
+        }
+        filled += n;
+    }
+    Ok(filled)
 }
 
 /* ========== File Receiving ========== */
 
+/// Byte ranges of a file that are on disk, merged as chunks come in
+///
+/// Counting by range rather than adding up chunk lengths keeps chunks that
+/// overlap, or arrive again at another offset, from being counted twice.
+#[derive(Debug, Clone, Default)]
+pub struct CoveredRanges {
+    /// Sorted `start..end` ranges that neither overlap nor touch
+    ranges: Vec<(u64, u64)>,
+    len: u64,
+}
+
+impl CoveredRanges {
+    /// The first `len` bytes, as a resumed `.part` file has them
+    pub fn up_to(len: u64) -> Self {
+        let mut covered = Self::default();
+        covered.insert(0, len);
+        covered
+    }
+
+    /// Mark `start..end` as on disk, returning how many of those bytes weren't yet
+    pub fn insert(&mut self, start: u64, end: u64) -> u64 {
+        if start >= end {
+            return 0;
+        }
+        // The ranges that overlap or touch `start..end` merge into one
+        let first = self.ranges.partition_point(|&(_, e)| e < start);
+        let last = self.ranges.partition_point(|&(s, _)| s <= end);
+        let (mut merged_start, mut merged_end) = (start, end);
+        let mut already = 0;
+        for &(s, e) in &self.ranges[first..last] {
+            merged_start = merged_start.min(s);
+            merged_end = merged_end.max(e);
+            already += e.min(end).saturating_sub(s.max(start));
+        }
+        self.ranges.splice(first..last, [(merged_start, merged_end)]);
+
+        let added = end - start - already;
+        self.len += added;
+        added
+    }
+
+    /// Bytes covered so far
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// Whether nothing is covered yet
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
 /// Receive file chunks and write to destination
+///
+/// Chunks are written at their offsets, so they may arrive in any order;
+/// the file is complete once every byte has been written.
 pub struct FileReceiver {
     file: File,
     file_index: usize,
-    expected_chunks: u64,
-    received_chunks: u64,
+    file_size: u64,
+    /// Offsets of the chunks written so far, to spot duplicates
+    written: HashSet<u64>,
+    covered: CoveredRanges,
     path: PathBuf,
+    events: Option<mpsc::UnboundedSender<NetworkEvent>>,
 }
 
 impl FileReceiver {
-    /// Create a new file receiver
+    /// Create a new file receiver for a file of `file_size` bytes
     pub async fn new<P: AsRef<Path>>(
         path: P,
         file_index: usize,
+        file_size: u64,
     ) -> Result<Self> {
         let path = path.as_ref().to_path_buf();
         
@@ -210,12 +1504,20 @@ impl FileReceiver {
         Ok(Self {
             file,
             file_index,
-            expected_chunks: 0,
-            received_chunks: 0,
+            file_size,
+            written: HashSet::new(),
+            covered: CoveredRanges::default(),
             path,
+            events: None,
         })
     }
 
+    /// Report chunks and completion to `events` instead of keeping quiet
+    pub fn with_events(mut self, events: mpsc::UnboundedSender<NetworkEvent>) -> Self {
+        self.events = Some(events);
+        self
+    }
+
     /// Write a chunk to the file
     pub async fn write_chunk(&mut self, chunk: FileChunk) -> Result<()> {
         // Validate chunk
@@ -227,60 +1529,107 @@ impl FileReceiver {
             );
         }
 
-        // Update expected chunks on first chunk
-        if self.expected_chunks == 0 {
-            self.expected_chunks = chunk.total_chunks;
+        let offset = chunk.file_offset();
+        if self.written.contains(&offset) {
+            report(self.events.as_ref(), NetworkEvent::DuplicateChunk { file_index: self.file_index, chunk_number: chunk.chunk_number });
+            return Ok(());
         }
 
-        // Write data
+        let data = chunk_data(&chunk)?;
+        check_chunk_bounds(&chunk, data.len(), self.file_size)?;
+
+        self.file
+            .seek(SeekFrom::Start(offset))
+            .await
+            .context("Failed to seek to chunk offset")?;
         self.file
-            .write_all(&chunk.data)
+            .write_all(&data)
             .await
             .context("Failed to write chunk data")?;
 
-        self.received_chunks += 1;
+        self.written.insert(offset);
+        self.covered.insert(offset, offset + data.len() as u64);
 
-        println!(
-            "📥 Received chunk {}/{} for file {}",
-            self.received_chunks, self.expected_chunks, self.file_index
-        );
+        report(self.events.as_ref(), NetworkEvent::ChunkReceived {
+            file_index: self.file_index,
+            chunk_number: chunk.chunk_number,
+            bytes: data.len() as u64,
+        });
 
         Ok(())
     }
 
-    /// Check if all chunks have been received
+    /// Check if every byte of the file has been written
     pub fn is_complete(&self) -> bool {
-        self.expected_chunks > 0 && self.received_chunks >= self.expected_chunks
+        self.covered.len() >= self.file_size
     }
 
     /// Finalize the file and verify hash
-    pub async fn finalize(mut self, expected_hash: Option<[u8; 32]>) -> Result<()> {
+    pub async fn finalize(
+        mut self,
+        expected_hash: Option<[u8; 32]>,
+        hash_algo: HashAlgo,
+    ) -> Result<()> {
         // Flush and sync
         self.file.flush().await.context("Failed to flush file")?;
         self.file.sync_all().await.context("Failed to sync file")?;
         drop(self.file);
+        report(self.events.as_ref(), NetworkEvent::FileWritten {
+            file_index: self.file_index,
+            chunks: self.written.len(),
+            bytes: self.covered.len(),
+        });
 
         // Verify hash if provided
-        if let Some(expected_hash) = expected_hash {
-            let actual_hash = calculate_file_hash(&self.path).await?;
-            if actual_hash != expected_hash {
-                anyhow::bail!(
-                    "Hash mismatch for {:?}: expected {:x?}, got {:x?}",
-                    self.path,
-                    expected_hash,
-                    actual_hash
-                );
+        let status = match expected_hash {
+            Some(expected_hash) => {
+                verify_file_hash(&self.path, expected_hash, hash_algo).await?;
+                FileStatus::Verified
             }
-            println!("✅ Hash verified for {:?}", self.path);
-        }
+            None => FileStatus::Unverified,
+        };
 
-        println!("✅ File complete: {:?}", self.path);
+        report(self.events.as_ref(), NetworkEvent::FileCompleted {
+            file_index: self.file_index,
+            status,
+            transfer_bytes: self.file_size,
+            transfer_total: self.file_size,
+        });
         Ok(())
     }
 }
 
+/// Refuse a chunk that would write past the end of the announced file
+pub fn check_chunk_bounds(chunk: &FileChunk, len: usize, file_size: u64) -> Result<()> {
+    let end = chunk.file_offset().checked_add(len as u64);
+    if end.is_none_or(|end| end > file_size) {
+        anyhow::bail!(
+            "Chunk {} of file {} ends past the file's {} bytes",
+            chunk.chunk_number,
+            chunk.file_index,
+            file_size
+        );
+    }
+    Ok(())
+}
+
 /* ========== Utility Functions ========== */
 
+/// Format a transfer rate, e.g. `"4.20 MB/s"`
+pub fn format_speed(bytes_per_sec: u64) -> String {
+    format!("{}/s", format_bytes(bytes_per_sec))
+}
+
+/// Format a duration for people, e.g. `"4.2s"`, `"3m 05s"` or `"1h 02m"`
+pub fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    match secs {
+        0..60 => format!("{:.1}s", duration.as_secs_f64()),
+        60..3600 => format!("{}m {:02}s", secs / 60, secs % 60),
+        _ => format!("{}h {:02}m", secs / 3600, secs % 3600 / 60),
+    }
+}
+
 /// Format bytes as human-readable string
 pub fn format_bytes(bytes: u64) -> String {
     const UNITS: &[&str] = &["B", "KB", "MB", "GB", "TB"];
@@ -295,6 +1644,24 @@ pub fn format_bytes(bytes: u64) -> String {
     format!("{:.2} {}", size, UNITS[unit_index])
 }
 
+/// Parse a size such as `1500`, `512K`, `10M`, `10MB` or `2G` (binary multiples) into bytes
+pub fn parse_size(value: &str) -> Option<u64> {
+    let value = value.trim();
+    // "5MB" and "5MiB" mean the same as "5M"
+    let value = value
+        .strip_suffix("iB")
+        .or_else(|| value.strip_suffix(['b', 'B']))
+        .unwrap_or(value);
+    let (digits, multiplier) = match value.chars().last() {
+        Some('k' | 'K') => (&value[..value.len() - 1], 1024),
+        Some('m' | 'M') => (&value[..value.len() - 1], 1024 * 1024),
+        Some('g' | 'G') => (&value[..value.len() - 1], 1024 * 1024 * 1024),
+        _ => (value, 1),
+    };
+
+    digits.parse::<u64>().ok()?.checked_mul(multiplier)
+}
+
 /// Calculate transfer progress percentage
 pub fn calculate_progress(received: u64, total: u64) -> f64 {
     if total == 0 {
@@ -303,3 +1670,127 @@ pub fn calculate_progress(received: u64, total: u64) -> f64 {
         (received as f64 / total as f64) * 100.0
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn relative(paths: &[&str]) -> Vec<Option<String>> {
+        relative_paths(&paths.iter().map(PathBuf::from).collect::<Vec<_>>())
+    }
+
+    fn some(paths: &[&str]) -> Vec<Option<String>> {
+        paths.iter().map(|path| Some(path.to_string())).collect()
+    }
+
+    #[test]
+    fn plain_relative_paths_are_sent_as_given() {
+        assert_eq!(relative(&["a.txt", "./docs/b.txt", "docs/sub/c.txt"]), some(&["a.txt", "docs/b.txt", "docs/sub/c.txt"]));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn absolute_paths_are_sent_relative_to_their_common_root() {
+        assert_eq!(relative(&["/a/x.txt", "/b/x.txt"]), some(&["a/x.txt", "b/x.txt"]));
+        assert_eq!(
+            relative(&["/home/u/docs/a.txt", "/home/u/docs/sub/b.txt"]),
+            some(&["a.txt", "sub/b.txt"])
+        );
+        assert_eq!(relative(&["/home/u/docs/a.txt"]), some(&["a.txt"]));
+        assert_eq!(relative(&["/home/u/one/../two/a.txt", "/home/u/two/./b.txt"]), some(&["a.txt", "b.txt"]));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn relative_paths_join_absolute_ones_under_a_common_root() {
+        let cwd = std::env::current_dir().unwrap();
+        let inside = cwd.join("x.txt");
+        let paths = [PathBuf::from("docs/x.txt"), inside];
+        let sent = relative_paths(&paths);
+        assert_eq!(sent, some(&["docs/x.txt", "x.txt"]));
+
+        let paths = [PathBuf::from("../x.txt"), PathBuf::from("x.txt")];
+        let sent = relative_paths(&paths);
+        let dir = cwd.file_name().unwrap().to_str().unwrap();
+        assert_eq!(sent, some(&["x.txt", &format!("{}/x.txt", dir)]));
+    }
+
+    fn sized(sizes: &[u64], total_size: u64) -> FileList {
+        let file = |(i, &size)| FileMetadata {
+            name: format!("{}.bin", i),
+            relative_path: String::new(),
+            size,
+            hash: None,
+            hash_algo: HashAlgo::Blake3,
+            mode: None,
+            duplicate_of: None,
+        };
+        FileList { files: sizes.iter().enumerate().map(file).collect(), total_size, unverified: false }
+    }
+
+    #[test]
+    fn total_limit_counts_the_files_not_the_stated_total() {
+        let limits = SizeLimits { max_file_size: None, max_total_size: Some(100) };
+        assert!(limits.exceeded_by(&sized(&[60, 60], 120)).is_some());
+        assert!(limits.exceeded_by(&sized(&[60, 60], 10)).is_some());
+        assert!(limits.exceeded_by(&sized(&[u64::MAX, u64::MAX], 0)).is_some());
+        assert!(limits.exceeded_by(&sized(&[40, 60], 100)).is_none());
+    }
+
+    #[test]
+    fn stated_sizes_must_add_up() {
+        validate_sizes(&sized(&[40, 60], 100), None).unwrap();
+        validate_sizes(&sized(&[40, 60], 100), Some((2, 100))).unwrap();
+        assert!(validate_sizes(&sized(&[40, 60], 10), None).is_err());
+        assert!(validate_sizes(&sized(&[40, 60], 100), Some((2, 10))).is_err());
+        assert!(validate_sizes(&sized(&[40, 60], 100), Some((1, 100))).is_err());
+    }
+
+    #[test]
+    fn overlapping_and_repeated_ranges_count_once() {
+        let mut covered = CoveredRanges::up_to(100);
+        assert_eq!(covered.insert(50, 150), 50);
+        assert_eq!(covered.insert(50, 150), 0);
+        assert_eq!(covered.insert(300, 400), 100);
+        assert_eq!(covered.insert(150, 300), 150);
+        assert_eq!(covered.len(), 400);
+        assert_eq!(covered.ranges, [(0, 400)]);
+
+        // Out of order, touching and bridging ranges
+        let mut covered = CoveredRanges::default();
+        assert!(covered.is_empty());
+        assert_eq!(covered.insert(20, 30), 10);
+        assert_eq!(covered.insert(0, 10), 10);
+        assert_eq!(covered.insert(40, 50), 10);
+        assert_eq!(covered.insert(10, 20), 10);
+        assert_eq!(covered.ranges, [(0, 30), (40, 50)]);
+        assert_eq!(covered.insert(5, 45), 10);
+        assert_eq!(covered.ranges, [(0, 50)]);
+        assert_eq!(covered.len(), 50);
+        assert_eq!(covered.insert(7, 7), 0);
+    }
+
+    #[test]
+    fn unfinished_files_are_renamed_unless_asked_otherwise() {
+        assert_eq!(PartialPolicy::default(), PartialPolicy::Rename);
+        assert_eq!("KEEP".parse(), Ok(PartialPolicy::Keep));
+        assert!("shred".parse::<PartialPolicy>().is_err());
+    }
+
+    #[test]
+    fn copies_of_skipped_files_are_sent_once() {
+        // 0 is skipped with its copies 1 and 3 wanted, 4 skipped too; 5 copies 2
+        let mut duplicates = [None, Some(0), None, Some(0), Some(0), Some(2)];
+        reassign_copies(&mut duplicates, |file_index| [0, 4].contains(&file_index));
+        assert_eq!(duplicates, [None, None, None, Some(1), Some(0), Some(2)]);
+    }
+
+    #[test]
+    fn output_paths_refuse_to_escape() {
+        assert!(output_path_of("../etc/passwd").is_err());
+        assert!(output_path_of("/etc/passwd").is_err());
+        assert!(output_path_of("docs/../../x").is_err());
+        assert!(output_path_of("").is_err());
+        assert_eq!(output_path_of("docs/./b.txt").unwrap(), Path::new("docs").join("b.txt"));
+    }
+}
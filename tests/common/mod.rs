@@ -0,0 +1,363 @@
+// Helpers shared by the integration tests: files on disk and in-memory pipes
+// standing in for libp2p streams
+
+#![allow(dead_code)]
+
+use anyhow::Result;
+use fastdrop::network::{self, ChunkWire, ReceiveSettings, ReceiveSummary};
+use fastdrop::protocol::{
+    CompressionAlgo, FileList, FileMetadata, HashAlgo, ResumeFile, ResumeRequest, TransferResponse, CAP_BINARY_CHUNKS,
+    CAP_CHUNK_ACK, PROTOCOL_VERSION,
+};
+use fastdrop::stats::TransferStats;
+use fastdrop::transfer::ChunkReader;
+use futures::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, Once};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio::time::Sleep;
+use tokio_util::compat::{Compat, TokioAsyncReadCompatExt};
+use tokio_util::sync::CancellationToken;
+
+/// Run every test of the binary from the target's scratch directory, since
+/// received files land relative to the current directory
+fn enter_scratch_dir() {
+    static ENTER: Once = Once::new();
+    ENTER.call_once(|| std::env::set_current_dir(env!("CARGO_TARGET_TMPDIR")).unwrap());
+}
+
+/// A fresh, empty directory for one test, relative to the current directory
+pub fn workdir(name: &str) -> PathBuf {
+    enter_scratch_dir();
+    let dir = PathBuf::from(name);
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Bytes that don't repeat within a chunk, so misplaced chunks are noticed
+pub fn contents(size: usize, seed: u64) -> Vec<u8> {
+    let mut state = seed.wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1;
+    (0..size)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as u8
+        })
+        .collect()
+}
+
+/// Write `size` bytes of test data to `path`, returning them
+pub fn write_file(path: &Path, size: usize, seed: u64) -> Vec<u8> {
+    let data = contents(size, seed);
+    std::fs::write(path, &data).unwrap();
+    data
+}
+
+/// An accepted response offering files of the given relative paths and sizes
+/// in fixed-size chunks, each verified with BLAKE3
+pub fn response(files: &[(&Path, u64)], chunk_size: usize) -> TransferResponse {
+    let files: Vec<FileMetadata> = files
+        .iter()
+        .map(|(path, size)| FileMetadata {
+            name: path.file_name().unwrap().to_string_lossy().into_owned(),
+            relative_path: path.to_string_lossy().replace('\\', "/"),
+            size: *size,
+            hash: None,
+            hash_algo: HashAlgo::Blake3,
+            mode: None,
+            duplicate_of: None,
+        })
+        .collect();
+    TransferResponse {
+        request_id: 1,
+        file_list: FileList {
+            total_size: files.iter().map(|f| f.size).sum(),
+            files,
+            unverified: false,
+        },
+        accepted: true,
+        reject_reason: None,
+        reason: None,
+        version: PROTOCOL_VERSION,
+        capabilities: vec![CAP_CHUNK_ACK.to_string(), CAP_BINARY_CHUNKS.to_string()],
+        compression: CompressionAlgo::None,
+        dictionary: None,
+        manifest_sig: [0u8; 64],
+        chunk_size: chunk_size as u32,
+        key_share: None,
+        signed_file_list: Vec::new(),
+    }
+}
+
+/// Binary chunk frames, as current peers agree on
+pub fn wire(window: u64) -> ChunkWire {
+    ChunkWire { window, retransmit: false, binary: true }
+}
+
+/// Send `path` as file `file_index` in chunks of `chunk_size`, then close the stream
+pub async fn send_file<T>(stream: &mut T, path: &Path, file_index: usize, chunk_size: usize, wire: ChunkWire) -> Result<()>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    send_file_from(stream, path, file_index, chunk_size, wire, None).await
+}
+
+/// `send_file`, continuing where `resume` says the receiver left off
+pub async fn send_file_from<T>(
+    stream: &mut T,
+    path: &Path,
+    file_index: usize,
+    chunk_size: usize,
+    wire: ChunkWire,
+    resume: Option<&ResumeFile>,
+) -> Result<()>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut chunks = ChunkReader::open(
+        path,
+        file_index,
+        Some(HashAlgo::Blake3),
+        resume,
+        CompressionAlgo::None,
+        None,
+        Some(chunk_size),
+    )
+    .await?;
+    let stats = Mutex::new(TransferStats::new());
+    network::send_chunks_over_stream(stream, &mut chunks, None, wire, &CancellationToken::new(), &stats, None).await?;
+    stream.close().await?;
+    Ok(())
+}
+
+/// Send each of `paths` as the file at its index, one after another on the same stream, then close it
+pub async fn send_files<T>(stream: &mut T, paths: &[&Path], chunk_size: usize, wire: ChunkWire) -> Result<()>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    let stats = Mutex::new(TransferStats::new());
+    for (file_index, path) in paths.iter().enumerate() {
+        let mut chunks = ChunkReader::open(
+            path,
+            file_index,
+            Some(HashAlgo::Blake3),
+            None,
+            CompressionAlgo::None,
+            None,
+            Some(chunk_size),
+        )
+        .await?;
+        network::send_chunks_over_stream(stream, &mut chunks, None, wire, &CancellationToken::new(), &stats, None).await?;
+    }
+    stream.close().await?;
+    Ok(())
+}
+
+/// Receive the files of `response` until the stream ends
+pub async fn receive<T>(stream: &mut T, response: &TransferResponse, resume: &ResumeRequest) -> Result<ReceiveSummary>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    let (_pause, pause) = watch::channel(false);
+    let settings = ReceiveSettings::default();
+    network::receive_and_write_chunks_streaming(stream, response, resume, &[], &settings, &CancellationToken::new(), &pause)
+        .await
+}
+
+/* ========== Pipes ========== */
+
+/// Both ends of an in-memory stream holding up to `capacity` unread bytes
+pub fn pipe(capacity: usize) -> (Compat<tokio::io::DuplexStream>, Compat<tokio::io::DuplexStream>) {
+    let (a, b) = tokio::io::duplex(capacity);
+    (a.compat(), b.compat())
+}
+
+/// A sending end that gets `budget` bytes through and then loses the
+/// connection: the pipe closes, so the receiver sees the stream end there,
+/// and the write fails
+pub struct Severed<T> {
+    inner: T,
+    budget: usize,
+}
+
+impl<T> Severed<T> {
+    pub fn after(inner: T, budget: usize) -> Self {
+        Self { inner, budget }
+    }
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for Severed<T> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for Severed<T> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        if this.budget == 0 {
+            return match Pin::new(&mut this.inner).poll_close(cx) {
+                Poll::Ready(_) => Poll::Ready(Err(io::ErrorKind::ConnectionReset.into())),
+                Poll::Pending => Poll::Pending,
+            };
+        }
+        let len = buf.len().min(this.budget);
+        let written = Pin::new(&mut this.inner).poll_write(cx, &buf[..len]);
+        if let Poll::Ready(Ok(n)) = written {
+            this.budget -= n;
+        }
+        written
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_close(cx)
+    }
+}
+
+/// Bytes written into a pipe that haven't been read out of the other end yet
+#[derive(Default)]
+pub struct InFlight {
+    written: AtomicU64,
+    read: AtomicU64,
+    max: AtomicU64,
+}
+
+impl InFlight {
+    /// Most bytes that were ever waiting in the pipe at once
+    pub fn max(&self) -> u64 {
+        self.max.load(Ordering::Relaxed)
+    }
+}
+
+/// One end of a pipe that counts what goes through it into an `InFlight`,
+/// and sleeps `delay` before every read to play a slow disk or link
+///
+/// Only one direction is metered: what the sending end writes and the
+/// receiving end reads. The replies going the other way pass untouched.
+pub struct Metered<T> {
+    inner: T,
+    in_flight: Arc<InFlight>,
+    sending: bool,
+    delay: Duration,
+    sleep: Option<Pin<Box<Sleep>>>,
+}
+
+impl<T> Metered<T> {
+    /// The sending end, whose writes count as in flight
+    pub fn sending(inner: T, in_flight: Arc<InFlight>) -> Self {
+        Self { inner, in_flight, sending: true, delay: Duration::ZERO, sleep: None }
+    }
+
+    /// The receiving end, whose reads take bytes out of flight after `delay`
+    pub fn receiving(inner: T, in_flight: Arc<InFlight>, delay: Duration) -> Self {
+        Self { inner, in_flight, sending: false, delay, sleep: None }
+    }
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for Metered<T> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        if this.sending {
+            return Pin::new(&mut this.inner).poll_read(cx, buf);
+        }
+        if !this.delay.is_zero() {
+            let delay = this.delay;
+            let sleep = this.sleep.get_or_insert_with(|| Box::pin(tokio::time::sleep(delay)));
+            if sleep.as_mut().poll(cx).is_pending() {
+                return Poll::Pending;
+            }
+        }
+        let read = Pin::new(&mut this.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(n)) = read {
+            this.sleep = None;
+            this.in_flight.read.fetch_add(n as u64, Ordering::Relaxed);
+        }
+        read
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for Metered<T> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        let written = Pin::new(&mut this.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = written
+            && this.sending
+        {
+            let in_flight = &this.in_flight;
+            let total = in_flight.written.fetch_add(n as u64, Ordering::Relaxed) + n as u64;
+            let waiting = total.saturating_sub(in_flight.read.load(Ordering::Relaxed));
+            in_flight.max.fetch_max(waiting, Ordering::Relaxed);
+        }
+        written
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_close(cx)
+    }
+}
+
+/* ========== Memory ========== */
+
+/// Resident memory of this process not backed by a file, where the OS
+/// reports it (Linux)
+///
+/// Pages of memory-mapped files are left out: they are the page cache,
+/// which the kernel drops as it needs the room.
+pub fn resident_bytes() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|line| line.starts_with("RssAnon:"))?;
+    let kib: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kib * 1024)
+}
+
+/// Run `work`, returning how far resident memory rose above where it started,
+/// or `None` where it can't be measured
+///
+/// One measurement runs at a time, so tests of the same binary don't add up.
+pub async fn memory_growth<F: Future>(work: F) -> (F::Output, Option<u64>) {
+    static MEASURING: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+    let _measuring = MEASURING.lock().await;
+    let Some(before) = resident_bytes() else {
+        return (work.await, None);
+    };
+
+    let done = Arc::new(AtomicBool::new(false));
+    let peak = Arc::new(AtomicU64::new(before));
+    let sampler = std::thread::spawn({
+        let (done, peak) = (done.clone(), peak.clone());
+        move || {
+            while !done.load(Ordering::Relaxed) {
+                if let Some(now) = resident_bytes() {
+                    peak.fetch_max(now, Ordering::Relaxed);
+                }
+                std::thread::sleep(Duration::from_millis(5));
+            }
+        }
+    });
+    let output = work.await;
+    done.store(true, Ordering::Relaxed);
+    sampler.join().unwrap();
+    (output, Some(peak.load(Ordering::Relaxed) - before))
+}
+
+/// An all-zero file of `size` bytes that takes no room on disk, where the
+/// filesystem supports holes
+pub fn sparse_file(path: &Path, size: u64) {
+    std::fs::File::create(path).unwrap().set_len(size).unwrap();
+}
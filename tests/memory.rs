@@ -0,0 +1,78 @@
+// Memory stays flat however large the files: nothing holds a whole file
+
+mod common;
+
+use fastdrop::protocol::{CompressionAlgo, FileStatus, HashAlgo, ResumeRequest};
+use fastdrop::transfer::ChunkReader;
+
+/// Memory the tests allow a transfer beyond what the process already uses,
+/// well below the size of the files they move
+const MEMORY_CEILING: u64 = 64 * 1024 * 1024;
+
+const MB: u64 = 1024 * 1024;
+
+/// BLAKE3 of `size` zero bytes, hashed a megabyte at a time
+fn zeros_hash(size: u64) -> [u8; 32] {
+    let zeros = vec![0u8; MB as usize];
+    let mut hasher = blake3::Hasher::new();
+    for _ in 0..size / MB {
+        hasher.update(&zeros);
+    }
+    *hasher.finalize().as_bytes()
+}
+
+#[tokio::test]
+async fn reading_a_large_file_keeps_memory_flat() {
+    let dir = common::workdir("memory-read");
+    let source = dir.join("sparse.bin");
+    let size = 6 * MEMORY_CEILING;
+    common::sparse_file(&source, size);
+
+    let read = async {
+        let mut chunks = ChunkReader::open(&source, 0, Some(HashAlgo::Blake3), None, CompressionAlgo::None, None, None)
+            .await
+            .unwrap();
+        let mut bytes = 0;
+        while let Some(chunk) = chunks.next_chunk().await.unwrap() {
+            bytes += chunk.data.len() as u64;
+        }
+        (bytes, chunks.file_hash())
+    };
+    let ((bytes, hash), growth) = common::memory_growth(read).await;
+
+    assert_eq!(bytes, size);
+    assert_eq!(hash, Some(zeros_hash(size)));
+    if let Some(growth) = growth {
+        assert!(growth < MEMORY_CEILING, "memory grew by {} MB reading a {} MB file", growth / MB, size / MB);
+    }
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn receiving_a_file_larger_than_the_ceiling_keeps_memory_flat() {
+    let dir = common::workdir("memory-receive");
+    let source = dir.join("sparse.bin");
+    let size = 4 * MEMORY_CEILING;
+    common::sparse_file(&source, size);
+    let target = dir.join("received.bin");
+    let chunk_size = 256 * 1024;
+    let response = common::response(&[(&target, size)], chunk_size);
+
+    let (mut sending, mut receiving) = common::pipe(1024 * 1024);
+    let resume = ResumeRequest::default();
+    let transfer = async {
+        tokio::join!(
+            common::send_file(&mut sending, &source, 0, chunk_size, common::wire(64)),
+            common::receive(&mut receiving, &response, &resume),
+        )
+    };
+    let ((sent, received), growth) = common::memory_growth(transfer).await;
+
+    sent.unwrap();
+    assert_eq!(received.unwrap().statuses[0].status, FileStatus::Verified);
+    assert_eq!(std::fs::metadata(&target).unwrap().len(), size);
+    if let Some(growth) = growth {
+        assert!(growth < MEMORY_CEILING, "memory grew by {} MB receiving a {} MB file", growth / MB, size / MB);
+    }
+    std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,128 @@
+// Stream limits: a receiver opening streams in a loop can't pile up work,
+// peers turned away don't crowd out the one being served, and file streams
+// are only taken from the peer they are expected from
+
+mod common;
+
+use fastdrop::network::{self, Admission, ReceiveSettings, StreamLimits, MAX_REFUSING_STREAMS};
+use fastdrop::protocol::{ErrorCode, FileStatus, Frame, FrameKind, ResumeRequest};
+use libp2p::PeerId;
+use std::sync::Arc;
+use tokio::sync::watch;
+use tokio_util::sync::CancellationToken;
+
+#[tokio::test]
+async fn many_streams_from_one_receiver_are_served_refused_then_reset() {
+    let limits = Arc::new(StreamLimits::new(4));
+    let peer = PeerId::random();
+
+    let mut served = Vec::new();
+    let mut refusals = tokio::task::JoinSet::new();
+    let mut refused = Vec::new();
+    let mut reset = Vec::new();
+    for _ in 0..1000 {
+        // A one byte pipe the receiver doesn't read, so refusals stay under way
+        let (mut ours, theirs) = common::pipe(1);
+        match limits.admit(peer) {
+            Admission::Open(slot) => served.push((slot, ours, theirs)),
+            Admission::Refuse(slot) => {
+                refusals.spawn(async move {
+                    network::refuse_busy(&mut ours).await.unwrap();
+                    drop(slot);
+                });
+                refused.push(theirs);
+            }
+            Admission::Reset => {
+                drop(ours);
+                reset.push(theirs);
+            }
+        }
+    }
+    assert_eq!(served.len(), 4);
+    assert_eq!(refused.len(), MAX_REFUSING_STREAMS);
+    assert_eq!(reset.len(), 1000 - 4 - MAX_REFUSING_STREAMS);
+
+    // Another receiver still gets its streams
+    assert!(matches!(limits.admit(PeerId::random()), Admission::Open(_)));
+
+    for mut stream in refused {
+        let frame = network::read_frame(&mut stream, &[FrameKind::Error], 1024, None).await.unwrap();
+        assert!(matches!(frame, Some(Frame::Error(error)) if error.code == ErrorCode::Busy));
+    }
+    for mut stream in reset {
+        let frame = network::read_frame(&mut stream, &[FrameKind::Error], 1024, None).await.unwrap();
+        assert!(frame.is_none());
+    }
+    refusals.join_all().await;
+
+    // Refusals done, there is room to refuse again; served streams done, room to serve
+    assert!(matches!(limits.admit(peer), Admission::Refuse(_)));
+    served.clear();
+    assert!(matches!(limits.admit(peer), Admission::Open(_)));
+}
+
+#[test]
+fn streams_turned_away_leave_the_open_slots_alone() {
+    let limits = Arc::new(StreamLimits::new(4));
+
+    // Peers that can't start a transfer only ever count as refusals
+    let mut turned_away = Vec::new();
+    for _ in 0..MAX_REFUSING_STREAMS {
+        match limits.refuse() {
+            Admission::Refuse(slot) => turned_away.push(slot),
+            _ => panic!("refusal under the limit was not admitted"),
+        }
+    }
+    assert!(matches!(limits.refuse(), Admission::Reset));
+
+    // The receiver being served still has every one of its streams
+    let peer = PeerId::random();
+    let served: Vec<_> = (0..4).map(|_| limits.admit(peer)).collect();
+    assert!(served.iter().all(|admission| matches!(admission, Admission::Open(_))));
+
+    turned_away.clear();
+    assert!(matches!(limits.refuse(), Admission::Refuse(_)));
+}
+
+#[tokio::test]
+async fn file_streams_from_another_peer_are_dropped() {
+    const CHUNK_SIZE: usize = 4096;
+    let dir = common::workdir("foreign-file-stream");
+    let source = dir.join("source.bin");
+    let data = common::write_file(&source, 10 * CHUNK_SIZE + 7, 1);
+    let forged = dir.join("forged.bin");
+    common::write_file(&forged, data.len(), 2);
+    let target = dir.join("received.bin");
+    let response = common::response(&[(&target, data.len() as u64)], CHUNK_SIZE);
+
+    // The other peer's stream comes first, and would be taken for the file
+    let sender = PeerId::random();
+    let (mut foreign, foreign_end) = common::pipe(64 * 1024);
+    let (mut sending, sender_end) = common::pipe(64 * 1024);
+    let mut incoming = futures::stream::iter([(PeerId::random(), foreign_end), (sender, sender_end)]);
+
+    let (_pause, pause) = watch::channel(false);
+    let (events, mut reports) = tokio::sync::mpsc::unbounded_channel();
+    let settings = ReceiveSettings { events: Some(events), ..Default::default() };
+    let resume = ResumeRequest::default();
+    let cancel = CancellationToken::new();
+    let (forging, sent, received) = tokio::join!(
+        common::send_file(&mut foreign, &forged, 0, CHUNK_SIZE, common::wire(64)),
+        common::send_file(&mut sending, &source, 0, CHUNK_SIZE, common::wire(64)),
+        network::receive_files_over_streams(&mut incoming, sender, &response, &resume, &[], &settings, &cancel, &pause),
+    );
+    sent.unwrap();
+    assert!(forging.is_err(), "the other peer's stream was read");
+    let summary = received.unwrap();
+    assert_eq!(summary.statuses[0].status, FileStatus::Verified);
+    assert_eq!(std::fs::read(&target).unwrap(), data);
+
+    let mut refused = Vec::new();
+    while let Ok(event) = reports.try_recv() {
+        if let network::NetworkEvent::StreamRefused { peer } = event {
+            refused.push(peer);
+        }
+    }
+    assert_eq!(refused.len(), 1);
+    assert_ne!(refused[0], sender);
+}
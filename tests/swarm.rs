@@ -0,0 +1,90 @@
+// Listening: a real TCP swarm on an ephemeral port reports what it bound
+
+use fastdrop::network::{self, NetworkEvent};
+use fastdrop::protocol::TransportProtocol;
+use libp2p::identity::Keypair;
+use libp2p::multiaddr::Protocol;
+use libp2p::Multiaddr;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+
+/// A TCP swarm without UPnP listening on `addr`, port 0 for any free one
+fn tcp_swarm_on(addr: &str) -> libp2p::Swarm<network::FileTransferBehaviour> {
+    let mut swarm = network::build_swarm(Keypair::generate_ed25519(), TransportProtocol::Tcp, false).unwrap();
+    swarm.listen_on(addr.parse().unwrap()).unwrap();
+    swarm
+}
+
+fn tcp_port(addr: &Multiaddr) -> Option<u16> {
+    addr.iter().find_map(|p| match p {
+        Protocol::Tcp(port) => Some(port),
+        _ => None,
+    })
+}
+
+/// Every `Listening` event reported so far
+fn listening(reports: &mut mpsc::UnboundedReceiver<NetworkEvent>) -> Vec<(Multiaddr, bool)> {
+    let mut listening = Vec::new();
+    while let Ok(event) = reports.try_recv() {
+        if let NetworkEvent::Listening { address, usable } = event {
+            listening.push((address, usable));
+        }
+    }
+    listening
+}
+
+#[tokio::test]
+async fn bound_addresses_are_returned_with_their_port() {
+    let mut swarm = tcp_swarm_on("/ip4/0.0.0.0/tcp/0");
+    let (events, mut reports) = mpsc::unbounded_channel();
+
+    let addrs = network::listen_and_wait(&mut swarm, None, 1, Duration::from_secs(10), Some(&events))
+        .await
+        .unwrap();
+
+    assert!(!addrs.is_empty());
+    for addr in &addrs {
+        assert!(network::is_usable_listen_addr(addr), "{} should not be advertised", addr);
+        assert!(tcp_port(addr).is_some_and(|port| port != 0), "{} has no bound port", addr);
+    }
+    // All interfaces share the one ephemeral port
+    let port = tcp_port(&addrs[0]);
+    assert!(addrs.iter().all(|addr| tcp_port(addr) == port));
+
+    // Loopback is bound too, reported but kept out of the result
+    let listening = listening(&mut reports);
+    let loopback: Vec<_> = listening
+        .iter()
+        .filter(|(addr, _)| matches!(addr.iter().next(), Some(Protocol::Ip4(ip)) if ip.is_loopback()))
+        .collect();
+    assert!(!loopback.is_empty());
+    assert!(loopback.iter().all(|(addr, usable)| !usable && !addrs.contains(addr)));
+}
+
+#[tokio::test]
+async fn only_loopback_bound_is_an_error() {
+    let mut swarm = tcp_swarm_on("/ip4/127.0.0.1/tcp/0");
+    let (events, mut reports) = mpsc::unbounded_channel();
+
+    let result = network::listen_and_wait(&mut swarm, None, 1, Duration::from_secs(2), Some(&events)).await;
+
+    let error = format!("{:#}", result.unwrap_err());
+    assert!(error.contains("No usable listen addresses"), "{}", error);
+    let listening = listening(&mut reports);
+    assert_eq!(listening.len(), 1);
+    assert!(!listening[0].1);
+    assert!(tcp_port(&listening[0].0).is_some_and(|port| port != 0));
+}
+
+#[tokio::test]
+async fn waiting_for_more_addresses_than_exist_stops_at_the_timeout() {
+    let mut swarm = tcp_swarm_on("/ip4/0.0.0.0/tcp/0");
+    let timeout = Duration::from_secs(2);
+
+    let started = Instant::now();
+    let addrs = network::listen_and_wait(&mut swarm, None, 1000, timeout, None).await.unwrap();
+
+    assert!(started.elapsed() >= timeout);
+    assert!(started.elapsed() < timeout * 3);
+    assert!(!addrs.is_empty());
+}
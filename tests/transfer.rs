@@ -0,0 +1,437 @@
+// Both ends of the chunk transfer, run against each other over in-memory pipes
+
+mod common;
+
+use common::{InFlight, Metered, Severed};
+use fastdrop::network::{self, ChunkWire, NetworkEvent, ReceiveSummary, ACK_INTERVAL, DEFAULT_ACK_WINDOW};
+use fastdrop::protocol::{self, Capability, FileChunk, FileStatus, Frame, HashAlgo, ResumeRequest, TransferComplete};
+use futures::AsyncWriteExt;
+use fastdrop::transfer::{self, FileReceiver};
+use std::sync::Arc;
+use std::time::Duration;
+
+/* ========== Ack Window ========== */
+
+const CHUNK_SIZE: usize = 4096;
+
+/// A binary chunk frame is the data plus at most this much header
+const FRAME_OVERHEAD: u64 = 4 + 58 + 32;
+
+/// Send a file of `chunks` chunks to a receiver that sleeps before every read,
+/// returning the most bytes that waited in the pipe at once
+async fn send_to_slow_receiver(name: &str, chunks: usize, window: u64) -> u64 {
+    let dir = common::workdir(name);
+    let source = dir.join("source.bin");
+    let data = common::write_file(&source, chunks * CHUNK_SIZE, 1);
+    let target = dir.join("received.bin");
+    let response = common::response(&[(&target, data.len() as u64)], CHUNK_SIZE);
+
+    // Room for the whole file, so only the window holds the sender back
+    let (sending, receiving) = common::pipe(2 * data.len());
+    let in_flight = Arc::new(InFlight::default());
+    let mut sending = Metered::sending(sending, in_flight.clone());
+    let mut receiving = Metered::receiving(receiving, in_flight.clone(), Duration::from_millis(1));
+
+    let resume = ResumeRequest::default();
+    let (sent, received) = tokio::join!(
+        common::send_file(&mut sending, &source, 0, CHUNK_SIZE, common::wire(window)),
+        common::receive(&mut receiving, &response, &resume),
+    );
+    sent.unwrap();
+    let summary = received.unwrap();
+    assert_eq!(summary.statuses[0].status, FileStatus::Verified);
+    assert_eq!(std::fs::read(&target).unwrap(), data);
+    in_flight.max()
+}
+
+#[tokio::test]
+async fn window_holds_back_a_fast_sender() {
+    let window = 32;
+    let max = send_to_slow_receiver("window-fast-sender", 128, window).await;
+    let frame = CHUNK_SIZE as u64 + FRAME_OVERHEAD;
+    assert!(max <= window * frame, "{} bytes in flight with a window of {} chunks", max, window);
+    // The sender did run ahead of the receiver, as far as the window let it
+    assert!(max > ACK_INTERVAL * frame, "only {} bytes in flight", max);
+}
+
+#[tokio::test]
+async fn window_below_the_ack_interval_still_completes() {
+    // Acks only come every ACK_INTERVAL chunks, so a smaller window is raised to that
+    let max = send_to_slow_receiver("window-below-ack-interval", 40, 1).await;
+    let frame = CHUNK_SIZE as u64 + FRAME_OVERHEAD;
+    assert!(max <= ACK_INTERVAL * frame, "{} bytes in flight", max);
+}
+
+/* ========== Chunk Order ========== */
+
+fn chunk_of(data: &[u8], chunk_number: usize, chunk_size: usize) -> FileChunk {
+    let start = chunk_number * chunk_size;
+    let end = (start + chunk_size).min(data.len());
+    FileChunk {
+        file_index: 0,
+        chunk_number: chunk_number as u64,
+        total_chunks: data.len().div_ceil(chunk_size) as u64,
+        data: data[start..end].to_vec(),
+        file_hash: None,
+        crc32: None,
+        compressed: false,
+        uncompressed_len: 0,
+        chunk_size: chunk_size as u32,
+        offset: Some(start as u64),
+        nonce: None,
+    }
+}
+
+#[tokio::test]
+async fn chunks_written_in_shuffled_order_rebuild_the_file() {
+    let dir = common::workdir("shuffled");
+    // A short last chunk, so it isn't just whole chunks moving around
+    let data = common::contents(37 * 1000 + 123, 3);
+    let chunks = data.len().div_ceil(1000);
+    let hash = *blake3::hash(&data).as_bytes();
+
+    let target = dir.join("received.bin");
+    let mut receiver = FileReceiver::new(&target, 0, data.len() as u64).await.unwrap();
+    // 17 is coprime to the 38 chunks, so this visits each one once, scattered
+    for i in 0..chunks {
+        let chunk_number = (i * 17 + 5) % chunks;
+        receiver.write_chunk(chunk_of(&data, chunk_number, 1000)).await.unwrap();
+        assert_eq!(receiver.is_complete(), i + 1 == chunks);
+    }
+    // A chunk sent again changes nothing
+    receiver.write_chunk(chunk_of(&data, 4, 1000)).await.unwrap();
+    assert!(receiver.is_complete());
+
+    receiver.finalize(Some(hash), HashAlgo::Blake3).await.unwrap();
+    assert_eq!(std::fs::read(&target).unwrap(), data);
+}
+
+#[tokio::test]
+async fn streamed_chunks_in_shuffled_order_rebuild_the_file() {
+    let dir = common::workdir("shuffled-stream");
+    let data = common::contents(23 * 1000 + 45, 9);
+    let chunks = data.len().div_ceil(1000);
+    let target = dir.join("received.bin");
+    let response = common::response(&[(&target, data.len() as u64)], 1000);
+
+    // Room for everything either side writes, so neither waits on the other
+    let (mut sending, mut receiving) = common::pipe(4 * data.len());
+    // 7 is coprime to the 24 chunks, so this visits each one once, scattered
+    for i in 0..chunks {
+        let chunk = chunk_of(&data, (i * 7 + 3) % chunks, 1000);
+        network::write_frame(&mut sending, &Frame::Chunk(chunk)).await.unwrap();
+    }
+    sending.close().await.unwrap();
+
+    let summary = common::receive(&mut receiving, &response, &ResumeRequest::default()).await.unwrap();
+    assert_eq!(summary.completed.len(), 1);
+    assert!(summary.partial.is_empty());
+    assert_eq!(std::fs::read(&target).unwrap(), data);
+}
+
+#[tokio::test]
+async fn file_receiver_reports_instead_of_printing() {
+    let dir = common::workdir("receiver-events");
+    let data = common::contents(3 * 1000 + 10, 4);
+    let hash = *blake3::hash(&data).as_bytes();
+
+    let (events, mut reports) = tokio::sync::mpsc::unbounded_channel();
+    let target = dir.join("received.bin");
+    let mut receiver = FileReceiver::new(&target, 0, data.len() as u64).await.unwrap().with_events(events);
+    for chunk_number in [0, 1, 1, 2, 3] {
+        receiver.write_chunk(chunk_of(&data, chunk_number, 1000)).await.unwrap();
+    }
+    receiver.finalize(Some(hash), HashAlgo::Blake3).await.unwrap();
+
+    let mut received = Vec::new();
+    let mut duplicates = Vec::new();
+    let mut completed = None;
+    while let Some(event) = reports.recv().await {
+        match event {
+            NetworkEvent::ChunkReceived { chunk_number, bytes, .. } => received.push((chunk_number, bytes)),
+            NetworkEvent::DuplicateChunk { chunk_number, .. } => duplicates.push(chunk_number),
+            NetworkEvent::FileWritten { chunks, bytes, .. } => assert_eq!((chunks, bytes), (4, data.len() as u64)),
+            NetworkEvent::FileCompleted { status, .. } => completed = Some(status),
+            event => panic!("unexpected event {:?}", event),
+        }
+    }
+    assert_eq!(received, [(0, 1000), (1, 1000), (2, 1000), (3, 10)]);
+    assert_eq!(duplicates, [1]);
+    assert_eq!(completed, Some(FileStatus::Verified));
+}
+
+#[tokio::test]
+async fn empty_file_next_to_a_full_one_is_received() {
+    let dir = common::workdir("empty-file");
+    let empty = dir.join("empty.bin");
+    std::fs::write(&empty, b"").unwrap();
+    let full = dir.join("full.bin");
+    let data = common::write_file(&full, 3 * CHUNK_SIZE + 1, 7);
+    let empty_target = dir.join("empty-received.bin");
+    let full_target = dir.join("full-received.bin");
+    let response = common::response(&[(&empty_target, 0), (&full_target, data.len() as u64)], CHUNK_SIZE);
+
+    let (mut sending, mut receiving) = common::pipe(64 * 1024);
+    let resume = ResumeRequest::default();
+    let sources = [empty.as_path(), full.as_path()];
+    let (sent, received) = tokio::join!(
+        common::send_files(&mut sending, &sources, CHUNK_SIZE, common::wire(DEFAULT_ACK_WINDOW)),
+        common::receive(&mut receiving, &response, &resume),
+    );
+    sent.unwrap();
+    let mut summary = received.unwrap();
+    summary.note_not_started(&response.file_list, &[]);
+    assert!(summary.not_started.is_empty(), "{:?}", summary.not_started);
+    let receipt = summary.receipt(&response.file_list);
+    assert!(receipt.files.iter().all(|f| f.status == FileStatus::Verified), "{:?}", receipt.files);
+    assert_eq!(std::fs::read(&empty_target).unwrap(), b"");
+    assert_eq!(std::fs::read(&full_target).unwrap(), data);
+}
+
+#[tokio::test]
+async fn empty_files_an_older_sender_never_sends_are_created() {
+    let dir = common::workdir("empty-file-older-sender");
+    let full = dir.join("full.bin");
+    let data = common::write_file(&full, 2 * CHUNK_SIZE, 8);
+    let empty_target = dir.join("empty-received.bin");
+    let full_target = dir.join("full-received.bin");
+    let response = common::response(&[(&empty_target, 0), (&full_target, data.len() as u64)], CHUNK_SIZE);
+
+    // Only the full file goes out, as senders did before empty chunks
+    let (mut sending, mut receiving) = common::pipe(64 * 1024);
+    let resume = ResumeRequest::default();
+    let (sent, received) = tokio::join!(
+        common::send_file(&mut sending, &full, 1, CHUNK_SIZE, common::wire(DEFAULT_ACK_WINDOW)),
+        common::receive(&mut receiving, &response, &resume),
+    );
+    sent.unwrap();
+    let mut summary = received.unwrap();
+    network::create_empty_files(&response.file_list, &[], &mut summary).await.unwrap();
+    summary.note_not_started(&response.file_list, &[]);
+
+    assert!(summary.not_started.is_empty(), "{:?}", summary.not_started);
+    let receipt = summary.receipt(&response.file_list);
+    assert_eq!(receipt.files[0].status, FileStatus::Unverified);
+    assert_eq!(receipt.files[1].status, FileStatus::Verified);
+    assert_eq!(std::fs::read(&empty_target).unwrap(), b"");
+}
+
+/* ========== Closing ========== */
+
+/// How the sender's end of the stream goes away after the chunks
+enum Close {
+    /// After the end-of-transfer message
+    Clean,
+    /// Between two frames, with nothing said
+    Abrupt,
+    /// Partway into the frame of the next chunk
+    MidFrame,
+}
+
+/// Send the first `sent` chunks of a 10 chunk file, then close as `close`
+async fn close_after(name: &str, sent: usize, close: Close) -> (anyhow::Result<ReceiveSummary>, std::path::PathBuf) {
+    let dir = common::workdir(name);
+    let data = common::contents(10 * 1000, 5);
+    let target = dir.join("received.bin");
+    let response = common::response(&[(&target, data.len() as u64)], 1000);
+
+    // Room for everything either side writes, so neither waits on the other
+    let (mut sending, mut receiving) = common::pipe(4 * data.len());
+    for chunk_number in 0..sent {
+        network::write_frame(&mut sending, &Frame::Chunk(chunk_of(&data, chunk_number, 1000))).await.unwrap();
+    }
+    match close {
+        Close::Clean => {
+            let complete = TransferComplete { file_count: 1, total_bytes: data.len() as u64, file_hashes: vec![None] };
+            network::write_complete(&mut sending, complete).await.unwrap();
+        }
+        Close::Abrupt => {}
+        Close::MidFrame => {
+            let mut frame = futures::io::Cursor::new(Vec::new());
+            network::write_frame(&mut frame, &Frame::Chunk(chunk_of(&data, sent, 1000))).await.unwrap();
+            let frame = frame.into_inner();
+            sending.write_all(&frame[..frame.len() / 2]).await.unwrap();
+        }
+    }
+    // Only the sender's writing half closes; the receiver can still ack into it
+    sending.close().await.unwrap();
+
+    let summary = common::receive(&mut receiving, &response, &ResumeRequest::default()).await;
+    (summary, target)
+}
+
+#[tokio::test]
+async fn clean_close_completes_the_transfer() {
+    let (summary, target) = close_after("close-clean", 10, Close::Clean).await;
+    let summary = summary.unwrap();
+    assert_eq!(summary.completed.len(), 1);
+    assert!(summary.partial.is_empty());
+    assert!(summary.transfer_complete.is_some());
+    assert_eq!(std::fs::read(&target).unwrap(), common::contents(10 * 1000, 5));
+}
+
+#[tokio::test]
+async fn abrupt_close_keeps_the_file_partial() {
+    let (summary, target) = close_after("close-abrupt", 4, Close::Abrupt).await;
+    let summary = summary.unwrap();
+    assert!(summary.completed.is_empty());
+    assert_eq!(summary.partial.len(), 1);
+    assert!(summary.transfer_complete.is_none());
+    assert!(!target.exists());
+    assert!(transfer::partial_path(&target).exists());
+}
+
+#[tokio::test]
+async fn abrupt_close_after_the_last_chunk_is_not_a_finished_transfer() {
+    // The file is whole, but without the end-of-transfer message nothing vouches for the rest
+    let (summary, _) = close_after("close-abrupt-last", 10, Close::Abrupt).await;
+    let summary = summary.unwrap();
+    assert_eq!(summary.completed.len(), 1);
+    assert!(summary.transfer_complete.is_none());
+}
+
+#[tokio::test]
+async fn close_mid_frame_fails_the_transfer() {
+    let (summary, target) = close_after("close-mid-frame", 4, Close::MidFrame).await;
+    let error = format!("{:#}", summary.unwrap_err());
+    assert!(error.contains("Failed to read"), "{}", error);
+    assert!(!target.exists());
+}
+
+/* ========== Resume ========== */
+
+#[tokio::test]
+async fn transfer_cut_halfway_resumes_to_the_same_file() {
+    let dir = common::workdir("resume-after-cut");
+    let source = dir.join("source.bin");
+    let data = common::write_file(&source, 40 * CHUNK_SIZE + 123, 6);
+    let target = dir.join("received.bin");
+    let response = common::response(&[(&target, data.len() as u64)], CHUNK_SIZE);
+
+    // The connection drops right after the 20th chunk's frame
+    let frame = 4 + 58 + CHUNK_SIZE;
+    let (sending, mut receiving) = common::pipe(2 * data.len());
+    let mut sending = Severed::after(sending, 20 * frame);
+    let nothing = ResumeRequest::default();
+    let (sent, received) = tokio::join!(
+        common::send_file(&mut sending, &source, 0, CHUNK_SIZE, common::wire(DEFAULT_ACK_WINDOW)),
+        common::receive(&mut receiving, &response, &nothing),
+    );
+    assert!(sent.is_err());
+    let summary = received.unwrap();
+    assert!(summary.completed.is_empty());
+    assert_eq!(summary.partial.len(), 1);
+    drop((sending, receiving));
+
+    // The next session picks up from the .part file
+    let files = transfer::find_resume_points(&response.file_list, Some(CHUNK_SIZE)).await.unwrap();
+    assert_eq!(files.len(), 1);
+    assert_eq!(files[0].offset, 20 * CHUNK_SIZE as u64);
+    let resume = ResumeRequest { files };
+
+    let (mut sending, mut receiving) = common::pipe(2 * data.len());
+    let (sent, received) = tokio::join!(
+        common::send_file_from(&mut sending, &source, 0, CHUNK_SIZE, common::wire(DEFAULT_ACK_WINDOW), resume.get(0)),
+        common::receive(&mut receiving, &response, &resume),
+    );
+    sent.unwrap();
+    let summary = received.unwrap();
+    assert_eq!(summary.statuses[0].status, FileStatus::Verified);
+    assert_eq!(std::fs::read(&target).unwrap(), data);
+}
+
+#[tokio::test]
+async fn renamed_partial_files_never_replace_an_earlier_one() {
+    let dir = common::workdir("partial-rename");
+    let target = dir.join("received.bin");
+    let response = common::response(&[(&target, 100)], CHUNK_SIZE);
+    let renamed = |name: &str| dir.join(name);
+
+    // Two failed attempts, one after another, each leaving a .part file behind
+    for attempt in 0..2u8 {
+        std::fs::write(transfer::partial_path(&target), [attempt; 10]).unwrap();
+        transfer::handle_partial_files(&response.file_list, transfer::PartialPolicy::Rename).await.unwrap();
+    }
+    assert!(!transfer::partial_path(&target).exists());
+    assert_eq!(std::fs::read(renamed("received.bin.partial")).unwrap(), [0; 10]);
+    assert_eq!(std::fs::read(renamed("received.bin (1).partial")).unwrap(), [1; 10]);
+}
+
+/* ========== Retransmits ========== */
+
+#[tokio::test]
+async fn chunks_skipped_while_awaiting_a_resend_are_not_retransmits() {
+    let dir = common::workdir("skipped-not-retransmitted");
+    let data = common::contents(8 * 1000, 6);
+    let target = dir.join("received.bin");
+    let mut response = common::response(&[(&target, data.len() as u64)], 1000);
+    response.capabilities.push(protocol::CAP_RETRANSMIT.to_string());
+
+    let checked = |chunk_number| {
+        let mut chunk = chunk_of(&data, chunk_number, 1000);
+        chunk.crc32 = Some(crc32fast::hash(&chunk.data));
+        chunk
+    };
+    let mut corrupted = checked(1);
+    corrupted.data[0] ^= 0xff;
+
+    // Chunk 1 arrives corrupted with 2 and 3 in flight behind it, then the
+    // sender goes back to 1 as the receiver asked
+    let mut frames = vec![checked(0), corrupted, checked(2), checked(3)];
+    frames.extend((1..8).map(checked));
+    let (mut sending, mut receiving) = common::pipe(64 * 1024);
+    for chunk in frames {
+        network::write_frame(&mut sending, &Frame::Chunk(chunk)).await.unwrap();
+    }
+    sending.close().await.unwrap();
+
+    let summary = common::receive(&mut receiving, &response, &ResumeRequest::default()).await.unwrap();
+    assert_eq!(std::fs::read(&target).unwrap(), data);
+    assert_eq!(summary.stats.retransmits, 1);
+}
+
+/* ========== Capabilities ========== */
+
+/// Send a file to a receiver advertising `offered`, with the sender agreeing
+/// to what it knows of those and both sides going by the agreed list
+async fn send_agreeing_on(name: &str, offered: &[&str]) -> Vec<String> {
+    let dir = common::workdir(name);
+    let source = dir.join("source.bin");
+    let data = common::write_file(&source, 10 * CHUNK_SIZE + 7, 2);
+    let target = dir.join("received.bin");
+
+    let offered: Vec<String> = offered.iter().map(|c| c.to_string()).collect();
+    let mut response = common::response(&[(&target, data.len() as u64)], CHUNK_SIZE);
+    response.capabilities = protocol::common_capabilities(&offered);
+    let wire = ChunkWire {
+        window: DEFAULT_ACK_WINDOW,
+        retransmit: response.supports(Capability::Retransmit),
+        binary: response.supports(Capability::BinaryChunks),
+    };
+
+    let (mut sending, mut receiving) = common::pipe(64 * 1024);
+    let resume = ResumeRequest::default();
+    let (sent, received) = tokio::join!(
+        common::send_file(&mut sending, &source, 0, CHUNK_SIZE, wire),
+        common::receive(&mut receiving, &response, &resume),
+    );
+    sent.unwrap();
+    let summary = received.unwrap();
+    assert_eq!(summary.statuses[0].status, FileStatus::Verified);
+    assert_eq!(std::fs::read(&target).unwrap(), data);
+    response.capabilities
+}
+
+#[tokio::test]
+async fn older_receiver_gets_the_baseline_chunk_format() {
+    // Binary chunks and retransmits are left out of the agreement
+    let agreed = send_agreeing_on("older-receiver", &[protocol::CAP_CHUNK_ACK]).await;
+    assert_eq!(agreed, [protocol::CAP_CHUNK_ACK]);
+}
+
+#[tokio::test]
+async fn capabilities_the_sender_lacks_are_not_agreed() {
+    let offered = [protocol::CAP_BINARY_CHUNKS, "teleport-v9", protocol::CAP_RETRANSMIT];
+    let agreed = send_agreeing_on("newer-receiver", &offered).await;
+    assert_eq!(agreed, [protocol::CAP_BINARY_CHUNKS, protocol::CAP_RETRANSMIT]);
+}